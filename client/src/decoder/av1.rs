@@ -0,0 +1,421 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use dav1d::{PlanarImageComponent, Plane, Settings};
+use log::{debug, warn};
+use rtp::packet::Packet;
+
+use super::codec::{LVDepacketizer, LVVideoDecoder, LVYUVFrame};
+
+// Aggregation header bits, see https://aomediacodec.github.io/av1-rtp-spec/#44-av1-aggregation-header
+const AGGREGATION_Z: u8 = 0b1000_0000;
+const AGGREGATION_Y: u8 = 0b0100_0000;
+const AGGREGATION_W_SHIFT: u8 = 4;
+const AGGREGATION_N: u8 = 0b0000_1000;
+
+const OBU_HAS_EXTENSION_BIT: u8 = 0b0000_0100;
+const OBU_HAS_SIZE_BIT: u8 = 0b0000_0010;
+// The payloader strips these, the decoder wants one at the start of every temporal unit.
+const TEMPORAL_DELIMITER: [u8; 2] = [0x12, 0x00];
+
+fn read_leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, byte) in data.iter().enumerate().take(8) {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn put_leb128(out: &mut BytesMut, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.put_u8(byte);
+            return;
+        }
+        out.put_u8(byte | 0x80);
+    }
+}
+
+// Undoes the RTP payload format for AV1. OBUs come out the way a decoder expects them in a
+// low overhead bitstream: with their size fields back in and a temporal delimiter in front of
+// each temporal unit.
+#[derive(Default)]
+pub struct LVAv1Depacketizer {
+    // All packets of a temporal unit share an RTP timestamp.
+    timestamp: Option<u32>,
+    // To notice lost packets, a fragment that spans one can't be put back together.
+    sequence_number: Option<u16>,
+    // An OBU that's split across packets.
+    fragment: Vec<u8>,
+}
+
+impl LVAv1Depacketizer {
+    fn put_obu(out: &mut BytesMut, obu: &[u8]) {
+        if obu.is_empty() {
+            return;
+        }
+        let header_size = if obu[0] & OBU_HAS_EXTENSION_BIT != 0 {
+            2
+        } else {
+            1
+        };
+        if obu.len() < header_size {
+            warn!("dropping truncated OBU {:?}", obu);
+            return;
+        }
+        // The sender is meant to clear the size field, but take OBUs that kept it as is.
+        if obu[0] & OBU_HAS_SIZE_BIT != 0 {
+            out.put_slice(obu);
+            return;
+        }
+        out.put_u8(obu[0] | OBU_HAS_SIZE_BIT);
+        out.put_slice(&obu[1..header_size]);
+        put_leb128(out, obu.len() - header_size);
+        out.put_slice(&obu[header_size..]);
+    }
+}
+
+impl LVDepacketizer for LVAv1Depacketizer {
+    fn is_frame_start(&self, packet: &Packet) -> bool {
+        self.timestamp != Some(packet.header.timestamp)
+    }
+
+    fn depacketize(&mut self, packet: &Packet) -> Result<Bytes, Box<dyn std::error::Error>> {
+        let payload = &packet.payload;
+        if payload.is_empty() {
+            return Err("empty AV1 payload".into());
+        }
+
+        let sequence_number = packet.header.sequence_number;
+        if let Some(last) = self.sequence_number {
+            if sequence_number != last.wrapping_add(1) && !self.fragment.is_empty() {
+                debug!(
+                    "lost packets before {}, dropping {} bytes of unfinished OBU",
+                    sequence_number,
+                    self.fragment.len()
+                );
+                self.fragment.clear();
+            }
+        }
+        self.sequence_number = Some(sequence_number);
+
+        let mut out = BytesMut::new();
+        if self.is_frame_start(packet) {
+            // Whatever was left over belonged to a temporal unit we didn't get all of.
+            if !self.fragment.is_empty() {
+                debug!("dropping {} bytes of unfinished OBU", self.fragment.len());
+                self.fragment.clear();
+            }
+            out.put_slice(&TEMPORAL_DELIMITER);
+            self.timestamp = Some(packet.header.timestamp);
+        }
+
+        let header = payload[0];
+        let continues_fragment = header & AGGREGATION_Z != 0;
+        let ends_in_fragment = header & AGGREGATION_Y != 0;
+        let element_count = (header >> AGGREGATION_W_SHIFT) & 0b11;
+        if header & AGGREGATION_N != 0 {
+            debug!("packet {} starts a new sequence", sequence_number);
+        }
+
+        let mut data = &payload[1..];
+        let mut index = 0;
+        while !data.is_empty() {
+            index += 1;
+            // With W set, the last element has no size and runs to the end of the packet.
+            let element = if element_count != 0 && index == element_count {
+                std::mem::take(&mut data)
+            } else {
+                let (size, length) = read_leb128(data).ok_or("bad OBU element size")?;
+                if length + size > data.len() {
+                    return Err("OBU element runs past the end of the packet".into());
+                }
+                let element = &data[length..length + size];
+                data = &data[length + size..];
+                element
+            };
+
+            let is_first = index == 1;
+            let is_last = data.is_empty();
+            if is_first && continues_fragment {
+                // We lost the start of it, the rest is no use to the decoder.
+                if self.fragment.is_empty() {
+                    debug!("dropping the rest of an OBU we missed the start of");
+                    continue;
+                }
+                self.fragment.extend_from_slice(element);
+            } else {
+                if !self.fragment.is_empty() {
+                    warn!("OBU fragment was never finished, dropping it");
+                    self.fragment.clear();
+                }
+                self.fragment.extend_from_slice(element);
+            }
+
+            if !(is_last && ends_in_fragment) {
+                Self::put_obu(&mut out, &self.fragment);
+                self.fragment.clear();
+            }
+        }
+
+        Ok(out.freeze())
+    }
+}
+
+// AV1 decoding through dav1d.
+pub struct LVDav1dDecoder {
+    decoder: dav1d::Decoder,
+    // Kept around so the frame we hand out can borrow from them.
+    planes: Option<[Plane; 3]>,
+}
+
+impl LVDav1dDecoder {
+    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut settings = Settings::new();
+        // Hand out every frame as soon as it's decoded instead of filling a pipeline first.
+        settings.set_max_frame_delay(1);
+        Ok(Self {
+            decoder: dav1d::Decoder::with_settings(&settings)?,
+            planes: None,
+        })
+    }
+}
+
+impl LVVideoDecoder for LVDav1dDecoder {
+    fn decode(
+        &mut self,
+        data: &[u8],
+    ) -> Result<Option<LVYUVFrame<'_>>, Box<dyn std::error::Error>> {
+        match self.decoder.send_data(data.to_vec(), None, None, None) {
+            Ok(()) => {}
+            // The decoder wants its pictures taken out first, it keeps the data for later.
+            Err(dav1d::Error::Again) => debug!("dav1d has data pending"),
+            Err(e) => return Err(e.into()),
+        }
+
+        let picture = match self.decoder.get_picture() {
+            Ok(picture) => picture,
+            Err(dav1d::Error::Again) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if let Err(e) = self.decoder.send_pending_data() {
+            if !matches!(e, dav1d::Error::Again) {
+                return Err(e.into());
+            }
+        }
+        if picture.bit_depth() != 8 || picture.pixel_layout() != dav1d::PixelLayout::I420 {
+            return Err(format!(
+                "can't display {}-bit {:?} AV1",
+                picture.bit_depth(),
+                picture.pixel_layout()
+            )
+            .into());
+        }
+
+        let strides = [
+            picture.stride(PlanarImageComponent::Y) as usize,
+            picture.stride(PlanarImageComponent::U) as usize,
+            picture.stride(PlanarImageComponent::V) as usize,
+        ];
+        let (width, height) = (picture.width(), picture.height());
+        let [y, u, v] = self.planes.insert([
+            picture.plane(PlanarImageComponent::Y),
+            picture.plane(PlanarImageComponent::U),
+            picture.plane(PlanarImageComponent::V),
+        ]);
+
+        Ok(Some(LVYUVFrame {
+            width,
+            height,
+            y,
+            u,
+            v,
+            strides,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rtp::header::Header;
+
+    use super::*;
+
+    fn rtp_packet(sequence_number: u16, timestamp: u32, payload: &[u8]) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                timestamp,
+                ..Default::default()
+            },
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    // An OBU_FRAME with no extension and the size field cleared, as the payloader sends it.
+    fn obu(body: &[u8]) -> Vec<u8> {
+        [&[0x30][..], body].concat()
+    }
+
+    // The same OBU the way the decoder wants it, with its size.
+    fn sized_obu(body: &[u8]) -> Vec<u8> {
+        [&[0x32, body.len() as u8][..], body].concat()
+    }
+
+    #[test]
+    fn leb128_round_trips() {
+        for value in [0, 1, 127, 128, 300, 16_383, 16_384, u32::MAX as usize] {
+            let mut out = BytesMut::new();
+            put_leb128(&mut out, value);
+            assert_eq!(read_leb128(&out), Some((value, out.len())), "{}", value);
+        }
+        let mut out = BytesMut::new();
+        put_leb128(&mut out, 300);
+        assert_eq!(&out[..], [0xac, 0x02]);
+
+        // Trailing bytes are left alone, unterminated ones are an error
+        assert_eq!(read_leb128(&[0x05, 0xff]), Some((5, 1)));
+        assert_eq!(read_leb128(&[0x80, 0x80]), None);
+        assert_eq!(read_leb128(&[]), None);
+    }
+
+    #[test]
+    fn put_obu_adds_the_size_field() {
+        let mut out = BytesMut::new();
+        LVAv1Depacketizer::put_obu(&mut out, &obu(&[1, 2, 3]));
+        assert_eq!(&out[..], sized_obu(&[1, 2, 3]));
+
+        // The extension byte stays in front of the size
+        let mut out = BytesMut::new();
+        LVAv1Depacketizer::put_obu(&mut out, &[0x34, 0xe8, 9]);
+        assert_eq!(&out[..], [0x36, 0xe8, 1, 9]);
+
+        // OBUs that kept their size, empty ones and ones missing their extension byte
+        for (obu, expected) in [
+            (&[0x32, 1, 7][..], &[0x32, 1, 7][..]),
+            (&[], &[]),
+            (&[0x34], &[]),
+        ] {
+            let mut out = BytesMut::new();
+            LVAv1Depacketizer::put_obu(&mut out, obu);
+            assert_eq!(&out[..], expected);
+        }
+    }
+
+    #[test]
+    fn depacketizes_aggregated_obus() {
+        let mut depacketizer = LVAv1Depacketizer::default();
+        // W=2: the first OBU has a size, the second runs to the end
+        let first = obu(&[1, 2]);
+        let second = obu(&[3, 4, 5]);
+        let payload = [&[0x20, first.len() as u8][..], &first, &second].concat();
+        let packet = rtp_packet(1, 1000, &payload);
+        assert!(depacketizer.is_frame_start(&packet));
+        assert_eq!(
+            &depacketizer.depacketize(&packet).unwrap()[..],
+            [
+                &TEMPORAL_DELIMITER[..],
+                &sized_obu(&[1, 2]),
+                &sized_obu(&[3, 4, 5])
+            ]
+            .concat()
+        );
+
+        // W=0: every OBU has a size. Same timestamp, so no temporal delimiter
+        let payload = [&[0x00, second.len() as u8][..], &second].concat();
+        let packet = rtp_packet(2, 1000, &payload);
+        assert!(!depacketizer.is_frame_start(&packet));
+        assert_eq!(
+            &depacketizer.depacketize(&packet).unwrap()[..],
+            sized_obu(&[3, 4, 5])
+        );
+
+        assert!(depacketizer.depacketize(&rtp_packet(3, 1000, &[])).is_err());
+        assert!(depacketizer
+            .depacketize(&rtp_packet(4, 1000, &[0x00, 10, 0x30]))
+            .is_err());
+    }
+
+    #[test]
+    fn joins_fragmented_obus() {
+        let mut depacketizer = LVAv1Depacketizer::default();
+        let whole = obu(&[1, 2, 3, 4, 5, 6]);
+        // Y: the OBU continues in the next packet, Z: it continues from the previous one
+        let start = [&[0x50][..], &whole[..3]].concat();
+        let middle = [&[0xd0][..], &whole[3..5]].concat();
+        let end = [&[0x90][..], &whole[5..]].concat();
+
+        assert_eq!(
+            &depacketizer
+                .depacketize(&rtp_packet(10, 2000, &start))
+                .unwrap()[..],
+            TEMPORAL_DELIMITER
+        );
+        assert!(depacketizer
+            .depacketize(&rtp_packet(11, 2000, &middle))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            &depacketizer
+                .depacketize(&rtp_packet(12, 2000, &end))
+                .unwrap()[..],
+            sized_obu(&[1, 2, 3, 4, 5, 6])
+        );
+    }
+
+    #[test]
+    fn drops_fragments_across_lost_packets() {
+        let mut depacketizer = LVAv1Depacketizer::default();
+        let whole = obu(&[1, 2, 3, 4, 5, 6]);
+        let start = [&[0x50][..], &whole[..3]].concat();
+        let middle = [&[0xd0][..], &whole[3..5]].concat();
+        let end = [&[0x90][..], &whole[5..]].concat();
+
+        // The middle went missing, gluing start and end together would be a corrupt OBU
+        depacketizer
+            .depacketize(&rtp_packet(u16::MAX, 3000, &start))
+            .unwrap();
+        assert!(depacketizer
+            .depacketize(&rtp_packet(1, 3000, &end))
+            .unwrap()
+            .is_empty());
+
+        // Sequence numbers wrap, that's not a loss
+        depacketizer
+            .depacketize(&rtp_packet(2, 3000, &start))
+            .unwrap();
+        depacketizer
+            .depacketize(&rtp_packet(3, 3000, &middle))
+            .unwrap();
+        assert_eq!(
+            &depacketizer
+                .depacketize(&rtp_packet(4, 3000, &end))
+                .unwrap()[..],
+            sized_obu(&[1, 2, 3, 4, 5, 6])
+        );
+        depacketizer
+            .depacketize(&rtp_packet(u16::MAX, 4000, &start))
+            .unwrap();
+        depacketizer
+            .depacketize(&rtp_packet(0, 4000, &middle))
+            .unwrap();
+        assert_eq!(
+            &depacketizer
+                .depacketize(&rtp_packet(1, 4000, &end))
+                .unwrap()[..],
+            sized_obu(&[1, 2, 3, 4, 5, 6])
+        );
+
+        // A loss with nothing half finished doesn't throw away whole OBUs
+        let single = [&[0x10][..], &obu(&[7])].concat();
+        assert_eq!(
+            &depacketizer
+                .depacketize(&rtp_packet(9, 4000, &single))
+                .unwrap()[..],
+            sized_obu(&[7])
+        );
+    }
+}
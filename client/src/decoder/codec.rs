@@ -0,0 +1,99 @@
+use bytes::Bytes;
+use log::error;
+use net::codec::LVCodec;
+use openh264::{
+    decoder::{Decoder, DecoderConfig},
+    formats::YUVSource,
+};
+use rtp::{codecs::h264::H264Packet, packet::Packet, packetizer::Depacketizer};
+
+#[cfg(feature = "av1")]
+use super::av1::{LVAv1Depacketizer, LVDav1dDecoder};
+
+// A decoded I420 picture, borrowed from whichever decoder produced it.
+pub struct LVYUVFrame<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub y: &'a [u8],
+    pub u: &'a [u8],
+    pub v: &'a [u8],
+    pub strides: [usize; 3],
+}
+
+// Turns RTP packets back into the codec's bitstream.
+pub trait LVDepacketizer: Send {
+    // Whether this packet starts a new chunk of bitstream, so whatever came before it can be
+    // decoded.
+    fn is_frame_start(&self, packet: &Packet) -> bool;
+    fn depacketize(&mut self, packet: &Packet) -> Result<Bytes, Box<dyn std::error::Error>>;
+}
+
+pub trait LVVideoDecoder {
+    // Returns None if the decoder needs more data before it has a picture.
+    fn decode(&mut self, data: &[u8])
+        -> Result<Option<LVYUVFrame<'_>>, Box<dyn std::error::Error>>;
+}
+
+pub fn new_depacketizer(
+    codec: LVCodec,
+) -> Result<Box<dyn LVDepacketizer>, Box<dyn std::error::Error>> {
+    match codec {
+        LVCodec::H264 => Ok(Box::<H264Packet>::default()),
+        #[cfg(feature = "av1")]
+        LVCodec::Av1 => Ok(Box::<LVAv1Depacketizer>::default()),
+        #[allow(unreachable_patterns)]
+        codec => Err(format!("built without support for {:?}", codec).into()),
+    }
+}
+
+pub fn new_decoder(codec: LVCodec) -> Result<Box<dyn LVVideoDecoder>, Box<dyn std::error::Error>> {
+    match codec {
+        LVCodec::H264 => Ok(Box::new(Decoder::with_config(
+            DecoderConfig::new().debug(true),
+        )?)),
+        #[cfg(feature = "av1")]
+        LVCodec::Av1 => Ok(Box::new(LVDav1dDecoder::new()?)),
+        #[allow(unreachable_patterns)]
+        codec => Err(format!("built without support for {:?}", codec).into()),
+    }
+}
+
+impl LVDepacketizer for H264Packet {
+    fn is_frame_start(&self, packet: &Packet) -> bool {
+        self.is_partition_head(&packet.payload)
+    }
+
+    fn depacketize(&mut self, packet: &Packet) -> Result<Bytes, Box<dyn std::error::Error>> {
+        Ok(Depacketizer::depacketize(self, &packet.payload)?)
+    }
+}
+
+impl LVVideoDecoder for Decoder {
+    fn decode(
+        &mut self,
+        data: &[u8],
+    ) -> Result<Option<LVYUVFrame<'_>>, Box<dyn std::error::Error>> {
+        match Decoder::decode(self, data) {
+            Ok(Some(yuv)) => {
+                let strides = yuv.strides_yuv();
+                Ok(Some(LVYUVFrame {
+                    // openh264 pads its planes, we've always used the stride as the width.
+                    width: strides.0 as u32,
+                    height: yuv.height() as u32,
+                    // The plain accessors borrow from yuv, these borrow from the decoder.
+                    y: yuv.y_with_stride(),
+                    u: yuv.u_with_stride(),
+                    v: yuv.v_with_stride(),
+                    strides: [strides.0, strides.1, strides.2],
+                }))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                if let Some(bt) = e.backtrace() {
+                    error!("backtrace: {}", bt);
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
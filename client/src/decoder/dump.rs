@@ -0,0 +1,154 @@
+// Dumps the stream as it reached the decoder into a file, so a session can be looked at (or
+// played back) later. The access units go in untouched, timestamped with when their last
+// packet arrived, so the file also shows the network's jitter.
+
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use log::{info, warn};
+use net::{
+    codec::LVCodec,
+    container::{self, LVContainerFormat, LVMuxer, LVTrackInfo},
+};
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+
+struct LVDumpFile {
+    muxer: Box<dyn LVMuxer>,
+    codec: LVCodec,
+    width: u32,
+    height: u32,
+    // Arrival time (us on our clock) of the first access unit
+    first_us: u64,
+    last_us: u64,
+}
+
+pub struct LVStreamDump {
+    path: PathBuf,
+    format: LVContainerFormat,
+    // The stream changing codec or size starts another file
+    file: Option<LVDumpFile>,
+    warned_av1: bool,
+}
+
+impl LVStreamDump {
+    // LV_DUMP=PATH, .mp4 or .mkv. Like recordings, the files get the time they started at added
+    // to their name.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(path) = std::env::var("LV_DUMP") else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(path);
+        let format = LVContainerFormat::from_path(&path)
+            .ok_or_else(|| format!("can't tell the format of {:?}, use .mp4 or .mkv", path))?;
+        LVStatisticsCollector::register_data("client_dump_bytes", LVDataType::TimeSeries);
+        info!("dumping the received stream to {:?}", path);
+        Ok(Some(Self {
+            path,
+            format,
+            file: None,
+            warned_av1: false,
+        }))
+    }
+
+    // Takes a whole access unit as the depacketizer put it together. Nothing is written until
+    // the first keyframe, the file has to start with one.
+    pub fn write_frame(
+        &mut self,
+        codec: LVCodec,
+        data: &[u8],
+        recv_us: u64,
+        width: u32,
+        height: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self
+            .file
+            .as_ref()
+            .is_some_and(|file| file.codec != codec || file.width != width || file.height != height)
+        {
+            self.finish();
+        }
+
+        let (sample, keyframe, parameter_sets) = container::sample(codec, data);
+        if self.file.is_none() {
+            // Don't know the size before the first frame decoded
+            if !keyframe || width == 0 || height == 0 {
+                return Ok(());
+            }
+            // The av1C comes from the encoder, there's nothing to build it from here.
+            if codec == LVCodec::Av1 {
+                if !std::mem::replace(&mut self.warned_av1, true) {
+                    warn!("can't dump AV1 streams, only H.264");
+                }
+                return Ok(());
+            }
+            let Some(codec_config) =
+                parameter_sets.and_then(|(sps, pps)| container::avcc(sps, pps))
+            else {
+                warn!("keyframe without SPS and PPS, can't start a dump");
+                return Ok(());
+            };
+            self.start(
+                LVTrackInfo {
+                    codec,
+                    width,
+                    height,
+                    codec_config,
+                },
+                recv_us,
+            )?;
+        }
+
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        let recv_us = recv_us.max(file.last_us);
+        file.muxer
+            .write_frame(&sample, recv_us - file.first_us, keyframe)?;
+        file.last_us = recv_us;
+        LVStatisticsCollector::update_data(
+            "client_dump_bytes",
+            LVDataPoint::FloatValue(sample.len() as f32),
+        );
+        Ok(())
+    }
+
+    fn start(
+        &mut self,
+        track: LVTrackInfo,
+        first_us: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let path = container::file_path(&self.path);
+        let out = BufWriter::new(File::create(&path)?);
+        let muxer = container::new_muxer(self.format, out, &track)?;
+        info!(
+            "dumping {:?} {}x{} to {:?}",
+            track.codec, track.width, track.height, path
+        );
+        self.file = Some(LVDumpFile {
+            muxer,
+            codec: track.codec,
+            width: track.width,
+            height: track.height,
+            first_us,
+            last_us: first_us,
+        });
+        Ok(())
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(mut file) = self.file.take() {
+            match file.muxer.finish() {
+                Ok(()) => info!("finished dump, {} bytes", file.muxer.bytes_written()),
+                Err(e) => warn!("failed to finish dump: {:?}", e),
+            }
+        }
+    }
+}
+
+impl Drop for LVStreamDump {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
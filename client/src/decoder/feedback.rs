@@ -3,40 +3,218 @@ use std::{
     net::{TcpListener, TcpStream},
     sync::Arc,
     thread,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant},
 };
 
-use log::{debug, error};
-use net::feedback_packet::{LVAck, LVFeedbackPacket, ACK_TYPE, FEEDBACK_TYPE};
+use flume::RecvTimeoutError;
+use log::{debug, error, info};
+use net::{
+    clock::{self, LVClockSync, LVClockSyncPacket},
+    control_packet::{LVControlPacket, LVHandshake},
+    feedback_packet::{
+        LVAck, LVFeedbackPacket, LVMonitorSwitch, LVReferenceInvalidation, LVRefineAck, ACK_TYPE,
+        CLOCK_SYNC_REPLY_TYPE, CLOCK_SYNC_TYPE, FEEDBACK_TYPE, MONITOR_SWITCH_TYPE,
+        RECORD_TOGGLE_TYPE, REFERENCE_INVALIDATION_TYPE, REFINE_ACK_TYPE, REFINE_LOSS_TYPE,
+    },
+};
 use parking_lot::Mutex;
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
 
 const QUANTUM: u16 = 1000;
 
+// Messages other parts of the client want sent to the server right away, rather than on the
+// next feedback tick.
+#[derive(Debug)]
+pub enum LVFeedbackMessage {
+    MonitorSwitch(LVMonitorSwitch),
+    // Our half of a clock sync exchange the server started
+    ClockSyncReply(LVClockSyncPacket),
+    // We lost a frame, re-sync from the last one we have
+    ReferenceInvalidation(LVReferenceInvalidation),
+    // Start recording on the server, or stop if it already is
+    RecordToggle,
+    // We're drawing the refined tile with this id
+    RefineAck(LVRefineAck),
+    // We missed refinement packets and dropped our refined tiles
+    RefineLoss,
+}
+
+impl LVFeedbackMessage {
+    fn write_to(&self, stream: &mut TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+        let data = match self {
+            LVFeedbackMessage::MonitorSwitch(switch) => {
+                let mut data = bincode::serialize(switch)?;
+                data.insert(0, MONITOR_SWITCH_TYPE);
+                data
+            }
+            LVFeedbackMessage::ClockSyncReply(reply) => {
+                // Stamped as late as possible so time spent in the queue isn't counted as
+                // network delay.
+                let reply = LVClockSyncPacket {
+                    transmit_ts: clock::now_us(),
+                    ..*reply
+                };
+                let mut data = bincode::serialize(&reply)?;
+                data.insert(0, CLOCK_SYNC_REPLY_TYPE);
+                data
+            }
+            LVFeedbackMessage::ReferenceInvalidation(invalidation) => {
+                let mut data = bincode::serialize(invalidation)?;
+                data.insert(0, REFERENCE_INVALIDATION_TYPE);
+                data
+            }
+            LVFeedbackMessage::RecordToggle => vec![RECORD_TOGGLE_TYPE],
+            LVFeedbackMessage::RefineAck(ack) => {
+                let mut data = bincode::serialize(ack)?;
+                data.insert(0, REFINE_ACK_TYPE);
+                data
+            }
+            LVFeedbackMessage::RefineLoss => vec![REFINE_LOSS_TYPE],
+        };
+        stream.write_all(&data)?;
+        Ok(())
+    }
+}
+
+// Reads what the server sends back over the feedback connection.
+fn start_control_reader(
+    mut stream: TcpStream,
+    handshake: Arc<Mutex<Option<LVHandshake>>>,
+    framerate: Arc<Mutex<Option<u32>>>,
+    feedback_send: flume::Sender<LVFeedbackMessage>,
+    clock: Arc<Mutex<LVClockSync>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    thread::Builder::new()
+        .name("control_thread".to_string())
+        .spawn(move || loop {
+            let packet = LVControlPacket::read_from(&mut stream);
+            let recv_ts = clock::now_us();
+            match packet {
+                Ok(LVControlPacket::Handshake(new_handshake)) => {
+                    info!(
+                        "server has {} monitor(s), streaming {}",
+                        new_handshake.monitors.len(),
+                        new_handshake.current_monitor
+                    );
+                    for (i, monitor) in new_handshake.monitors.iter().enumerate() {
+                        info!("monitor {}: {:?}", i, monitor);
+                    }
+                    *handshake.lock() = Some(new_handshake);
+                }
+                Ok(LVControlPacket::Framerate(fps)) => {
+                    info!("server is streaming at {} fps", fps);
+                    *framerate.lock() = Some(fps);
+                }
+                Ok(LVControlPacket::Encoder(backend)) => {
+                    info!("server is encoding with {}", backend);
+                }
+                Ok(LVControlPacket::ClockSyncReply(reply)) => {
+                    let mut clock = clock.lock();
+                    clock.add_sample(&reply, recv_ts);
+                    if let Some(offset) = clock.offset_us(recv_ts) {
+                        debug!(
+                            "server clock is {}us off ours, drifting {:.2}ppm",
+                            offset,
+                            clock.drift_ppm()
+                        );
+                        LVStatisticsCollector::update_data(
+                            "client_clock_offset",
+                            LVDataPoint::FloatValue(offset as f32 / 1000.),
+                        );
+                        LVStatisticsCollector::update_data(
+                            "client_clock_drift",
+                            LVDataPoint::FloatValue(clock.drift_ppm() as f32),
+                        );
+                    }
+                }
+                Ok(LVControlPacket::ClockSyncRequest(request)) => {
+                    let reply = LVClockSyncPacket {
+                        origin_ts: request.origin_ts,
+                        recv_ts,
+                        transmit_ts: 0,
+                    };
+                    if let Err(e) = feedback_send.try_send(LVFeedbackMessage::ClockSyncReply(reply))
+                    {
+                        error!("failed to queue clock sync reply {:?}", e);
+                    }
+                }
+                Err(e) => {
+                    error!("failed to read control packet, stopping {:?}", e);
+                    return;
+                }
+            }
+        })?;
+    Ok(())
+}
+
 pub fn start(
     feedback_addr: &str,
     feedback_pkt: Arc<Mutex<(LVAck, LVFeedbackPacket)>>,
+    handshake: Arc<Mutex<Option<LVHandshake>>>,
+    framerate: Arc<Mutex<Option<u32>>>,
+    feedback_send: flume::Sender<LVFeedbackMessage>,
+    feedback_recv: flume::Receiver<LVFeedbackMessage>,
+    clock: Arc<Mutex<LVClockSync>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    LVStatisticsCollector::register_data("client_clock_offset", LVDataType::TimeSeries);
+    LVStatisticsCollector::register_data("client_clock_drift", LVDataType::TimeSeries);
+
     let feedback_addr = feedback_addr.to_owned();
     let x = TcpListener::bind(feedback_addr)?;
     thread::spawn(move || {
-        let feedback_timer = timer::Timer::new();
-
         debug!("connecting to feedback server...");
 
         for feedback_stream in x.incoming() {
             match feedback_stream {
                 Ok(mut feedback_stream) => {
                     debug!("connected to feedback server");
+                    match feedback_stream.try_clone() {
+                        Ok(reader) => {
+                            if let Err(e) = start_control_reader(
+                                reader,
+                                handshake.clone(),
+                                framerate.clone(),
+                                feedback_send.clone(),
+                                clock.clone(),
+                            ) {
+                                error!("failed to start control reader {:?}", e);
+                            }
+                        }
+                        Err(e) => error!("failed to clone feedback stream {:?}", e),
+                    }
+
+                    let quantum = Duration::from_millis(QUANTUM.into());
+                    let mut last_feedback = Instant::now()
+                        .checked_sub(quantum)
+                        .unwrap_or_else(Instant::now);
                     loop {
+                        // Send anything urgent as soon as it shows up, and the regular
+                        // feedback once per quantum.
+                        let until_feedback = quantum.saturating_sub(last_feedback.elapsed());
+                        match feedback_recv.recv_timeout(until_feedback) {
+                            Ok(msg) => {
+                                debug!("sending {:?} to server", msg);
+                                if let Err(e) = msg.write_to(&mut feedback_stream) {
+                                    error!("failed to send {:?} with error {:?}", msg, e);
+                                }
+                                continue;
+                            }
+                            Err(RecvTimeoutError::Timeout) => {}
+                            Err(RecvTimeoutError::Disconnected) => thread::sleep(until_feedback),
+                        }
+                        last_feedback = Instant::now();
+
                         {
                             debug!("writing feedback packet to server");
                             let mut pkt = feedback_pkt.lock();
                             debug!("feebdback packet is {:?}", pkt);
 
                             // Copying *rolls eyes*
-                            // NOTE: one day this will come and byte us (haha get it) because
-                            // we don't pad this and sizeof feedback is 17 but the server is always reading 19 bytes of data
-                            // because of the ACK_TYPE. we should probably pad this but it works right now and I'm lazy.
+                            // The server reads the type byte and then exactly as many bytes as
+                            // that packet type needs, so no padding is necessary.
                             let mut data: Vec<u8> = bincode::serialize(&pkt.1).unwrap();
                             debug!(
                                 "feedback packet after serialization is {:?} and len is {}",
@@ -60,7 +238,11 @@ pub fn start(
                             pkt.1.lost_packets = 0;
                             pkt.1.ecc_decoder_failures = 0;
 
-                            // send the ACK packet, which was already populated and always gets rewriten, so we don't have to write anything.
+                            // send the ACK packet, which was already populated and always gets rewriten, so we only fill in how long it waited for us.
+                            if pkt.0.recv_ts != 0 {
+                                pkt.0.hold_us =
+                                    clock::now_us().saturating_sub(pkt.0.recv_ts) as u32;
+                            }
 
                             let mut data: Vec<u8> = bincode::serialize(&pkt.0).unwrap();
                             data.insert(0, ACK_TYPE);
@@ -78,7 +260,16 @@ pub fn start(
                             // no need to reset the ACK as we just set it the next time.
                         }
 
-                        thread::sleep(std::time::Duration::from_millis(QUANTUM.into()));
+                        // And check on the server's clock.
+                        let request = LVClockSyncPacket {
+                            origin_ts: clock::now_us(),
+                            ..Default::default()
+                        };
+                        let mut data: Vec<u8> = bincode::serialize(&request).unwrap();
+                        data.insert(0, CLOCK_SYNC_TYPE);
+                        if let Err(e) = feedback_stream.write_all(&data) {
+                            error!("failed to send clock sync request with error {:?}", e)
+                        }
                     }
                 }
                 Err(e) => error!("Failed to unwrap feedback stream! {:?}", e),
@@ -1,4 +1,11 @@
+#[cfg(feature = "av1")]
+pub mod av1;
+pub mod codec;
+pub mod dump;
 pub mod feedback;
 pub mod input;
 pub mod network;
+pub mod pcap;
+pub mod refine;
+pub mod replay;
 pub mod video;
@@ -3,15 +3,19 @@ use std::{
     io::Write,
     net::{SocketAddrV4, TcpStream, UdpSocket},
     os::fd::{AsRawFd, RawFd},
+    path::Path,
     sync::Arc,
     thread,
     time::Instant,
 };
 
 use bytes::BytesMut;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use net::{
+    clock::{self, LVClockSync},
+    control_packet::LVHandshake,
     feedback_packet::{LVAck, LVFeedbackPacket},
+    impair::{LVImpairedSocket, LVImpairment},
     input::LVInputEvent,
 };
 use parking_lot::{Mutex, RwLock};
@@ -20,7 +24,10 @@ use thingbuf::mpsc::{blocking::Sender, errors::Closed};
 
 use crate::decoder::input;
 
-use super::feedback;
+use super::{
+    feedback::{self, LVFeedbackMessage},
+    pcap::LVPcapWriter,
+};
 
 const MTU_SIZE: usize = 1200;
 
@@ -32,6 +39,8 @@ pub struct LVNetwork {
 pub struct LVPacketHolder {
     pub payload: BytesMut,
     pub amt: usize,
+    // When the packet came off the socket, in microseconds since the UNIX epoch
+    pub recv_us: u64,
 }
 
 impl Default for LVPacketHolder {
@@ -43,6 +52,7 @@ impl Default for LVPacketHolder {
                 bm
             },
             amt: 0,
+            recv_us: 0,
         }
     }
 }
@@ -61,32 +71,15 @@ impl LVNetwork {
         inp_recv: flume::Receiver<LVInputEvent>,
         feedback_pkt: Arc<Mutex<(LVAck, LVFeedbackPacket)>>,
         udp_fd: Arc<RwLock<Option<RawFd>>>,
+        handshake: Arc<Mutex<Option<LVHandshake>>>,
+        framerate: Arc<Mutex<Option<u32>>>,
+        feedback_send: flume::Sender<LVFeedbackMessage>,
+        feedback_recv: flume::Receiver<LVFeedbackMessage>,
+        clock: Arc<Mutex<LVClockSync>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        // Everything is bound by the time this returns, so the server can connect right away.
         let addr = self.addr.clone();
-
-        thread::Builder::new()
-            .name("network_thread".to_string())
-            .spawn(move || {
-                if let Err(e) =
-                    Self::socket_loop(packet_push, inp_recv, feedback_pkt, &addr, udp_fd)
-                {
-                    error!("socket receive loop failed with error {:?}", e);
-                } else {
-                    info!("socket receive loop exited.");
-                }
-            });
-
-        Ok(())
-    }
-
-    fn socket_loop(
-        packet_push: Sender<LVPacketHolder>,
-        inp_recv: flume::Receiver<LVInputEvent>,
-        feedback_pkt: Arc<Mutex<(LVAck, LVFeedbackPacket)>>,
-        addr: &str,
-        udp_fd: Arc<RwLock<Option<RawFd>>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let sock = UdpSocket::bind(addr)?;
+        let sock = UdpSocket::bind(&addr)?;
         let sock = Socket::from(sock);
 
         debug!("current recv size {:?}", sock.recv_buffer_size());
@@ -95,6 +88,8 @@ impl LVNetwork {
 
         let sock: UdpSocket = sock.into();
         *udp_fd.write() = Some(sock.as_raw_fd());
+        // LV_IMPAIR puts an emulated bad link in front of us
+        let sock = LVImpairedSocket::new(sock, LVImpairment::from_env()?);
 
         debug!("starting thread for socket, listening on {}", addr);
 
@@ -104,7 +99,15 @@ impl LVNetwork {
         debug!("initializing feedback server to {:?}", feedback_addr);
 
         // TODO: don't fail so loudly.
-        feedback::start(&feedback_addr.to_string(), feedback_pkt.clone())?;
+        feedback::start(
+            &feedback_addr.to_string(),
+            feedback_pkt.clone(),
+            handshake,
+            framerate,
+            feedback_send,
+            feedback_recv,
+            clock,
+        )?;
 
         // Input setup
         let mut input_bind_addr = feedback_addr.clone();
@@ -114,14 +117,51 @@ impl LVNetwork {
 
         input::start(input_bind_addr, inp_recv)?;
 
+        thread::Builder::new()
+            .name("network_thread".to_string())
+            .spawn(move || {
+                if let Err(e) = Self::socket_loop(packet_push, sock, &addr) {
+                    error!("socket receive loop failed with error {:?}", e);
+                } else {
+                    info!("socket receive loop exited.");
+                }
+            })?;
+
+        Ok(())
+    }
+
+    fn socket_loop(
+        packet_push: Sender<LVPacketHolder>,
+        sock: LVImpairedSocket,
+        addr: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // LV_CAPTURE=PATH keeps every datagram we get, for replaying with --replay.
+        let mut capture = match std::env::var("LV_CAPTURE") {
+            Ok(path) => {
+                info!("capturing received packets to {}", path);
+                Some(LVPcapWriter::create(Path::new(&path), addr.parse()?)?)
+            }
+            Err(_) => None,
+        };
+
         loop {
             // By using the packet push, we avoid allocating on the heap every iteration.
             match packet_push.try_send_ref() {
                 Ok(mut data_ref) => {
                     let (amt, src) = sock.recv_from(&mut data_ref.payload)?;
                     data_ref.amt = amt;
+                    data_ref.recv_us = clock::now_us();
 
                     debug!("recv received {} bytes from {}", amt, src);
+
+                    if let Some(writer) = capture.as_mut() {
+                        if let Err(e) =
+                            writer.write(&data_ref.payload[..amt], src, data_ref.recv_us)
+                        {
+                            warn!("failed to capture packet, stopping the capture: {:?}", e);
+                            capture = None;
+                        }
+                    }
                 }
                 Err(e) => error!("thingbuf try_send_ref returns {:?}", e),
             }
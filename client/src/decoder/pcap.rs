@@ -0,0 +1,328 @@
+// Packet captures of the video stream, in the classic pcap format so Wireshark opens them too.
+// The datagrams are written as raw IPv4 + UDP packets with the time they came off the socket,
+// and read back the same way for replaying.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+// Microsecond timestamps, written in our byte order
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_VERSION: (u16, u16) = (2, 4);
+const SNAPLEN: u32 = 65535;
+// Packets start at the IP header, no link layer
+const LINKTYPE_RAW: u32 = 101;
+
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+
+// Buffered records are written out at least this often, so a capture cut off by the client
+// getting killed loses at most this much.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct LVPcapWriter {
+    out: BufWriter<File>,
+    // Where the datagrams were sent to, our socket
+    local: SocketAddrV4,
+    last_flush: Instant,
+}
+
+impl LVPcapWriter {
+    pub fn create(path: &Path, local: SocketAddrV4) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&PCAP_MAGIC.to_ne_bytes())?;
+        out.write_all(&PCAP_VERSION.0.to_ne_bytes())?;
+        out.write_all(&PCAP_VERSION.1.to_ne_bytes())?;
+        // thiszone, sigfigs, snaplen, network
+        for field in [0, 0, SNAPLEN, LINKTYPE_RAW] {
+            out.write_all(&field.to_ne_bytes())?;
+        }
+        Ok(Self {
+            out,
+            local,
+            last_flush: Instant::now(),
+        })
+    }
+
+    // recv_us is when the datagram arrived, in microseconds since the UNIX epoch.
+    pub fn write(
+        &mut self,
+        data: &[u8],
+        src: SocketAddr,
+        recv_us: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // We only ever bind IPv4, but don't give up on the capture if that changes.
+        let src = match src {
+            SocketAddr::V4(src) => src,
+            SocketAddr::V6(src) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, src.port()),
+        };
+        let len = IPV4_HEADER_LEN + UDP_HEADER_LEN + data.len();
+
+        let mut record = Vec::with_capacity(16 + len);
+        for field in [
+            (recv_us / 1_000_000) as u32,
+            (recv_us % 1_000_000) as u32,
+            len as u32,
+            len as u32,
+        ] {
+            record.extend_from_slice(&field.to_ne_bytes());
+        }
+
+        let mut ip = [0u8; IPV4_HEADER_LEN];
+        // Version 4, 5 words of header
+        ip[0] = 0x45;
+        ip[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        // Don't fragment
+        ip[6] = 0x40;
+        ip[8] = 64;
+        ip[9] = IPPROTO_UDP;
+        ip[12..16].copy_from_slice(&src.ip().octets());
+        ip[16..20].copy_from_slice(&self.local.ip().octets());
+        let checksum = ipv4_checksum(&ip);
+        ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+        record.extend_from_slice(&ip);
+
+        // No UDP checksum, it's optional over IPv4
+        record.extend_from_slice(&src.port().to_be_bytes());
+        record.extend_from_slice(&self.local.port().to_be_bytes());
+        record.extend_from_slice(&((UDP_HEADER_LEN + data.len()) as u16).to_be_bytes());
+        record.extend_from_slice(&[0, 0]);
+        record.extend_from_slice(data);
+
+        self.out.write_all(&record)?;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.out.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LVPcapWriter {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+// A UDP payload and when it arrived, in microseconds since the UNIX epoch
+pub type LVDatagram = (Vec<u8>, u64);
+
+pub struct LVPcapReader {
+    input: BufReader<File>,
+    // The file was written on a machine with the other byte order
+    swapped: bool,
+}
+
+impl LVPcapReader {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 24];
+        input.read_exact(&mut header)?;
+
+        let magic = u32::from_ne_bytes(header[0..4].try_into()?);
+        let swapped = match magic {
+            PCAP_MAGIC => false,
+            _ if magic.swap_bytes() == PCAP_MAGIC => true,
+            _ => return Err(format!("{:?} isn't a microsecond pcap file", path).into()),
+        };
+        let reader = Self { input, swapped };
+        let linktype = reader.u32_at(&header, 20);
+        if linktype != LINKTYPE_RAW {
+            return Err(format!(
+                "{:?} has link type {}, only raw IP captures from LV_CAPTURE can be replayed",
+                path, linktype
+            )
+            .into());
+        }
+        Ok(reader)
+    }
+
+    fn u32_at(&self, data: &[u8], at: usize) -> u32 {
+        let value = u32::from_ne_bytes(data[at..at + 4].try_into().unwrap());
+        if self.swapped {
+            value.swap_bytes()
+        } else {
+            value
+        }
+    }
+
+    // The next UDP payload and when it arrived, None at the end of the file. A record cut
+    // short at the end counts as the end.
+    pub fn next_datagram(&mut self) -> Result<Option<LVDatagram>, Box<dyn std::error::Error>> {
+        loop {
+            let mut header = [0u8; 16];
+            let mut packet = Vec::new();
+            match self.input.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            let recv_us =
+                self.u32_at(&header, 0) as u64 * 1_000_000 + self.u32_at(&header, 4) as u64;
+            packet.resize(self.u32_at(&header, 8) as usize, 0);
+            match self.input.read_exact(&mut packet) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+
+            // Anything that isn't UDP over IPv4 didn't come from us, skip it.
+            let ip_len = packet.first().map(|b| (b & 0xf) as usize * 4).unwrap_or(0);
+            if ip_len < IPV4_HEADER_LEN
+                || packet.len() < ip_len + UDP_HEADER_LEN
+                || packet[0] >> 4 != 4
+                || packet[9] != IPPROTO_UDP
+            {
+                continue;
+            }
+            return Ok(Some((packet[ip_len + UDP_HEADER_LEN..].to_vec(), recv_us)));
+        }
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("lv-pcap-{}-{}.pcap", name, std::process::id()))
+    }
+
+    fn local() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 9000)
+    }
+
+    fn read_all(path: &Path) -> Vec<LVDatagram> {
+        let mut reader = LVPcapReader::open(path).unwrap();
+        let mut datagrams = Vec::new();
+        while let Some(datagram) = reader.next_datagram().unwrap() {
+            datagrams.push(datagram);
+        }
+        datagrams
+    }
+
+    #[test]
+    fn checksums_ipv4_headers() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(ipv4_checksum(&header), 0xb861);
+    }
+
+    #[test]
+    fn round_trips_datagrams() {
+        let path = temp_path("round-trip");
+        let src: SocketAddr = "10.0.0.1:9001".parse().unwrap();
+        let datagrams = [
+            (vec![1, 2, 3], 1_700_000_000_000_001),
+            (vec![], 1_700_000_000_999_999),
+            (vec![0xab; 1200], 1_700_000_001_000_000),
+        ];
+        let mut writer = LVPcapWriter::create(&path, local()).unwrap();
+        for (data, recv_us) in &datagrams {
+            writer.write(data, src, *recv_us).unwrap();
+        }
+        // Not IPv4, but still kept
+        writer
+            .write(&[4], "[::1]:9001".parse().unwrap(), 1)
+            .unwrap();
+        drop(writer);
+
+        let mut expected = datagrams.to_vec();
+        expected.push((vec![4], 1));
+        assert_eq!(read_all(&path), expected);
+
+        // The IP and UDP headers say what Wireshark should show
+        let file = std::fs::read(&path).unwrap();
+        let ip = &file[24 + 16..][..IPV4_HEADER_LEN];
+        assert_eq!(ipv4_checksum(ip), 0);
+        assert_eq!(ip[12..16], [10, 0, 0, 1]);
+        assert_eq!(ip[16..20], [10, 0, 0, 2]);
+        let udp = &file[24 + 16 + IPV4_HEADER_LEN..][..UDP_HEADER_LEN];
+        assert_eq!(udp[..6], [0x23, 0x29, 0x23, 0x28, 0, 11]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn skips_other_packets_and_stops_at_a_cut_record() {
+        let path = temp_path("skips");
+        let mut writer = LVPcapWriter::create(&path, local()).unwrap();
+        writer
+            .write(&[1], "10.0.0.1:9001".parse().unwrap(), 5)
+            .unwrap();
+        drop(writer);
+
+        let mut file = std::fs::read(&path).unwrap();
+        let udp = file[24..].to_vec();
+        // The same record as TCP, then the UDP one again cut short
+        let mut tcp = udp.clone();
+        tcp[16 + 9] = 6;
+        file.extend_from_slice(&tcp);
+        file.extend_from_slice(&udp);
+        file.extend_from_slice(&udp[..udp.len() - 1]);
+        std::fs::write(&path, &file).unwrap();
+
+        assert_eq!(read_all(&path), [(vec![1], 5), (vec![1], 5)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_the_other_byte_order() {
+        let path = temp_path("swapped");
+        let mut writer = LVPcapWriter::create(&path, local()).unwrap();
+        writer
+            .write(&[7, 8], "10.0.0.1:9001".parse().unwrap(), 3_000_004)
+            .unwrap();
+        drop(writer);
+
+        // Every pcap field is in the writer's byte order, the packet itself is big endian
+        let mut file = std::fs::read(&path).unwrap();
+        file[0..4].reverse();
+        file[4..6].reverse();
+        file[6..8].reverse();
+        for field in (8..24).step_by(4).chain((24..40).step_by(4)) {
+            file[field..field + 4].reverse();
+        }
+        std::fs::write(&path, &file).unwrap();
+
+        assert_eq!(read_all(&path), [(vec![7, 8], 3_000_004)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_captures() {
+        let path = temp_path("rejects");
+        drop(LVPcapWriter::create(&path, local()).unwrap());
+        let header = std::fs::read(&path).unwrap();
+
+        // Nanosecond timestamps
+        let mut file = header.clone();
+        file[0..4].copy_from_slice(&0xa1b23c4du32.to_ne_bytes());
+        std::fs::write(&path, &file).unwrap();
+        assert!(LVPcapReader::open(&path).is_err());
+
+        // Ethernet
+        let mut file = header;
+        file[20..24].copy_from_slice(&1u32.to_ne_bytes());
+        std::fs::write(&path, &file).unwrap();
+        assert!(LVPcapReader::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, HashMap};
+
+use log::{debug, error, warn};
+use net::{
+    feedback_packet::LVRefineAck,
+    packet::{LVRefineHeader, LVRefineKind},
+};
+use rtp::packet::Packet;
+
+use super::feedback::LVFeedbackMessage;
+
+// Tiles still missing chunks once this many newer ones started arriving lost a packet, they
+// won't complete.
+const MAX_PENDING_TILES: usize = 32;
+
+// A lossless RGBA copy of part of the screen
+struct LVRefinedTile {
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+}
+
+struct LVPendingTile {
+    x: u16,
+    y: u16,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+}
+
+// The server's lossless copies of static screen areas, drawn over every decoded frame until
+// the server says the area changed.
+pub struct LVRefineOverlay {
+    tiles: HashMap<(u16, u16), LVRefinedTile>,
+    // By tile id, which only goes up, so the oldest is the first
+    pending: BTreeMap<u32, LVPendingTile>,
+    // Of the last refinement packet, a gap means we missed a clear and can't trust any tile
+    sequence_number: Option<u16>,
+    // Tells the server which tiles we have and when we lost them
+    feedback_send: flume::Sender<LVFeedbackMessage>,
+}
+
+impl LVRefineOverlay {
+    pub fn new(feedback_send: flume::Sender<LVFeedbackMessage>) -> Self {
+        Self {
+            tiles: HashMap::new(),
+            pending: BTreeMap::new(),
+            sequence_number: None,
+            feedback_send,
+        }
+    }
+
+    // Drops everything, for when the frame under it can't be trusted or changed size.
+    pub fn clear(&mut self) {
+        self.tiles.clear();
+        self.pending.clear();
+    }
+
+    pub fn add_packet(&mut self, packet: &Packet) -> Result<(), Box<dyn std::error::Error>> {
+        let header = LVRefineHeader::from_bytes(&packet.payload)
+            .ok_or("refinement packet without a header")?;
+        let data = &packet.payload[LVRefineHeader::no_bytes()..];
+
+        let sequence_number = packet.header.sequence_number;
+        if self
+            .sequence_number
+            .is_some_and(|last| sequence_number != last.wrapping_add(1))
+        {
+            warn!(
+                "lost refinement packets before {}, dropping refined tiles",
+                sequence_number
+            );
+            self.clear();
+            self.send(LVFeedbackMessage::RefineLoss);
+        }
+        self.sequence_number = Some(sequence_number);
+
+        match header.kind {
+            LVRefineKind::Clear => {
+                for tile in data.chunks_exact(4) {
+                    let x = u16::from_be_bytes([tile[0], tile[1]]);
+                    let y = u16::from_be_bytes([tile[2], tile[3]]);
+                    self.tiles.remove(&(x, y));
+                    self.pending
+                        .retain(|_, pending| (pending.x, pending.y) != (x, y));
+                }
+            }
+            LVRefineKind::Tile => {
+                let pending = self
+                    .pending
+                    .entry(header.tile_id)
+                    .or_insert_with(|| LVPendingTile {
+                        x: header.x,
+                        y: header.y,
+                        chunks: vec![None; header.chunks as usize],
+                        received: 0,
+                    });
+                let Some(chunk) = pending.chunks.get_mut(header.chunk as usize) else {
+                    return Err(format!("chunk {} of {}", header.chunk, header.chunks).into());
+                };
+                if chunk.is_none() {
+                    *chunk = Some(data.to_vec());
+                    pending.received += 1;
+                }
+
+                if pending.received == pending.chunks.len() {
+                    let pending = self.pending.remove(&header.tile_id).unwrap();
+                    if self.finish_tile(pending) {
+                        self.send(LVFeedbackMessage::RefineAck(LVRefineAck {
+                            tile_id: header.tile_id,
+                        }));
+                    }
+                }
+                while self.pending.len() > MAX_PENDING_TILES {
+                    self.pending.pop_first();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, message: LVFeedbackMessage) {
+        if let Err(e) = self.feedback_send.try_send(message) {
+            error!("failed to queue refinement feedback {:?}", e);
+        }
+    }
+
+    // Whether the tile decoded and gets drawn now.
+    fn finish_tile(&mut self, pending: LVPendingTile) -> bool {
+        let data: Vec<u8> = pending.chunks.into_iter().flatten().flatten().collect();
+        match qoi::decode_to_vec(&data) {
+            Ok((header, rgba)) if rgba.len() == 4 * (header.width * header.height) as usize => {
+                debug!(
+                    "refined {}x{} tile at {}, {}",
+                    header.width, header.height, pending.x, pending.y
+                );
+                self.tiles.insert(
+                    (pending.x, pending.y),
+                    LVRefinedTile {
+                        width: header.width,
+                        height: header.height,
+                        rgba,
+                    },
+                );
+                true
+            }
+            Ok(_) => {
+                warn!("refined tile at {}, {} isn't RGBA", pending.x, pending.y);
+                false
+            }
+            Err(e) => {
+                warn!("failed to decode refined tile {:?}", e);
+                false
+            }
+        }
+    }
+
+    // Draws the tiles over a width x height RGBA frame.
+    pub fn composite(&self, frame: &mut [u8], width: u32, height: u32) {
+        let stride = 4 * width as usize;
+        for (&(x, y), tile) in &self.tiles {
+            if x as u32 + tile.width > width || y as u32 + tile.height > height {
+                continue;
+            }
+            let row_bytes = 4 * tile.width as usize;
+            for (row, pixels) in tile.rgba.chunks_exact(row_bytes).enumerate() {
+                let start = (y as usize + row) * stride + 4 * x as usize;
+                frame[start..start + row_bytes].copy_from_slice(pixels);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rtp::header::Header;
+
+    use super::*;
+
+    fn rtp_packet(sequence_number: u16, header: LVRefineHeader, data: &[u8]) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                ..Default::default()
+            },
+            payload: [&header.to_bytes()[..], data].concat().into(),
+        }
+    }
+
+    // A 2x2 tile of one color at x, y, split into chunks the way the server sends it.
+    fn tile_packets(first_seq: u16, tile_id: u32, x: u16, y: u16, color: [u8; 4]) -> Vec<Packet> {
+        let data = qoi::encode_to_vec(color.repeat(4), 2, 2).unwrap();
+        let chunks = data.chunks(data.len().div_ceil(3));
+        let count = chunks.len() as u16;
+        chunks
+            .enumerate()
+            .map(|(chunk, bytes)| {
+                let header = LVRefineHeader {
+                    kind: LVRefineKind::Tile,
+                    tile_id,
+                    chunk: chunk as u16,
+                    chunks: count,
+                    x,
+                    y,
+                };
+                rtp_packet(first_seq + chunk as u16, header, bytes)
+            })
+            .collect()
+    }
+
+    fn clear_packet(sequence_number: u16, x: u16, y: u16) -> Packet {
+        let header = LVRefineHeader {
+            kind: LVRefineKind::Clear,
+            tile_id: 0,
+            chunk: 0,
+            chunks: 1,
+            x: 0,
+            y: 0,
+        };
+        let data = [x.to_be_bytes(), y.to_be_bytes()].concat();
+        rtp_packet(sequence_number, header, &data)
+    }
+
+    // Composites over a black 4x4 frame, the pixel at 2, 2 shows whether the tile there is
+    // drawn.
+    fn pixel_at_2_2(overlay: &LVRefineOverlay) -> [u8; 4] {
+        let mut frame = vec![0; 4 * 4 * 4];
+        overlay.composite(&mut frame, 4, 4);
+        let start = 4 * (2 * 4 + 2);
+        frame[start..start + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn tiles_are_drawn_and_confirmed_once_complete() {
+        let (send, recv) = flume::unbounded();
+        let mut overlay = LVRefineOverlay::new(send);
+        let packets = tile_packets(0, 7, 2, 2, [1, 2, 3, 255]);
+        assert_eq!(packets.len(), 3);
+
+        for packet in &packets[..2] {
+            overlay.add_packet(packet).unwrap();
+        }
+        assert_eq!(pixel_at_2_2(&overlay), [0; 4]);
+        assert!(recv.is_empty());
+
+        overlay.add_packet(&packets[2]).unwrap();
+        assert_eq!(pixel_at_2_2(&overlay), [1, 2, 3, 255]);
+        match recv.try_recv() {
+            Ok(LVFeedbackMessage::RefineAck(ack)) => assert_eq!({ ack.tile_id }, 7),
+            message => panic!("expected an ack, got {:?}", message),
+        }
+
+        // The area changed, back to the video
+        overlay.add_packet(&clear_packet(3, 2, 2)).unwrap();
+        assert_eq!(pixel_at_2_2(&overlay), [0; 4]);
+        assert!(recv.is_empty());
+    }
+
+    #[test]
+    fn lost_packets_drop_the_tiles() {
+        let (send, recv) = flume::unbounded();
+        let mut overlay = LVRefineOverlay::new(send);
+        for packet in &tile_packets(u16::MAX - 2, 1, 2, 2, [9, 9, 9, 255]) {
+            overlay.add_packet(packet).unwrap();
+        }
+        assert!(matches!(
+            recv.try_recv(),
+            Ok(LVFeedbackMessage::RefineAck(_))
+        ));
+
+        // Sequence numbers wrap, that's not a gap
+        overlay.add_packet(&clear_packet(0, 0, 0)).unwrap();
+        assert_eq!(pixel_at_2_2(&overlay), [9, 9, 9, 255]);
+        assert!(recv.is_empty());
+
+        // The missing packet might have been a clear for this tile
+        overlay.add_packet(&clear_packet(2, 0, 0)).unwrap();
+        assert_eq!(pixel_at_2_2(&overlay), [0; 4]);
+        assert!(matches!(recv.try_recv(), Ok(LVFeedbackMessage::RefineLoss)));
+    }
+}
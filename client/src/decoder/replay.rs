@@ -0,0 +1,148 @@
+// Plays a capture from LV_CAPTURE back into the decode loop in place of the network thread.
+// The packets go in in the same order with the same arrival times, so the decoder ends up
+// with the same frames and a dump with the same timing. The latency the server measured is
+// reported again, but nothing that needs our clock: the capture's arrival times can't be
+// compared to it.
+
+use std::{
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{error, info};
+use thingbuf::mpsc::blocking::Sender;
+
+use super::{network::LVPacketHolder, pcap::LVPcapReader};
+
+pub struct LVReplay {
+    path: PathBuf,
+    // 1 is the pace it was captured at, 2 twice as fast, 0 as fast as the decoder goes
+    speed: f32,
+}
+
+impl LVReplay {
+    pub fn new(path: PathBuf, speed: f32) -> Result<Self, Box<dyn std::error::Error>> {
+        if speed.is_nan() || speed < 0. {
+            return Err(format!("replay speed {} has to be 0 or more", speed).into());
+        }
+        Ok(Self { path, speed })
+    }
+
+    // Opens the capture here so a bad file fails straight away, then feeds it in from its
+    // own thread. The sender is dropped at the end, which ends the decode loop.
+    pub fn run(
+        &self,
+        packet_push: Sender<LVPacketHolder>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let reader = LVPcapReader::open(&self.path)?;
+        let speed = self.speed;
+        info!("replaying {:?} at {}x", self.path, speed);
+
+        thread::Builder::new()
+            .name("replay_thread".to_string())
+            .spawn(move || {
+                if let Err(e) = Self::replay_loop(reader, speed, packet_push) {
+                    error!("replay loop failed with error {:?}", e);
+                } else {
+                    info!("replay finished");
+                }
+            })?;
+
+        Ok(())
+    }
+
+    fn replay_loop(
+        mut reader: LVPcapReader,
+        speed: f32,
+        packet_push: Sender<LVPacketHolder>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        let mut first_us: Option<u64> = None;
+        let mut packets = 0;
+
+        while let Some((datagram, recv_us)) = reader.next_datagram()? {
+            let first = *first_us.get_or_insert(recv_us);
+            if speed > 0. {
+                let due = Duration::from_micros(recv_us.saturating_sub(first)).div_f32(speed);
+                if let Some(wait) = due.checked_sub(start.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+
+            // Blocking, unlike the network thread. Packets it couldn't queue stayed in the
+            // socket instead of getting lost, so the capture never has any the decoder missed.
+            let mut slot = packet_push
+                .send_ref()
+                .map_err(|_| "the decoder stopped taking packets")?;
+            // Same as recv_from does with anything bigger than the buffer
+            let amt = datagram.len().min(slot.payload.len());
+            slot.payload[..amt].copy_from_slice(&datagram[..amt]);
+            slot.amt = amt;
+            slot.recv_us = recv_us;
+            packets += 1;
+        }
+
+        info!("replayed {} packets in {:.1?}", packets, start.elapsed());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        path::Path,
+    };
+
+    use super::*;
+    use crate::decoder::pcap::{LVDatagram, LVPcapWriter};
+
+    fn replay(path: &Path) -> Vec<LVDatagram> {
+        let (packet_push, packet_recv) = thingbuf::mpsc::blocking::channel::<LVPacketHolder>(4);
+        LVReplay::new(path.to_path_buf(), 0.)
+            .unwrap()
+            .run(packet_push)
+            .unwrap();
+        let mut packets = Vec::new();
+        while let Some(packet) = packet_recv.recv_ref() {
+            packets.push((packet.payload[..packet.amt].to_vec(), packet.recv_us));
+        }
+        packets
+    }
+
+    #[test]
+    fn replays_the_same_packets_every_time() {
+        let path = std::env::temp_dir().join(format!("lv-replay-{}.pcap", std::process::id()));
+        let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9000);
+        let mut writer = LVPcapWriter::create(&path, local).unwrap();
+        // More than the channel holds, so the replay has to wait for the decoder
+        let datagrams: Vec<LVDatagram> = (0..20u8)
+            .map(|i| (vec![i; 100 + i as usize], 1_000_000 + i as u64 * 16_667))
+            .collect();
+        for (data, recv_us) in &datagrams {
+            writer
+                .write(data, "127.0.0.1:9001".parse().unwrap(), *recv_us)
+                .unwrap();
+        }
+        drop(writer);
+
+        let first = replay(&path);
+        assert_eq!(first, datagrams);
+        assert_eq!(replay(&path), first);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_speeds_and_files() {
+        assert!(LVReplay::new(PathBuf::from("x.pcap"), -1.).is_err());
+        assert!(LVReplay::new(PathBuf::from("x.pcap"), f32::NAN).is_err());
+
+        let (packet_push, _packet_recv) = thingbuf::mpsc::blocking::channel::<LVPacketHolder>(4);
+        let missing = std::env::temp_dir().join("lv-replay-missing.pcap");
+        assert!(LVReplay::new(missing, 1.)
+            .unwrap()
+            .run(packet_push)
+            .is_err());
+    }
+}
@@ -1,36 +1,52 @@
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use dcv_color_primitives::{convert_image, get_buffers_size, ColorSpace, ImageFormat};
 use log::{debug, error, info, trace, warn};
-use openh264::{
-    decoder::{Decoder, DecoderConfig},
-    formats::YUVSource,
-};
 use parking_lot::{Mutex, RwLock};
 use reed_solomon_simd::ReedSolomonDecoder;
-use rtp::{codecs::h264::H264Packet, packet::Packet, packetizer::Depacketizer};
+use rtp::packet::Packet;
 use statistics::{
     collector::LVStatisticsCollector,
     statistics::{LVDataPoint, LVDataType},
 };
-use std::{collections::VecDeque, os::fd::RawFd, sync::Arc, thread, time::Instant};
+use std::{
+    collections::VecDeque,
+    os::fd::RawFd,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 use thingbuf::mpsc::blocking::Receiver;
 use webrtc_util::Unmarshal;
 
 use net::{
-    feedback_packet::{self, LVAck, LVFeedbackPacket},
+    clock::{self, LVClockSync},
+    codec::LVCodec,
+    feedback_packet::{self, LVAck, LVFeedbackPacket, LVReferenceInvalidation},
     packet::{
-        LVErasureInformation, EC_RATIO_RECOVERY_PACKETS, EC_RATIO_REGULAR_PACKETS, SIMD_PACKET_SIZE,
+        LVErasureInformation, LVFrameId, LVFrameSize, LVFrameTimestamps, EC_RATIO_RECOVERY_PACKETS,
+        EC_RATIO_REGULAR_PACKETS, FRAME_ID_EXTENSION_ID, FRAME_SIZE_EXTENSION_ID,
+        FRAME_TIMESTAMPS_EXTENSION_ID, REFINE_PAYLOAD_TYPE, SIMD_PACKET_SIZE,
     },
 };
 
-use crate::decoder::network::LVPacketHolder;
-use crate::double_buffer::DoubleBuffer;
+use crate::decoder::{
+    codec::{self, LVDepacketizer, LVVideoDecoder},
+    dump::LVStreamDump,
+    feedback::LVFeedbackMessage,
+    network::LVPacketHolder,
+    refine::LVRefineOverlay,
+};
+use crate::double_buffer::{DoubleBuffer, LVFrameTiming};
 
 use nix::ioctl_read_bad;
 use nix::libc::TIOCOUTQ;
 
 ioctl_read_bad!(tiocoutq, TIOCOUTQ, u32);
 
+// If the server hasn't sent a recovery point this long after we reported a loss, the report
+// (or the recovery point) probably got lost too.
+const INVALIDATION_RESEND: Duration = Duration::from_secs(1);
+
 pub struct LVDecoder {
     width: u32,
     height: u32,
@@ -38,8 +54,37 @@ pub struct LVDecoder {
     buffer: Vec<u8>,
     src_format: ImageFormat,
     dst_format: ImageFormat,
-    decoder: Decoder,
-    pkt: H264Packet,
+    // Picked from the payload type, so the server can switch codecs under us.
+    codec: LVCodec,
+    decoder: Box<dyn LVVideoDecoder>,
+    pkt: Box<dyn LVDepacketizer>,
+
+    // Latency breakdown for the data in self.buffer: the server's timestamps and when the last
+    // packet of it arrived.
+    frame_timestamps: Option<LVFrameTimestamps>,
+    frame_recv_us: u64,
+    // Replayed packets arrive with the capture's receive times, which aren't on our clock
+    replaying: bool,
+    // The server's captured and encoded size for the data in self.buffer
+    frame_size: Option<LVFrameSize>,
+    clock: Arc<Mutex<LVClockSync>>,
+
+    // Loss tracking, in net::packet::LVFrameId ids. frame_id is for the data in self.buffer.
+    frame_id: Option<LVFrameId>,
+    last_frame_id: u32,
+    last_good_frame: u32,
+    // The FEC lost a block since the last decode
+    block_lost: bool,
+    // The first frame we lost, until the server sends a recovery point
+    lost_frame: Option<u32>,
+    last_invalidation: Option<Instant>,
+    feedback_send: flume::Sender<LVFeedbackMessage>,
+
+    // Lossless tiles of static areas the server sent, drawn over every frame
+    overlay: LVRefineOverlay,
+
+    // Copy of every access unit we got, when LV_DUMP is set
+    dump: Option<LVStreamDump>,
 }
 
 impl LVDecoder {
@@ -48,18 +93,49 @@ impl LVDecoder {
         double_buffer: Arc<DoubleBuffer>,
         src_format: ImageFormat,
         dst_format: ImageFormat,
-        decoder: Decoder,
-    ) -> Self {
-        Self {
+        clock: Arc<Mutex<LVClockSync>>,
+        feedback_send: flume::Sender<LVFeedbackMessage>,
+        replaying: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let codec = LVCodec::default();
+        let overlay = LVRefineOverlay::new(feedback_send.clone());
+        Ok(Self {
             width: 0,
             height: 0,
             double_buffer,
             buffer: Vec::new(),
             src_format,
             dst_format,
-            decoder,
-            pkt: H264Packet::default(),
-        }
+            codec,
+            decoder: codec::new_decoder(codec)?,
+            pkt: codec::new_depacketizer(codec)?,
+            frame_timestamps: None,
+            frame_recv_us: 0,
+            replaying,
+            frame_size: None,
+            clock,
+            frame_id: None,
+            last_frame_id: 0,
+            last_good_frame: 0,
+            block_lost: false,
+            lost_frame: None,
+            last_invalidation: None,
+            feedback_send,
+            overlay,
+            dump: LVStreamDump::from_env()?,
+        })
+    }
+
+    // Starts over with a decoder for whatever the payload type says the stream is now.
+    fn switch_codec(&mut self, payload_type: u8) -> Result<(), Box<dyn std::error::Error>> {
+        let codec = LVCodec::from_payload_type(payload_type)
+            .ok_or_else(|| format!("unknown payload type {}", payload_type))?;
+        info!("stream codec changed from {:?} to {:?}", self.codec, codec);
+        self.decoder = codec::new_decoder(codec)?;
+        self.pkt = codec::new_depacketizer(codec)?;
+        self.codec = codec;
+        self.buffer.clear();
+        Ok(())
     }
 
     pub fn run(
@@ -67,12 +143,22 @@ impl LVDecoder {
         packet_recv: Receiver<LVPacketHolder>,
         feedback_pkt: Arc<Mutex<(LVAck, LVFeedbackPacket)>>,
         udp_fd: Arc<RwLock<Option<RawFd>>>,
+        clock: Arc<Mutex<LVClockSync>>,
+        feedback_send: flume::Sender<LVFeedbackMessage>,
+        replaying: bool,
     ) {
         thread::Builder::new()
             .name("decoder_thread".to_string())
             .spawn(move || {
-                if let Err(e) = Self::decode_loop(double_buffer, packet_recv, feedback_pkt, udp_fd)
-                {
+                if let Err(e) = Self::decode_loop(
+                    double_buffer,
+                    packet_recv,
+                    feedback_pkt,
+                    udp_fd,
+                    clock,
+                    feedback_send,
+                    replaying,
+                ) {
                     error!("decode loop failed with error {:?}", e);
                 } else {
                     info!("decode receive loop exited.");
@@ -94,9 +180,124 @@ impl LVDecoder {
         }
     }
 
+    // Logs the server side and network part of the latency breakdown for a frame that just
+    // finished decoding, and returns what the UI needs to add the present part.
+    fn frame_timing(&self, decoded_ts: u64) -> Option<LVFrameTiming> {
+        let timestamps = self.frame_timestamps?;
+
+        LVStatisticsCollector::update_data(
+            "client_latency_capture",
+            LVDataPoint::TimeElapsed(Duration::from_micros(timestamps.capture_us.into())),
+        );
+        LVStatisticsCollector::update_data(
+            "client_latency_encode",
+            LVDataPoint::TimeElapsed(Duration::from_micros(timestamps.encode_us.into())),
+        );
+        // The decoder only sees a frame is complete once the next one starts arriving, so
+        // this includes waiting for that. Left out in replays, decoded_ts is from now and the
+        // receive time from the capture.
+        if !self.replaying {
+            LVStatisticsCollector::update_data(
+                "client_latency_decode",
+                LVDataPoint::TimeElapsed(Duration::from_micros(
+                    decoded_ts.saturating_sub(self.frame_recv_us),
+                )),
+            );
+        }
+
+        // Anything crossing the network needs the server's clock converted to ours.
+        let clock = self.clock.lock();
+        let encoded_ts =
+            timestamps.capture_ts + timestamps.capture_us as u64 + timestamps.encode_us as u64;
+        if let Some(encoded_ts) = clock.to_local(encoded_ts) {
+            let network_us = self.frame_recv_us as i64 - encoded_ts;
+            LVStatisticsCollector::update_data(
+                "client_latency_network",
+                LVDataPoint::TimeElapsed(Duration::from_micros(network_us.max(0) as u64)),
+            );
+        }
+        let capture_ts = clock.to_local(timestamps.capture_ts);
+
+        debug!(
+            "frame timing: {:?}, received at {}, decoded at {}, captured at {:?} on our clock",
+            timestamps, self.frame_recv_us, decoded_ts, capture_ts
+        );
+
+        Some(LVFrameTiming {
+            capture_ts,
+            decoded_ts,
+        })
+    }
+
+    // The FEC couldn't rebuild a block, so whatever frame it was part of is broken.
+    pub fn block_lost(&mut self) {
+        self.block_lost = true;
+    }
+
+    // The newest frame we decoded with all its references intact, 0 if none yet.
+    pub fn last_good_frame(&self) -> u32 {
+        self.last_good_frame
+    }
+
+    // Keeps track of which frames made it, and tells the server when one didn't so it can
+    // send a recovery point. Everything after a lost frame is broken until then, because it
+    // was predicted from something we don't have.
+    fn track_frame(&mut self, decoded: bool) {
+        // Servers that don't send frame ids recover from the FEC failure count instead.
+        let Some(frame) = self.frame_id else {
+            return;
+        };
+        let gap = self.last_frame_id != 0 && frame.id > self.last_frame_id + 1;
+        let intact = decoded && !self.block_lost;
+        self.block_lost = false;
+
+        if intact && (frame.recovery_point || (self.lost_frame.is_none() && !gap)) {
+            if self.lost_frame.take().is_some() {
+                info!("recovered at frame {}", frame.id);
+            }
+            self.last_good_frame = frame.id;
+        } else if self.lost_frame.is_none() {
+            let lost = if gap {
+                self.last_frame_id + 1
+            } else {
+                frame.id
+            };
+            warn!(
+                "lost frame {}, last good frame was {}",
+                lost, self.last_good_frame
+            );
+            // We can't tell which refined tiles are still right, the server sends them again.
+            self.overlay.clear();
+            LVStatisticsCollector::update_data("client_lost_frames", LVDataPoint::Increment);
+            self.lost_frame = Some(lost);
+            self.send_invalidation();
+        } else if self
+            .last_invalidation
+            .is_none_or(|last| last.elapsed() >= INVALIDATION_RESEND)
+        {
+            self.send_invalidation();
+        }
+        self.last_frame_id = frame.id;
+    }
+
+    fn send_invalidation(&mut self) {
+        let invalidation = LVReferenceInvalidation {
+            last_good_frame: self.last_good_frame,
+            lost_frame: self.lost_frame.unwrap_or_default(),
+        };
+        if let Err(e) = self
+            .feedback_send
+            .try_send(LVFeedbackMessage::ReferenceInvalidation(invalidation))
+        {
+            error!("failed to queue reference invalidation {:?}", e);
+        }
+        self.last_invalidation = Some(Instant::now());
+    }
+
     pub fn depacketize_decode(
         &mut self,
         packet: &Packet,
+        recv_us: u64,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let time = Instant::now();
 
@@ -105,19 +306,36 @@ impl LVDecoder {
             packet.header.sequence_number
         );
 
-        let is_partition_head = self.pkt.is_partition_head(&packet.payload);
+        if packet.header.payload_type == REFINE_PAYLOAD_TYPE {
+            return self.overlay.add_packet(packet);
+        }
+        if packet.header.payload_type != self.codec.payload_type() {
+            self.switch_codec(packet.header.payload_type)?;
+        }
+
+        let is_partition_head = self.pkt.is_frame_start(packet);
         debug!("is partition head {}", is_partition_head);
         if is_partition_head {
             // Decode and clear buffer
             if !self.buffer.is_empty() {
-                match self.decoder.decode(&self.buffer) {
+                let decoded = match self.decoder.decode(&self.buffer) {
                     Ok(yuv) => {
                         if let Some(ref yuv_data) = yuv {
-                            // Set up target buffer/data for calls to YUV->RGBA conversion
-                            if self.double_buffer.uninitialized() {
-                                let strides_yuv = yuv_data.strides_yuv();
-                                self.width = strides_yuv.0 as u32;
-                                self.height = yuv_data.height() as u32;
+                            // Set up target buffer/data for calls to YUV->RGBA conversion.
+                            // This also runs when the server changes resolution mid-stream,
+                            // since the new SPS makes the decoder output a different size.
+                            let (new_width, new_height) = (yuv_data.width, yuv_data.height);
+                            if self.double_buffer.uninitialized()
+                                || new_width != self.width
+                                || new_height != self.height
+                            {
+                                info!(
+                                    "stream size changed from {}x{} to {}x{}",
+                                    self.width, self.height, new_width, new_height
+                                );
+                                self.width = new_width;
+                                self.height = new_height;
+                                self.overlay.clear();
                                 self.double_buffer.initialize(
                                     (4 * self.width * self.height) as usize,
                                     self.width as usize,
@@ -139,15 +357,15 @@ impl LVDecoder {
                                     &mut src_sizes,
                                 )?;
 
-                                let y = &yuv_data.y()[0..]; //src_sizes[0] + 1];
-                                let u = &yuv_data.u()[0..]; //src_sizes[1] + 1];
-                                let v = &yuv_data.v()[0..]; //src_sizes[2] + 1];
+                                let y = yuv_data.y;
+                                let u = yuv_data.u;
+                                let v = yuv_data.v;
 
                                 debug!(
                                         "converting image... dest buf size is {}, src_sizes is {:#?}, ysize usize vsize: [{}, {}, {}], strides from class are {:?}",
                                         rgba_buffer.as_mut().unwrap().buffer.len(),
                                         src_sizes, y.len(), u.len(), v.len(),
-                                        yuv_data.strides_yuv()
+                                        yuv_data.strides
                                     );
 
                                 // Convert YUV to Rgba8Uint so it can be copied to wgpu buffer.
@@ -155,7 +373,7 @@ impl LVDecoder {
                                     self.width,
                                     self.height,
                                     &self.src_format,
-                                    None,
+                                    Some(&yuv_data.strides),
                                     &[y, u, v],
                                     &self.dst_format,
                                     None,
@@ -166,32 +384,56 @@ impl LVDecoder {
                                         warn!("converting image failed with {:?}, continuing", e)
                                     }
                                 }
+                                self.overlay.composite(
+                                    &mut rgba_buffer.as_mut().unwrap().buffer,
+                                    self.width,
+                                    self.height,
+                                );
+
+                                rgba_buffer.as_mut().unwrap().timing =
+                                    self.frame_timing(clock::now_us());
+                                rgba_buffer.as_mut().unwrap().source_size =
+                                    self.frame_size.map(|size| {
+                                        (size.source_width as u32, size.source_height as u32)
+                                    });
                             }
 
                             // swap doublebuffer
                             self.double_buffer.swap();
                         }
                         // debug!("h264_data {:?}", h264_data);
+                        true
                     }
                     Err(e) => {
                         error!("Failed to decode pkt {}", e);
-                        if let Some(bt) = e.backtrace() {
-                            error!("backtrace: {}", bt);
-                        }
 
                         LVStatisticsCollector::update_data(
                             "client_failed_decode_packets",
                             LVDataPoint::Increment,
                         );
+                        false
+                    }
+                };
+                if let Some(dump) = self.dump.as_mut() {
+                    if let Err(e) = dump.write_frame(
+                        self.codec,
+                        &self.buffer,
+                        self.frame_recv_us,
+                        self.width,
+                        self.height,
+                    ) {
+                        warn!("failed to dump frame, stopping the dump: {:?}", e);
+                        self.dump = None;
                     }
                 }
+                self.track_frame(decoded);
             } else {
                 debug!("skipping decode empty packet");
             }
             // if there's an empty packet and a boundary we need to clear the buffer. In both cases the buffer must be cleared.
             self.buffer.clear();
         }
-        let depacketized_payload = self.pkt.depacketize(&packet.payload)?;
+        let depacketized_payload = self.pkt.depacketize(packet)?;
         if depacketized_payload.is_empty() {
             trace!(
                 "depacketized payload is empty! payload is {:?}",
@@ -210,6 +452,36 @@ impl LVDecoder {
         }
         self.buffer.extend_from_slice(&depacketized_payload);
 
+        // Every packet of a frame carries the same timestamps, so whichever arrives last wins.
+        if let Some(timestamps) = packet
+            .header
+            .get_extension(FRAME_TIMESTAMPS_EXTENSION_ID)
+            .and_then(|extension| LVFrameTimestamps::from_bytes(&extension))
+        {
+            self.frame_timestamps = Some(timestamps);
+        }
+        if let Some(size) = packet
+            .header
+            .get_extension(FRAME_SIZE_EXTENSION_ID)
+            .and_then(|extension| LVFrameSize::from_bytes(&extension))
+        {
+            if self.frame_size != Some(size) {
+                info!(
+                    "server is sending {}x{} of its {}x{} capture",
+                    size.width, size.height, size.source_width, size.source_height
+                );
+            }
+            self.frame_size = Some(size);
+        }
+        if let Some(frame_id) = packet
+            .header
+            .get_extension(FRAME_ID_EXTENSION_ID)
+            .and_then(|extension| LVFrameId::from_bytes(&extension))
+        {
+            self.frame_id = Some(frame_id);
+        }
+        self.frame_recv_us = recv_us;
+
         LVStatisticsCollector::update_data(
             "client_decode_packet",
             LVDataPoint::TimeElapsed(time.elapsed()),
@@ -223,12 +495,20 @@ impl LVDecoder {
         packet_recv: Receiver<LVPacketHolder>,
         feedback_pkt: Arc<Mutex<(LVAck, LVFeedbackPacket)>>,
         udp_fd: Arc<RwLock<Option<RawFd>>>,
+        clock: Arc<Mutex<LVClockSync>>,
+        feedback_send: flume::Sender<LVFeedbackMessage>,
+        replaying: bool,
     ) -> Result<(), Box<dyn std::error::Error>> {
         debug!("starting thread for decode");
 
         LVStatisticsCollector::register_data("client_packets_out_of_order", LVDataType::Aggregate);
         LVStatisticsCollector::register_data("client_decode_packet", LVDataType::TimeSeries);
         LVStatisticsCollector::register_data("client_failed_decode_packets", LVDataType::Aggregate);
+        LVStatisticsCollector::register_data("client_latency_capture", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("client_latency_encode", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("client_latency_network", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("client_latency_decode", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("client_lost_frames", LVDataType::Aggregate);
 
         let src_format = ImageFormat {
             pixel_format: dcv_color_primitives::PixelFormat::I420,
@@ -240,8 +520,14 @@ impl LVDecoder {
             color_space: ColorSpace::Rgb,
             num_planes: 1,
         };
-        let mut decoder = Decoder::with_config(DecoderConfig::new().debug(true))?;
-        let mut video_dec = Self::new(double_buffer, src_format, dst_format, decoder);
+        let mut video_dec = Self::new(
+            double_buffer,
+            src_format,
+            dst_format,
+            clock,
+            feedback_send,
+            replaying,
+        )?;
 
         let mut width: u32 = 0;
         let mut height: u32 = 0;
@@ -341,11 +627,12 @@ impl LVDecoder {
                                     "RECOVERY: sending packet {} to decoder",
                                     rs_inorder_packets + i
                                 );
-                                video_dec.depacketize_decode(pkt_inorder)?;
+                                video_dec.depacketize_decode(pkt_inorder, data_ext.recv_us)?;
                             }
                         }
                         Err(e) => {
                             ecc_decoder_failures += 1;
+                            video_dec.block_lost();
                             warn!("recovery failed with {:?}", e);
                         }
                     }
@@ -441,15 +728,17 @@ impl LVDecoder {
             }
 
             lvheader_prev_fragment_index = lvheader.fragment_index as u32;
-            video_dec.depacketize_decode(&packet)?;
+            video_dec.depacketize_decode(&packet, data_ext.recv_us)?;
 
             match Self::bytes_in_send_queue(udp_fd.clone()) {
                 Ok(Some(d)) => {
                     average_qocc += d;
                     average_qocc_iterations += 1;
                 }
-                _ => {
-                    warn!("udp fd none or err");
+                // Replaying a capture, there's no socket
+                Ok(None) => {}
+                Err(_) => {
+                    warn!("udp fd err");
                 }
             }
 
@@ -469,7 +758,10 @@ impl LVDecoder {
                     pkt.1.total_packets = total_packets;
                     pkt.1.lost_packets = lost_packets as u16;
                     pkt.1.ecc_decoder_failures = ecc_decoder_failures;
-                    pkt.1.average_buffer_occupancy = average_qocc / average_qocc_iterations;
+                    pkt.1.last_good_frame = video_dec.last_good_frame();
+                    pkt.1.average_buffer_occupancy = average_qocc
+                        .checked_div(average_qocc_iterations)
+                        .unwrap_or(0);
 
                     if reset {
                         total_blocks = 0;
@@ -483,6 +775,7 @@ impl LVDecoder {
 
                     pkt.0.rtp_seqno = packet.header.sequence_number;
                     pkt.0.send_ts = lvheader.send_timestamp;
+                    pkt.0.recv_ts = data_ext.recv_us;
                 }
                 None => {
                     warn!("Failed to lock feedback packet")
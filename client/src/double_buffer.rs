@@ -1,3 +1,5 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use log::debug;
 use parking_lot::{
     lock_api::{RwLockReadGuard, RwLockWriteGuard},
@@ -8,11 +10,27 @@ pub struct Frame {
     pub buffer: Vec<u8>,
     pub width: usize,
     pub height: usize,
+    // Set by the decoder, taken by the UI once the frame is on screen.
+    pub timing: Option<LVFrameTiming>,
+    // Size of the frame the server captured, which is bigger than this one when it scaled the
+    // stream down. Input coordinates refer to this size.
+    pub source_size: Option<(u32, u32)>,
+}
+
+// What the UI needs to finish a frame's latency breakdown, all on the client's clock in
+// microseconds since the UNIX epoch.
+#[derive(Clone, Copy, Debug)]
+pub struct LVFrameTiming {
+    // When the server started capturing it. None until the clocks have been synced.
+    pub capture_ts: Option<i64>,
+    pub decoded_ts: u64,
 }
 
 pub struct DoubleBuffer {
     back: RwLock<Option<Frame>>,
     front: RwLock<Option<Frame>>,
+    // How many frames have been swapped to the front, so readers can tell they have a new one
+    swaps: AtomicU64,
 }
 
 impl DoubleBuffer {
@@ -22,12 +40,17 @@ impl DoubleBuffer {
                 buffer: vec![0; capacity],
                 width,
                 height,
+                timing: None,
+                source_size: None,
             })),
             front: RwLock::new(Some(Frame {
                 buffer: vec![0; capacity],
                 width,
                 height,
+                timing: None,
+                source_size: None,
             })),
+            swaps: AtomicU64::new(0),
         }
     }
 
@@ -35,19 +58,25 @@ impl DoubleBuffer {
         Self {
             back: RwLock::new(None),
             front: RwLock::new(None),
+            swaps: AtomicU64::new(0),
         }
     }
 
+    // (Re)allocate both frames. Called again whenever the stream resolution changes.
     pub fn initialize(&self, capacity: usize, width: usize, height: usize) {
         *self.back.write() = Some(Frame {
             buffer: vec![0; capacity],
             width,
             height,
+            timing: None,
+            source_size: None,
         });
         *self.front.write() = Some(Frame {
             buffer: vec![0; capacity],
             width,
             height,
+            timing: None,
+            source_size: None,
         });
     }
 
@@ -71,6 +100,16 @@ impl DoubleBuffer {
         );
 
         std::mem::swap(&mut *back_mut, &mut *front_mut);
+        self.swaps.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn swaps(&self) -> u64 {
+        self.swaps.load(Ordering::Acquire)
+    }
+
+    // Timing of the frame at the front, once per frame.
+    pub fn take_timing(&self) -> Option<LVFrameTiming> {
+        self.front.write().as_mut()?.timing.take()
     }
 
     // immutable reference to front
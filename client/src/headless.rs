@@ -0,0 +1,267 @@
+// Takes the decoder's frames off the DoubleBuffer without a window, for load and soak tests
+// and machines without a GPU. Every frame is counted and finishes the latency breakdown like
+// the UI would when presenting it; the outputs can do more with the pixels.
+
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use flume::{Receiver, TryRecvError};
+use log::{error, info, warn};
+use net::clock;
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+
+use crate::{double_buffer::DoubleBuffer, quality::LVQualitySink, ui::screenshot};
+
+// How often to look for a new frame. Well under a frame even at high framerates.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Gets a copy of every frame the headless client manages to take, RGBA.
+pub trait LVFrameSink: Send {
+    fn frame(
+        &mut self,
+        rgba: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn finish(&mut self) {}
+}
+
+// What to do with the frames besides counting them
+#[derive(Clone, Debug, PartialEq)]
+pub enum LVHeadlessOutput {
+    Count,
+    // Every Nth frame as frame-<number>.png in the directory
+    Write { dir: PathBuf, every: u64 },
+    // PSNR and SSIM against the originals, see quality.rs
+    Quality { originals: PathBuf },
+}
+
+impl FromStr for LVHeadlessOutput {
+    type Err = Box<dyn std::error::Error>;
+
+    // count, write:DIR[,N] or quality:DIR
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "count" => Ok(LVHeadlessOutput::Count),
+            // Directories may contain commas, so only treat the tail as N if it parses as one.
+            Some(("write", value)) => match value.rsplit_once(',') {
+                Some((dir, every)) if every.parse::<u64>().is_ok() => Ok(LVHeadlessOutput::Write {
+                    dir: dir.into(),
+                    every: every.parse::<u64>()?.max(1),
+                }),
+                _ => Ok(LVHeadlessOutput::Write {
+                    dir: value.into(),
+                    every: 1,
+                }),
+            },
+            Some(("quality", value)) => Ok(LVHeadlessOutput::Quality {
+                originals: value.into(),
+            }),
+            _ => Err(format!(
+                "unknown headless output {:?}, use count, write:DIR[,N] or quality:DIR",
+                s
+            )
+            .into()),
+        }
+    }
+}
+
+impl LVHeadlessOutput {
+    pub fn sink(&self) -> Result<Option<Box<dyn LVFrameSink>>, Box<dyn std::error::Error>> {
+        match self {
+            LVHeadlessOutput::Count => Ok(None),
+            LVHeadlessOutput::Write { dir, every } => {
+                Ok(Some(Box::new(LVFrameWriter::new(dir.clone(), *every)?)))
+            }
+            LVHeadlessOutput::Quality { originals } => {
+                Ok(Some(Box::new(LVQualitySink::new(originals)?)))
+            }
+        }
+    }
+}
+
+pub struct LVFrameWriter {
+    dir: PathBuf,
+    every: u64,
+    frames: u64,
+}
+
+impl LVFrameWriter {
+    pub fn new(dir: PathBuf, every: u64) -> Result<Self, Box<dyn std::error::Error>> {
+        std::fs::create_dir_all(&dir)?;
+        info!("writing every {} frame(s) to {:?}", every, dir);
+        Ok(Self {
+            dir,
+            every,
+            frames: 0,
+        })
+    }
+}
+
+impl LVFrameSink for LVFrameWriter {
+    fn frame(
+        &mut self,
+        rgba: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.frames % self.every == 0 {
+            let path = self.dir.join(format!("frame-{:06}.png", self.frames));
+            screenshot::write_png(&path, rgba, width as u32, height as u32)?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+}
+
+pub struct LVHeadless {
+    quit_rx: Receiver<bool>,
+    sinks: Vec<Box<dyn LVFrameSink>>,
+    // Stop after this many decoded frames
+    max_frames: Option<u64>,
+}
+
+impl LVHeadless {
+    pub fn new(quit_rx: Receiver<bool>, max_frames: Option<u64>) -> Self {
+        Self {
+            quit_rx,
+            sinks: Vec::new(),
+            max_frames,
+        }
+    }
+
+    pub fn add_sink(&mut self, sink: Box<dyn LVFrameSink>) {
+        self.sinks.push(sink);
+    }
+
+    // Runs until ctrl-c or max_frames, returns how many frames were decoded.
+    pub fn run(
+        &mut self,
+        double_buffer: Arc<DoubleBuffer>,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        LVStatisticsCollector::register_data("client_headless_frames", LVDataType::Aggregate);
+        LVStatisticsCollector::register_data("client_headless_missed", LVDataType::Aggregate);
+        // The UI registers these when there is one
+        LVStatisticsCollector::register_data("client_latency_present", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("client_latency_total", LVDataType::TimeSeries);
+
+        let start = Instant::now();
+        let mut last_report = Instant::now();
+        let mut reported_frames = 0;
+        // Frames the decoder swapped in, and the ones that were swapped out again before we
+        // got to them because the sinks were too slow.
+        let mut frames = 0;
+        let mut missed = 0;
+        let mut last_swap = double_buffer.swaps();
+        // Copied out so the sinks don't hold up the decoder's next swap
+        let mut rgba = Vec::new();
+
+        loop {
+            match self.quit_rx.try_recv() {
+                Ok(val) if val => {
+                    info!("Ctrl-c received, statistics logged, quitting...");
+                    break;
+                }
+                Err(e) => {
+                    if e != TryRecvError::Empty {
+                        error!("quit_rx from statistics module gave {:?}", e)
+                    }
+                }
+                _ => warn!("quit_rx gave false value!"),
+            }
+
+            let swaps = double_buffer.swaps();
+            if swaps == last_swap {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            let new_frames = swaps - last_swap;
+            last_swap = swaps;
+            frames += new_frames;
+            missed += new_frames - 1;
+            for _ in 0..new_frames {
+                LVStatisticsCollector::update_data(
+                    "client_headless_frames",
+                    LVDataPoint::Increment,
+                );
+            }
+            for _ in 1..new_frames {
+                LVStatisticsCollector::update_data(
+                    "client_headless_missed",
+                    LVDataPoint::Increment,
+                );
+            }
+
+            // Finish the latency breakdown the decoder started, taking the frame is as close
+            // to presenting it as we get.
+            if let Some(timing) = double_buffer.take_timing() {
+                let presented_ts = clock::now_us();
+                LVStatisticsCollector::update_data(
+                    "client_latency_present",
+                    LVDataPoint::TimeElapsed(Duration::from_micros(
+                        presented_ts.saturating_sub(timing.decoded_ts),
+                    )),
+                );
+                if let Some(capture_ts) = timing.capture_ts {
+                    let total_us = (presented_ts as i64 - capture_ts).max(0) as u64;
+                    LVStatisticsCollector::update_data(
+                        "client_latency_total",
+                        LVDataPoint::TimeElapsed(Duration::from_micros(total_us)),
+                    );
+                }
+            }
+
+            if !self.sinks.is_empty() {
+                let (width, height) = match &*double_buffer.front() {
+                    Some(frame) => {
+                        rgba.clear();
+                        rgba.extend_from_slice(&frame.buffer);
+                        (frame.width, frame.height)
+                    }
+                    None => continue,
+                };
+                for sink in self.sinks.iter_mut() {
+                    if let Err(e) = sink.frame(&rgba, width, height) {
+                        error!("frame sink failed with {:?}", e);
+                    }
+                }
+            }
+
+            if last_report.elapsed() >= REPORT_INTERVAL {
+                info!(
+                    "{} frames decoded, {:.1} fps, {} missed",
+                    frames,
+                    (frames - reported_frames) as f64 / last_report.elapsed().as_secs_f64(),
+                    missed
+                );
+                reported_frames = frames;
+                last_report = Instant::now();
+            }
+
+            if self.max_frames.is_some_and(|max| frames >= max) {
+                break;
+            }
+        }
+
+        for sink in self.sinks.iter_mut() {
+            sink.finish();
+        }
+        info!(
+            "headless client took {} frames in {:.1?}, {} missed",
+            frames,
+            start.elapsed(),
+            missed
+        );
+        Ok(frames)
+    }
+}
@@ -0,0 +1,9 @@
+// The client pipeline, network -> decoder -> DoubleBuffer -> window, as a library so it can be
+// run without the window (headless.rs) or in process next to a server (see the loopback crate).
+// main.rs is the command line on top.
+
+pub mod decoder;
+pub mod double_buffer;
+pub mod headless;
+pub mod quality;
+pub mod ui;
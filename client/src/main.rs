@@ -1,31 +1,34 @@
 use std::{
     net::{SocketAddr, SocketAddrV4},
     os::fd::RawFd,
+    path::PathBuf,
     sync::Arc,
 };
 
-use decoder::{
-    feedback, input,
-    network::{LVNetwork, LVPacketHolder},
-    video::LVDecoder,
+use client::{
+    decoder::{
+        feedback::LVFeedbackMessage,
+        network::{LVNetwork, LVPacketHolder},
+        replay::LVReplay,
+        video::LVDecoder,
+    },
+    double_buffer::DoubleBuffer,
+    headless::{LVHeadless, LVHeadlessOutput},
+    ui::VideoUI,
 };
-use double_buffer::DoubleBuffer;
 use flexi_logger::Logger;
 use log::{error, info};
 use net::{
+    clock::LVClockSync,
+    control_packet::LVHandshake,
     feedback_packet::{LVAck, LVFeedbackPacket},
     input::LVInputEvent,
 };
 use parking_lot::{Mutex, RwLock};
 use statistics::collector::LVStatisticsCollector;
-use ui::VideoUI;
 
 use pollster::FutureExt as _;
 
-mod decoder;
-mod double_buffer;
-mod ui;
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     Logger::try_with_str("trace, calloop=info, wgpu=info, client::decoder::video=info, client::decoder::feedback=info, client::decoder::network=info, client::ui::wgpu_state=info, client::double_buffer=info")?.start()?;
 
@@ -33,32 +36,161 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Bind value
     match std::env::args().nth(1) {
-        Some(addr) => {
-            let db = Arc::new(DoubleBuffer::new_uninitialized());
-            let db_ui = db.clone();
-
-            // Set up mpsc
-            let (pkt_push, pkt_recv) = thingbuf::mpsc::blocking::channel::<LVPacketHolder>(1000);
-            let (inp_push, inp_recv) = flume::bounded::<LVInputEvent>(10);
-
-            let feedback_pkt: Arc<Mutex<(LVAck, LVFeedbackPacket)>> =
-                Arc::new(Mutex::new((Default::default(), Default::default())));
+        Some(arg) if arg == "--replay" => match std::env::args().nth(2) {
+            Some(path) => {
+                let speed = match std::env::args().nth(3) {
+                    Some(speed) => speed.parse()?,
+                    None => 1.,
+                };
+                replay(PathBuf::from(path), speed, quit_rx)?;
+            }
+            None => println!("Usage: ./client --replay capture.pcap [speed]"),
+        },
+        Some(arg) if arg == "--headless" => match std::env::args().nth(2) {
+            Some(addr) => {
+                // Optional: count (the default), write:DIR[,N] or quality:DIR
+                let output: LVHeadlessOutput = match std::env::args().nth(3) {
+                    Some(output) => output.parse()?,
+                    None => LVHeadlessOutput::Count,
+                };
+                // Optional: stop after this many frames
+                let max_frames = match std::env::args().nth(4) {
+                    Some(frames) => Some(frames.parse()?),
+                    None => None,
+                };
 
-            let udp_fd: Arc<RwLock<Option<RawFd>>> = Arc::new(RwLock::new(None));
-
-            let receiver = LVNetwork::new(&addr)?;
-
-            receiver.run(pkt_push, inp_recv, feedback_pkt.clone(), udp_fd.clone())?;
-            LVDecoder::run(db, pkt_recv, feedback_pkt.clone(), udp_fd);
+                let pipeline = LVPipeline::start(&addr)?;
+                let mut headless = LVHeadless::new(quit_rx, max_frames);
+                if let Some(sink) = output.sink()? {
+                    headless.add_sink(sink);
+                }
+                headless.run(pipeline.db)?;
+            }
+            None => println!("Usage: ./client --headless addr [count|write:DIR[,N]|quality:DIR] [frames]"),
+        },
+        Some(addr) => {
+            let pipeline = LVPipeline::start(&addr)?;
 
             // Start ui
             let ui = VideoUI::new(quit_rx)?;
-            ui.run(db_ui, inp_push).block_on()?;
+            ui.run(
+                pipeline.db,
+                pipeline.inp_push,
+                pipeline.feedback_push,
+                pipeline.handshake,
+                pipeline.framerate,
+            )
+            .block_on()?;
         }
-        None => println!("Usage: ./client addr"),
+        None => println!(
+            "Usage: ./client addr, ./client --headless addr, or ./client --replay capture.pcap [speed]"
+        ),
     }
 
     LVStatisticsCollector::quit();
 
     Ok(())
 }
+
+// The network and decode threads, with what the UI (or the headless loop) needs from them.
+struct LVPipeline {
+    db: Arc<DoubleBuffer>,
+    // Held even without a UI, the input thread gives up once it's dropped
+    inp_push: flume::Sender<LVInputEvent>,
+    feedback_push: flume::Sender<LVFeedbackMessage>,
+    // Monitor layout reported by the server
+    handshake: Arc<Mutex<Option<LVHandshake>>>,
+    // Framerate the server is currently streaming at, so the UI can present at the same pace
+    framerate: Arc<Mutex<Option<u32>>>,
+}
+
+impl LVPipeline {
+    fn start(addr: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let db = Arc::new(DoubleBuffer::new_uninitialized());
+
+        // Set up mpsc
+        let (pkt_push, pkt_recv) = thingbuf::mpsc::blocking::channel::<LVPacketHolder>(1000);
+        let (inp_push, inp_recv) = flume::bounded::<LVInputEvent>(10);
+        let (feedback_push, feedback_recv) = flume::bounded::<LVFeedbackMessage>(10);
+
+        let handshake: Arc<Mutex<Option<LVHandshake>>> = Arc::new(Mutex::new(None));
+        let framerate: Arc<Mutex<Option<u32>>> = Arc::new(Mutex::new(None));
+
+        // Tracks the server's clock so its timestamps can be compared with ours
+        let clock: Arc<Mutex<LVClockSync>> = Arc::new(Mutex::new(LVClockSync::new()));
+
+        let feedback_pkt: Arc<Mutex<(LVAck, LVFeedbackPacket)>> =
+            Arc::new(Mutex::new((Default::default(), Default::default())));
+
+        let udp_fd: Arc<RwLock<Option<RawFd>>> = Arc::new(RwLock::new(None));
+
+        let receiver = LVNetwork::new(addr)?;
+
+        receiver.run(
+            pkt_push,
+            inp_recv,
+            feedback_pkt.clone(),
+            udp_fd.clone(),
+            handshake.clone(),
+            framerate.clone(),
+            feedback_push.clone(),
+            feedback_recv,
+            clock.clone(),
+        )?;
+        LVDecoder::run(
+            db.clone(),
+            pkt_recv,
+            feedback_pkt,
+            udp_fd,
+            clock,
+            feedback_push.clone(),
+            false,
+        );
+
+        Ok(Self {
+            db,
+            inp_push,
+            feedback_push,
+            handshake,
+            framerate,
+        })
+    }
+}
+
+// Runs a capture from LV_CAPTURE through the decoder and shows it, with nothing on the other
+// end. Input and feedback go nowhere.
+fn replay(
+    path: PathBuf,
+    speed: f32,
+    quit_rx: flume::Receiver<bool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Arc::new(DoubleBuffer::new_uninitialized());
+    let db_ui = db.clone();
+
+    let (pkt_push, pkt_recv) = thingbuf::mpsc::blocking::channel::<LVPacketHolder>(1000);
+    let (inp_push, _inp_recv) = flume::bounded::<LVInputEvent>(10);
+    let (feedback_push, _feedback_recv) = flume::bounded::<LVFeedbackMessage>(10);
+
+    let handshake: Arc<Mutex<Option<LVHandshake>>> = Arc::new(Mutex::new(None));
+    let framerate: Arc<Mutex<Option<u32>>> = Arc::new(Mutex::new(None));
+    // Never synced, so the network and total latency aren't reported. The decoder is told it's
+    // a replay and leaves out the decode part, the rest comes from the server's timestamps.
+    let clock: Arc<Mutex<LVClockSync>> = Arc::new(Mutex::new(LVClockSync::new()));
+    let feedback_pkt: Arc<Mutex<(LVAck, LVFeedbackPacket)>> =
+        Arc::new(Mutex::new((Default::default(), Default::default())));
+
+    LVReplay::new(path, speed)?.run(pkt_push)?;
+    LVDecoder::run(
+        db,
+        pkt_recv,
+        feedback_pkt,
+        Arc::new(RwLock::new(None)),
+        clock,
+        feedback_push.clone(),
+        true,
+    );
+
+    let ui = VideoUI::new(quit_rx)?;
+    ui.run(db_ui, inp_push, feedback_push, handshake, framerate)
+        .block_on()
+}
@@ -0,0 +1,155 @@
+// Measures decoded frames against the originals the server streamed, for tuning bitrate and QP
+// by numbers instead of by eye. The server has to run with LV_QUALITY=1 and play a clip from
+// disk (file:DIR of .png frames, or a single .png), and we need the same clip here: the id
+// stamped into each frame counts the frames the server captured, so id modulo the number of
+// frames is the original's index. That holds as long as the server's capturer isn't rebuilt.
+
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use net::quality::{self, LVQualityReport};
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+
+use crate::headless::LVFrameSink;
+
+pub struct LVQualitySink {
+    originals: Vec<PathBuf>,
+    // The last original read, originals repeat when the clip loops or is a single image
+    cached: Option<(usize, Vec<u8>, usize, usize)>,
+    measured: u64,
+    unmatched: u64,
+    psnr_sum: f64,
+    ssim_sum: f64,
+    warned_stamp: bool,
+    warned_size: bool,
+}
+
+impl LVQualitySink {
+    pub fn new(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        // Picked the same way as the server's file capturer
+        let originals = if path.is_dir() {
+            let mut paths = std::fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        } else {
+            vec![path.to_owned()]
+        };
+        if originals.is_empty() {
+            return Err(format!("no .png files in {:?}", path).into());
+        }
+
+        LVStatisticsCollector::register_data("client_quality_psnr", LVDataType::XYData);
+        LVStatisticsCollector::register_data("client_quality_ssim", LVDataType::XYData);
+        LVStatisticsCollector::register_data("client_quality_unmatched", LVDataType::Aggregate);
+        info!(
+            "measuring quality against {} original frame(s) from {:?}",
+            originals.len(),
+            path
+        );
+
+        Ok(Self {
+            originals,
+            cached: None,
+            measured: 0,
+            unmatched: 0,
+            psnr_sum: 0.,
+            ssim_sum: 0.,
+            warned_stamp: false,
+            warned_size: false,
+        })
+    }
+
+    fn unmatched(&mut self) {
+        self.unmatched += 1;
+        LVStatisticsCollector::update_data("client_quality_unmatched", LVDataPoint::Increment);
+    }
+
+    fn measure(
+        &mut self,
+        rgba: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<Option<(u32, LVQualityReport)>, Box<dyn std::error::Error>> {
+        let Some(id) = quality::read_stamp(rgba, width, height) else {
+            if !std::mem::replace(&mut self.warned_stamp, true) {
+                warn!("decoded frame has no stamp, is the server running with LV_QUALITY=1?");
+            }
+            return Ok(None);
+        };
+
+        let index = id as usize % self.originals.len();
+        if self.cached.as_ref().map(|cached| cached.0) != Some(index) {
+            let (original, original_width, original_height) = read_png(&self.originals[index])?;
+            self.cached = Some((index, original, original_width, original_height));
+        }
+        let Some((_, original, original_width, original_height)) = &self.cached else {
+            return Ok(None);
+        };
+
+        // The server scaled the stream down, there's nothing to compare against
+        if (*original_width, *original_height) != (width, height) {
+            if !std::mem::replace(&mut self.warned_size, true) {
+                warn!(
+                    "decoded frame is {}x{} but the originals are {}x{}, not measuring",
+                    width, height, original_width, original_height
+                );
+            }
+            return Ok(None);
+        }
+        Ok(Some((id, quality::measure(rgba, original, width, height))))
+    }
+}
+
+impl LVFrameSink for LVQualitySink {
+    fn frame(
+        &mut self,
+        rgba: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.measure(rgba, width, height)? {
+            Some((id, report)) => {
+                self.measured += 1;
+                self.psnr_sum += report.psnr;
+                self.ssim_sum += report.ssim;
+                LVStatisticsCollector::update_data(
+                    "client_quality_psnr",
+                    LVDataPoint::XYValue((id as f32, report.psnr as f32)),
+                );
+                LVStatisticsCollector::update_data(
+                    "client_quality_ssim",
+                    LVDataPoint::XYValue((id as f32, report.ssim as f32)),
+                );
+            }
+            None => self.unmatched(),
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        if self.measured == 0 {
+            warn!("no frames measured, {} unmatched", self.unmatched);
+            return;
+        }
+        info!(
+            "measured {} frames, mean PSNR {:.2} dB, mean SSIM {:.4}, {} unmatched",
+            self.measured,
+            self.psnr_sum / self.measured as f64,
+            self.ssim_sum / self.measured as f64,
+            self.unmatched
+        );
+    }
+}
+
+// As RGBA, read the same way the server's file capturer reads them
+fn read_png(path: &Path) -> Result<(Vec<u8>, usize, usize), Box<dyn std::error::Error>> {
+    let image = image::open(path)?.to_rgba8();
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    Ok((image.into_raw(), width, height))
+}
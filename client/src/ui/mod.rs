@@ -1,8 +1,12 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use flume::{Receiver, TryRecvError};
 use log::{error, info, warn};
-use net::input::LVInputEvent;
+use net::{control_packet::LVHandshake, input::LVInputEvent};
+use parking_lot::Mutex;
 use winit::{
     dpi::{LogicalSize, PhysicalSize, Size},
     event::*,
@@ -11,11 +15,15 @@ use winit::{
     window::{Window, WindowBuilder},
 };
 
+pub mod screenshot;
 mod wgpu_state;
 
 use wgpu_state::WGPUState;
 
-use crate::double_buffer::{self, DoubleBuffer};
+use crate::{
+    decoder::feedback::LVFeedbackMessage,
+    double_buffer::{self, DoubleBuffer},
+};
 
 pub struct VideoUI {
     quit_rx: Receiver<bool>,
@@ -30,6 +38,9 @@ impl VideoUI {
         &self,
         double_buffer: Arc<DoubleBuffer>,
         input_send: flume::Sender<LVInputEvent>,
+        feedback_send: flume::Sender<LVFeedbackMessage>,
+        handshake: Arc<Mutex<Option<LVHandshake>>>,
+        framerate: Arc<Mutex<Option<u32>>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let eloop = EventLoop::new()?;
         let window = WindowBuilder::new()
@@ -40,7 +51,12 @@ impl VideoUI {
             .with_resizable(false)
             .build(&eloop)?;
 
-        let mut state = WGPUState::new(window, double_buffer, input_send).await;
+        let mut state =
+            WGPUState::new(window, double_buffer, input_send, feedback_send, handshake).await;
+
+        // When the next redraw is due. Redrawing faster than the server sends frames just
+        // presents the same frame again, so pace redraws to the stream's framerate.
+        let mut next_redraw = Instant::now();
 
         eloop.run(move |event, elwt| {
             match self.quit_rx.try_recv() {
@@ -95,7 +111,23 @@ impl VideoUI {
                         }
                     }
                 }
-                Event::AboutToWait => state.window().request_redraw(),
+                Event::AboutToWait => match *framerate.lock() {
+                    Some(fps) => {
+                        let now = Instant::now();
+                        if now >= next_redraw {
+                            state.window().request_redraw();
+                            let period = Duration::from_secs(1) / fps.max(1);
+                            next_redraw += period;
+                            // Don't try to catch up on redraws we missed.
+                            if next_redraw < now {
+                                next_redraw = now + period;
+                            }
+                        }
+                        elwt.set_control_flow(ControlFlow::WaitUntil(next_redraw));
+                    }
+                    // The server hasn't told us yet, keep redrawing as fast as we can.
+                    None => state.window().request_redraw(),
+                },
                 _ => {}
             }
         })?;
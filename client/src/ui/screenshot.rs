@@ -0,0 +1,36 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Writes an RGBA frame to screenshot-<unix time>-<ms>.png in the working directory, or
+// LV_SCREENSHOT_DIR if it's set.
+pub fn save(rgba: &[u8], width: u32, height: u32) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let dir = std::env::var("LV_SCREENSHOT_DIR").unwrap_or_else(|_| ".".to_string());
+    let path = PathBuf::from(dir).join(format!(
+        "screenshot-{}-{:03}.png",
+        now.as_secs(),
+        now.subsec_millis()
+    ));
+
+    write_png(&path, rgba, width, height)?;
+    Ok(path)
+}
+
+pub fn write_png(
+    path: &Path,
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&rgba[..(4 * width * height) as usize])?;
+    writer.finish()?;
+    Ok(())
+}
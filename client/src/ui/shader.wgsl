@@ -23,7 +23,36 @@ var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
 
+// Catmull-Rom weights for the four texels around a sample, t being how far the sample is
+// between the middle two.
+fn catmull_rom_weights(t: vec2<f32>) -> array<vec2<f32>, 4> {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    return array<vec2<f32>, 4>(
+        0.5 * (-t3 + 2.0 * t2 - t),
+        0.5 * (3.0 * t3 - 5.0 * t2 + 2.0),
+        0.5 * (-3.0 * t3 + 4.0 * t2 + t),
+        0.5 * (t3 - t2),
+    );
+}
+
+// The server shrinks the stream when there isn't enough bandwidth for the full resolution, so
+// the texture can be a lot smaller than the window. Bicubic keeps text edges sharper than the
+// sampler's bilinear filtering, and gives back the texels unchanged when the sizes match.
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let size = vec2<i32>(textureDimensions(t_diffuse));
+    let pos = in.tex_coords * vec2<f32>(size) - 0.5;
+    let base = floor(pos);
+    var weights = catmull_rom_weights(pos - base);
+
+    var color = vec4<f32>(0.0);
+    for (var j = 0; j < 4; j++) {
+        for (var i = 0; i < 4; i++) {
+            let texel = clamp(vec2<i32>(base) + vec2<i32>(i - 1, j - 1), vec2<i32>(0), size - 1);
+            color += textureLoad(t_diffuse, texel, 0) * weights[i].x * weights[j].y;
+        }
+    }
+    // The negative lobes can overshoot next to hard edges.
+    return clamp(color, vec4<f32>(0.0), vec4<f32>(1.0));
 }
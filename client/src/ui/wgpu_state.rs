@@ -1,11 +1,26 @@
-use std::sync::Arc;
-
-use log::{debug, info};
-use net::input::{LVInputEvent, LVKeyboardEvent, LVMouseClickEvent, LVMouseMoveEvent};
+use std::{sync::Arc, thread, time::Duration};
+
+use log::{debug, info, warn};
+use net::{
+    clock,
+    control_packet::{LVHandshake, ALL_MONITORS},
+    feedback_packet::LVMonitorSwitch,
+    input::{LVInputEvent, LVKeyboardEvent, LVMouseClickEvent, LVMouseMoveEvent},
+};
+use parking_lot::Mutex;
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
 use wgpu::util::DeviceExt;
-use winit::{event::WindowEvent, window::Window};
+use winit::{
+    event::{ElementState, WindowEvent},
+    keyboard::{KeyCode, ModifiersState, PhysicalKey},
+    window::Window,
+};
 
-use crate::double_buffer::DoubleBuffer;
+use super::screenshot;
+use crate::{decoder::feedback::LVFeedbackMessage, double_buffer::DoubleBuffer};
 
 pub struct WGPUState {
     surface: wgpu::Surface,
@@ -28,6 +43,14 @@ pub struct WGPUState {
 
     double_buffer: Arc<DoubleBuffer>,
     input_send: flume::Sender<LVInputEvent>,
+
+    // Monitor switching
+    feedback_send: flume::Sender<LVFeedbackMessage>,
+    handshake: Arc<Mutex<Option<LVHandshake>>>,
+    modifiers: ModifiersState,
+
+    // Size of the server's capture, which the stream may have been scaled down from
+    source_size: Option<(u32, u32)>,
 }
 
 #[repr(C)]
@@ -91,9 +114,14 @@ impl WGPUState {
         window: Window,
         double_buffer: Arc<DoubleBuffer>,
         input_send: flume::Sender<LVInputEvent>,
+        feedback_send: flume::Sender<LVFeedbackMessage>,
+        handshake: Arc<Mutex<Option<LVHandshake>>>,
     ) -> Self {
         let size = window.inner_size();
 
+        LVStatisticsCollector::register_data("client_latency_present", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("client_latency_total", LVDataType::TimeSeries);
+
         let num_vertices = VERTICES.len() as u32;
 
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
@@ -181,11 +209,26 @@ impl WGPUState {
             diffuse_texture,
             diffuse_bind_group,
             input_send,
+            feedback_send,
+            handshake,
+            modifiers: ModifiersState::empty(),
+            source_size: None,
         }
     }
     pub fn window(&self) -> &Window {
         &self.window
     }
+    // The stream is stretched over the whole window, so map window coordinates onto the
+    // server's capture. Its size doesn't change when the stream gets scaled down.
+    fn to_source(&self, x: f64, y: f64) -> (f64, f64) {
+        match self.source_size {
+            Some((width, height)) if self.size.width > 0 && self.size.height > 0 => (
+                x * width as f64 / self.size.width as f64,
+                y * height as f64 / self.size.height as f64,
+            ),
+            _ => (x, y),
+        }
+    }
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
@@ -194,14 +237,111 @@ impl WGPUState {
             self.surface.configure(&self.device, &self.config);
         }
     }
+    // Ctrl+Alt+R starts or stops recording on the server.
+    fn record_hotkey(&self, key_code: KeyCode, state: ElementState) -> bool {
+        if !(self.modifiers.control_key() && self.modifiers.alt_key()) || key_code != KeyCode::KeyR
+        {
+            return false;
+        }
+        if state == ElementState::Pressed {
+            info!("toggling recording on the server");
+            let _ = self.feedback_send.try_send(LVFeedbackMessage::RecordToggle);
+        }
+        true
+    }
+
+    // Ctrl+Alt+S saves the frame on screen as a PNG.
+    fn screenshot_hotkey(&self, key_code: KeyCode, state: ElementState) -> bool {
+        if !(self.modifiers.control_key() && self.modifiers.alt_key()) || key_code != KeyCode::KeyS
+        {
+            return false;
+        }
+        if state == ElementState::Pressed {
+            match &*self.double_buffer.front() {
+                Some(frame) => {
+                    let rgba = frame.buffer.clone();
+                    let (width, height) = (frame.width as u32, frame.height as u32);
+                    // Encoding a big frame takes a while, don't hold up the event loop.
+                    thread::spawn(move || match screenshot::save(&rgba, width, height) {
+                        Ok(path) => info!("saved screenshot to {:?}", path),
+                        Err(e) => warn!("failed to save screenshot: {:?}", e),
+                    });
+                }
+                None => warn!("no frame to take a screenshot of yet"),
+            }
+        }
+        true
+    }
+
+    // Ctrl+Alt+Left/Right cycles through the server's monitors, Ctrl+Alt+1..9 picks one and
+    // Ctrl+Alt+0 streams all of them at once. Returns true if the key was a hotkey.
+    fn monitor_hotkey(&self, key_code: KeyCode, state: ElementState) -> bool {
+        if !(self.modifiers.control_key() && self.modifiers.alt_key()) {
+            return false;
+        }
+
+        let monitor = {
+            let handshake = self.handshake.lock();
+            let Some(handshake) = handshake.as_ref() else {
+                warn!("no monitor list from the server yet");
+                return false;
+            };
+            let count = handshake.monitors.len() as u32;
+            let current = handshake.current_monitor;
+            match key_code {
+                KeyCode::ArrowRight if count > 0 => {
+                    if current == ALL_MONITORS {
+                        0
+                    } else {
+                        (current + 1) % count
+                    }
+                }
+                KeyCode::ArrowLeft if count > 0 => {
+                    if current == ALL_MONITORS || current == 0 {
+                        count - 1
+                    } else {
+                        current - 1
+                    }
+                }
+                KeyCode::Digit0 => ALL_MONITORS,
+                KeyCode::Digit1 => 0,
+                KeyCode::Digit2 => 1,
+                KeyCode::Digit3 => 2,
+                KeyCode::Digit4 => 3,
+                KeyCode::Digit5 => 4,
+                KeyCode::Digit6 => 5,
+                KeyCode::Digit7 => 6,
+                KeyCode::Digit8 => 7,
+                KeyCode::Digit9 => 8,
+                _ => return false,
+            }
+        };
+
+        // Swallow the release too so the server never sees half a key press.
+        if state == ElementState::Pressed {
+            info!("switching to monitor {}", monitor);
+            let _ = self
+                .feedback_send
+                .try_send(LVFeedbackMessage::MonitorSwitch(LVMonitorSwitch {
+                    monitor,
+                }));
+        }
+        true
+    }
+
     pub fn input(&mut self, event: &WindowEvent) -> bool {
         match event {
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers.state();
+            }
             WindowEvent::CursorMoved { position, .. } => {
                 debug!("cursor moved to position {:?}", position);
-                let _ = self.input_send
+                let (x, y) = self.to_source(position.x, position.y);
+                let _ = self
+                    .input_send
                     .try_send(LVInputEvent::MouseMoveEvent(LVMouseMoveEvent {
-                        x: position.x + 40.0,
-                        y: position.y,
+                        x: x + 40.0,
+                        y,
                     }));
             }
             WindowEvent::MouseInput { button, state, .. } => {
@@ -212,8 +352,14 @@ impl WGPUState {
                     )));
             }
             WindowEvent::KeyboardInput { event, .. } => match event.physical_key {
-                winit::keyboard::PhysicalKey::Code(key_code) => {
+                PhysicalKey::Code(key_code) => {
                     let state = event.state;
+                    if self.record_hotkey(key_code, state)
+                        || self.screenshot_hotkey(key_code, state)
+                        || self.monitor_hotkey(key_code, state)
+                    {
+                        return true;
+                    }
                     debug!(
                         "keyboard pressed physical key {:?}, type of press {:?}",
                         key_code, state
@@ -223,7 +369,7 @@ impl WGPUState {
                             key_code, state,
                         )));
                 }
-                winit::keyboard::PhysicalKey::Unidentified(_) => {}
+                PhysicalKey::Unidentified(_) => {}
             },
             // We aren't going to do anything with this for now.
             WindowEvent::MouseWheel { delta, phase, .. } => {
@@ -242,17 +388,36 @@ impl WGPUState {
     }
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         // try to get a frame here.
+        let timing = self.double_buffer.take_timing();
 
         if let Some(rgba_buffer) = &*self.double_buffer.front() {
             debug!("rgba buffer is {:?}", &rgba_buffer.buffer[..20]);
+            if rgba_buffer.source_size.is_some() {
+                self.source_size = rgba_buffer.source_size;
+            }
             // Set up the bind group if it hasn't been created yet.
 
-            if let None = self.diffuse_bind_group {
-                let texture_size = wgpu::Extent3d {
-                    width: rgba_buffer.width as u32,
-                    height: rgba_buffer.height as u32,
-                    depth_or_array_layers: 1,
-                };
+            let texture_size = wgpu::Extent3d {
+                width: rgba_buffer.width as u32,
+                height: rgba_buffer.height as u32,
+                depth_or_array_layers: 1,
+            };
+
+            // (Re)create the texture and bind group on the first frame and whenever the stream
+            // changes resolution.
+            if self.diffuse_bind_group.is_none() || self.texture_size != Some(texture_size) {
+                info!(
+                    "allocating {}x{} texture",
+                    texture_size.width, texture_size.height
+                );
+                if self.texture_size.is_some() {
+                    let _ = self
+                        .window
+                        .request_inner_size(winit::dpi::PhysicalSize::new(
+                            texture_size.width,
+                            texture_size.height,
+                        ));
+                }
 
                 self.texture_size = Some(texture_size);
 
@@ -435,6 +600,26 @@ impl WGPUState {
 
         self.queue.submit(std::iter::once(encoder.finish()));
         output.present();
+
+        // Finish the latency breakdown the decoder started for this frame.
+        if let Some(timing) = timing {
+            let presented_ts = clock::now_us();
+            LVStatisticsCollector::update_data(
+                "client_latency_present",
+                LVDataPoint::TimeElapsed(Duration::from_micros(
+                    presented_ts.saturating_sub(timing.decoded_ts),
+                )),
+            );
+            if let Some(capture_ts) = timing.capture_ts {
+                let total_us = (presented_ts as i64 - capture_ts).max(0) as u64;
+                debug!("glass-to-glass latency {}us", total_us);
+                LVStatisticsCollector::update_data(
+                    "client_latency_total",
+                    LVDataPoint::TimeElapsed(Duration::from_micros(total_us)),
+                );
+            }
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,435 @@
+// Runs the server pipeline (capture -> encode -> packetize -> send) and the client pipeline
+// (network -> decode -> DoubleBuffer, no window) in one process over localhost, so the whole
+// stream can be tested without a display or a second machine. The input emulator is a mock
+// that hands the events it gets back to us, and the feedback connection is the real one, so
+// the bitrate controller runs as it would between two machines. With quality on, the server
+// stamps its frames and hands us a copy of each, so decoded frames can be measured against the
+// exact frame they came from.
+
+use std::{
+    collections::VecDeque,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket},
+    os::fd::RawFd,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use client::{
+    decoder::{
+        feedback::LVFeedbackMessage,
+        network::{LVNetwork, LVPacketHolder},
+        video::LVDecoder,
+    },
+    double_buffer::DoubleBuffer,
+};
+use log::{debug, error, info};
+use net::{
+    clock::LVClockSync,
+    control_packet::LVHandshake,
+    feedback_packet::{LVAck, LVFeedbackPacket},
+    impair::LVImpairment,
+    input::LVInputEvent,
+    quality::{self, LVQualityReport},
+};
+use parking_lot::{Mutex, RwLock};
+use server::{
+    capture::{
+        synthetic::LVSyntheticCapturer, LVCaptureTarget, LVCapturer, LVFrame, LVTestPattern,
+    },
+    encoder,
+    input::{LVInputEmulator, LVInputMapping},
+    server::{
+        feedback_server::LVFeedbackServer, input_server::LVInputServer,
+        streaming_server::LVStreamingServer,
+    },
+};
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+
+// How often to look at the DoubleBuffer for a new frame
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+// Where the client goes, relative to the server's port
+const CLIENT_PORT_OFFSET: u16 = 10;
+// Everything that gets bound, relative to the server's port, and whether it's TCP: the
+// server's video and input sockets, then the client's video, feedback and input ones.
+const PORTS: [(u16, bool); 5] = [
+    (0, false),
+    (3, false),
+    (CLIENT_PORT_OFFSET, false),
+    (CLIENT_PORT_OFFSET + 2, true),
+    (CLIENT_PORT_OFFSET + 3, false),
+];
+// How many ports the OS hands out to try before giving up on finding free ones
+const PORT_ATTEMPTS: usize = 100;
+// Stamped source frames kept for matching, a few seconds' worth. Anything older that gets
+// decoded is too late to matter.
+const SOURCE_HISTORY: usize = 256;
+
+pub struct LVLoopbackConfig {
+    // The server binds this and port + 3, the client port + 10 to port + 13. 0 picks ports
+    // that are free and weren't given to another loopback in this process.
+    pub port: u16,
+    pub target: LVCaptureTarget,
+    pub fps: u32,
+    // Put in front of the server's video socket instead of whatever LV_IMPAIR says
+    pub impairment: Option<LVImpairment>,
+    // Stamp the source frames and keep them for next_quality, instead of whatever LV_QUALITY
+    // says
+    pub quality: bool,
+}
+
+impl Default for LVLoopbackConfig {
+    fn default() -> Self {
+        Self {
+            port: 0,
+            target: LVCaptureTarget::Pattern {
+                pattern: LVTestPattern::Bars,
+                width: 320,
+                height: 240,
+            },
+            fps: 30,
+            impairment: None,
+            quality: false,
+        }
+    }
+}
+
+// A copy of what the decoder put in the DoubleBuffer, RGBA.
+pub struct LVDecodedFrame {
+    pub rgba: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+}
+
+// Hands the server's input events to the test instead of a desktop.
+struct LVMockInputEmulator {
+    events: flume::Sender<LVInputEvent>,
+}
+
+impl LVInputEmulator for LVMockInputEmulator {
+    fn write_event(&mut self, ev: LVInputEvent) -> Result<(), anyhow::Error> {
+        debug!("mock emulator got {:?}", ev);
+        self.events.send(ev)?;
+        Ok(())
+    }
+}
+
+pub struct LVLoopback {
+    db: Arc<DoubleBuffer>,
+    input_push: flume::Sender<LVInputEvent>,
+    // What made it through to the server's input emulator
+    input_events: flume::Receiver<LVInputEvent>,
+    bitrate: Arc<std::sync::Mutex<u32>>,
+    // Stamped source frames by id, RGBA, oldest first
+    sources: Arc<Mutex<VecDeque<(u32, Vec<u8>)>>>,
+    // Kept so the client's feedback thread doesn't see the channel disconnect
+    _feedback_push: flume::Sender<LVFeedbackMessage>,
+    quit_tx: flume::Sender<bool>,
+}
+
+impl LVLoopback {
+    pub fn start(config: LVLoopbackConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let localhost = Ipv4Addr::LOCALHOST;
+        let port = match config.port {
+            0 => free_port()?,
+            port => port,
+        };
+        let server_addr = SocketAddrV4::new(localhost, port);
+        let server_input_addr = SocketAddrV4::new(localhost, port + 3);
+        let client_addr = SocketAddrV4::new(localhost, port + CLIENT_PORT_OFFSET);
+        let client_feedback_addr = SocketAddrV4::new(localhost, client_addr.port() + 2);
+        let client_input_addr = SocketAddrV4::new(localhost, client_addr.port() + 3);
+        info!(
+            "starting loopback, server on {} and client on {}",
+            server_addr, client_addr
+        );
+
+        // Client, as main.rs sets it up minus the window. Its sockets are bound once run
+        // returns, so the server can connect to them straight away.
+        let db = Arc::new(DoubleBuffer::new_uninitialized());
+        let (pkt_push, pkt_recv) = thingbuf::mpsc::blocking::channel::<LVPacketHolder>(1000);
+        let (input_push, inp_recv) = flume::bounded::<LVInputEvent>(10);
+        let (feedback_push, feedback_recv) = flume::bounded::<LVFeedbackMessage>(10);
+        let handshake: Arc<Mutex<Option<LVHandshake>>> = Arc::new(Mutex::new(None));
+        let client_framerate: Arc<Mutex<Option<u32>>> = Arc::new(Mutex::new(None));
+        let clock: Arc<Mutex<LVClockSync>> = Arc::new(Mutex::new(LVClockSync::new()));
+        let feedback_pkt: Arc<Mutex<(LVAck, LVFeedbackPacket)>> =
+            Arc::new(Mutex::new((Default::default(), Default::default())));
+        let udp_fd: Arc<RwLock<Option<RawFd>>> = Arc::new(RwLock::new(None));
+
+        LVNetwork::new(&client_addr.to_string())?.run(
+            pkt_push,
+            inp_recv,
+            feedback_pkt.clone(),
+            udp_fd.clone(),
+            handshake,
+            client_framerate,
+            feedback_push.clone(),
+            feedback_recv,
+            clock.clone(),
+        )?;
+        LVDecoder::run(
+            db.clone(),
+            pkt_recv,
+            feedback_pkt,
+            udp_fd,
+            clock,
+            feedback_push.clone(),
+            false,
+        );
+
+        // Server
+        encoder::registry::probe(encoder::registry::preference_from_env()?);
+        let capture_target = Arc::new(std::sync::Mutex::new(config.target));
+        let framerate = Arc::new(std::sync::Mutex::new(config.fps));
+        let encoder_backend = Arc::new(std::sync::Mutex::new(None));
+
+        let feedback_server = LVFeedbackServer::new(
+            &client_feedback_addr.to_string(),
+            capture_target.clone(),
+            framerate.clone(),
+            encoder_backend.clone(),
+        );
+        let bitrate = feedback_server.begin();
+
+        let input_mapping = Arc::new(std::sync::Mutex::new(LVInputMapping::default()));
+        let (events_push, input_events) = flume::unbounded();
+
+        let (quit_tx, quit_rx) = flume::bounded(1);
+        let mut streaming_server = LVStreamingServer::new(
+            &server_addr.to_string(),
+            &client_addr.to_string(),
+            config.fps,
+            capture_target,
+            900000,
+            quit_rx,
+            bitrate.clone(),
+            input_mapping.clone(),
+            framerate,
+            encoder_backend,
+            feedback_server.recovery(),
+            feedback_server.recording(),
+        )?;
+        streaming_server.set_impairment(config.impairment);
+        streaming_server.set_stamp_frames(config.quality);
+
+        let sources = Arc::new(Mutex::new(VecDeque::new()));
+        if config.quality {
+            LVStatisticsCollector::register_data("loopback_quality_psnr", LVDataType::XYData);
+            LVStatisticsCollector::register_data("loopback_quality_ssim", LVDataType::XYData);
+            let (tap_push, tap_recv) = flume::bounded::<LVFrame>(2);
+            streaming_server.set_source_tap(tap_push);
+            let sources = sources.clone();
+            thread::Builder::new()
+                .name("loopback_source_thread".to_string())
+                .spawn(move || {
+                    // Ends with the capture thread
+                    while let Ok(frame) = tap_recv.recv() {
+                        let Some(id) = frame.stamp() else {
+                            continue;
+                        };
+                        let rgba = to_rgba(&frame);
+                        // Back to the capturer's pool
+                        drop(frame);
+                        let mut sources = sources.lock();
+                        sources.push_back((id, rgba));
+                        if sources.len() > SOURCE_HISTORY {
+                            sources.pop_front();
+                        }
+                    }
+                })?;
+        }
+
+        LVInputServer::new(&server_input_addr.to_string()).start_receive_loop(
+            SocketAddr::V4(client_input_addr),
+            Box::new(LVMockInputEmulator {
+                events: events_push,
+            }),
+            input_mapping,
+        )?;
+
+        thread::Builder::new()
+            .name("loopback_server_thread".to_string())
+            .spawn(move || {
+                if let Err(e) = streaming_server.begin() {
+                    error!("loopback server failed with error {:?}", e);
+                }
+            })?;
+
+        Ok(Self {
+            db,
+            input_push,
+            input_events,
+            bitrate,
+            sources,
+            _feedback_push: feedback_push,
+            quit_tx,
+        })
+    }
+
+    // The next frame the decoder finishes, None if there isn't one in time.
+    pub fn next_frame(&self, timeout: Duration) -> Option<LVDecodedFrame> {
+        let start = Instant::now();
+        let swaps = self.db.swaps();
+        while start.elapsed() < timeout {
+            if self.db.swaps() != swaps {
+                if let Some(frame) = self.db.front().as_ref() {
+                    return Some(LVDecodedFrame {
+                        rgba: frame.buffer.clone(),
+                        width: frame.width,
+                        height: frame.height,
+                    });
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        None
+    }
+
+    // Measures the next decoded frame against the source frame it came from, skipping frames
+    // whose stamp didn't come through or whose source is gone. Needs quality in the config.
+    pub fn next_quality(&self, timeout: Duration) -> Option<LVQualityReport> {
+        let start = Instant::now();
+        while let Some(frame) = self.next_frame(timeout.saturating_sub(start.elapsed())) {
+            let Some(id) = quality::read_stamp(&frame.rgba, frame.width, frame.height) else {
+                continue;
+            };
+            let sources = self.sources.lock();
+            let Some((_, source)) = sources.iter().find(|(source_id, _)| *source_id == id) else {
+                continue;
+            };
+            if source.len() != 4 * frame.width * frame.height {
+                continue;
+            }
+            let report = quality::measure(&frame.rgba, source, frame.width, frame.height);
+            // Against the bitrate now, which is what the frame was encoded at give or take a
+            // feedback interval
+            let bitrate = self.bitrate() as f32;
+            LVStatisticsCollector::update_data(
+                "loopback_quality_psnr",
+                LVDataPoint::XYValue((bitrate, report.psnr as f32)),
+            );
+            LVStatisticsCollector::update_data(
+                "loopback_quality_ssim",
+                LVDataPoint::XYValue((bitrate, report.ssim as f32)),
+            );
+            return Some(report);
+        }
+        None
+    }
+
+    // Goes out the way the window's events do
+    pub fn send_input(&self, ev: LVInputEvent) -> Result<(), Box<dyn std::error::Error>> {
+        self.input_push.send(ev)?;
+        Ok(())
+    }
+
+    // The next event the server's input emulator was given
+    pub fn next_input(&self, timeout: Duration) -> Option<LVInputEvent> {
+        self.input_events.recv_timeout(timeout).ok()
+    }
+
+    // What the feedback server currently wants the encoder at
+    pub fn bitrate(&self) -> u32 {
+        *self.bitrate.lock().expect("Failed to lock bitrate mtx")
+    }
+
+    // Waits for the bitrate to satisfy f, returns it or None if it didn't in time.
+    pub fn wait_for_bitrate(&self, f: impl Fn(u32) -> bool, timeout: Duration) -> Option<u32> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            let bitrate = self.bitrate();
+            if f(bitrate) {
+                return Some(bitrate);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        None
+    }
+}
+
+impl Drop for LVLoopback {
+    // Stops the server's send loop. The client threads have no way to be stopped and keep
+    // their sockets until the process exits, hence the separate ports per test.
+    fn drop(&mut self) {
+        let _ = self.quit_tx.try_send(true);
+    }
+}
+
+// A server port where everything in PORTS is free. The client's sockets stay bound after a
+// loopback is dropped, but between here and binding them another test could get the same
+// ports, so each one is only handed out once.
+fn free_port() -> Result<u16, Box<dyn std::error::Error>> {
+    static HANDED_OUT: std::sync::Mutex<Vec<u16>> = std::sync::Mutex::new(Vec::new());
+    let mut handed_out = HANDED_OUT.lock().expect("Failed to lock handed out ports");
+    let span = PORTS
+        .iter()
+        .map(|&(offset, _)| offset)
+        .max()
+        .unwrap_or_default();
+    for _ in 0..PORT_ATTEMPTS {
+        // Wherever the OS would put an ephemeral port, and the ones after it
+        let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port();
+        if port.checked_add(span).is_none()
+            || handed_out.iter().any(|&other| other.abs_diff(port) <= span)
+        {
+            continue;
+        }
+        let free = PORTS.iter().all(|&(offset, tcp)| {
+            let addr = (Ipv4Addr::LOCALHOST, port + offset);
+            if tcp {
+                TcpListener::bind(addr).is_ok()
+            } else {
+                UdpSocket::bind(addr).is_ok()
+            }
+        });
+        if free {
+            handed_out.push(port);
+            return Ok(port);
+        }
+    }
+    Err("couldn't find free ports for the loopback".into())
+}
+
+// The first count frames of a test pattern, converted to RGBA like the decoder's output.
+pub fn source_frames(
+    pattern: LVTestPattern,
+    width: u32,
+    height: u32,
+    count: usize,
+) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut capturer = LVSyntheticCapturer::new(pattern, width, height);
+    let mut frames = Vec::with_capacity(count);
+    for _ in 0..count {
+        frames.push(to_rgba(&capturer.capture()?));
+    }
+    Ok(frames)
+}
+
+// Captured frames are BGRA and may have padding at the end of the rows
+fn to_rgba(frame: &LVFrame) -> Vec<u8> {
+    let width = frame.width() as usize;
+    let mut rgba = Vec::with_capacity(4 * width * frame.height() as usize);
+    for row in frame.as_bytes().chunks_exact(frame.stride()) {
+        for bgra in row[..4 * width].chunks_exact(4) {
+            rgba.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]]);
+        }
+    }
+    rgba
+}
+
+// The decoded frame doesn't say which source frame it was, so compare it with all of them and
+// take the closest. None if it isn't the size of the sources, e.g. the server scaled it down.
+pub fn best_psnr(frame: &LVDecodedFrame, sources: &[Vec<u8>]) -> Option<f64> {
+    let len = 4 * frame.width * frame.height;
+    sources
+        .iter()
+        .filter(|source| source.len() == len && frame.rgba.len() >= len)
+        .map(|source| quality::psnr(&frame.rgba[..len], source, frame.width, frame.height))
+        .max_by(f64::total_cmp)
+}
@@ -0,0 +1,155 @@
+// End to end over localhost. Every test gets its own free ports, they run at the same time.
+
+use std::time::Duration;
+
+use loopback::{best_psnr, source_frames, LVLoopback, LVLoopbackConfig};
+use net::{
+    impair::LVImpairment,
+    input::{ElementState, KeyCode, LVInputEvent, LVKeyboardEvent, LVMouseMoveEvent},
+};
+use server::capture::{LVCaptureTarget, LVTestPattern};
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+// The bars move 4 pixels a frame and repeat every width pixels
+const BARS_PERIOD: usize = WIDTH as usize / 4;
+const MIN_PSNR: f64 = 30.;
+const MIN_SSIM: f64 = 0.9;
+// Where the feedback server starts the bitrate off before the client's first report
+const INITIAL_BITRATE: u32 = 80000;
+// What the first report brings it to on a clean link
+const FEEDBACK_BITRATE: u32 = 900000;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn config() -> LVLoopbackConfig {
+    LVLoopbackConfig {
+        target: LVCaptureTarget::Pattern {
+            pattern: LVTestPattern::Bars,
+            width: WIDTH,
+            height: HEIGHT,
+        },
+        ..Default::default()
+    }
+}
+
+#[test]
+fn decoded_frames_match_sources() {
+    let loopback = LVLoopback::start(config()).unwrap();
+    let sources = source_frames(LVTestPattern::Bars, WIDTH, HEIGHT, BARS_PERIOD).unwrap();
+
+    // The first frames go out at the feedback server's starting bitrate, wait for the real one
+    loopback
+        .wait_for_bitrate(|bitrate| bitrate >= FEEDBACK_BITRATE, TIMEOUT)
+        .expect("the client's feedback never raised the bitrate");
+    std::thread::sleep(Duration::from_secs(1));
+
+    let mut psnrs: Vec<f64> = (0..30)
+        .map(|_| {
+            let frame = loopback
+                .next_frame(TIMEOUT)
+                .expect("no frame was decoded in time");
+            assert_eq!(
+                (frame.width, frame.height),
+                (WIDTH as usize, HEIGHT as usize)
+            );
+            best_psnr(&frame, &sources).unwrap()
+        })
+        .collect();
+    psnrs.sort_by(f64::total_cmp);
+    let median = psnrs[psnrs.len() / 2];
+    assert!(
+        median >= MIN_PSNR,
+        "median luma PSNR {:.1} dB is under {} dB, all of them: {:?}",
+        median,
+        MIN_PSNR,
+        psnrs
+    );
+}
+
+#[test]
+fn stamped_frames_measure_against_their_sources() {
+    let loopback = LVLoopback::start(LVLoopbackConfig {
+        quality: true,
+        ..config()
+    })
+    .unwrap();
+
+    loopback
+        .wait_for_bitrate(|bitrate| bitrate >= FEEDBACK_BITRATE, TIMEOUT)
+        .expect("the client's feedback never raised the bitrate");
+    std::thread::sleep(Duration::from_secs(1));
+
+    let reports: Vec<_> = (0..30)
+        .map(|_| {
+            loopback
+                .next_quality(TIMEOUT)
+                .expect("no decoded frame could be matched to its source")
+        })
+        .collect();
+    let mut psnrs: Vec<f64> = reports.iter().map(|report| report.psnr).collect();
+    let mut ssims: Vec<f64> = reports.iter().map(|report| report.ssim).collect();
+    psnrs.sort_by(f64::total_cmp);
+    ssims.sort_by(f64::total_cmp);
+    assert!(
+        psnrs[psnrs.len() / 2] >= MIN_PSNR,
+        "median luma PSNR is under {} dB: {:?}",
+        MIN_PSNR,
+        psnrs
+    );
+    assert!(
+        ssims[ssims.len() / 2] >= MIN_SSIM,
+        "median SSIM is under {}: {:?}",
+        MIN_SSIM,
+        ssims
+    );
+}
+
+#[test]
+fn input_reaches_emulator() {
+    let loopback = LVLoopback::start(config()).unwrap();
+
+    // LVInputEvent has no PartialEq, its Debug output has every field.
+    let events = [
+        LVInputEvent::MouseMoveEvent(LVMouseMoveEvent { x: 12.5, y: 100. }),
+        LVInputEvent::KeyboardEvent(LVKeyboardEvent::new(KeyCode::KeyA, ElementState::Pressed)),
+    ];
+    for ev in events {
+        let sent = format!("{:?}", ev);
+        loopback.send_input(ev).unwrap();
+        let received = loopback
+            .next_input(TIMEOUT)
+            .expect("the input emulator never got the event");
+        assert_eq!(format!("{:?}", received), sent);
+    }
+}
+
+#[test]
+fn feedback_sets_bitrate() {
+    let loopback = LVLoopback::start(config()).unwrap();
+
+    let bitrate = loopback
+        .wait_for_bitrate(|bitrate| bitrate != INITIAL_BITRATE, TIMEOUT)
+        .expect("the bitrate never moved from where the feedback server starts it");
+    assert!(
+        bitrate >= FEEDBACK_BITRATE,
+        "bitrate went to {} on a clean link",
+        bitrate
+    );
+}
+
+#[test]
+fn loss_lowers_bitrate() {
+    let loopback = LVLoopback::start(LVLoopbackConfig {
+        impairment: Some(LVImpairment::parse("loss=0.1,seed=1").unwrap()),
+        ..config()
+    })
+    .unwrap();
+
+    loopback
+        .wait_for_bitrate(
+            |bitrate| bitrate != INITIAL_BITRATE && bitrate < FEEDBACK_BITRATE,
+            TIMEOUT,
+        )
+        .expect("the bitrate didn't come down with 10% of the packets lost");
+}
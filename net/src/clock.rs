@@ -0,0 +1,286 @@
+use std::{
+    collections::VecDeque,
+    mem::size_of,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+// How many recent exchanges the offset is picked from. One exchange happens per feedback
+// quantum, so the offset is never more than this many seconds old.
+const FILTER_WINDOW: usize = 8;
+// How many filtered offsets the drift is fitted over.
+const DRIFT_WINDOW: usize = 64;
+// Don't trust the drift until the offsets cover at least this much time, otherwise jitter in
+// a couple of samples turns into a huge slope.
+const MIN_DRIFT_SPAN_US: u64 = 10_000_000;
+
+// Microseconds since the UNIX epoch on this host's clock. Milliseconds are too coarse to tell
+// the pipeline stages apart on a LAN.
+pub fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
+// One half of an NTP-style exchange. The side starting it fills in origin_ts, the other side
+// sends it back with when it received it and when it sent the reply. Whoever started it notes
+// when the reply arrived, which gives all four timestamps.
+#[repr(C, packed)]
+#[derive(
+    Serialize, Deserialize, bytemuck::NoUninit, bytemuck::AnyBitPattern, Clone, Copy, Default, Debug,
+)]
+pub struct LVClockSyncPacket {
+    // Requester's clock when the request was sent
+    pub origin_ts: u64,
+    // Responder's clock when the request arrived
+    pub recv_ts: u64,
+    // Responder's clock when the reply was sent
+    pub transmit_ts: u64,
+}
+
+impl LVClockSyncPacket {
+    pub fn no_bytes() -> usize {
+        3 * size_of::<u64>()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct LVClockSample {
+    // Our clock halfway through the exchange
+    local_us: u64,
+    // Remote clock minus ours
+    offset_us: i64,
+    // Round trip, minus the time the other side spent on it
+    delay_us: u64,
+}
+
+// Keeps track of how far the other side's clock is from ours, so its timestamps can be
+// compared with local ones. Like NTP, the offset comes from the exchange with the smallest
+// delay out of the last few, since that one had the least queueing and the most symmetric
+// path. A least squares fit over the filtered offsets gives the drift between the two
+// clocks, which keeps the offset accurate between exchanges.
+pub struct LVClockSync {
+    samples: VecDeque<LVClockSample>,
+    history: VecDeque<LVClockSample>,
+    best: Option<LVClockSample>,
+    // How many microseconds per second the remote clock gains on ours
+    drift_ppm: f64,
+}
+
+impl LVClockSync {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(FILTER_WINDOW),
+            history: VecDeque::with_capacity(DRIFT_WINDOW),
+            best: None,
+            drift_ppm: 0.,
+        }
+    }
+
+    // Feeds in a completed exchange that we started. reply_recv_ts is our clock when the reply
+    // arrived.
+    pub fn add_sample(&mut self, packet: &LVClockSyncPacket, reply_recv_ts: u64) {
+        let (t0, t1, t2, t3) = (
+            packet.origin_ts as i64,
+            packet.recv_ts as i64,
+            packet.transmit_ts as i64,
+            reply_recv_ts as i64,
+        );
+        if t0 == 0 || t3 < t0 {
+            return;
+        }
+
+        let sample = LVClockSample {
+            local_us: (t0 + (t3 - t0) / 2) as u64,
+            offset_us: ((t1 - t0) + (t2 - t3)) / 2,
+            delay_us: ((t3 - t0) - (t2 - t1)).max(0) as u64,
+        };
+
+        if self.samples.len() == FILTER_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+
+        let best = *self
+            .samples
+            .iter()
+            .min_by_key(|sample| sample.delay_us)
+            .unwrap();
+
+        // The same exchange usually stays the best for a while, only fit it once.
+        if self
+            .history
+            .back()
+            .is_none_or(|last| last.local_us != best.local_us)
+        {
+            if self.history.len() == DRIFT_WINDOW {
+                self.history.pop_front();
+            }
+            self.history.push_back(best);
+            self.update_drift();
+        }
+
+        self.best = Some(best);
+    }
+
+    fn update_drift(&mut self) {
+        let (first, last) = match (self.history.front(), self.history.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return,
+        };
+        if last.local_us - first.local_us < MIN_DRIFT_SPAN_US {
+            return;
+        }
+
+        // Relative to the first sample so the sums stay small enough for an f64.
+        let n = self.history.len() as f64;
+        let points = self.history.iter().map(|sample| {
+            (
+                (sample.local_us - first.local_us) as f64 / 1e6,
+                (sample.offset_us - first.offset_us) as f64,
+            )
+        });
+        let (sum_x, sum_y, sum_xx, sum_xy) = points.fold(
+            (0., 0., 0., 0.),
+            |(sum_x, sum_y, sum_xx, sum_xy), (x, y)| {
+                (sum_x + x, sum_y + y, sum_xx + x * x, sum_xy + x * y)
+            },
+        );
+        let denominator = n * sum_xx - sum_x * sum_x;
+        if denominator > 0. {
+            self.drift_ppm = (n * sum_xy - sum_x * sum_y) / denominator;
+        }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.best.is_some()
+    }
+
+    // Remote clock minus ours at the given local time.
+    pub fn offset_us(&self, local_us: u64) -> Option<i64> {
+        let best = self.best?;
+        let elapsed = (local_us as i64 - best.local_us as i64) as f64 / 1e6;
+        Some(best.offset_us + (self.drift_ppm * elapsed) as i64)
+    }
+
+    // Converts a timestamp from the other side's clock to ours.
+    pub fn to_local(&self, remote_us: u64) -> Option<i64> {
+        // The offset barely moves in the time between the two, so looking it up at the remote
+        // time is close enough.
+        Some(remote_us as i64 - self.offset_us(remote_us)?)
+    }
+
+    // Converts one of our timestamps to the other side's clock.
+    pub fn to_remote(&self, local_us: u64) -> Option<i64> {
+        Some(local_us as i64 + self.offset_us(local_us)?)
+    }
+
+    pub fn drift_ppm(&self) -> f64 {
+        self.drift_ppm
+    }
+
+    // Delay of the exchange the offset was taken from.
+    pub fn delay_us(&self) -> Option<u64> {
+        self.best.map(|best| best.delay_us)
+    }
+}
+
+impl Default for LVClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An exchange started at local time t0 with the remote clock offset_us ahead of ours, the
+    // request taking `there` and the reply `back` microseconds, and the other side holding on
+    // to it for 100us.
+    fn exchange(t0: u64, offset_us: i64, there: u64, back: u64) -> (LVClockSyncPacket, u64) {
+        let recv_ts = (t0 + there) as i64 + offset_us;
+        let transmit_ts = recv_ts + 100;
+        let reply_recv_ts = (transmit_ts - offset_us) as u64 + back;
+        (
+            LVClockSyncPacket {
+                origin_ts: t0,
+                recv_ts: recv_ts as u64,
+                transmit_ts: transmit_ts as u64,
+            },
+            reply_recv_ts,
+        )
+    }
+
+    #[test]
+    fn symmetric_exchange_gives_offset_and_delay() {
+        let mut clock = LVClockSync::new();
+        assert!(!clock.is_synced());
+        let (packet, reply_recv_ts) = exchange(1_000_000, 5_000, 1_000, 1_000);
+        clock.add_sample(&packet, reply_recv_ts);
+
+        assert!(clock.is_synced());
+        assert_eq!(clock.offset_us(1_000_000), Some(5_000));
+        assert_eq!(clock.delay_us(), Some(2_000));
+        assert_eq!(clock.to_remote(2_000_000), Some(2_005_000));
+        assert_eq!(clock.to_local(2_005_000), Some(2_000_000));
+    }
+
+    #[test]
+    fn keeps_the_exchange_with_the_least_delay() {
+        let mut clock = LVClockSync::new();
+        let (packet, reply_recv_ts) = exchange(1_000_000, -3_000, 500, 500);
+        clock.add_sample(&packet, reply_recv_ts);
+        // Queued for 20ms on the way there only, which throws its offset off by 10ms
+        let (packet, reply_recv_ts) = exchange(2_000_000, -3_000, 20_500, 500);
+        clock.add_sample(&packet, reply_recv_ts);
+
+        assert_eq!(clock.delay_us(), Some(1_000));
+        assert_eq!(clock.offset_us(1_000_000), Some(-3_000));
+    }
+
+    #[test]
+    fn ignores_unanswered_and_backwards_exchanges() {
+        let mut clock = LVClockSync::new();
+        clock.add_sample(&LVClockSyncPacket::default(), 1_000_000);
+        let (packet, _) = exchange(1_000_000, 0, 1_000, 1_000);
+        clock.add_sample(&packet, 999_999);
+        assert!(!clock.is_synced());
+        assert_eq!(clock.offset_us(1_000_000), None);
+    }
+
+    #[test]
+    fn fits_drift_between_clocks() {
+        let mut clock = LVClockSync::new();
+        // The remote clock gains 50us every second, one exchange a second
+        for second in 0..30u64 {
+            let t0 = 1_000_000 + second * 1_000_000;
+            let (packet, reply_recv_ts) = exchange(t0, 2_000 + 50 * second as i64, 1_000, 1_000);
+            clock.add_sample(&packet, reply_recv_ts);
+        }
+
+        assert!(
+            (clock.drift_ppm() - 50.).abs() < 1.,
+            "{}",
+            clock.drift_ppm()
+        );
+        // Ten seconds after the last exchange the offset has moved on by the drift
+        let offset = clock.offset_us(40_001_000).unwrap();
+        assert!((offset - (2_000 + 50 * 39)).abs() < 20, "{}", offset);
+    }
+
+    #[test]
+    fn packet_size_matches_serialized_size() {
+        let packet = LVClockSyncPacket {
+            origin_ts: 1,
+            recv_ts: 2,
+            transmit_ts: 3,
+        };
+        assert_eq!(
+            bincode::serialize(&packet).unwrap().len(),
+            LVClockSyncPacket::no_bytes()
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+// The video codecs we can stream with. Each one gets its own RTP payload type, which is how
+// the client tells which depacketizer and decoder to use.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LVCodec {
+    #[default]
+    H264,
+    Av1,
+}
+
+impl LVCodec {
+    // Dynamic payload types, there's no SDP to negotiate them so they're fixed here.
+    pub fn payload_type(self) -> u8 {
+        match self {
+            LVCodec::H264 => 96,
+            LVCodec::Av1 => 98,
+        }
+    }
+
+    pub fn from_payload_type(payload_type: u8) -> Option<Self> {
+        match payload_type {
+            96 => Some(LVCodec::H264),
+            98 => Some(LVCodec::Av1),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for LVCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "h264" | "avc" => Ok(LVCodec::H264),
+            "av1" => Ok(LVCodec::Av1),
+            _ => Err(format!("unknown codec {:?}, expected h264 or av1", s)),
+        }
+    }
+}
+
+// The encoder implementations the server can be built with. The server tells the client which
+// one it ended up with, mostly so it shows up in the client's logs when chasing artifacts.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LVEncoderBackend {
+    OpenH264,
+    Nvenc,
+    Vaapi,
+    Rav1e,
+}
+
+impl LVEncoderBackend {
+    pub fn codec(self) -> LVCodec {
+        match self {
+            LVEncoderBackend::Rav1e => LVCodec::Av1,
+            _ => LVCodec::H264,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LVEncoderBackend::OpenH264 => "openh264",
+            LVEncoderBackend::Nvenc => "nvenc",
+            LVEncoderBackend::Vaapi => "vaapi",
+            LVEncoderBackend::Rav1e => "rav1e",
+        }
+    }
+}
+
+impl std::fmt::Display for LVEncoderBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for LVEncoderBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "openh264" => Ok(LVEncoderBackend::OpenH264),
+            "nvenc" | "nvidia" => Ok(LVEncoderBackend::Nvenc),
+            "vaapi" => Ok(LVEncoderBackend::Vaapi),
+            "rav1e" => Ok(LVEncoderBackend::Rav1e),
+            _ => Err(format!(
+                "unknown encoder {:?}, expected openh264, nvenc, vaapi or rav1e",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,332 @@
+// Matroska, live style: the segment and the clusters have unknown sizes, so nothing ever has
+// to be seeked back to and patched.
+
+use std::io::Write;
+
+use crate::codec::LVCodec;
+
+use super::{LVMuxer, LVTrackInfo};
+
+const EBML: u32 = 0x1a45dfa3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42f7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42f2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42f3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549a966;
+const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+const MUXING_APP: u32 = 0x4d80;
+const WRITING_APP: u32 = 0x5741;
+
+const TRACKS: u32 = 0x1654ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_NUMBER: u32 = 0xd7;
+const TRACK_UID: u32 = 0x73c5;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63a2;
+const VIDEO: u32 = 0xe0;
+const PIXEL_WIDTH: u32 = 0xb0;
+const PIXEL_HEIGHT: u32 = 0xba;
+
+const CLUSTER: u32 = 0x1f43b675;
+const TIMESTAMP: u32 = 0xe7;
+const SIMPLE_BLOCK: u32 = 0xa3;
+
+// An 8 byte size with every value bit set
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+const TRACK_VIDEO: u64 = 1;
+// Block timestamps are 16 bit offsets from the cluster's, in ms
+const MAX_CLUSTER_MS: u64 = 5000;
+
+pub struct LVMkvMuxer<W: Write> {
+    out: W,
+    bytes: u64,
+    // Timestamp of the open cluster in ms, None before the first frame
+    cluster_ts: Option<u64>,
+}
+
+impl<W: Write> LVMkvMuxer<W> {
+    pub fn new(mut out: W, track: &LVTrackInfo) -> std::io::Result<Self> {
+        let mut header = Vec::new();
+        element(
+            &mut header,
+            EBML,
+            &[
+                uint(EBML_VERSION, 1),
+                uint(EBML_READ_VERSION, 1),
+                uint(EBML_MAX_ID_LENGTH, 4),
+                uint(EBML_MAX_SIZE_LENGTH, 8),
+                string(DOC_TYPE, "matroska"),
+                uint(DOC_TYPE_VERSION, 4),
+                uint(DOC_TYPE_READ_VERSION, 2),
+            ]
+            .concat(),
+        );
+        id(&mut header, SEGMENT);
+        header.extend_from_slice(&UNKNOWN_SIZE);
+        element(
+            &mut header,
+            INFO,
+            &[
+                // Timestamps in ms
+                uint(TIMESTAMP_SCALE, 1_000_000),
+                string(MUXING_APP, "lv"),
+                string(WRITING_APP, "lv"),
+            ]
+            .concat(),
+        );
+
+        let codec_id = match track.codec {
+            LVCodec::H264 => "V_MPEG4/ISO/AVC",
+            LVCodec::Av1 => "V_AV1",
+        };
+        let mut entry = Vec::new();
+        element(
+            &mut entry,
+            TRACK_ENTRY,
+            &[
+                uint(TRACK_NUMBER, 1),
+                uint(TRACK_UID, 1),
+                uint(TRACK_TYPE, TRACK_VIDEO),
+                string(CODEC_ID, codec_id),
+                binary(CODEC_PRIVATE, &track.codec_config),
+                binary(
+                    VIDEO,
+                    &[
+                        uint(PIXEL_WIDTH, track.width as u64),
+                        uint(PIXEL_HEIGHT, track.height as u64),
+                    ]
+                    .concat(),
+                ),
+            ]
+            .concat(),
+        );
+        element(&mut header, TRACKS, &entry);
+
+        out.write_all(&header)?;
+        Ok(Self {
+            out,
+            bytes: header.len() as u64,
+            cluster_ts: None,
+        })
+    }
+}
+
+impl<W: Write> LVMuxer for LVMkvMuxer<W> {
+    fn write_frame(&mut self, data: &[u8], pts: u64, keyframe: bool) -> std::io::Result<()> {
+        let pts_ms = pts / 1000;
+        let mut out = Vec::with_capacity(data.len() + 32);
+
+        // Clusters start at keyframes so players can seek to them, and before the offset gets
+        // too big for a block.
+        let cluster_ts = match self.cluster_ts {
+            Some(cluster_ts) if !keyframe && pts_ms - cluster_ts < MAX_CLUSTER_MS => cluster_ts,
+            _ => {
+                id(&mut out, CLUSTER);
+                out.extend_from_slice(&UNKNOWN_SIZE);
+                out.extend_from_slice(&uint(TIMESTAMP, pts_ms));
+                self.cluster_ts = Some(pts_ms);
+                pts_ms
+            }
+        };
+
+        // Track number as a 1 byte vint, the timestamp offset, then the flags
+        let mut block = Vec::with_capacity(data.len() + 4);
+        block.push(0x81);
+        block.extend_from_slice(&((pts_ms - cluster_ts) as i16).to_be_bytes());
+        block.push(if keyframe { 0x80 } else { 0 });
+        block.extend_from_slice(data);
+        element(&mut out, SIMPLE_BLOCK, &block);
+
+        self.out.write_all(&out)?;
+        self.bytes += out.len() as u64;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.bytes
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Element ids already carry their length marker, so they're written as is.
+fn id(out: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().position(|&b| b != 0).unwrap_or(3);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+// The shortest vint that fits, all ones is reserved for unknown sizes.
+fn size(out: &mut Vec<u8>, size: u64) {
+    let len = (1..=8).find(|len| size < (1 << (7 * len)) - 1).unwrap_or(8);
+    let marked = size | (1 << (7 * len));
+    out.extend_from_slice(&marked.to_be_bytes()[8 - len..]);
+}
+
+fn element(out: &mut Vec<u8>, element_id: u32, data: &[u8]) {
+    id(out, element_id);
+    size(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+fn binary(element_id: u32, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 8);
+    element(&mut out, element_id, data);
+    out
+}
+
+fn uint(element_id: u32, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().position(|&b| b != 0).unwrap_or(7);
+    binary(element_id, &bytes[skip..])
+}
+
+fn string(element_id: u32, value: &str) -> Vec<u8> {
+    binary(element_id, value.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A vint as (raw, value, length), ids keep their marker and sizes don't
+    fn vint(data: &[u8]) -> (u64, u64, usize) {
+        let len = data[0].leading_zeros() as usize + 1;
+        let raw = data[..len].iter().fold(0, |raw, &b| raw << 8 | b as u64);
+        (raw, raw & !(1 << (7 * len)), len)
+    }
+
+    // Elements in order. An unknown size element comes out empty with its children after it,
+    // which is how the segment and the clusters read.
+    fn elements(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut elements = Vec::new();
+        while !data.is_empty() {
+            let (element_id, _, id_len) = vint(data);
+            let (_, size, size_len) = vint(&data[id_len..]);
+            data = &data[id_len + size_len..];
+            let len = if size_len == 8 && size == (1 << 56) - 1 {
+                0
+            } else {
+                size as usize
+            };
+            elements.push((element_id as u32, &data[..len]));
+            data = &data[len..];
+        }
+        elements
+    }
+
+    fn child(data: &[u8], element_id: u32) -> &[u8] {
+        elements(data)
+            .into_iter()
+            .find(|&(id, _)| id == element_id)
+            .map(|(_, data)| data)
+            .unwrap()
+    }
+
+    fn track(codec: LVCodec) -> LVTrackInfo {
+        LVTrackInfo {
+            codec,
+            width: 1920,
+            height: 1080,
+            codec_config: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn writes_shortest_sizes() {
+        let sized = |value| {
+            let mut out = Vec::new();
+            size(&mut out, value);
+            out
+        };
+        assert_eq!(sized(0), [0x80]);
+        assert_eq!(sized(126), [0xfe]);
+        // All ones is the unknown size
+        assert_eq!(sized(127), [0x40, 0x7f]);
+        assert_eq!(sized(16382), [0x7f, 0xfe]);
+        assert_eq!(sized(16383), [0x20, 0x3f, 0xff]);
+    }
+
+    #[test]
+    fn writes_elements() {
+        let mut out = Vec::new();
+        id(&mut out, EBML);
+        id(&mut out, SIMPLE_BLOCK);
+        assert_eq!(out, [0x1a, 0x45, 0xdf, 0xa3, 0xa3]);
+
+        assert_eq!(uint(EBML_VERSION, 1), [0x42, 0x86, 0x81, 0x01]);
+        assert_eq!(uint(TIMESTAMP, 0), [0xe7, 0x81, 0x00]);
+        assert_eq!(uint(TIMESTAMP, 0x1234), [0xe7, 0x82, 0x12, 0x34]);
+        assert_eq!(
+            string(DOC_TYPE, "mkv"),
+            [0x42, 0x82, 0x83, b'm', b'k', b'v']
+        );
+    }
+
+    #[test]
+    fn header_describes_the_track() {
+        let mut out = Vec::new();
+        let written = LVMkvMuxer::new(&mut out, &track(LVCodec::Av1))
+            .unwrap()
+            .bytes_written();
+        assert_eq!(written, out.len() as u64);
+
+        let top = elements(&out);
+        let ids: Vec<u32> = top.iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, [EBML, SEGMENT, INFO, TRACKS]);
+        assert_eq!(child(top[0].1, DOC_TYPE), b"matroska");
+        assert_eq!(
+            child(top[2].1, TIMESTAMP_SCALE),
+            &1_000_000u32.to_be_bytes()[1..]
+        );
+
+        let entry = child(top[3].1, TRACK_ENTRY);
+        assert_eq!(child(entry, CODEC_ID), b"V_AV1");
+        assert_eq!(child(entry, CODEC_PRIVATE), [1, 2, 3]);
+        let video = child(entry, VIDEO);
+        assert_eq!(child(video, PIXEL_WIDTH), 1920u16.to_be_bytes());
+        assert_eq!(child(video, PIXEL_HEIGHT), 1080u16.to_be_bytes());
+    }
+
+    #[test]
+    fn clusters_start_at_keyframes() {
+        let mut out = Vec::new();
+        let mut muxer = LVMkvMuxer::new(&mut out, &track(LVCodec::H264)).unwrap();
+        let header_len = muxer.bytes_written() as usize;
+        // A keyframe, a frame, one too far from the cluster, and another keyframe
+        for (pts, keyframe) in [
+            (0, true),
+            (33_000, false),
+            (6_000_000, false),
+            (6_033_000, true),
+        ] {
+            muxer.write_frame(&[0xaa, 0xbb], pts, keyframe).unwrap();
+        }
+        muxer.finish().unwrap();
+        let written = muxer.bytes_written();
+        assert_eq!(written, out.len() as u64);
+
+        let frames = elements(&out[header_len..]);
+        let expected: [(u32, &[u8]); 10] = [
+            (CLUSTER, &[]),
+            (TIMESTAMP, &[0]),
+            (SIMPLE_BLOCK, &[0x81, 0, 0, 0x80, 0xaa, 0xbb]),
+            (SIMPLE_BLOCK, &[0x81, 0, 33, 0, 0xaa, 0xbb]),
+            (CLUSTER, &[]),
+            (TIMESTAMP, &[0x17, 0x70]),
+            (SIMPLE_BLOCK, &[0x81, 0, 0, 0, 0xaa, 0xbb]),
+            (CLUSTER, &[]),
+            (TIMESTAMP, &[0x17, 0x91]),
+            (SIMPLE_BLOCK, &[0x81, 0, 0, 0x80, 0xaa, 0xbb]),
+        ];
+        assert_eq!(frames, expected);
+    }
+}
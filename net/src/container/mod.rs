@@ -0,0 +1,345 @@
+// Writing encoded video into files. The server records the stream it sends and the client
+// dumps the one it receives, both as fragmented MP4 or Matroska. Both are written so that a
+// file cut off by a crash still plays up to the last frame.
+
+mod mkv;
+mod mp4;
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::codec::LVCodec;
+
+const NAL_IDR_SLICE: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_TEMPORAL_DELIMITER: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LVContainerFormat {
+    Mp4,
+    Mkv,
+}
+
+impl LVContainerFormat {
+    // From the extension, .mp4 or .mkv (or .webm for AV1).
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("mp4") | Some("m4v") => Some(Self::Mp4),
+            Some("mkv") | Some("webm") => Some(Self::Mkv),
+            _ => None,
+        }
+    }
+}
+
+// What the muxers need to know about the video track.
+pub struct LVTrackInfo {
+    pub codec: LVCodec,
+    pub width: u32,
+    pub height: u32,
+    // avcC or av1C, the decoder configuration record both containers carry
+    pub codec_config: Vec<u8>,
+}
+
+pub trait LVMuxer {
+    // A sample in the container's format (length prefixed NALs, or OBUs without temporal
+    // delimiters). Timestamps are microseconds since the start of the file.
+    fn write_frame(&mut self, data: &[u8], pts: u64, keyframe: bool) -> std::io::Result<()>;
+    fn bytes_written(&self) -> u64;
+    // Writes out anything still buffered. Nothing has to be patched up afterwards.
+    fn finish(&mut self) -> std::io::Result<()>;
+}
+
+// Writes the file header for the track, frames follow through the muxer.
+pub fn new_muxer<W: Write + 'static>(
+    format: LVContainerFormat,
+    out: W,
+    track: &LVTrackInfo,
+) -> std::io::Result<Box<dyn LVMuxer>> {
+    Ok(match format {
+        LVContainerFormat::Mp4 => Box::new(mp4::LVMp4Muxer::new(out, track)?),
+        LVContainerFormat::Mkv => Box::new(mkv::LVMkvMuxer::new(out, track)?),
+    })
+}
+
+// Where a new file for path goes. Recordings rotate and dumps start over when the stream
+// changes, so every file gets the time it was started at: path.mkv -> path-1700000000.mkv, or
+// path-1700000000-1.mkv if that's taken.
+pub fn file_path(path: &Path) -> PathBuf {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    // Files can start more than once a second
+    let mut file = path.with_file_name(format!("{}-{}{}", stem, started, ext));
+    let mut index = 1;
+    while file.exists() {
+        file = path.with_file_name(format!("{}-{}-{}{}", stem, started, index, ext));
+        index += 1;
+    }
+    file
+}
+
+// A sample, whether it's a keyframe, and H.264's SPS and PPS if the access unit carries them.
+pub type LVSample<'a> = (Vec<u8>, bool, Option<(&'a [u8], &'a [u8])>);
+
+// An access unit as it goes over the wire (Annex B or an OBU stream) turned into a sample.
+pub fn sample(codec: LVCodec, data: &[u8]) -> LVSample<'_> {
+    match codec {
+        LVCodec::H264 => h264_sample(data),
+        LVCodec::Av1 => av1_sample(data),
+    }
+}
+
+// Splits an Annex B stream into NAL units, start codes stripped.
+fn annex_b_nals(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let ends: Vec<usize> = starts
+        .iter()
+        .skip(1)
+        .map(|&start| {
+            // The next start code, and the zero of a 4 byte one in front of it
+            let mut end = start - 3;
+            while end > 0 && data[end - 1] == 0 {
+                end -= 1;
+            }
+            end
+        })
+        .chain(std::iter::once(data.len()))
+        .collect();
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(move |(start, end)| &data[start..end.max(start)])
+        .filter(|nal| !nal.is_empty())
+}
+
+// Annex B to 4 byte length prefixes, which both containers want. Parameter sets stay in
+// band as well, in case the encoder changes them along the way.
+fn h264_sample(data: &[u8]) -> LVSample<'_> {
+    let mut sample = Vec::with_capacity(data.len());
+    let mut keyframe = false;
+    let (mut sps, mut pps) = (None, None);
+    for nal in annex_b_nals(data) {
+        match nal[0] & 0x1f {
+            NAL_AUD => continue,
+            NAL_IDR_SLICE => keyframe = true,
+            NAL_SPS => sps = Some(nal),
+            NAL_PPS => pps = Some(nal),
+            _ => {}
+        }
+        sample.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        sample.extend_from_slice(nal);
+    }
+    (sample, keyframe, sps.zip(pps))
+}
+
+// AVCDecoderConfigurationRecord with one SPS and one PPS.
+pub fn avcc(sps: &[u8], pps: &[u8]) -> Option<Vec<u8>> {
+    if sps.len() < 4 {
+        return None;
+    }
+    let mut avcc = vec![
+        1,
+        // profile_idc, constraint flags, level_idc
+        sps[1],
+        sps[2],
+        sps[3],
+        // 4 byte lengths
+        0xfc | 3,
+        // one SPS
+        0xe0 | 1,
+    ];
+    avcc.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(sps);
+    avcc.push(1);
+    avcc.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(pps);
+    Some(avcc)
+}
+
+fn leb128(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+    for (i, byte) in data.iter().take(8).enumerate() {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+// Drops the temporal delimiters, which neither container stores. A sequence header means a
+// keyframe, rav1e only repeats it on those.
+fn av1_sample(data: &[u8]) -> LVSample<'_> {
+    let mut sample = Vec::with_capacity(data.len());
+    let mut keyframe = false;
+    let mut rest = data;
+    while let Some(&header) = rest.first() {
+        let obu_type = (header >> 3) & 0xf;
+        let header_len = if header & 0x4 != 0 { 2 } else { 1 };
+        // Without a size field the OBU runs to the end of the data
+        let obu_len = if header & 0x2 != 0 {
+            match rest.get(header_len..).and_then(leb128) {
+                Some((size, size_len)) => header_len + size_len + size as usize,
+                None => break,
+            }
+        } else {
+            rest.len()
+        };
+        let obu = &rest[..obu_len.min(rest.len())];
+        rest = &rest[obu.len()..];
+        match obu_type {
+            OBU_TEMPORAL_DELIMITER => continue,
+            OBU_SEQUENCE_HEADER => keyframe = true,
+            _ => {}
+        }
+        sample.extend_from_slice(obu);
+    }
+    (sample, keyframe, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 4] = [0x67, 0x42, 0xc0, 0x1f];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+
+    #[test]
+    fn picks_the_format_from_the_extension() {
+        let format = |path: &str| LVContainerFormat::from_path(Path::new(path));
+        assert_eq!(format("a.mp4"), Some(LVContainerFormat::Mp4));
+        assert_eq!(format("a.m4v"), Some(LVContainerFormat::Mp4));
+        assert_eq!(format("dir/a.mkv"), Some(LVContainerFormat::Mkv));
+        assert_eq!(format("a.webm"), Some(LVContainerFormat::Mkv));
+        assert_eq!(format("a.h264"), None);
+        assert_eq!(format("mp4"), None);
+    }
+
+    #[test]
+    fn file_paths_never_overwrite() {
+        let path = std::env::temp_dir().join(format!("lv-container-{}.mkv", std::process::id()));
+        let first = file_path(&path);
+        assert_eq!(first.parent(), path.parent());
+        let first_stem = first.file_stem().unwrap().to_string_lossy().into_owned();
+        let started = first_stem
+            .strip_prefix(&format!("lv-container-{}-", std::process::id()))
+            .unwrap();
+        assert!(started.parse::<u64>().is_ok(), "{}", first_stem);
+        assert_eq!(first.extension().unwrap(), "mkv");
+
+        // Taken, another file in the same second gets an index
+        std::fs::File::create(&first).unwrap();
+        let second = file_path(&path);
+        std::fs::remove_file(&first).unwrap();
+        let second_name = second.file_name().unwrap().to_string_lossy().into_owned();
+        // Unless the clock ticked over in between
+        assert!(
+            second_name == format!("{}-1.mkv", first_stem) || !second_name.starts_with(&first_stem),
+            "{}",
+            second_name
+        );
+    }
+
+    #[test]
+    fn splits_annex_b() {
+        // A 4 byte start code, a 3 byte one, and trailing zeros before the next
+        let data = [
+            0, 0, 0, 1, 0x09, 0xf0, 0, 0, 1, 0x67, 1, 0, 0, 0, 0, 1, 0x65, 2, 3,
+        ];
+        let nals: Vec<&[u8]> = annex_b_nals(&data).collect();
+        assert_eq!(nals, [&[0x09, 0xf0][..], &[0x67, 1], &[0x65, 2, 3]]);
+
+        assert_eq!(annex_b_nals(&[]).count(), 0);
+        assert_eq!(annex_b_nals(&[0, 0, 1]).count(), 0);
+    }
+
+    #[test]
+    fn h264_access_unit_to_length_prefixed() {
+        let mut data = vec![0, 0, 0, 1, 0x09, 0xf0];
+        for nal in [&SPS[..], &PPS, &[0x65, 0x88, 0x84]] {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(nal);
+        }
+        let (au, keyframe, params) = sample(LVCodec::H264, &data);
+        assert!(keyframe);
+        assert_eq!(params, Some((&SPS[..], &PPS[..])));
+        // The AUD is gone, the rest is prefixed
+        let mut expected = vec![0, 0, 0, 4];
+        expected.extend_from_slice(&SPS);
+        expected.extend_from_slice(&[0, 0, 0, 4]);
+        expected.extend_from_slice(&PPS);
+        expected.extend_from_slice(&[0, 0, 0, 3, 0x65, 0x88, 0x84]);
+        assert_eq!(au, expected);
+
+        let (au, keyframe, params) = sample(LVCodec::H264, &[0, 0, 1, 0x41, 0x9a]);
+        assert!(!keyframe);
+        assert_eq!(params, None);
+        assert_eq!(au, [0, 0, 0, 2, 0x41, 0x9a]);
+    }
+
+    #[test]
+    fn builds_avcc() {
+        let avcc = avcc(&SPS, &PPS).unwrap();
+        assert_eq!(
+            avcc,
+            [
+                1, 0x42, 0xc0, 0x1f, 0xff, 0xe1, 0, 4, 0x67, 0x42, 0xc0, 0x1f, 1, 0, 4, 0x68, 0xce,
+                0x3c, 0x80
+            ]
+        );
+        assert_eq!(super::avcc(&[0x67, 0x42], &PPS), None);
+    }
+
+    #[test]
+    fn reads_leb128() {
+        assert_eq!(leb128(&[0x05]), Some((5, 1)));
+        assert_eq!(leb128(&[0xe5, 0x8e, 0x26, 0xff]), Some((624_485, 3)));
+        assert_eq!(leb128(&[0x80, 0x80]), None);
+        assert_eq!(leb128(&[0x80; 9]), None);
+    }
+
+    #[test]
+    fn av1_drops_temporal_delimiters() {
+        // Temporal delimiter, a sequence header, then a frame without a size field
+        let data = [0x12, 0x00, 0x0a, 0x02, 0xaa, 0xbb, 0x30, 0x01, 0x02, 0x03];
+        let (au, keyframe, params) = sample(LVCodec::Av1, &data);
+        assert!(keyframe);
+        assert_eq!(params, None);
+        assert_eq!(au, data[2..]);
+
+        let (au, keyframe, _) = sample(LVCodec::Av1, &[0x12, 0x00, 0x32, 0x01, 0xcc]);
+        assert!(!keyframe);
+        assert_eq!(au, [0x32, 0x01, 0xcc]);
+    }
+
+    #[test]
+    fn av1_stops_at_a_broken_size() {
+        let (au, _, _) = sample(LVCodec::Av1, &[0x32, 0x01, 0xcc, 0x32, 0x80]);
+        assert_eq!(au, [0x32, 0x01, 0xcc]);
+    }
+}
@@ -0,0 +1,383 @@
+// Fragmented MP4: an empty moov up front, then a moof + mdat per frame. Each fragment is
+// complete on its own, so there's no index to write at the end.
+
+use std::io::Write;
+
+use crate::codec::LVCodec;
+
+use super::{LVMuxer, LVTrackInfo};
+
+// Track timestamps are in 90kHz ticks like RTP, the movie header's in ms.
+const TIMESCALE: u32 = 90_000;
+const MOVIE_TIMESCALE: u32 = 1000;
+const TRACK_ID: u32 = 1;
+
+// sample_depends_on = 2 (an I frame), and sample_depends_on = 1 + sample_is_non_sync_sample
+const KEYFRAME_FLAGS: u32 = 0x0200_0000;
+const FRAME_FLAGS: u32 = 0x0101_0000;
+
+// Identity, in 16.16 and 2.30 fixed point
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x4000_0000];
+
+struct LVPendingSample {
+    data: Vec<u8>,
+    // In TIMESCALE ticks
+    decode_time: u64,
+    keyframe: bool,
+}
+
+pub struct LVMp4Muxer<W: Write> {
+    out: W,
+    bytes: u64,
+    sequence: u32,
+    // A sample's duration is only known once the next one arrives, so each is held back a
+    // frame.
+    pending: Option<LVPendingSample>,
+    last_duration: u32,
+}
+
+impl<W: Write> LVMp4Muxer<W> {
+    pub fn new(mut out: W, track: &LVTrackInfo) -> std::io::Result<Self> {
+        let (brand, entry, config) = match track.codec {
+            // avc3 rather than avc1, parameter sets stay in band
+            LVCodec::H264 => (b"avc1", b"avc3", b"avcC"),
+            LVCodec::Av1 => (b"av01", b"av01", b"av1C"),
+        };
+
+        let mut header = Vec::new();
+        mp4_box(&mut header, b"ftyp", |ftyp| {
+            ftyp.extend_from_slice(b"isom");
+            ftyp.extend_from_slice(&0x200u32.to_be_bytes());
+            for compatible in [b"isom", b"iso6", b"mp41", brand] {
+                ftyp.extend_from_slice(compatible);
+            }
+        });
+        mp4_box(&mut header, b"moov", |moov| {
+            full_box(moov, b"mvhd", 0, 0, |mvhd| {
+                // creation and modification time
+                put_u32s(mvhd, &[0, 0, MOVIE_TIMESCALE, 0]);
+                // rate, volume
+                put_u32s(mvhd, &[0x10000]);
+                mvhd.extend_from_slice(&0x100u16.to_be_bytes());
+                mvhd.extend_from_slice(&[0; 10]);
+                put_u32s(mvhd, &MATRIX);
+                mvhd.extend_from_slice(&[0; 24]);
+                // next_track_ID
+                put_u32s(mvhd, &[TRACK_ID + 1]);
+            });
+            mp4_box(moov, b"trak", |trak| {
+                // enabled, in movie
+                full_box(trak, b"tkhd", 0, 3, |tkhd| {
+                    put_u32s(tkhd, &[0, 0, TRACK_ID, 0, 0]);
+                    tkhd.extend_from_slice(&[0; 8]);
+                    // layer, alternate group, volume, reserved
+                    tkhd.extend_from_slice(&[0; 8]);
+                    put_u32s(tkhd, &MATRIX);
+                    put_u32s(tkhd, &[track.width << 16, track.height << 16]);
+                });
+                mp4_box(trak, b"mdia", |mdia| {
+                    full_box(mdia, b"mdhd", 0, 0, |mdhd| {
+                        put_u32s(mdhd, &[0, 0, TIMESCALE, 0]);
+                        // language "und", pre_defined
+                        mdhd.extend_from_slice(&[0x55, 0xc4, 0, 0]);
+                    });
+                    full_box(mdia, b"hdlr", 0, 0, |hdlr| {
+                        put_u32s(hdlr, &[0]);
+                        hdlr.extend_from_slice(b"vide");
+                        hdlr.extend_from_slice(&[0; 12]);
+                        hdlr.extend_from_slice(b"lv video\0");
+                    });
+                    mp4_box(mdia, b"minf", |minf| {
+                        full_box(minf, b"vmhd", 0, 1, |vmhd| vmhd.extend_from_slice(&[0; 8]));
+                        mp4_box(minf, b"dinf", |dinf| {
+                            full_box(dinf, b"dref", 0, 0, |dref| {
+                                put_u32s(dref, &[1]);
+                                // The samples are in this file
+                                full_box(dref, b"url ", 0, 1, |_| {});
+                            });
+                        });
+                        mp4_box(minf, b"stbl", |stbl| {
+                            full_box(stbl, b"stsd", 0, 0, |stsd| {
+                                put_u32s(stsd, &[1]);
+                                mp4_box(stsd, entry, |visual| {
+                                    visual_sample_entry(visual, track);
+                                    mp4_box(visual, config, |record| {
+                                        record.extend_from_slice(&track.codec_config)
+                                    });
+                                });
+                            });
+                            // The sample tables are empty, the fragments describe the samples
+                            for table in [b"stts", b"stsc", b"stco"] {
+                                full_box(stbl, table, 0, 0, |table| put_u32s(table, &[0]));
+                            }
+                            full_box(stbl, b"stsz", 0, 0, |stsz| put_u32s(stsz, &[0, 0]));
+                        });
+                    });
+                });
+            });
+            mp4_box(moov, b"mvex", |mvex| {
+                full_box(mvex, b"trex", 0, 0, |trex| {
+                    put_u32s(trex, &[TRACK_ID, 1, 0, 0, 0]);
+                });
+            });
+        });
+
+        out.write_all(&header)?;
+        Ok(Self {
+            out,
+            bytes: header.len() as u64,
+            sequence: 1,
+            pending: None,
+            last_duration: TIMESCALE / 60,
+        })
+    }
+
+    fn write_fragment(&mut self, sample: LVPendingSample, duration: u32) -> std::io::Result<()> {
+        let mut fragment = Vec::with_capacity(sample.data.len() + 128);
+        let mut data_offset_at = 0;
+        mp4_box(&mut fragment, b"moof", |moof| {
+            full_box(moof, b"mfhd", 0, 0, |mfhd| put_u32s(mfhd, &[self.sequence]));
+            mp4_box(moof, b"traf", |traf| {
+                // default-base-is-moof, offsets are from the start of the moof
+                full_box(traf, b"tfhd", 0, 0x020000, |tfhd| {
+                    put_u32s(tfhd, &[TRACK_ID])
+                });
+                full_box(traf, b"tfdt", 1, 0, |tfdt| {
+                    tfdt.extend_from_slice(&sample.decode_time.to_be_bytes())
+                });
+                // data-offset, sample-duration, sample-size and sample-flags present
+                full_box(traf, b"trun", 0, 0x000701, |trun| {
+                    put_u32s(trun, &[1]);
+                    data_offset_at = trun.len();
+                    put_u32s(trun, &[0]);
+                    let flags = if sample.keyframe {
+                        KEYFRAME_FLAGS
+                    } else {
+                        FRAME_FLAGS
+                    };
+                    put_u32s(trun, &[duration, sample.data.len() as u32, flags]);
+                });
+            });
+        });
+        // The sample starts right after the mdat header.
+        let data_offset = (fragment.len() + 8) as u32;
+        fragment[data_offset_at..data_offset_at + 4].copy_from_slice(&data_offset.to_be_bytes());
+
+        fragment.extend_from_slice(&(sample.data.len() as u32 + 8).to_be_bytes());
+        fragment.extend_from_slice(b"mdat");
+        fragment.extend_from_slice(&sample.data);
+
+        self.out.write_all(&fragment)?;
+        self.bytes += fragment.len() as u64;
+        self.sequence += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> LVMuxer for LVMp4Muxer<W> {
+    fn write_frame(&mut self, data: &[u8], pts: u64, keyframe: bool) -> std::io::Result<()> {
+        let decode_time = pts * TIMESCALE as u64 / 1_000_000;
+        if let Some(previous) = self.pending.take() {
+            let duration = (decode_time - previous.decode_time).max(1) as u32;
+            self.last_duration = duration;
+            self.write_fragment(previous, duration)?;
+        }
+        self.pending = Some(LVPendingSample {
+            data: data.to_vec(),
+            decode_time,
+            keyframe,
+        });
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.bytes
+    }
+
+    // The last frame gets as long as the one before it.
+    fn finish(&mut self) -> std::io::Result<()> {
+        if let Some(last) = self.pending.take() {
+            self.write_fragment(last, self.last_duration)?;
+        }
+        self.out.flush()
+    }
+}
+
+fn visual_sample_entry(out: &mut Vec<u8>, track: &LVTrackInfo) {
+    // reserved, data_reference_index
+    out.extend_from_slice(&[0; 6]);
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&[0; 16]);
+    out.extend_from_slice(&(track.width as u16).to_be_bytes());
+    out.extend_from_slice(&(track.height as u16).to_be_bytes());
+    // 72 dpi both ways, reserved, frame_count
+    put_u32s(out, &[0x0048_0000, 0x0048_0000, 0]);
+    out.extend_from_slice(&1u16.to_be_bytes());
+    // compressorname
+    out.extend_from_slice(&[0; 32]);
+    // depth, pre_defined = -1
+    out.extend_from_slice(&[0x00, 0x18, 0xff, 0xff]);
+}
+
+fn put_u32s(out: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+// Writes the box header, lets `body` fill it in, then goes back for the size.
+fn mp4_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    mp4_box(out, kind, |out| {
+        out.push(version);
+        out.extend_from_slice(&flags.to_be_bytes()[1..]);
+        body(out);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boxes(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut boxes = Vec::new();
+        while !data.is_empty() {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            boxes.push((&data[4..8], &data[8..size]));
+            data = &data[size..];
+        }
+        boxes
+    }
+
+    fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> &'a [u8] {
+        boxes(data)
+            .into_iter()
+            .find(|&(k, _)| k == kind)
+            .map(|(_, body)| body)
+            .unwrap()
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn track() -> LVTrackInfo {
+        LVTrackInfo {
+            codec: LVCodec::H264,
+            width: 1280,
+            height: 720,
+            codec_config: vec![1, 0x42, 0xc0, 0x1f],
+        }
+    }
+
+    #[test]
+    fn boxes_carry_their_size() {
+        let mut out = Vec::new();
+        full_box(&mut out, b"test", 1, 0x020304, |body| body.push(0xaa));
+        assert_eq!(out, [0, 0, 0, 13, b't', b'e', b's', b't', 1, 2, 3, 4, 0xaa]);
+
+        let mut out = Vec::new();
+        mp4_box(&mut out, b"outr", |outer| mp4_box(outer, b"innr", |_| {}));
+        assert_eq!(u32_at(&out, 0), 16);
+        assert_eq!(u32_at(&out, 8), 8);
+    }
+
+    #[test]
+    fn header_describes_the_track() {
+        let mut out = Vec::new();
+        let written = LVMp4Muxer::new(&mut out, &track()).unwrap().bytes_written();
+        assert_eq!(written, out.len() as u64);
+
+        let top = boxes(&out);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, b"ftyp");
+        assert_eq!(&top[0].1[..4], b"isom");
+        assert!(top[0].1.ends_with(b"avc1"));
+
+        let moov = top[1].1;
+        let kinds: Vec<&[u8]> = boxes(moov).iter().map(|&(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"mvhd", b"trak", b"mvex"]);
+        let tkhd = child(child(moov, b"trak"), b"tkhd");
+        assert_eq!(u32_at(tkhd, 12), TRACK_ID);
+        assert_eq!(u32_at(tkhd, tkhd.len() - 8), 1280 << 16);
+        assert_eq!(u32_at(tkhd, tkhd.len() - 4), 720 << 16);
+
+        let mdia = child(child(moov, b"trak"), b"mdia");
+        assert_eq!(u32_at(child(mdia, b"mdhd"), 12), TIMESCALE);
+        let stbl = child(child(mdia, b"minf"), b"stbl");
+        // Version and flags, then the entry count
+        let stsd = child(stbl, b"stsd");
+        assert_eq!(u32_at(stsd, 4), 1);
+        let avc3 = child(&stsd[8..], b"avc3");
+        assert_eq!(&avc3[24..28], [5, 0, 2, 0xd0]);
+        // The config record follows the 78 byte visual sample entry
+        assert_eq!(child(&avc3[78..], b"avcC"), [1, 0x42, 0xc0, 0x1f]);
+    }
+
+    #[test]
+    fn fragments_hold_one_sample_each() {
+        let mut out = Vec::new();
+        let mut muxer = LVMp4Muxer::new(&mut out, &track()).unwrap();
+        let header_len = muxer.bytes_written() as usize;
+        let frames: [(&[u8], u64, bool); 3] = [
+            (&[0xaa; 5], 0, true),
+            (&[0xbb; 3], 33_333, false),
+            (&[0xcc; 4], 66_666, false),
+        ];
+        for (i, &(data, pts, keyframe)) in frames.iter().enumerate() {
+            muxer.write_frame(data, pts, keyframe).unwrap();
+            // Held back until the next one gives it a duration
+            assert_eq!(muxer.bytes_written() == header_len as u64, i == 0);
+        }
+        muxer.finish().unwrap();
+        let written = muxer.bytes_written();
+        assert_eq!(written, out.len() as u64);
+
+        let fragments = boxes(&out[header_len..]);
+        assert_eq!(fragments.len(), 6);
+        let mut start = header_len;
+        for (i, pair) in fragments.chunks(2).enumerate() {
+            let [(moof_kind, moof), (mdat_kind, mdat)] = pair else {
+                unreachable!()
+            };
+            assert_eq!((*moof_kind, *mdat_kind), (&b"moof"[..], &b"mdat"[..]));
+            assert_eq!(*mdat, frames[i].0);
+            assert_eq!(u32_at(child(moof, b"mfhd"), 4), i as u32 + 1);
+
+            let traf = child(moof, b"traf");
+            let tfdt = child(traf, b"tfdt");
+            let decode_time = u64::from_be_bytes(tfdt[4..12].try_into().unwrap());
+            assert_eq!(decode_time, [0, 2999, 5999][i]);
+
+            let trun = child(traf, b"trun");
+            assert_eq!(u32_at(trun, 4), 1);
+            // The data offset is from the start of the moof and lands on the sample
+            let data_offset = u32_at(trun, 8) as usize;
+            assert_eq!(&out[start + data_offset..][..mdat.len()], *mdat);
+            assert_eq!(u32_at(trun, 12), [2999, 3000, 3000][i]);
+            assert_eq!(u32_at(trun, 16), mdat.len() as u32);
+            let flags = if frames[i].2 {
+                KEYFRAME_FLAGS
+            } else {
+                FRAME_FLAGS
+            };
+            assert_eq!(u32_at(trun, 20), flags);
+
+            start += moof.len() + mdat.len() + 16;
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{clock::LVClockSyncPacket, codec::LVEncoderBackend};
+
+// Control packets are small, anything longer than this is a corrupt or hostile length prefix.
+pub const MAX_CONTROL_PACKET_LEN: usize = 64 * 1024;
+
+// Sentinel monitor index meaning "compose every monitor into one stream".
+pub const ALL_MONITORS: u32 = u32::MAX;
+
+// Messages the server sends to the client over the feedback connection. Unlike the
+// fixed-size feedback and ack packets these can vary in size, so every packet is prefixed
+// with its length in bytes (u32, big endian).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum LVControlPacket {
+    // Sent when the feedback connection comes up and whenever the streamed monitor changes.
+    Handshake(LVHandshake),
+    // The server's answer to a CLOCK_SYNC_TYPE feedback message.
+    ClockSyncReply(LVClockSyncPacket),
+    // The server starting its own clock sync exchange, answered with CLOCK_SYNC_REPLY_TYPE.
+    ClockSyncRequest(LVClockSyncPacket),
+    // The framerate the server is currently capturing and encoding at. Sent on connect and
+    // whenever it adapts to congestion.
+    Framerate(u32),
+    // The encoder the server is using. Sent once it's known and again whenever the encoder is
+    // rebuilt, since a rebuild can fall back to a different backend.
+    Encoder(LVEncoderBackend),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LVHandshake {
+    pub monitors: Vec<LVMonitorInfo>,
+    // Index into monitors, or ALL_MONITORS
+    pub current_monitor: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct LVMonitorInfo {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub primary: bool,
+}
+
+impl LVControlPacket {
+    pub fn write_to(&self, stream: &mut impl Write) -> Result<(), Box<dyn std::error::Error>> {
+        let data = bincode::serialize(self)?;
+        stream.write_all(&(data.len() as u32).to_be_bytes())?;
+        stream.write_all(&data)?;
+        Ok(())
+    }
+
+    pub fn read_from(stream: &mut impl Read) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_CONTROL_PACKET_LEN {
+            return Err(format!(
+                "control packet is {} bytes, more than the {} allowed",
+                len, MAX_CONTROL_PACKET_LEN
+            )
+            .into());
+        }
+        let mut data = vec![0; len];
+        stream.read_exact(&mut data)?;
+        Ok(bincode::deserialize(&data)?)
+    }
+}
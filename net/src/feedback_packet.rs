@@ -4,6 +4,18 @@ use serde::{Deserialize, Serialize};
 
 pub const ACK_TYPE: u8 = 0;
 pub const FEEDBACK_TYPE: u8 = 1;
+pub const MONITOR_SWITCH_TYPE: u8 = 2;
+// The client starting a clock sync exchange, and the client answering one the server started.
+// Both carry a net::clock::LVClockSyncPacket.
+pub const CLOCK_SYNC_TYPE: u8 = 3;
+pub const CLOCK_SYNC_REPLY_TYPE: u8 = 4;
+pub const REFERENCE_INVALIDATION_TYPE: u8 = 5;
+// Start or stop recording the stream on the server. Just the type, there's no body.
+pub const RECORD_TOGGLE_TYPE: u8 = 6;
+// The client put a refined tile (net::packet::LVRefineHeader) together and is drawing it.
+pub const REFINE_ACK_TYPE: u8 = 7;
+// The client missed refinement packets and dropped its refined tiles. No body.
+pub const REFINE_LOSS_TYPE: u8 = 8;
 
 const EMPTY_PKT: LVFeedbackPacket = LVFeedbackPacket {
     time_quantum: 0,
@@ -13,6 +25,7 @@ const EMPTY_PKT: LVFeedbackPacket = LVFeedbackPacket {
     ecc_decoder_failures: 0,
     average_buffer_occupancy: 0,
     lost_packets: 0,
+    last_good_frame: 0,
 };
 
 #[repr(C)]
@@ -27,13 +40,18 @@ pub struct LVFeedbackHeader {
 pub struct LVAck {
     // Sequence number for packet
     pub rtp_seqno: u16,
-    // Time
+    // Server time the packet was sent (us), copied from its LVErasureInformation
     pub send_ts: u128,
+    // Client time the packet was received (us)
+    pub recv_ts: u64,
+    // How long the client held on to the ack before sending it (us). Acks only go out once per
+    // feedback quantum, so this has to come off the RTT.
+    pub hold_us: u32,
 }
 
 impl LVAck {
     pub fn no_bytes() -> usize {
-        size_of::<u128>() + size_of::<u16>()
+        size_of::<u128>() + size_of::<u16>() + size_of::<u64>() + size_of::<u32>()
     }
 }
 
@@ -60,6 +78,10 @@ pub struct LVFeedbackPacket {
 
     // Average buffer occupancy over the last time quantum on the client
     pub average_buffer_occupancy: u32,
+
+    // The newest net::packet::LVFrameId the client decoded with all its references intact,
+    // 0 if none yet. The encoder uses this to know which long-term references are safe.
+    pub last_good_frame: u32,
 }
 
 impl LVFeedbackPacket {
@@ -67,3 +89,54 @@ impl LVFeedbackPacket {
         bytemuck::bytes_of(&EMPTY_PKT).len()
     }
 }
+
+// Ask the server to stream a different monitor (or net::control_packet::ALL_MONITORS).
+#[repr(C, packed)]
+#[derive(
+    Serialize, Deserialize, bytemuck::NoUninit, bytemuck::AnyBitPattern, Clone, Copy, Default, Debug,
+)]
+pub struct LVMonitorSwitch {
+    pub monitor: u32,
+}
+
+impl LVMonitorSwitch {
+    pub fn no_bytes() -> usize {
+        size_of::<u32>()
+    }
+}
+
+// The client lost (or failed to decode) a frame and everything after it is suspect until the
+// server sends a recovery point. The server can encode against last_good_frame instead of
+// starting over with a keyframe, if its encoder still has that frame as a reference.
+#[repr(C, packed)]
+#[derive(
+    Serialize, Deserialize, bytemuck::NoUninit, bytemuck::AnyBitPattern, Clone, Copy, Default, Debug,
+)]
+pub struct LVReferenceInvalidation {
+    // 0 if the client never decoded a frame cleanly
+    pub last_good_frame: u32,
+    // The first frame that was lost or broken
+    pub lost_frame: u32,
+}
+
+impl LVReferenceInvalidation {
+    pub fn no_bytes() -> usize {
+        2 * size_of::<u32>()
+    }
+}
+
+// The server only counts a tile as refined once the client says it has it, a tile that got
+// lost would never be sent again otherwise.
+#[repr(C, packed)]
+#[derive(
+    Serialize, Deserialize, bytemuck::NoUninit, bytemuck::AnyBitPattern, Clone, Copy, Default, Debug,
+)]
+pub struct LVRefineAck {
+    pub tile_id: u32,
+}
+
+impl LVRefineAck {
+    pub fn no_bytes() -> usize {
+        size_of::<u32>()
+    }
+}
@@ -0,0 +1,738 @@
+// An in-process network emulator, for testing FEC and rate control without tc netem. The
+// socket wrapper drops, delays, reorders, duplicates and rate limits datagrams on their way
+// out (server) or in (client), like a bad link between the two would.
+//
+// Configured with LV_IMPAIR, comma or space separated key=value pairs:
+//   loss=0.02 burst=4   Gilbert-Elliott loss, 2% on average in bursts of ~4 packets. Or set
+//                       ge_p, ge_r, good_loss and bad_loss directly.
+//   delay=30 jitter=10  one way delay and jitter in ms, dist=uniform (default) or dist=normal
+//   reorder=0.05        chance a packet skips the delay, overtaking the ones in flight
+//   duplicate=0.01      chance a packet arrives twice
+//   rate=5000           token bucket in kbit/s, with rate_burst bytes of burst and queue ms of
+//                       queue before it drops
+//   seed=1              runs with the same seed and traffic drop the same packets
+//   trace=link.txt      lines of "<seconds> key=value ...", applied that long after the
+//                       socket was made
+
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, VecDeque},
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LVDelayDistribution {
+    // Anywhere within jitter of the delay
+    Uniform,
+    // jitter is the standard deviation
+    Normal,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LVImpairment {
+    // Gilbert-Elliott: the chance of going from the good state to the bad one and back, per
+    // packet, and the loss rate in each.
+    pub ge_p: f64,
+    pub ge_r: f64,
+    pub good_loss: f64,
+    pub bad_loss: f64,
+    pub delay: Duration,
+    pub jitter: Duration,
+    pub distribution: LVDelayDistribution,
+    pub reorder: f64,
+    pub duplicate: f64,
+    // Token bucket, None for no limit
+    pub rate_kbps: Option<u64>,
+    pub rate_burst: usize,
+    // Packets that would wait longer than this for the bucket are dropped, like a full router
+    // queue.
+    pub queue_limit: Duration,
+    pub seed: u64,
+    // Settings to switch to, by time since the socket was made
+    pub trace: Vec<(Duration, String)>,
+}
+
+impl Default for LVImpairment {
+    fn default() -> Self {
+        Self {
+            ge_p: 0.,
+            ge_r: 1.,
+            good_loss: 0.,
+            bad_loss: 1.,
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            distribution: LVDelayDistribution::Uniform,
+            reorder: 0.,
+            duplicate: 0.,
+            rate_kbps: None,
+            rate_burst: 15_000,
+            queue_limit: Duration::from_millis(100),
+            seed: 1,
+            trace: Vec::new(),
+        }
+    }
+}
+
+impl LVImpairment {
+    // None without LV_IMPAIR, the sockets are left alone then.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        match std::env::var("LV_IMPAIR") {
+            Ok(spec) => Ok(Some(Self::parse(&spec)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut impairment = Self::default();
+        impairment.apply(spec)?;
+        Ok(impairment)
+    }
+
+    // Changes the settings named in spec and leaves the rest.
+    pub fn apply(&mut self, spec: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let probability = |key: &str, value: &str| -> Result<f64, Box<dyn std::error::Error>> {
+            let p: f64 = value.parse()?;
+            if !(0. ..=1.).contains(&p) {
+                return Err(format!("{} has to be between 0 and 1, not {}", key, p).into());
+            }
+            Ok(p)
+        };
+        let ms = |value: &str| -> Result<Duration, Box<dyn std::error::Error>> {
+            Ok(Duration::from_secs_f64(
+                value.parse::<f64>()?.max(0.) / 1000.,
+            ))
+        };
+
+        // loss and burst are two views of the same parameters, work them out at the end
+        let (mut loss, mut burst) = (None, None);
+        for setting in spec.split(|c: char| c == ',' || c.is_whitespace()) {
+            if setting.is_empty() {
+                continue;
+            }
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("impairment setting {:?} isn't key=value", setting))?;
+            match key {
+                "loss" => loss = Some(probability(key, value)?),
+                "burst" => burst = Some(value.parse::<f64>()?.max(1.)),
+                "ge_p" => self.ge_p = probability(key, value)?,
+                "ge_r" => self.ge_r = probability(key, value)?,
+                "good_loss" => self.good_loss = probability(key, value)?,
+                "bad_loss" => self.bad_loss = probability(key, value)?,
+                "delay" => self.delay = ms(value)?,
+                "jitter" => self.jitter = ms(value)?,
+                "dist" => {
+                    self.distribution = match value {
+                        "uniform" => LVDelayDistribution::Uniform,
+                        "normal" => LVDelayDistribution::Normal,
+                        _ => return Err(format!("unknown delay distribution {}", value).into()),
+                    }
+                }
+                "reorder" => self.reorder = probability(key, value)?,
+                "duplicate" => self.duplicate = probability(key, value)?,
+                "rate" => {
+                    self.rate_kbps = match value.parse()? {
+                        0 => None,
+                        kbps => Some(kbps),
+                    }
+                }
+                "rate_burst" => self.rate_burst = value.parse()?,
+                "queue" => self.queue_limit = ms(value)?,
+                "seed" => self.seed = value.parse()?,
+                "trace" => self.trace = read_trace(value)?,
+                _ => return Err(format!("unknown impairment setting {}", key).into()),
+            }
+        }
+
+        if loss.is_some() || burst.is_some() {
+            let loss = loss.unwrap_or_else(|| self.average_loss());
+            if loss >= 1. {
+                return Err("loss has to be below 1".into());
+            }
+            // Lossless good state, lossy bad state, and a stationary bad state probability of
+            // loss: p / (p + r) = loss
+            self.ge_r = 1. / burst.unwrap_or(1. / self.ge_r.max(f64::MIN_POSITIVE));
+            self.ge_p = (loss * self.ge_r / (1. - loss)).min(1.);
+            self.good_loss = 0.;
+            self.bad_loss = 1.;
+        }
+        Ok(())
+    }
+
+    pub fn average_loss(&self) -> f64 {
+        if self.ge_p + self.ge_r == 0. {
+            return self.good_loss;
+        }
+        let bad = self.ge_p / (self.ge_p + self.ge_r);
+        bad * self.bad_loss + (1. - bad) * self.good_loss
+    }
+}
+
+// "<seconds> key=value ..." per line, # for comments
+fn read_trace(path: &str) -> Result<Vec<(Duration, String)>, Box<dyn std::error::Error>> {
+    let mut trace = Vec::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (at, spec) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        trace.push((Duration::from_secs_f64(at.parse()?), spec.to_string()));
+    }
+    trace.sort_by_key(|(at, _)| *at);
+    Ok(trace)
+}
+
+struct LVDelayedPacket {
+    due: Instant,
+    // Keeps packets due at the same time in order
+    seq: u64,
+    data: Vec<u8>,
+    addr: SocketAddr,
+}
+
+// BinaryHeap is a max heap, the earliest packet goes first.
+impl Ord for LVDelayedPacket {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .due
+            .cmp(&self.due)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for LVDelayedPacket {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for LVDelayedPacket {
+    fn eq(&self, other: &Self) -> bool {
+        self.due == other.due && self.seq == other.seq
+    }
+}
+
+impl Eq for LVDelayedPacket {}
+
+// One direction of the emulated link.
+struct LVLink {
+    rng: StdRng,
+    // Gilbert-Elliott state
+    bad: bool,
+    // Token bucket, in bytes. Negative while packets are queued behind it.
+    tokens: f64,
+    refilled: Instant,
+    // Delayed packets don't overtake each other unless they're picked to be reordered
+    last_due: Instant,
+    queue: BinaryHeap<LVDelayedPacket>,
+    seq: u64,
+    passed: u64,
+    dropped: u64,
+}
+
+impl LVLink {
+    fn new(seed: u64, burst: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            bad: false,
+            tokens: burst as f64,
+            refilled: now,
+            last_due: now,
+            queue: BinaryHeap::new(),
+            seq: 0,
+            passed: 0,
+            dropped: 0,
+        }
+    }
+
+    // Decides what the link does with a packet sent now, and queues it for when it comes out
+    // the other end, if it does.
+    fn schedule(&mut self, config: &LVImpairment, data: &[u8], addr: SocketAddr, now: Instant) {
+        self.bad = if self.bad {
+            !self.rng.gen_bool(config.ge_r)
+        } else {
+            self.rng.gen_bool(config.ge_p)
+        };
+        let loss = if self.bad {
+            config.bad_loss
+        } else {
+            config.good_loss
+        };
+        if self.rng.gen_bool(loss) {
+            self.dropped += 1;
+            return;
+        }
+
+        let mut departs = now;
+        if let Some(kbps) = config.rate_kbps {
+            let bytes_per_sec = kbps as f64 * 1000. / 8.;
+            let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * bytes_per_sec).min(config.rate_burst as f64);
+            self.refilled = now;
+            let tokens = self.tokens - data.len() as f64;
+            if tokens < 0. {
+                let wait = Duration::from_secs_f64(-tokens / bytes_per_sec);
+                if wait > config.queue_limit {
+                    self.dropped += 1;
+                    return;
+                }
+                departs += wait;
+            }
+            self.tokens = tokens;
+        }
+
+        let due = if self.rng.gen_bool(config.reorder) {
+            departs
+        } else {
+            let jitter = config.jitter.as_secs_f64();
+            let offset = match config.distribution {
+                LVDelayDistribution::Uniform => self.rng.gen_range(-1.0..=1.0) * jitter,
+                LVDelayDistribution::Normal => {
+                    // Box-Muller
+                    let (u, v): (f64, f64) = (self.rng.gen(), self.rng.gen());
+                    (-2. * (1. - u).ln()).sqrt() * (2. * std::f64::consts::PI * v).cos() * jitter
+                }
+            };
+            let delay = Duration::from_secs_f64((config.delay.as_secs_f64() + offset).max(0.));
+            let due = (departs + delay).max(self.last_due);
+            self.last_due = due;
+            due
+        };
+
+        let copies = if self.rng.gen_bool(config.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            self.queue.push(LVDelayedPacket {
+                due,
+                seq: self.seq,
+                data: data.to_vec(),
+                addr,
+            });
+            self.seq += 1;
+        }
+        self.passed += 1;
+    }
+}
+
+struct LVImpairmentState {
+    config: LVImpairment,
+    // What's left of config.trace
+    trace: VecDeque<(Duration, String)>,
+    started: Instant,
+    send: LVLink,
+    recv: LVLink,
+    sender_started: bool,
+    closed: bool,
+}
+
+impl LVImpairmentState {
+    // Switches to whatever the trace says the link is like by now.
+    fn follow_trace(&mut self, now: Instant) {
+        while let Some((at, _)) = self.trace.front() {
+            if self.started + *at > now {
+                break;
+            }
+            let (at, spec) = self.trace.pop_front().unwrap();
+            match self.config.apply(&spec) {
+                Ok(()) => info!("impairment trace at {:?}: {}", at, spec),
+                Err(e) => warn!("bad impairment trace line {:?}: {:?}", spec, e),
+            }
+        }
+    }
+}
+
+// A UdpSocket that goes through the emulated link, or straight through without an impairment.
+pub struct LVImpairedSocket {
+    socket: UdpSocket,
+    state: Option<Arc<(Mutex<LVImpairmentState>, Condvar)>>,
+}
+
+impl LVImpairedSocket {
+    pub fn new(socket: UdpSocket, impairment: Option<LVImpairment>) -> Self {
+        let state = impairment.map(|config| {
+            info!(
+                "impairing the network on {:?}: {:?}",
+                socket.local_addr(),
+                config
+            );
+            let now = Instant::now();
+            Arc::new((
+                Mutex::new(LVImpairmentState {
+                    trace: config.trace.iter().cloned().collect(),
+                    started: now,
+                    send: LVLink::new(config.seed, config.rate_burst),
+                    recv: LVLink::new(config.seed.wrapping_add(1), config.rate_burst),
+                    config,
+                    sender_started: false,
+                    closed: false,
+                }),
+                Condvar::new(),
+            ))
+        });
+        Self { socket, state }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    // Always claims the whole datagram went out, whatever the link then does with it. Delayed
+    // packets are sent from a thread of their own.
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> std::io::Result<usize> {
+        let Some(state) = self.state.as_ref() else {
+            return self.socket.send_to(buf, addr);
+        };
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "no address to send to"))?;
+
+        let (lock, cvar) = &**state;
+        let mut state = lock.lock().expect("Failed to lock impairment state");
+        if !state.sender_started {
+            let socket = self.socket.try_clone()?;
+            let link = Arc::clone(self.state.as_ref().unwrap());
+            thread::Builder::new()
+                .name("impair_send_thread".to_string())
+                .spawn(move || Self::send_loop(socket, link))?;
+            state.sender_started = true;
+        }
+        let now = Instant::now();
+        state.follow_trace(now);
+        let state = &mut *state;
+        state.send.schedule(&state.config, buf, addr, now);
+        cvar.notify_one();
+        Ok(buf.len())
+    }
+
+    fn send_loop(socket: UdpSocket, state: Arc<(Mutex<LVImpairmentState>, Condvar)>) {
+        let (lock, cvar) = &*state;
+        loop {
+            let packet = {
+                let mut state = lock.lock().expect("Failed to lock impairment state");
+                loop {
+                    if state.closed {
+                        debug!(
+                            "impaired send link passed {} packets, dropped {}",
+                            state.send.passed, state.send.dropped
+                        );
+                        return;
+                    }
+                    let now = Instant::now();
+                    let wait = match state.send.queue.peek() {
+                        Some(packet) if packet.due <= now => break state.send.queue.pop().unwrap(),
+                        Some(packet) => packet.due - now,
+                        None => Duration::from_secs(1),
+                    };
+                    state = cvar
+                        .wait_timeout(state, wait)
+                        .expect("Failed to lock impairment state")
+                        .0;
+                }
+            };
+            if let Err(e) = socket.send_to(&packet.data, packet.addr) {
+                warn!("impaired send_to returned {:?}", e);
+            }
+        }
+    }
+
+    // Blocks until a packet makes it through the link.
+    pub fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let Some(state) = self.state.as_ref() else {
+            return self.socket.recv_from(buf);
+        };
+        // Anything longer than buf gets cut off on the way out anyway
+        let mut datagram = vec![0; buf.len()];
+        loop {
+            let timeout = {
+                let mut state = state.0.lock().expect("Failed to lock impairment state");
+                let now = Instant::now();
+                match state.recv.queue.peek() {
+                    Some(packet) if packet.due <= now => {
+                        let packet = state.recv.queue.pop().unwrap();
+                        let len = packet.data.len().min(buf.len());
+                        buf[..len].copy_from_slice(&packet.data[..len]);
+                        return Ok((len, packet.addr));
+                    }
+                    // A zero timeout means none at all
+                    Some(packet) => Some((packet.due - now).max(Duration::from_micros(1))),
+                    None => None,
+                }
+            };
+            self.socket.set_read_timeout(timeout)?;
+            match self.socket.recv_from(&mut datagram) {
+                Ok((len, addr)) => {
+                    let now = Instant::now();
+                    let mut state = state.0.lock().expect("Failed to lock impairment state");
+                    state.follow_trace(now);
+                    let state = &mut *state;
+                    state
+                        .recv
+                        .schedule(&state.config, &datagram[..len], addr, now);
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl AsRawFd for LVImpairedSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl Drop for LVImpairedSocket {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.as_ref() {
+            let (lock, cvar) = &**state;
+            let mut state = lock.lock().expect("Failed to lock impairment state");
+            state.closed = true;
+            if state.recv.passed + state.recv.dropped > 0 {
+                debug!(
+                    "impaired receive link passed {} packets, dropped {}",
+                    state.recv.passed, state.recv.dropped
+                );
+            }
+            cvar.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:9000".parse().unwrap()
+    }
+
+    // Sends count packets of len bytes through a fresh link all at once, each starting with
+    // its index
+    fn run(config: &LVImpairment, count: u32, len: usize) -> (LVLink, Instant) {
+        let mut link = LVLink::new(config.seed, config.rate_burst);
+        let now = Instant::now();
+        for i in 0..count {
+            let mut data = vec![0; len.max(4)];
+            data[..4].copy_from_slice(&i.to_be_bytes());
+            link.schedule(config, &data, addr(), now);
+        }
+        (link, now)
+    }
+
+    // (index, due) of what came out, in the order it came out
+    fn drain(link: &mut LVLink) -> Vec<(u32, Instant)> {
+        std::iter::from_fn(|| link.queue.pop())
+            .map(|packet| {
+                let index = u32::from_be_bytes(packet.data[..4].try_into().unwrap());
+                (index, packet.due)
+            })
+            .collect()
+    }
+
+    fn indices(link: &mut LVLink) -> Vec<u32> {
+        drain(link).into_iter().map(|(index, _)| index).collect()
+    }
+
+    #[test]
+    fn parses_settings() {
+        assert_eq!(LVImpairment::parse("").unwrap(), LVImpairment::default());
+
+        let impairment =
+            LVImpairment::parse("delay=30, jitter=5 dist=normal,reorder=0.1 rate=8000 seed=7")
+                .unwrap();
+        assert_eq!(impairment.delay, Duration::from_millis(30));
+        assert_eq!(impairment.jitter, Duration::from_millis(5));
+        assert_eq!(impairment.distribution, LVDelayDistribution::Normal);
+        assert_eq!(impairment.reorder, 0.1);
+        assert_eq!(impairment.rate_kbps, Some(8000));
+        assert_eq!(impairment.seed, 7);
+        assert_eq!(impairment.average_loss(), 0.);
+        assert_eq!(LVImpairment::parse("rate=0").unwrap().rate_kbps, None);
+
+        for bad in [
+            "loss",
+            "loss=2",
+            "loss=1",
+            "reorder=-0.1",
+            "dist=pareto",
+            "delay=soon",
+            "colour=blue",
+        ] {
+            assert!(LVImpairment::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn loss_and_burst_set_gilbert_elliott() {
+        let mut impairment = LVImpairment::parse("loss=0.02,burst=4").unwrap();
+        assert_eq!(impairment.ge_r, 0.25);
+        assert!((impairment.average_loss() - 0.02).abs() < 1e-9);
+        assert_eq!((impairment.good_loss, impairment.bad_loss), (0., 1.));
+
+        // A later loss keeps the burst length and the rest
+        impairment.apply("loss=0.1 delay=10").unwrap();
+        assert_eq!(impairment.ge_r, 0.25);
+        assert!((impairment.average_loss() - 0.1).abs() < 1e-9);
+        assert_eq!(impairment.delay, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn reads_traces() {
+        let path = std::env::temp_dir().join(format!("lv-impair-{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "# link trace\n2.5 loss=0.1\n\n0 delay=10 # start\n1\n",
+        )
+        .unwrap();
+        let trace = read_trace(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            trace,
+            [
+                (Duration::ZERO, "delay=10".to_string()),
+                (Duration::from_secs(1), String::new()),
+                (Duration::from_millis(2500), "loss=0.1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn same_seed_drops_the_same_packets() {
+        let config = LVImpairment::parse("loss=0.2 burst=3 seed=42").unwrap();
+        let kept = |config: &LVImpairment| indices(&mut run(config, 500, 4).0);
+        assert_eq!(kept(&config), kept(&config));
+        let other = LVImpairment {
+            seed: 43,
+            ..config.clone()
+        };
+        assert_ne!(kept(&config), kept(&other));
+    }
+
+    #[test]
+    fn loses_the_average_in_bursts() {
+        let config = LVImpairment::parse("loss=0.05 burst=4").unwrap();
+        let (mut link, _) = run(&config, 100_000, 4);
+        let kept = indices(&mut link);
+        assert_eq!(kept.len() as u64, link.passed);
+        assert_eq!(link.passed + link.dropped, 100_000);
+        let loss = link.dropped as f64 / 100_000.;
+        assert!((loss - 0.05).abs() < 0.01, "{}", loss);
+
+        // The gaps between the packets that made it
+        let bursts: Vec<u32> = kept
+            .windows(2)
+            .map(|pair| pair[1] - pair[0] - 1)
+            .filter(|&lost| lost > 0)
+            .collect();
+        let mean = bursts.iter().sum::<u32>() as f64 / bursts.len() as f64;
+        assert!((mean - 4.).abs() < 0.5, "{}", mean);
+    }
+
+    #[test]
+    fn delays_without_reordering() {
+        let config = LVImpairment::parse("delay=30 jitter=10").unwrap();
+        let (mut link, now) = run(&config, 1000, 4);
+        let out = drain(&mut link);
+        assert_eq!(out.len(), 1000);
+        for (i, &(index, due)) in out.iter().enumerate() {
+            assert_eq!(index, i as u32);
+            assert!(due >= now + Duration::from_millis(20));
+            assert!(due <= now + Duration::from_millis(40));
+        }
+
+        let config = LVImpairment::parse("delay=30 jitter=10 dist=normal").unwrap();
+        let out = drain(&mut run(&config, 1000, 4).0);
+        assert!(out.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn reorders_and_duplicates() {
+        let config = LVImpairment::parse("delay=30 reorder=1").unwrap();
+        let (mut link, now) = run(&config, 10, 4);
+        assert!(drain(&mut link).iter().all(|&(_, due)| due == now));
+
+        // Every other packet skips the delay and overtakes the rest
+        let config = LVImpairment::parse("delay=30 reorder=0.5").unwrap();
+        let out = indices(&mut run(&config, 100, 4).0);
+        assert_eq!(out.len(), 100);
+        assert!(out.windows(2).any(|pair| pair[0] > pair[1]));
+
+        let config = LVImpairment::parse("duplicate=1").unwrap();
+        let (mut link, _) = run(&config, 3, 4);
+        assert_eq!(link.passed, 3);
+        assert_eq!(indices(&mut link), [0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn rate_limits_and_drops_past_the_queue() {
+        // 1000 bytes a second, a 1000 byte burst and 600ms of queue
+        let config = LVImpairment::parse("rate=8 rate_burst=1000 queue=600").unwrap();
+        let (mut link, now) = run(&config, 5, 500);
+        assert_eq!((link.passed, link.dropped), (3, 2));
+        assert_eq!(
+            drain(&mut link),
+            [(0, now), (1, now), (2, now + Duration::from_millis(500))]
+        );
+    }
+
+    #[test]
+    fn impairs_real_sockets() {
+        let plain = UdpSocket::bind("127.0.0.1:0").unwrap();
+        plain
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // Sending, delayed
+        let impaired = LVImpairedSocket::new(
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            Some(LVImpairment::parse("delay=20").unwrap()),
+        );
+        let sent = Instant::now();
+        for i in 0..3u8 {
+            assert_eq!(
+                impaired.send_to(&[i], plain.local_addr().unwrap()).unwrap(),
+                1
+            );
+        }
+        let mut buf = [0; 16];
+        for i in 0..3u8 {
+            let (len, from) = plain.recv_from(&mut buf).unwrap();
+            assert_eq!(
+                (&buf[..len], from),
+                (&[i][..], impaired.socket().local_addr().unwrap())
+            );
+        }
+        assert!(sent.elapsed() >= Duration::from_millis(20));
+
+        // Receiving, duplicated
+        let impaired = LVImpairedSocket::new(
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            Some(LVImpairment::parse("duplicate=1").unwrap()),
+        );
+        plain
+            .send_to(b"lv", impaired.socket().local_addr().unwrap())
+            .unwrap();
+        for _ in 0..2 {
+            let (len, from) = impaired.recv_from(&mut buf).unwrap();
+            assert_eq!(
+                (&buf[..len], from),
+                (&b"lv"[..], plain.local_addr().unwrap())
+            );
+        }
+    }
+}
@@ -1,3 +1,9 @@
+pub mod clock;
+pub mod codec;
+pub mod container;
+pub mod control_packet;
 pub mod feedback_packet;
+pub mod impair;
 pub mod input;
 pub mod packet;
+pub mod quality;
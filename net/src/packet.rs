@@ -6,6 +6,16 @@ pub const MTU_SIZE: usize = 1200;
 pub const EC_RATIO_RECOVERY_PACKETS: u32 = 2;
 pub const EC_RATIO_REGULAR_PACKETS: u32 = 4;
 
+// RTP header extensions (RFC 8285 one-byte form) carried on every packet of a frame:
+// LVFrameTimestamps, LVFrameSize and LVFrameId.
+pub const FRAME_TIMESTAMPS_EXTENSION_ID: u8 = 1;
+pub const FRAME_SIZE_EXTENSION_ID: u8 = 2;
+pub const FRAME_ID_EXTENSION_ID: u8 = 3;
+// What the extensions add to each RTP packet: the 4 byte extension header, then a 1 byte
+// element header with the 16 byte timestamps, another with the 8 byte frame size and another
+// with the 5 byte frame id, padded to a multiple of 4.
+pub const FRAME_EXTENSIONS_BYTES: usize = 36;
+
 pub const SIMD_PACKET_SIZE: u32 =
     ((MTU_SIZE as u32 - LVErasureInformation::no_bytes() as u32 + 63) / 64) * 64;
 
@@ -28,7 +38,7 @@ pub struct LVErasureInformation {
     //
     // We can store this as a u16 because the largest packet size over UDP can be stored as a u16 value.
     pub pkt_sizes: [u16; EC_RATIO_REGULAR_PACKETS as usize],
-    // 64-bit UNIX timestamp denoting when the packet was sent from the server side.
+    // UNIX timestamp in microseconds denoting when the packet was sent from the server side.
     // This allows us to calculate the RTT (round-trip time) for a packet.
     pub send_timestamp: u128,
 }
@@ -105,3 +115,179 @@ impl LVErasureInformation {
         }
     }
 }
+
+// When a frame was captured and how long the server spent on it, all on the server's clock.
+// The client combines this with its own receive/decode/present times to break down where the
+// glass-to-glass latency goes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LVFrameTimestamps {
+    // Microseconds since the UNIX epoch when the capture started
+    pub capture_ts: u64,
+    // How long grabbing the frame took
+    pub capture_us: u32,
+    // From the end of the capture until the frame was encoded and packetized, including time
+    // spent waiting in the queue for the encoder
+    pub encode_us: u32,
+}
+
+impl LVFrameTimestamps {
+    pub const fn no_bytes() -> usize {
+        size_of::<u64>() + 2 * size_of::<u32>()
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::no_bytes()] {
+        let mut buf = [0; Self::no_bytes()];
+        buf[0..8].copy_from_slice(&self.capture_ts.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.capture_us.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.encode_us.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::no_bytes() {
+            return None;
+        }
+        Some(Self {
+            capture_ts: u64::from_be_bytes(buf[0..8].try_into().unwrap()),
+            capture_us: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+            encode_us: u32::from_be_bytes(buf[12..16].try_into().unwrap()),
+        })
+    }
+}
+
+// The size of the captured frame and the size it was encoded at. The server shrinks frames
+// when the bitrate gets too low for the full resolution, so the client needs the captured size
+// to map input back onto the real desktop.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LVFrameSize {
+    pub source_width: u16,
+    pub source_height: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl LVFrameSize {
+    pub const fn no_bytes() -> usize {
+        4 * size_of::<u16>()
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::no_bytes()] {
+        let mut buf = [0; Self::no_bytes()];
+        buf[0..2].copy_from_slice(&self.source_width.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.source_height.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.width.to_be_bytes());
+        buf[6..8].copy_from_slice(&self.height.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::no_bytes() {
+            return None;
+        }
+        Some(Self {
+            source_width: u16::from_be_bytes(buf[0..2].try_into().unwrap()),
+            source_height: u16::from_be_bytes(buf[2..4].try_into().unwrap()),
+            width: u16::from_be_bytes(buf[4..6].try_into().unwrap()),
+            height: u16::from_be_bytes(buf[6..8].try_into().unwrap()),
+        })
+    }
+}
+
+// Every encoded frame gets an id, counting up from 1 so 0 can stand for "nothing decoded yet".
+// The client reports the last one it decoded cleanly, which lets the server encode against a
+// reference the client is known to have instead of sending a keyframe after loss.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LVFrameId {
+    pub id: u32,
+    // Set on the first frame after the encoder acted on a loss report (or started over), so
+    // the client knows the picture is whole again from here on.
+    pub recovery_point: bool,
+}
+
+impl LVFrameId {
+    pub const fn no_bytes() -> usize {
+        size_of::<u32>() + size_of::<bool>()
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::no_bytes()] {
+        let mut buf = [0; Self::no_bytes()];
+        buf[0..4].copy_from_slice(&self.id.to_be_bytes());
+        buf[4] = self.recovery_point as u8;
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::no_bytes() {
+            return None;
+        }
+        Some(Self {
+            id: u32::from_be_bytes(buf[0..4].try_into().unwrap()),
+            recovery_point: buf[4] != 0,
+        })
+    }
+}
+
+// Lossless refinement of static screen areas goes out on the video socket with its own payload
+// type (the codecs use 96 and up, see codec.rs), so it's protected by the same FEC and arrives
+// in order with the frames.
+pub const REFINE_PAYLOAD_TYPE: u8 = 110;
+// Refinement works on a grid of square tiles, in encoded frame pixels. Tiles on the right and
+// bottom edges are cut short.
+pub const REFINE_TILE_SIZE: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LVRefineKind {
+    // A chunk of a QOI image (RGBA) of the tile at x, y
+    Tile = 0,
+    // The payload is a list of (x, y) u16 pairs of tiles that changed, the client has to stop
+    // drawing its refined copies of them. Sent ahead of the frame the change is in.
+    Clear = 1,
+}
+
+// Goes in front of the payload of every refinement RTP packet. A tile is usually bigger than
+// one packet, so it's split into chunks that the client puts back together by tile_id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LVRefineHeader {
+    pub kind: LVRefineKind,
+    pub tile_id: u32,
+    pub chunk: u16,
+    pub chunks: u16,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl LVRefineHeader {
+    pub const fn no_bytes() -> usize {
+        size_of::<u8>() + size_of::<u32>() + 4 * size_of::<u16>()
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::no_bytes()] {
+        let mut buf = [0; Self::no_bytes()];
+        buf[0] = self.kind as u8;
+        buf[1..5].copy_from_slice(&self.tile_id.to_be_bytes());
+        buf[5..7].copy_from_slice(&self.chunk.to_be_bytes());
+        buf[7..9].copy_from_slice(&self.chunks.to_be_bytes());
+        buf[9..11].copy_from_slice(&self.x.to_be_bytes());
+        buf[11..13].copy_from_slice(&self.y.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::no_bytes() {
+            return None;
+        }
+        let kind = match buf[0] {
+            0 => LVRefineKind::Tile,
+            1 => LVRefineKind::Clear,
+            _ => return None,
+        };
+        Some(Self {
+            kind,
+            tile_id: u32::from_be_bytes(buf[1..5].try_into().unwrap()),
+            chunk: u16::from_be_bytes(buf[5..7].try_into().unwrap()),
+            chunks: u16::from_be_bytes(buf[7..9].try_into().unwrap()),
+            x: u16::from_be_bytes(buf[9..11].try_into().unwrap()),
+            y: u16::from_be_bytes(buf[11..13].try_into().unwrap()),
+        })
+    }
+}
@@ -0,0 +1,216 @@
+// Objective quality measurements between what the server captured and what the client decoded.
+//
+// With LV_QUALITY=1 the server stamps an id into the top-left corner of every frame, a row of
+// black and white 8x8 cells big and blocky enough to survive the encoder at any bitrate we'd
+// stream at. Whoever has the original frames (the client with the same clip as the server, the
+// loopback harness) reads the id off the decoded frame, finds the original and compares the two.
+// The stamp band is left out of the comparison, so originals without one measure the same.
+
+// Side of a cell, aligned to the encoder's macroblocks
+pub const STAMP_CELL: usize = 8;
+const ID_BITS: usize = 24;
+const CHECK_BITS: usize = 8;
+pub const STAMP_WIDTH: usize = (ID_BITS + CHECK_BITS) * STAMP_CELL;
+pub const STAMP_HEIGHT: usize = STAMP_CELL;
+// Ids wrap around after this, a bit over three days at 60fps
+pub const MAX_ID: u32 = (1 << ID_BITS) - 1;
+
+// SSIM is computed over windows this big, not overlapping
+const SSIM_WINDOW: usize = 8;
+const SSIM_C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+const SSIM_C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+// The PSNR of identical frames, instead of infinity
+const MAX_PSNR: f64 = 100.;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LVQualityReport {
+    // Of the luma, in dB
+    pub psnr: f64,
+    // Of the luma, 1 for identical frames
+    pub ssim: f64,
+}
+
+// So a frame that was never stamped doesn't read as one
+fn check(id: u32) -> u8 {
+    (id ^ (id >> 8) ^ (id >> 16)) as u8 ^ 0x5a
+}
+
+// Stamps id into a width x height frame with 4 bytes per pixel, tightly packed. Returns false
+// if the frame is too small to take a stamp.
+pub fn stamp(buffer: &mut [u8], width: usize, height: usize, id: u32) -> bool {
+    if width < STAMP_WIDTH || height < STAMP_HEIGHT || buffer.len() < 4 * width * height {
+        return false;
+    }
+    let bits = (id & MAX_ID) as u64 | ((check(id & MAX_ID) as u64) << ID_BITS);
+    for row in buffer.chunks_exact_mut(4 * width).take(STAMP_HEIGHT) {
+        for (cell, pixels) in row[..4 * STAMP_WIDTH]
+            .chunks_exact_mut(4 * STAMP_CELL)
+            .enumerate()
+        {
+            let value = if (bits >> cell) & 1 == 1 { 255 } else { 0 };
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.copy_from_slice(&[value, value, value, 255]);
+            }
+        }
+    }
+    true
+}
+
+// Reads the id back off a decoded frame, RGBA or BGRA. None if there's no stamp, or it came
+// through too damaged to trust.
+pub fn read_stamp(buffer: &[u8], width: usize, height: usize) -> Option<u32> {
+    if width < STAMP_WIDTH || height < STAMP_HEIGHT || buffer.len() < 4 * width * height {
+        return None;
+    }
+    let mut bits = 0u64;
+    for cell in 0..ID_BITS + CHECK_BITS {
+        // The middle of the cell, away from the ringing at its edges
+        let mut sum = 0u32;
+        for y in STAMP_CELL / 4..STAMP_CELL * 3 / 4 {
+            for x in STAMP_CELL / 4..STAMP_CELL * 3 / 4 {
+                let at = 4 * (y * width + cell * STAMP_CELL + x);
+                // Red and blue weigh the same, so the byte order doesn't matter
+                sum += buffer[at] as u32 + 2 * buffer[at + 1] as u32 + buffer[at + 2] as u32;
+            }
+        }
+        let samples = (STAMP_CELL / 2) * (STAMP_CELL / 2);
+        if sum / samples as u32 > 2 * 255 {
+            bits |= 1 << cell;
+        }
+    }
+    let id = (bits & MAX_ID as u64) as u32;
+    (check(id) == (bits >> ID_BITS) as u8).then_some(id)
+}
+
+fn luma(pixel: &[u8]) -> f64 {
+    0.299 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.114 * pixel[2] as f64
+}
+
+// Luma PSNR of two RGBA frames of the same size, below the stamp band
+pub fn psnr(a: &[u8], b: &[u8], width: usize, height: usize) -> f64 {
+    let start = 4 * width * STAMP_HEIGHT.min(height);
+    let end = 4 * width * height;
+    let (sum, pixels) = a[start..end]
+        .chunks_exact(4)
+        .zip(b[start..end].chunks_exact(4))
+        .fold((0., 0), |(sum, pixels), (a, b)| {
+            (sum + (luma(a) - luma(b)).powi(2), pixels + 1)
+        });
+    if pixels == 0 {
+        return 0.;
+    }
+    let mse = sum / pixels as f64;
+    if mse == 0. {
+        return MAX_PSNR;
+    }
+    (10. * (255. * 255. / mse).log10()).min(MAX_PSNR)
+}
+
+// Luma SSIM of two RGBA frames of the same size, below the stamp band, averaged over 8x8
+// windows. Leftover pixels at the right and bottom edges aren't counted.
+pub fn ssim(a: &[u8], b: &[u8], width: usize, height: usize) -> f64 {
+    let mut total = 0.;
+    let mut windows = 0;
+    let n = (SSIM_WINDOW * SSIM_WINDOW) as f64;
+    for wy in (STAMP_HEIGHT..height.saturating_sub(SSIM_WINDOW - 1)).step_by(SSIM_WINDOW) {
+        for wx in (0..width.saturating_sub(SSIM_WINDOW - 1)).step_by(SSIM_WINDOW) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0., 0., 0., 0., 0.);
+            for y in wy..wy + SSIM_WINDOW {
+                for x in wx..wx + SSIM_WINDOW {
+                    let at = 4 * (y * width + x);
+                    let (la, lb) = (luma(&a[at..at + 3]), luma(&b[at..at + 3]));
+                    sum_a += la;
+                    sum_b += lb;
+                    sum_aa += la * la;
+                    sum_bb += lb * lb;
+                    sum_ab += la * lb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let cov = sum_ab / n - mean_a * mean_b;
+            total += ((2. * mean_a * mean_b + SSIM_C1) * (2. * cov + SSIM_C2))
+                / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (var_a + var_b + SSIM_C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 {
+        return 0.;
+    }
+    total / windows as f64
+}
+
+pub fn measure(decoded: &[u8], original: &[u8], width: usize, height: usize) -> LVQualityReport {
+    LVQualityReport {
+        psnr: psnr(decoded, original, width, height),
+        ssim: ssim(decoded, original, width, height),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 320;
+    const HEIGHT: usize = 64;
+
+    fn make_frame(pixel: impl Fn(usize, usize) -> u8) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(4 * WIDTH * HEIGHT);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let value = pixel(x, y);
+                frame.extend_from_slice(&[value, value / 2, 255 - value, 255]);
+            }
+        }
+        frame
+    }
+
+    fn gradient(x: usize, y: usize) -> u8 {
+        (x * 3 + y * 7) as u8
+    }
+
+    #[test]
+    fn stamps_read_back() {
+        for id in [0, 1, 0x12_3456, MAX_ID] {
+            let mut frame = make_frame(gradient);
+            assert!(stamp(&mut frame, WIDTH, HEIGHT, id));
+            assert_eq!(read_stamp(&frame, WIDTH, HEIGHT), Some(id));
+        }
+
+        // Ids wrap around
+        let mut frame = make_frame(gradient);
+        stamp(&mut frame, WIDTH, HEIGHT, MAX_ID + 5);
+        assert_eq!(read_stamp(&frame, WIDTH, HEIGHT), Some(4));
+
+        // Too small for a stamp
+        let mut small = vec![0; 4 * (STAMP_WIDTH - 1) * HEIGHT];
+        assert!(!stamp(&mut small, STAMP_WIDTH - 1, HEIGHT, 1));
+        assert_eq!(read_stamp(&small, STAMP_WIDTH - 1, HEIGHT), None);
+    }
+
+    #[test]
+    fn unstamped_frames_read_nothing() {
+        for value in [0, 255] {
+            let frame = make_frame(|_, _| value);
+            assert_eq!(read_stamp(&frame, WIDTH, HEIGHT), None, "{}", value);
+        }
+        assert_eq!(read_stamp(&make_frame(gradient), WIDTH, HEIGHT), None);
+    }
+
+    #[test]
+    fn identical_frames_measure_perfect() {
+        let frame = make_frame(gradient);
+        let report = measure(&frame, &frame, WIDTH, HEIGHT);
+        assert_eq!(report.psnr, MAX_PSNR);
+        assert!((report.ssim - 1.).abs() < 1e-9, "{}", report.ssim);
+
+        // Anything else is worse, and the stamp band doesn't count
+        let noisy = make_frame(|x, y| gradient(x, y).wrapping_add((x * y % 5) as u8));
+        let report = measure(&noisy, &frame, WIDTH, HEIGHT);
+        assert!(report.psnr < MAX_PSNR && report.ssim < 1., "{:?}", report);
+        let mut stamped = frame.clone();
+        stamp(&mut stamped, WIDTH, HEIGHT, 7);
+        assert_eq!(measure(&stamped, &frame, WIDTH, HEIGHT).psnr, MAX_PSNR);
+    }
+}
@@ -5,11 +5,10 @@ use std::{
 
 use crate::packager::LVPackager;
 use crate::{
-    capture::{linux::LVLinuxCapturer, LVCapturer},
-    encoder,
+    capture::{self, LVCaptureTarget},
+    encoder::{self, LVEncoderConfig},
 };
 use log::{debug, error, info};
-use screenshots::Screen;
 use webrtc_util::{Marshal, MarshalSize};
 
 const BITRATE: u32 = 250000;
@@ -17,13 +16,11 @@ const FRAMERATE: f32 = 60.0;
 const BIND_ADDR: &'static str = "127.0.0.1:29878";
 const ITERATIONS: u32 = 100;
 
-pub fn bench() -> Result<(), Box<dyn std::error::Error>> {
+pub fn bench(target: &LVCaptureTarget) -> Result<(), Box<dyn std::error::Error>> {
     let socket = UdpSocket::bind(BIND_ADDR)?;
-    let screen = *Screen::all()?.get(0).expect("Expected a screen");
-
     // Screen size from screen is unreliable, so we'll get it from the capture instead.
 
-    let mut capturer = LVLinuxCapturer::new(screen)?;
+    let mut capturer = capture::default_capturer(target)?;
     // Capture a frame to figure out the frame size
     let (width, height) = match capturer.capture() {
         Ok(frame) => (frame.width(), frame.height()),
@@ -38,7 +35,11 @@ pub fn bench() -> Result<(), Box<dyn std::error::Error>> {
         width, height
     );
 
-    let encoder = encoder::default_encoder(width, height, BITRATE, FRAMERATE)?;
+    let encoder = encoder::default_encoder(LVEncoderConfig {
+        codec: encoder::default_codec()?,
+        intra_refresh: encoder::default_intra_refresh()?,
+        ..LVEncoderConfig::new(width, height, BITRATE, FRAMERATE)
+    })?;
     let mut packager = LVPackager::new(encoder, FRAMERATE as u32)?;
 
     // bad benchmark
@@ -62,7 +63,7 @@ pub fn bench() -> Result<(), Box<dyn std::error::Error>> {
 
             // Encode/package frame
             let before = Instant::now();
-            let _ = packager.process_frame(frame, timer.elapsed().as_millis() as u64)?;
+            let _ = packager.process_frame(&frame, timer.elapsed().as_millis() as u64)?;
             let elapsed = before.elapsed();
             process_avg += elapsed.as_millis();
             info!(
@@ -0,0 +1,290 @@
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use dcv_color_primitives::{convert_image, ColorSpace, ImageFormat, PixelFormat};
+use log::{debug, info};
+
+use super::{LVBufferPool, LVCapturer, LVFrame, CAPTURE_BUFFERS};
+
+enum LVFileSource {
+    // Tightly packed BGRA frames back to back, exactly what the capturers produce.
+    Raw {
+        file: File,
+        width: u32,
+        height: u32,
+    },
+    // 8-bit 4:2:0 only, converted back to BGRA so it goes through the same path as a capture.
+    Y4m {
+        decoder: y4m::Decoder<BufReader<File>>,
+        width: u32,
+        height: u32,
+    },
+    // Either a single image or every .png in a directory in name order.
+    Png {
+        paths: Vec<PathBuf>,
+        next: usize,
+    },
+}
+
+// Plays back frames from disk, looping at the end, so the server can stream reproducible
+// content without a display. The capture thread's pacing decides the framerate, not the file.
+pub struct LVFileCapturer {
+    path: PathBuf,
+    source: LVFileSource,
+    pool: LVBufferPool,
+}
+
+impl LVFileCapturer {
+    pub fn new(path: &Path, size: Option<(u32, u32)>) -> Result<Self, Box<dyn std::error::Error>> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        let source = if path.is_dir() {
+            let mut paths = std::fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+                .collect::<Vec<_>>();
+            if paths.is_empty() {
+                return Err(anyhow!("no .png files in {:?}", path).into());
+            }
+            paths.sort();
+            info!("playing {} png frames from {:?}", paths.len(), path);
+            LVFileSource::Png { paths, next: 0 }
+        } else {
+            match extension.as_deref() {
+                Some("png") => LVFileSource::Png {
+                    paths: vec![path.to_owned()],
+                    next: 0,
+                },
+                Some("y4m") => Self::open_y4m(path)?,
+                _ => {
+                    let (width, height) = size.ok_or_else(|| {
+                        anyhow!("raw BGRA files need a size, e.g. file:{:?},1920x1080", path)
+                    })?;
+                    LVFileSource::Raw {
+                        file: File::open(path)?,
+                        width,
+                        height,
+                    }
+                }
+            }
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            source,
+            pool: LVBufferPool::new(CAPTURE_BUFFERS),
+        })
+    }
+
+    fn open_y4m(path: &Path) -> Result<LVFileSource, Box<dyn std::error::Error>> {
+        let decoder = y4m::decode(BufReader::new(File::open(path)?))?;
+        match decoder.get_colorspace() {
+            y4m::Colorspace::C420
+            | y4m::Colorspace::C420jpeg
+            | y4m::Colorspace::C420paldv
+            | y4m::Colorspace::C420mpeg2 => {}
+            colorspace => return Err(anyhow!("unsupported y4m colorspace {:?}", colorspace).into()),
+        }
+        let (width, height) = (decoder.get_width() as u32, decoder.get_height() as u32);
+        info!("playing {}x{} y4m from {:?}", width, height, path);
+        Ok(LVFileSource::Y4m {
+            decoder,
+            width,
+            height,
+        })
+    }
+}
+
+impl LVCapturer for LVFileCapturer {
+    fn capture(&mut self) -> Result<LVFrame, Box<dyn std::error::Error>> {
+        match &mut self.source {
+            LVFileSource::Raw {
+                file,
+                width,
+                height,
+            } => self.pool.frame(*width, *height, |buffer| {
+                match file.read_exact(buffer) {
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        debug!("looping {:?}", self.path);
+                        file.seek(SeekFrom::Start(0))?;
+                        file.read_exact(buffer)?;
+                    }
+                    result => result?,
+                }
+                Ok(())
+            }),
+            LVFileSource::Y4m {
+                decoder,
+                width,
+                height,
+            } => {
+                let (width, height) = (*width, *height);
+                let mut frame = decoder.read_frame();
+                if let Err(y4m::Error::EOF) = frame {
+                    // Reopening is the only way back to the first frame.
+                    debug!("looping {:?}", self.path);
+                    drop(frame);
+                    *decoder = y4m::decode(BufReader::new(File::open(&self.path)?))?;
+                    frame = decoder.read_frame();
+                }
+                let frame = frame?;
+                self.pool.frame(width, height, |buffer| {
+                    yuv_to_bgra(&frame, width, height, buffer)
+                })
+            }
+            LVFileSource::Png { paths, next } => {
+                let image = image::open(&paths[*next])?.to_rgba8();
+                *next = (*next + 1) % paths.len();
+                self.pool.frame(image.width(), image.height(), |buffer| {
+                    for (dst, src) in buffer.chunks_exact_mut(4).zip(image.pixels()) {
+                        let [r, g, b, a] = src.0;
+                        dst.copy_from_slice(&[b, g, r, a]);
+                    }
+                    Ok(())
+                })
+            }
+        }
+    }
+}
+
+fn yuv_to_bgra(
+    frame: &y4m::Frame,
+    width: u32,
+    height: u32,
+    buffer: &mut [u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let src_fmt = ImageFormat {
+        pixel_format: PixelFormat::I420,
+        color_space: ColorSpace::Bt601,
+        num_planes: 3,
+    };
+    let dst_fmt = ImageFormat {
+        pixel_format: PixelFormat::Bgra,
+        color_space: ColorSpace::Rgb,
+        num_planes: 1,
+    };
+    convert_image(
+        width,
+        height,
+        &src_fmt,
+        None,
+        &[
+            frame.get_y_plane(),
+            frame.get_u_plane(),
+            frame.get_v_plane(),
+        ],
+        &dst_fmt,
+        None,
+        &mut [buffer],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("lv-file-{}-{}", std::process::id(), name))
+    }
+
+    fn pixels(frame: &LVFrame) -> Vec<[u8; 4]> {
+        frame
+            .as_bytes()
+            .chunks_exact(4)
+            .map(|pixel| pixel.try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn plays_a_png_directory_in_name_order() {
+        let dir = temp_path("png");
+        std::fs::create_dir_all(&dir).unwrap();
+        // Written out of order, and something that isn't a png to skip
+        for (name, colour) in [("b.png", [0, 0, 255, 255]), ("a.png", [255, 0, 0, 128])] {
+            image::RgbaImage::from_pixel(3, 2, image::Rgba(colour))
+                .save(dir.join(name))
+                .unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), "not a frame").unwrap();
+
+        let mut capturer = LVFileCapturer::new(&dir, None).unwrap();
+        // RGBA on disk, BGRA out, looping after the last one
+        for expected in [[0, 0, 255, 128], [255, 0, 0, 255], [0, 0, 255, 128]] {
+            let frame = capturer.capture().unwrap();
+            assert_eq!((frame.width(), frame.height()), (3, 2));
+            assert_eq!(pixels(&frame), vec![expected; 6]);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let empty = temp_path("empty");
+        std::fs::create_dir_all(&empty).unwrap();
+        assert!(LVFileCapturer::new(&empty, None).is_err());
+        std::fs::remove_dir_all(&empty).unwrap();
+    }
+
+    #[test]
+    fn plays_y4m_420() {
+        let path = temp_path("frames.y4m");
+        let mut encoder = y4m::encode(4, 2, y4m::Ratio::new(30, 1))
+            .with_colorspace(y4m::Colorspace::C420)
+            .write_header(File::create(&path).unwrap())
+            .unwrap();
+        // Limited range BT.601 black and white, no chroma
+        for luma in [16, 235] {
+            encoder
+                .write_frame(&y4m::Frame::new([&[luma; 8], &[128; 2], &[128; 2]], None))
+                .unwrap();
+        }
+        drop(encoder);
+
+        let mut capturer = LVFileCapturer::new(&path, None).unwrap();
+        for expected in [0, 255, 0] {
+            let frame = capturer.capture().unwrap();
+            assert_eq!((frame.width(), frame.height()), (4, 2));
+            for pixel in pixels(&frame) {
+                for channel in &pixel[..3] {
+                    assert!(channel.abs_diff(expected) <= 2, "{:?}", pixel);
+                }
+                assert_eq!(pixel[3], 255);
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let path = temp_path("frames-444.y4m");
+        y4m::encode(4, 2, y4m::Ratio::new(30, 1))
+            .with_colorspace(y4m::Colorspace::C444)
+            .write_header(File::create(&path).unwrap())
+            .unwrap();
+        assert!(LVFileCapturer::new(&path, None).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn plays_raw_bgra_with_a_size() {
+        let path = temp_path("frames.bgra");
+        let mut file = File::create(&path).unwrap();
+        for i in 0..2u8 {
+            file.write_all(&[i, 1, 2, 255].repeat(2 * 3)).unwrap();
+        }
+        drop(file);
+
+        assert!(LVFileCapturer::new(&path, None).is_err());
+        let mut capturer = LVFileCapturer::new(&path, Some((2, 3))).unwrap();
+        for i in [0, 1, 0] {
+            let frame = capturer.capture().unwrap();
+            assert_eq!((frame.width(), frame.height()), (2, 3));
+            assert_eq!(pixels(&frame), vec![[i, 1, 2, 255]; 6]);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
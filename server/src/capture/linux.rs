@@ -1,45 +1,44 @@
 use anyhow::anyhow;
 use core::slice;
-use image::Rgb;
 use libc::{IPC_CREAT, IPC_PRIVATE, IPC_RMID};
-use log::debug;
+use log::{debug, info, warn};
 use screenshots::Screen;
-use std::{os::raw::c_char, time::Instant};
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+use std::{sync::Arc, time::Instant};
 use xcb::{
+    randr,
     shm::{Attach, GetImage, Seg},
-    x::{Drawable, ImageFormat, ImageOrder},
-    Connection,
+    x::{self, Drawable, ImageFormat, ImageOrder},
+    Connection, Xid, XidNew,
 };
 
-use super::LVCapturer;
+use super::{LVCaptureTarget, LVCapturer, LVFocus, LVFrame, LVFrameBuffer, CAPTURE_BUFFERS};
 
-pub struct LVLinuxCapturer {
-    conn: Connection,
-    get_image: GetImage,
-    bit_order: ImageOrder,
-    bgr_buffer: *mut u8,
-    bgr_buffer_len: usize,
+// A shared memory segment attached to both us and the X server. XShmGetImage writes straight
+// into it, and the encoder reads straight out of it.
+pub struct LVShmSegment {
+    seg: Seg,
+    ptr: *mut u8,
+    len: usize,
 }
 
-impl LVLinuxCapturer {
-    // TODO: there's probably a memory leak here.
-    pub fn new(screen: Screen) -> Result<Self, Box<dyn std::error::Error>> {
-        let (conn, index) =
-            xcb::Connection::connect_with_extensions(None, &[xcb::Extension::Shm], &[])?;
-
-        let width = (screen.display_info.width as f32 * screen.display_info.scale_factor) as u16;
-        let height = (screen.display_info.height as f32 * screen.display_info.scale_factor) as u16;
+// The segment is only written by the X server while the capturer holds the only reference
+// to it, so handing out shared read-only references to other threads is fine.
+unsafe impl Send for LVShmSegment {}
+unsafe impl Sync for LVShmSegment {}
 
-        let buffer_size = width as usize * height as usize * 4 as usize;
-
-        // Setup shared memory segment
-        let (bgr_buffer, seg) = unsafe {
-            let shm_id = libc::shmget(IPC_PRIVATE, buffer_size, IPC_CREAT | 0o600) as u32;
+impl LVShmSegment {
+    fn new(conn: &Connection, len: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        unsafe {
+            let shm_id = libc::shmget(IPC_PRIVATE, len, IPC_CREAT | 0o600) as u32;
             debug!("shm_id is {}", shm_id);
             // Map into process address space
-            let bgr_buffer = libc::shmat(shm_id as i32, std::ptr::null(), 0) as *mut u8;
-            debug!("bgr_buffer is {:p}", bgr_buffer);
-            if bgr_buffer == std::ptr::null_mut() {
+            let ptr = libc::shmat(shm_id as i32, std::ptr::null(), 0) as *mut u8;
+            debug!("shm segment is {:p}", ptr);
+            if ptr == std::ptr::null_mut() {
                 libc::perror(std::ptr::null());
                 return Err(anyhow!("failed to open shared memory buffer").into());
             }
@@ -55,67 +54,506 @@ impl LVLinuxCapturer {
             });
             conn.check_request(void_cookie)?;
 
-            (bgr_buffer, seg)
-        };
+            Ok(Self { seg, ptr, len })
+        }
+    }
+}
+
+impl LVFrameBuffer for LVShmSegment {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for LVShmSegment {
+    fn drop(&mut self) {
+        // The segment was marked IPC_RMID, so it is freed once the X server detaches as well.
+        unsafe {
+            libc::shmdt(self.ptr as *const libc::c_void);
+        }
+    }
+}
+
+pub struct LVLinuxCapturer {
+    conn: Connection,
+    root: x::Window,
+    root_size: (u16, u16),
+    get_image: GetImage,
+    bit_order: ImageOrder,
+    segments: Vec<Arc<LVShmSegment>>,
+    next_segment: usize,
+
+    // Set when following a single window instead of a part of the root window.
+    window: Option<x::Window>,
+    origin: (i32, i32),
+}
 
-        let (get_image, bit_order) = {
+impl LVLinuxCapturer {
+    pub fn new(target: &LVCaptureTarget) -> Result<Self, Box<dyn std::error::Error>> {
+        let (conn, index) = xcb::Connection::connect_with_extensions(
+            None,
+            &[xcb::Extension::Shm],
+            &[xcb::Extension::RandR],
+        )?;
+
+        let (root, root_width, root_height, bit_order) = {
             let setup = conn.get_setup();
             let x_screen = setup
                 .roots()
                 .nth(index as usize)
                 .ok_or_else(|| anyhow!("Could not find a screen."))?;
             (
-                GetImage {
-                    drawable: Drawable::Window(x_screen.root()),
-                    x: (screen.display_info.x as f32 * screen.display_info.scale_factor) as i16,
-                    y: (screen.display_info.y as f32 * screen.display_info.scale_factor) as i16,
-                    width,
-                    height,
-                    plane_mask: u32::MAX,
-                    format: ImageFormat::ZPixmap as u8, // ZPixmap
-                    shmseg: seg,
-                    offset: 0,
-                },
+                x_screen.root(),
+                x_screen.width_in_pixels(),
+                x_screen.height_in_pixels(),
                 setup.bitmap_format_bit_order(),
             )
         };
 
+        if bit_order != ImageOrder::LsbFirst {
+            return Err(anyhow!("Only LSB-first (BGRA) X servers are supported").into());
+        }
+
+        // Work out what we are reading from and where it sits on the desktop.
+        let (drawable, x, y, width, height, window) = match target {
+            LVCaptureTarget::Screen(screen_no) => {
+                let screen = *Screen::all()?
+                    .get(*screen_no)
+                    .ok_or_else(|| anyhow!("Screen {} does not exist", screen_no))?;
+                let scale = screen.display_info.scale_factor;
+                (
+                    Drawable::Window(root),
+                    (screen.display_info.x as f32 * scale) as i16,
+                    (screen.display_info.y as f32 * scale) as i16,
+                    (screen.display_info.width as f32 * scale) as u16,
+                    (screen.display_info.height as f32 * scale) as u16,
+                    None,
+                )
+            }
+            // The root window spans every monitor, so input coordinates already land on the
+            // right screen.
+            LVCaptureTarget::AllScreens => {
+                (Drawable::Window(root), 0, 0, root_width, root_height, None)
+            }
+            LVCaptureTarget::Region {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let (x, y, width, height) = clip_to_root(
+                    (*x as i32, *y as i32, *width, *height),
+                    root_width,
+                    root_height,
+                )
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Region {}x{} at {},{} is outside the {}x{} screen",
+                        width,
+                        height,
+                        x,
+                        y,
+                        root_width,
+                        root_height
+                    )
+                })?;
+                (
+                    Drawable::Window(root),
+                    x as i16,
+                    y as i16,
+                    width,
+                    height,
+                    None,
+                )
+            }
+            LVCaptureTarget::Window(_) | LVCaptureTarget::WindowTitle(_) => {
+                let window = match target {
+                    LVCaptureTarget::WindowTitle(title) => {
+                        find_window_by_title(&conn, root, title)?.ok_or_else(|| {
+                            anyhow!("No window has a title containing {:?}", title)
+                        })?
+                    }
+                    // SAFETY: the id comes from the user; if it's bogus the geometry request
+                    // below fails instead.
+                    LVCaptureTarget::Window(id) => unsafe { x::Window::new(*id) },
+                    _ => unreachable!(),
+                };
+                let geometry = conn.wait_for_reply(conn.send_request(&x::GetGeometry {
+                    drawable: Drawable::Window(window),
+                }))?;
+                let (window_x, window_y) = window_origin(&conn, root, window)?;
+                let (x, y, width, height) = clip_to_root(
+                    (window_x, window_y, geometry.width(), geometry.height()),
+                    root_width,
+                    root_height,
+                )
+                .ok_or_else(|| anyhow!("Window {:?} is off the screen", window))?;
+                info!(
+                    "following window {:?} ({}x{}, {}x{} of it on screen)",
+                    window,
+                    geometry.width(),
+                    geometry.height(),
+                    width,
+                    height
+                );
+                // Read from the window itself, but only the part that's on the root window,
+                // GetImage fails with BadMatch outside it. Without a compositor X returns
+                // whatever is on screen there, windows covering this one included.
+                (
+                    Drawable::Window(window),
+                    (x - window_x) as i16,
+                    (y - window_y) as i16,
+                    width,
+                    height,
+                    Some(window),
+                )
+            }
+            LVCaptureTarget::Pattern { .. } | LVCaptureTarget::File { .. } => {
+                return Err(anyhow!("{:?} is not an X11 capture target", target).into())
+            }
+        };
+
+        // Input lands relative to the top-left of what we capture, in root coordinates.
+        let origin = match window {
+            Some(window) => {
+                let (window_x, window_y) = window_origin(&conn, root, window)?;
+                (window_x + x as i32, window_y + y as i32)
+            }
+            None => (x as i32, y as i32),
+        };
+
+        let buffer_size = width as usize * height as usize * 4 as usize;
+
+        LVStatisticsCollector::register_data("server_capture_allocate", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data(
+            "server_capture_shm_get_image",
+            LVDataType::TimeSeries,
+        );
+        LVStatisticsCollector::register_data(
+            "server_capture_pool_exhausted",
+            LVDataType::Aggregate,
+        );
+
+        // Setup the shared memory segments up front so capturing never allocates.
+        let pre_alloc = Instant::now();
+        let mut segments = Vec::with_capacity(CAPTURE_BUFFERS);
+        for _ in 0..CAPTURE_BUFFERS {
+            segments.push(Arc::new(LVShmSegment::new(&conn, buffer_size)?));
+        }
+        LVStatisticsCollector::update_data(
+            "server_capture_allocate",
+            LVDataPoint::TimeElapsed(pre_alloc.elapsed()),
+        );
+
+        let get_image = GetImage {
+            drawable,
+            x,
+            y,
+            width,
+            height,
+            plane_mask: u32::MAX,
+            format: ImageFormat::ZPixmap as u8, // ZPixmap
+            shmseg: segments[0].seg,
+            offset: 0,
+        };
+
+        // Ask to be told about mode switches and monitor hotplug so we can rebuild ourselves
+        // with the new geometry.
+        if conn
+            .active_extensions()
+            .any(|ext| ext == xcb::Extension::RandR)
+        {
+            conn.check_request(conn.send_request_checked(&randr::SelectInput {
+                window: root,
+                enable: randr::NotifyMask::SCREEN_CHANGE
+                    | randr::NotifyMask::CRTC_CHANGE
+                    | randr::NotifyMask::OUTPUT_CHANGE,
+            }))?;
+        } else {
+            warn!("RandR is not available, resolution changes will not be detected");
+        }
+
         Ok(Self {
             conn,
+            root,
+            root_size: (root_width, root_height),
             bit_order,
             get_image,
-            bgr_buffer,
-            bgr_buffer_len: buffer_size,
+            segments,
+            next_segment: 0,
+            window,
+            origin,
         })
     }
+
+    // Where the window's top-left corner currently is in root coordinates.
+    fn window_origin(&self, window: x::Window) -> Result<(i32, i32), xcb::Error> {
+        window_origin(&self.conn, self.root, window)
+    }
+
+    // Find a segment that nobody downstream is still reading from.
+    fn free_segment(&mut self) -> Option<usize> {
+        for i in 0..self.segments.len() {
+            let candidate = (self.next_segment + i) % self.segments.len();
+            // get_mut only succeeds when we hold the only reference, and it synchronizes with
+            // the drop of the last frame that used the segment.
+            if Arc::get_mut(&mut self.segments[candidate]).is_some() {
+                return Some(candidate);
+            }
+        }
+        None
+    }
 }
 
 // TODO: https://stackoverflow.com/questions/34176795/any-efficient-way-of-converting-ximage-data-to-pixel-map-e-g-array-of-rgb-quad
-// TODO: use XShm for image buffer
 impl LVCapturer for LVLinuxCapturer {
     // Adapted from https://github.com/nashaofu/screenshots-rs/blob/master/src/linux/xorg.rs
-    fn capture(
-        &mut self,
-    ) -> Result<image::ImageBuffer<Rgb<u8>, Vec<u8>>, Box<dyn std::error::Error>> {
-        // I would really like to offload this screen to be elsewhere. It's a waste to do this every time.
+    fn capture(&mut self) -> Result<LVFrame, Box<dyn std::error::Error>> {
+        let segment_index = match self.free_segment() {
+            Some(i) => i,
+            None => {
+                LVStatisticsCollector::update_data(
+                    "server_capture_pool_exhausted",
+                    LVDataPoint::Increment,
+                );
+                return Err(anyhow!("all capture buffers are still in use").into());
+            }
+        };
+        self.next_segment = (segment_index + 1) % self.segments.len();
+
+        let segment = &self.segments[segment_index];
+        self.get_image.shmseg = segment.seg;
 
-        let get_image_cookie = self.conn.send_request(&(self.get_image));
         let time = Instant::now();
+        let get_image_cookie = self.conn.send_request(&(self.get_image));
         let _ = self.conn.wait_for_reply(get_image_cookie)?;
-        let bytes = unsafe { slice::from_raw_parts(self.bgr_buffer, self.bgr_buffer_len) };
         debug!("XShmGetImage took {:.4?}", time.elapsed());
 
-        if self.bit_order == ImageOrder::LsbFirst {
-            debug!("bytes len {}", bytes.len());
-        } else {
-            unimplemented!("RGBA not implemented");
-        }
+        LVStatisticsCollector::update_data(
+            "server_capture_shm_get_image",
+            LVDataPoint::TimeElapsed(time.elapsed()),
+        );
+
+        debug!(
+            "captured into segment {} ({} bytes, bit order {:?})",
+            segment_index, segment.len, self.bit_order
+        );
 
-        Ok(image::ImageBuffer::from_vec(
+        Ok(LVFrame::new(
             self.get_image.width.into(),
             self.get_image.height.into(),
-            bytes.to_vec(),
-        )
-        .ok_or(anyhow!("Does not fit in imgbuf"))?)
+            segment.clone(),
+        ))
+    }
+
+    fn needs_rebuild(&mut self) -> bool {
+        let mut changed = false;
+        // Drain everything that is queued so one mode switch doesn't trigger several rebuilds.
+        loop {
+            match self.conn.poll_for_event() {
+                Ok(Some(xcb::Event::RandR(randr::Event::ScreenChangeNotify(ev)))) => {
+                    info!("RandR screen change to {}x{}", ev.width(), ev.height());
+                    changed = true;
+                }
+                Ok(Some(xcb::Event::RandR(randr::Event::Notify(_)))) => {
+                    info!("RandR CRTC/output change");
+                    changed = true;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("polling X events failed with {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        // Follow the window around. Moving only changes where input goes, but a resize
+        // needs new buffers.
+        if let Some(window) = self.window {
+            let geometry = self
+                .conn
+                .wait_for_reply(self.conn.send_request(&x::GetGeometry {
+                    drawable: Drawable::Window(window),
+                }));
+            match (geometry, self.window_origin(window)) {
+                (Ok(geometry), Ok((window_x, window_y))) => {
+                    // Moving partly off the screen changes how much of it we can read too.
+                    match clip_to_root(
+                        (window_x, window_y, geometry.width(), geometry.height()),
+                        self.root_size.0,
+                        self.root_size.1,
+                    ) {
+                        Some((x, y, width, height))
+                            if (width, height) == (self.get_image.width, self.get_image.height) =>
+                        {
+                            self.get_image.x = (x - window_x) as i16;
+                            self.get_image.y = (y - window_y) as i16;
+                            self.origin = (x, y);
+                        }
+                        Some((_, _, width, height)) => {
+                            info!("window resized to {}x{} on screen", width, height);
+                            changed = true;
+                        }
+                        None => {
+                            warn!("window {:?} moved off the screen", window);
+                            changed = true;
+                        }
+                    }
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!("window {:?} went away: {:?}", window, e);
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+
+    fn origin(&self) -> (i32, i32) {
+        self.origin
+    }
+
+    fn focus(&mut self) -> Option<LVFocus> {
+        let (width, height) = (self.get_image.width as i32, self.get_image.height as i32);
+        // Send both requests before waiting, it's one round trip instead of two.
+        let pointer_cookie = self
+            .conn
+            .send_request(&x::QueryPointer { window: self.root });
+        let focus_cookie = self.conn.send_request(&x::GetInputFocus {});
+
+        let mut focus = LVFocus::default();
+        match self.conn.wait_for_reply(pointer_cookie) {
+            Ok(pointer) if pointer.same_screen() => {
+                let x = pointer.root_x() as i32 - self.origin.0;
+                let y = pointer.root_y() as i32 - self.origin.1;
+                if (0..width).contains(&x) && (0..height).contains(&y) {
+                    focus.cursor = Some((x, y));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => debug!("failed to query the pointer {:?}", e),
+        }
+
+        // No focus, PointerRoot (1) and the root window itself don't say anything useful.
+        let window = match self.conn.wait_for_reply(focus_cookie) {
+            Ok(reply) => reply.focus(),
+            Err(e) => {
+                debug!("failed to get the input focus {:?}", e);
+                return Some(focus);
+            }
+        };
+        if window.is_none() || window.resource_id() == 1 || window == self.root {
+            return Some(focus);
+        }
+        let geometry = self
+            .conn
+            .wait_for_reply(self.conn.send_request(&x::GetGeometry {
+                drawable: Drawable::Window(window),
+            }));
+        match (geometry, self.window_origin(window)) {
+            // Only the part of the window that's in the frame
+            (Ok(geometry), Ok((x, y))) => {
+                let (x, y) = (x - self.origin.0, y - self.origin.1);
+                let left = x.clamp(0, width);
+                let top = y.clamp(0, height);
+                let right = (x + geometry.width() as i32).clamp(0, width);
+                let bottom = (y + geometry.height() as i32).clamp(0, height);
+                if right > left && bottom > top {
+                    focus.window = Some((left, top, (right - left) as u32, (bottom - top) as u32));
+                }
+            }
+            // The window can go away between the two requests.
+            (Err(e), _) | (_, Err(e)) => debug!("failed to locate the focused window {:?}", e),
+        }
+
+        Some(focus)
     }
 }
+
+// Where the window's top-left corner currently is in root coordinates.
+fn window_origin(
+    conn: &Connection,
+    root: x::Window,
+    window: x::Window,
+) -> Result<(i32, i32), xcb::Error> {
+    let translated = conn.wait_for_reply(conn.send_request(&x::TranslateCoordinates {
+        src_window: window,
+        dst_window: root,
+        src_x: 0,
+        src_y: 0,
+    }))?;
+    Ok((translated.dst_x() as i32, translated.dst_y() as i32))
+}
+
+// The part of a rectangle (in root coordinates) that's on the root window, None if none of it
+// is.
+fn clip_to_root(
+    (x, y, width, height): (i32, i32, u16, u16),
+    root_width: u16,
+    root_height: u16,
+) -> Option<(i32, i32, u16, u16)> {
+    let left = x.clamp(0, root_width as i32);
+    let top = y.clamp(0, root_height as i32);
+    let right = (x + width as i32).clamp(0, root_width as i32);
+    let bottom = (y + height as i32).clamp(0, root_height as i32);
+    (right > left && bottom > top)
+        .then(|| (left, top, (right - left) as u16, (bottom - top) as u16))
+}
+
+// Depth-first search of the window tree for a window whose title contains `title`.
+fn find_window_by_title(
+    conn: &Connection,
+    root: x::Window,
+    title: &str,
+) -> Result<Option<x::Window>, Box<dyn std::error::Error>> {
+    let intern = |name: &[u8]| -> Result<x::Atom, xcb::Error> {
+        Ok(conn
+            .wait_for_reply(conn.send_request(&x::InternAtom {
+                only_if_exists: false,
+                name,
+            }))?
+            .atom())
+    };
+    // Every name contains the empty string, the root window would match
+    if title.is_empty() {
+        return Err(anyhow!("Window title to capture is empty").into());
+    }
+    let net_wm_name = intern(b"_NET_WM_NAME")?;
+    let utf8_string = intern(b"UTF8_STRING")?;
+
+    let mut stack = vec![root];
+    while let Some(window) = stack.pop() {
+        // Windows can go away while we walk the tree, skip the ones whose requests fail
+        for (property, r#type) in [
+            (net_wm_name, utf8_string),
+            (x::ATOM_WM_NAME, x::ATOM_STRING),
+        ] {
+            let name = match conn.wait_for_reply(conn.send_request(&x::GetProperty {
+                delete: false,
+                window,
+                property,
+                r#type,
+                long_offset: 0,
+                long_length: 1024,
+            })) {
+                Ok(name) => name,
+                Err(e) => {
+                    debug!("Skipping window {:?}: {:?}", window, e);
+                    continue;
+                }
+            };
+            if String::from_utf8_lossy(name.value::<u8>()).contains(title) {
+                return Ok(Some(window));
+            }
+        }
+
+        match conn.wait_for_reply(conn.send_request(&x::QueryTree { window })) {
+            Ok(tree) => stack.extend(tree.children().iter().rev()),
+            Err(e) => debug!("Skipping children of window {:?}: {:?}", window, e),
+        }
+    }
+
+    Ok(None)
+}
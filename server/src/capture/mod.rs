@@ -1,9 +1,508 @@
+pub mod file;
 pub mod linux;
+pub mod stamp;
+pub mod synthetic;
 
-use image::Rgb;
+#[cfg(feature = "wayland-capture")]
+pub mod pipewire;
+
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use log::info;
+use net::clock;
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+
+// Whether we are running inside a Wayland session, where X11 capture only sees XWayland windows.
+fn is_wayland_session() -> bool {
+    match std::env::var("XDG_SESSION_TYPE") {
+        Ok(session_type) => session_type == "wayland",
+        Err(_) => std::env::var_os("WAYLAND_DISPLAY").is_some(),
+    }
+}
+
+// Picks the capture backend for the session we are running in. LV_CAPTURE_BACKEND=x11 or
+// LV_CAPTURE_BACKEND=pipewire overrides the guess.
+pub fn default_capturer(
+    target: &LVCaptureTarget,
+) -> Result<Box<dyn LVCapturer + Send>, Box<dyn std::error::Error>> {
+    // These don't need a display at all.
+    match target {
+        LVCaptureTarget::Pattern {
+            pattern,
+            width,
+            height,
+        } => {
+            info!(
+                "generating {:?} test pattern at {}x{}",
+                pattern, width, height
+            );
+            return Ok(Box::new(synthetic::LVSyntheticCapturer::new(
+                *pattern, *width, *height,
+            )));
+        }
+        LVCaptureTarget::File { path, size } => {
+            info!("reading frames from {:?}", path);
+            return Ok(Box::new(file::LVFileCapturer::new(path, *size)?));
+        }
+        _ => {}
+    }
+
+    let wayland = match std::env::var("LV_CAPTURE_BACKEND").as_deref() {
+        Ok("x11") => false,
+        Ok("pipewire") => true,
+        Ok(other) => return Err(anyhow!("unknown capture backend {:?}", other).into()),
+        Err(_) => is_wayland_session(),
+    };
+
+    if wayland {
+        #[cfg(feature = "wayland-capture")]
+        {
+            info!("capturing {:?} through pipewire", target);
+            return Ok(Box::new(pipewire::LVPipeWireCapturer::new(target)?));
+        }
+        #[cfg(not(feature = "wayland-capture"))]
+        log::warn!("built without wayland-capture, falling back to X11 capture through XWayland");
+    }
+
+    info!("capturing {:?} through X11", target);
+    Ok(Box::new(linux::LVLinuxCapturer::new(target)?))
+}
+
+// What part of the desktop gets streamed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LVCaptureTarget {
+    // A whole monitor, indexed like screenshots::Screen::all()
+    Screen(usize),
+    // Every monitor composed into one stream, i.e. the whole root window
+    AllScreens,
+    // A single X11 window by id, followed as it moves and resizes
+    Window(u32),
+    // The first X11 window whose title contains the given string
+    WindowTitle(String),
+    // A fixed rectangle in root window coordinates
+    Region {
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+    },
+    // A generated test pattern, for running without a display
+    Pattern {
+        pattern: LVTestPattern,
+        width: u32,
+        height: u32,
+    },
+    // Frames read from a raw BGRA file, a Y4M file or a directory of PNGs, looped forever. Raw
+    // files carry no header so they need the size spelled out.
+    File {
+        path: PathBuf,
+        size: Option<(u32, u32)>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LVTestPattern {
+    // Colour bars sliding sideways, cheap to encode
+    Bars,
+    // Random pixels every frame, the worst case for the encoder
+    Noise,
+    // Lines of text scrolling upwards, like a terminal
+    Scroll,
+    // Milliseconds since start and the frame number in large digits
+    Timestamp,
+}
+
+impl FromStr for LVTestPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bars" => Ok(LVTestPattern::Bars),
+            "noise" => Ok(LVTestPattern::Noise),
+            "scroll" => Ok(LVTestPattern::Scroll),
+            "timestamp" => Ok(LVTestPattern::Timestamp),
+            _ => Err(anyhow!("unknown test pattern {:?}", s)),
+        }
+    }
+}
+
+fn parse_size(size: &str) -> Result<(u32, u32), anyhow::Error> {
+    let (width, height) = size
+        .split_once('x')
+        .ok_or_else(|| anyhow!("size should look like WxH"))?;
+    Ok((width.parse()?, height.parse()?))
+}
+
+impl LVCaptureTarget {
+    // Frames that don't come from a display, so there is nothing to send input to.
+    pub fn is_headless(&self) -> bool {
+        matches!(
+            self,
+            LVCaptureTarget::Pattern { .. } | LVCaptureTarget::File { .. }
+        )
+    }
+}
+
+impl Default for LVCaptureTarget {
+    fn default() -> Self {
+        LVCaptureTarget::Screen(0)
+    }
+}
+
+// Parses screen:N, screen:all, window:ID (decimal or 0x hex, as printed by xwininfo), title:TEXT,
+// region:X,Y,WxH, pattern:NAME[,WxH] and file:PATH[,WxH].
+impl FromStr for LVCaptureTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("capture target {:?} is missing a ':'", s))?;
+        match kind {
+            "screen" if value == "all" => Ok(LVCaptureTarget::AllScreens),
+            "screen" => Ok(LVCaptureTarget::Screen(value.parse()?)),
+            "window" => Ok(LVCaptureTarget::Window(match value.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16)?,
+                None => value.parse()?,
+            })),
+            "title" => Ok(LVCaptureTarget::WindowTitle(value.to_owned())),
+            "region" => {
+                let (origin, size) = value
+                    .rsplit_once(',')
+                    .ok_or_else(|| anyhow!("region should look like X,Y,WxH"))?;
+                let (x, y) = origin
+                    .split_once(',')
+                    .ok_or_else(|| anyhow!("region should look like X,Y,WxH"))?;
+                let (width, height) = size
+                    .split_once('x')
+                    .ok_or_else(|| anyhow!("region should look like X,Y,WxH"))?;
+                Ok(LVCaptureTarget::Region {
+                    x: x.parse()?,
+                    y: y.parse()?,
+                    width: width.parse()?,
+                    height: height.parse()?,
+                })
+            }
+            "pattern" => {
+                let (pattern, (width, height)) = match value.split_once(',') {
+                    Some((pattern, size)) => (pattern, parse_size(size)?),
+                    None => (value, (1280, 720)),
+                };
+                Ok(LVCaptureTarget::Pattern {
+                    pattern: pattern.parse()?,
+                    width,
+                    height,
+                })
+            }
+            // Paths may contain commas, so only treat the tail as a size if it parses as one.
+            "file" => match value.rsplit_once(',') {
+                Some((path, size)) if parse_size(size).is_ok() => Ok(LVCaptureTarget::File {
+                    path: path.into(),
+                    size: Some(parse_size(size)?),
+                }),
+                _ => Ok(LVCaptureTarget::File {
+                    path: value.into(),
+                    size: None,
+                }),
+            },
+            _ => Err(anyhow!("unknown capture target kind {:?}", kind)),
+        }
+    }
+}
+
+// Anything that can back the pixels of a captured frame. Capturers keep a pool of these
+// and hand out shared references, so the encoder can read straight out of e.g. an XShm
+// segment instead of a per-frame copy.
+pub trait LVFrameBuffer: Send + Sync {
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl LVFrameBuffer for Vec<u8> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+// Where the user is probably looking, in frame coordinates: the pointer and the window that
+// has keyboard focus. Either can be missing, e.g. while the pointer is on another monitor.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LVFocus {
+    pub cursor: Option<(i32, i32)>,
+    // x, y, width, height. May stick out of the frame.
+    pub window: Option<(i32, i32, u32, u32)>,
+}
+
+impl LVFocus {
+    // The same focus on a frame scaled down by `divisor`.
+    pub fn scaled(&self, divisor: u32) -> Self {
+        let divisor = divisor.max(1);
+        Self {
+            cursor: self
+                .cursor
+                .map(|(x, y)| (x / divisor as i32, y / divisor as i32)),
+            window: self.window.map(|(x, y, width, height)| {
+                (
+                    x / divisor as i32,
+                    y / divisor as i32,
+                    width / divisor,
+                    height / divisor,
+                )
+            }),
+        }
+    }
+}
+
+// A captured BGRA frame. Cloning is cheap; the underlying buffer goes back to the
+// capturer's pool once every clone has been dropped.
+#[derive(Clone)]
+pub struct LVFrame {
+    width: u32,
+    height: u32,
+    buffer: Arc<dyn LVFrameBuffer>,
+    // Microseconds since the UNIX epoch when the capture started, and how long it took
+    capture_ts: u64,
+    capture_time: Duration,
+    focus: Option<LVFocus>,
+    // The id LV_QUALITY stamped into the pixels
+    stamp: Option<u32>,
+}
+
+impl LVFrame {
+    pub fn new(width: u32, height: u32, buffer: Arc<dyn LVFrameBuffer>) -> Self {
+        Self {
+            width,
+            height,
+            buffer,
+            capture_ts: clock::now_us(),
+            capture_time: Duration::ZERO,
+            focus: None,
+            stamp: None,
+        }
+    }
+
+    // The capture thread knows when it asked for the frame, the capturer doesn't.
+    pub fn set_capture_time(&mut self, capture_ts: u64, capture_time: Duration) {
+        self.capture_ts = capture_ts;
+        self.capture_time = capture_time;
+    }
+
+    pub fn set_focus(&mut self, focus: Option<LVFocus>) {
+        self.focus = focus;
+    }
+
+    pub fn focus(&self) -> Option<LVFocus> {
+        self.focus
+    }
+
+    pub fn set_stamp(&mut self, stamp: Option<u32>) {
+        self.stamp = stamp;
+    }
+
+    pub fn stamp(&self) -> Option<u32> {
+        self.stamp
+    }
+
+    pub fn capture_ts(&self) -> u64 {
+        self.capture_ts
+    }
+
+    pub fn capture_time(&self) -> Duration {
+        self.capture_time
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // Bytes per row. Every capturer currently produces tightly packed BGRA.
+    pub fn stride(&self) -> usize {
+        4 * self.width as usize
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buffer.as_bytes()
+    }
+}
 
 pub trait LVCapturer {
-    fn capture(
+    fn capture(&mut self) -> Result<LVFrame, Box<dyn std::error::Error>>;
+
+    // Whether the captured area changed size (a RandR mode switch, a monitor being
+    // plugged in or removed, ...) so the capturer has to be recreated.
+    fn needs_rebuild(&mut self) -> bool {
+        false
+    }
+
+    // Position of the captured area's top-left corner on the desktop, used to map input
+    // coordinates from the client back onto the real screen.
+    fn origin(&self) -> (i32, i32) {
+        (0, 0)
+    }
+
+    // Where the pointer and the focused window are, for region of interest encoding. None if
+    // the capturer can't tell.
+    fn focus(&mut self) -> Option<LVFocus> {
+        None
+    }
+}
+
+// How many frames a capturer keeps buffers for: one being captured, up to two waiting in the
+// streaming server's queue and one being converted by the encoder.
+pub const CAPTURE_BUFFERS: usize = 4;
+
+// A handful of heap buffers for capturers that produce frames themselves instead of reading
+// them out of shared memory. A buffer is reused once every frame pointing at it has been dropped.
+pub struct LVBufferPool {
+    buffers: Vec<Arc<Vec<u8>>>,
+    capacity: usize,
+}
+
+impl LVBufferPool {
+    pub fn new(capacity: usize) -> Self {
+        LVStatisticsCollector::register_data(
+            "server_capture_pool_exhausted",
+            LVDataType::Aggregate,
+        );
+        Self {
+            buffers: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    // Fills a free buffer with a width x height BGRA frame and hands it out.
+    pub fn frame(
         &mut self,
-    ) -> Result<image::ImageBuffer<Rgb<u8>, Vec<u8>>, Box<dyn std::error::Error>>;
+        width: u32,
+        height: u32,
+        fill: impl FnOnce(&mut [u8]) -> Result<(), Box<dyn std::error::Error>>,
+    ) -> Result<LVFrame, Box<dyn std::error::Error>> {
+        let len = 4 * width as usize * height as usize;
+        let index = match self
+            .buffers
+            .iter_mut()
+            .position(|buffer| Arc::get_mut(buffer).is_some())
+        {
+            Some(index) => index,
+            None if self.buffers.len() < self.capacity => {
+                self.buffers.push(Arc::new(Vec::new()));
+                self.buffers.len() - 1
+            }
+            None => {
+                LVStatisticsCollector::update_data(
+                    "server_capture_pool_exhausted",
+                    LVDataPoint::Increment,
+                );
+                return Err(anyhow!("all capture buffers are in use").into());
+            }
+        };
+
+        let buffer = Arc::get_mut(&mut self.buffers[index]).expect("buffer was handed out");
+        buffer.resize(len, 0);
+        fill(buffer)?;
+
+        Ok(LVFrame::new(width, height, self.buffers[index].clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_capture_targets() {
+        for (s, target) in [
+            ("screen:1", LVCaptureTarget::Screen(1)),
+            ("screen:all", LVCaptureTarget::AllScreens),
+            ("window:1234", LVCaptureTarget::Window(1234)),
+            ("window:0x3a00007", LVCaptureTarget::Window(0x3a00007)),
+            (
+                "title:Firefox: a, b",
+                LVCaptureTarget::WindowTitle("Firefox: a, b".to_owned()),
+            ),
+            (
+                "region:-10,20,640x480",
+                LVCaptureTarget::Region {
+                    x: -10,
+                    y: 20,
+                    width: 640,
+                    height: 480,
+                },
+            ),
+            (
+                "pattern:noise",
+                LVCaptureTarget::Pattern {
+                    pattern: LVTestPattern::Noise,
+                    width: 1280,
+                    height: 720,
+                },
+            ),
+            (
+                "pattern:scroll,320x240",
+                LVCaptureTarget::Pattern {
+                    pattern: LVTestPattern::Scroll,
+                    width: 320,
+                    height: 240,
+                },
+            ),
+            (
+                "file:/tmp/a.bgra,64x32",
+                LVCaptureTarget::File {
+                    path: "/tmp/a.bgra".into(),
+                    size: Some((64, 32)),
+                },
+            ),
+            // Only a trailing size counts, anything else after a comma is part of the path
+            (
+                "file:/tmp/a,b.y4m",
+                LVCaptureTarget::File {
+                    path: "/tmp/a,b.y4m".into(),
+                    size: None,
+                },
+            ),
+        ] {
+            assert_eq!(s.parse::<LVCaptureTarget>().unwrap(), target, "{}", s);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_capture_targets() {
+        for s in [
+            "screen",
+            "screen:",
+            "screen:one",
+            "window:0xzz",
+            "window:-1",
+            "region:10,20",
+            "region:10,640x480",
+            "region:10,20,640",
+            "region:a,20,640x480",
+            "pattern:plaid",
+            "pattern:bars,640",
+            "monitor:0",
+        ] {
+            assert!(s.parse::<LVCaptureTarget>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parses_test_patterns() {
+        for (s, pattern) in [
+            ("bars", LVTestPattern::Bars),
+            ("noise", LVTestPattern::Noise),
+            ("scroll", LVTestPattern::Scroll),
+            ("timestamp", LVTestPattern::Timestamp),
+        ] {
+            assert_eq!(s.parse::<LVTestPattern>().unwrap(), pattern);
+        }
+        assert!("Bars".parse::<LVTestPattern>().is_err());
+        assert!("".parse::<LVTestPattern>().is_err());
+    }
 }
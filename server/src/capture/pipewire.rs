@@ -0,0 +1,593 @@
+use anyhow::anyhow;
+use ashpd::{
+    desktop::{
+        screencast::{CursorMode, PersistMode, Screencast, SourceType},
+        Session,
+    },
+    WindowIdentifier,
+};
+use log::{debug, error, info, warn};
+use nix::ioctl_write_ptr;
+use pipewire as pw;
+use pw::{
+    spa::{
+        self,
+        buffer::{Data, DataType},
+        param::{
+            format::{FormatProperties, MediaSubtype, MediaType},
+            format_utils,
+            video::{VideoFormat, VideoInfoRaw},
+            ParamType,
+        },
+        pod::{serialize::PodSerializer, Object, Pod, Property, PropertyFlags, Value},
+        utils::{Direction, Fraction, Rectangle, SpaTypes},
+    },
+    stream::{StreamFlags, StreamState},
+};
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+use std::{
+    io::Cursor,
+    os::fd::OwnedFd,
+    slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::{LVBufferPool, LVCaptureTarget, LVCapturer, LVFrame, CAPTURE_BUFFERS};
+
+// How long to wait for the compositor to hand over the first frame. The portal dialog has
+// already been answered by then, so this only covers stream negotiation.
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+// DRM_FORMAT_MOD_LINEAR. Linear DMA-BUFs can be mmapped and read like any other memory, anything
+// tiled would need a GPU round trip to detile.
+const DRM_FORMAT_MOD_LINEAR: i64 = 0;
+
+lazy_static::lazy_static! {
+    // Handed back by the portal so rebuilding the capturer (resolution changes, monitor
+    // switches) doesn't pop up the selection dialog again.
+    static ref RESTORE_TOKEN: Mutex<Option<String>> = Mutex::new(None);
+}
+
+// struct dma_buf_sync from linux/dma-buf.h
+#[repr(C)]
+struct DmaBufSync {
+    flags: u64,
+}
+
+const DMA_BUF_SYNC_READ: u64 = 1 << 0;
+const DMA_BUF_SYNC_START: u64 = 0 << 2;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+
+ioctl_write_ptr!(dma_buf_sync, b'b', 0, DmaBufSync);
+
+// Captures through PipeWire, which is the only way to get at the screen on a Wayland session.
+// The stream is normally set up through xdg-desktop-portal, but LV_PIPEWIRE_NODE=<id> connects
+// straight to a node on the local daemon instead, e.g. a videotestsrc ! pipewiresink pipeline or
+// a headless compositor, which is handy for testing without a desktop.
+pub struct LVPipeWireCapturer {
+    // Newest frame from the PipeWire thread, replaced whenever a new one arrives.
+    latest: Arc<Mutex<Option<LVFrame>>>,
+    frame_ready: flume::Receiver<()>,
+    last_frame: Option<LVFrame>,
+
+    failed: Arc<AtomicBool>,
+    quit_tx: pw::channel::Sender<()>,
+    pw_thread: Option<JoinHandle<()>>,
+
+    // Keeps the portal session open for as long as we are streaming.
+    session: Option<Session<'static>>,
+    origin: (i32, i32),
+}
+
+// Everything the process callback needs. Lives on the PipeWire thread.
+struct LVStreamState {
+    format: VideoInfoRaw,
+    dmabuf: bool,
+    crop: Option<(i32, i32, u32, u32)>,
+    pool: LVBufferPool,
+    latest: Arc<Mutex<Option<LVFrame>>>,
+    frame_ready: flume::Sender<()>,
+    failed: Arc<AtomicBool>,
+}
+
+impl LVPipeWireCapturer {
+    pub fn new(target: &LVCaptureTarget) -> Result<Self, Box<dyn std::error::Error>> {
+        LVStatisticsCollector::register_data(
+            "server_capture_pipewire_copy",
+            LVDataType::TimeSeries,
+        );
+
+        let (fd, node_id, session, origin, crop) = match std::env::var("LV_PIPEWIRE_NODE") {
+            Ok(node) => {
+                info!("connecting straight to PipeWire node {}", node);
+                (
+                    None,
+                    Some(node.parse()?),
+                    None,
+                    (0, 0),
+                    Self::crop_for(target, (0, 0)),
+                )
+            }
+            Err(_) => {
+                let (fd, node_id, session, origin) = pollster::block_on(Self::open_portal(target))?;
+                (
+                    Some(fd),
+                    Some(node_id),
+                    Some(session),
+                    origin,
+                    Self::crop_for(target, origin),
+                )
+            }
+        };
+
+        let latest = Arc::new(Mutex::new(None));
+        let failed = Arc::new(AtomicBool::new(false));
+        let (ready_push, frame_ready) = flume::bounded(1);
+        let (quit_tx, quit_rx) = pw::channel::channel();
+
+        let state = LVStreamState {
+            format: Default::default(),
+            dmabuf: false,
+            crop,
+            pool: LVBufferPool::new(CAPTURE_BUFFERS),
+            latest: latest.clone(),
+            frame_ready: ready_push,
+            failed: failed.clone(),
+        };
+        let thread_failed = failed.clone();
+        let pw_thread = thread::Builder::new()
+            .name("pipewire_capture".to_owned())
+            .spawn(move || {
+                if let Err(e) = run_stream(fd, node_id, state, quit_rx) {
+                    error!("pipewire capture stopped: {:?}", e);
+                    thread_failed.store(true, Ordering::Relaxed);
+                }
+            })?;
+
+        Ok(Self {
+            latest,
+            frame_ready,
+            last_frame: None,
+            failed,
+            quit_tx,
+            pw_thread: Some(pw_thread),
+            session,
+            origin,
+        })
+    }
+
+    // Asks the portal for a screencast of the target and returns the PipeWire remote, the node
+    // to read from and where the stream sits on the desktop.
+    async fn open_portal(
+        target: &LVCaptureTarget,
+    ) -> Result<(OwnedFd, u32, Session<'static>, (i32, i32)), Box<dyn std::error::Error>> {
+        let source_type = match target {
+            LVCaptureTarget::Screen(_) | LVCaptureTarget::Region { .. } => SourceType::Monitor,
+            LVCaptureTarget::AllScreens => {
+                warn!(
+                    "the screencast portal streams one monitor at a time, pick one in the dialog"
+                );
+                SourceType::Monitor
+            }
+            LVCaptureTarget::Window(_) | LVCaptureTarget::WindowTitle(_) => {
+                warn!("Wayland doesn't expose window ids or titles, pick the window in the dialog");
+                SourceType::Window
+            }
+            LVCaptureTarget::Pattern { .. } | LVCaptureTarget::File { .. } => {
+                return Err(anyhow!("{:?} is not a screencast target", target).into())
+            }
+        };
+
+        let proxy = Screencast::new().await?;
+        let session = proxy.create_session().await?;
+        let restore_token = RESTORE_TOKEN
+            .lock()
+            .expect("Failed to lock restore token")
+            .clone();
+        proxy
+            .select_sources(
+                &session,
+                CursorMode::Hidden,
+                source_type.into(),
+                false,
+                restore_token.as_deref(),
+                PersistMode::Application,
+            )
+            .await?;
+        let response = proxy
+            .start(&session, &WindowIdentifier::default())
+            .await?
+            .response()?;
+        if let Some(token) = response.restore_token() {
+            *RESTORE_TOKEN.lock().expect("Failed to lock restore token") = Some(token.to_owned());
+        }
+
+        let stream = response
+            .streams()
+            .first()
+            .ok_or_else(|| anyhow!("the portal did not return any streams"))?;
+        info!(
+            "portal stream node {} at {:?} size {:?}",
+            stream.pipe_wire_node_id(),
+            stream.position(),
+            stream.size()
+        );
+        let fd = proxy.open_pipe_wire_remote(&session).await?;
+
+        Ok((
+            fd,
+            stream.pipe_wire_node_id(),
+            session,
+            stream.position().unwrap_or((0, 0)),
+        ))
+    }
+
+    // Regions are cut out of the monitor stream they sit on.
+    fn crop_for(target: &LVCaptureTarget, origin: (i32, i32)) -> Option<(i32, i32, u32, u32)> {
+        match target {
+            LVCaptureTarget::Region {
+                x,
+                y,
+                width,
+                height,
+            } => Some((
+                *x as i32 - origin.0,
+                *y as i32 - origin.1,
+                *width as u32,
+                *height as u32,
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl LVCapturer for LVPipeWireCapturer {
+    fn capture(&mut self) -> Result<LVFrame, Box<dyn std::error::Error>> {
+        // The compositor only sends frames when something changed on screen, so keep handing
+        // out the previous one until it does.
+        if self.last_frame.is_none() {
+            self.frame_ready.recv_timeout(FIRST_FRAME_TIMEOUT)?;
+        }
+
+        if let Some(frame) = self.latest.lock().expect("Failed to lock frame").take() {
+            self.last_frame = Some(frame);
+        }
+
+        self.last_frame
+            .clone()
+            .ok_or_else(|| anyhow!("no frame from pipewire yet").into())
+    }
+
+    // The stream renegotiates sizes on its own and the packager follows the frame size, so we
+    // only need a new capturer when the stream died (e.g. the user stopped sharing).
+    fn needs_rebuild(&mut self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    fn origin(&self) -> (i32, i32) {
+        self.origin
+    }
+}
+
+impl Drop for LVPipeWireCapturer {
+    fn drop(&mut self) {
+        let _ = self.quit_tx.send(());
+        if let Some(thread) = self.pw_thread.take() {
+            let _ = thread.join();
+        }
+        if let Some(session) = self.session.take() {
+            if let Err(e) = pollster::block_on(session.close()) {
+                warn!("failed to close portal session: {:?}", e);
+            }
+        }
+    }
+}
+
+// Runs the PipeWire main loop until the capturer is dropped or the stream errors out.
+fn run_stream(
+    fd: Option<OwnedFd>,
+    node_id: Option<u32>,
+    state: LVStreamState,
+    quit_rx: pw::channel::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    pw::init();
+
+    let mainloop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&mainloop)?;
+    let core = match fd {
+        Some(fd) => context.connect_fd(fd, None)?,
+        None => context.connect(None)?,
+    };
+
+    let quit_loop = mainloop.clone();
+    let _quit = quit_rx.attach(mainloop.loop_(), move |_| quit_loop.quit());
+
+    let stream = pw::stream::Stream::new(
+        &core,
+        "lv-capture",
+        pw::properties::properties! {
+            *pw::keys::MEDIA_TYPE => "Video",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+            *pw::keys::MEDIA_ROLE => "Screen",
+        },
+    )?;
+
+    let error_loop = mainloop.clone();
+    let _listener = stream
+        .add_local_listener_with_user_data(state)
+        .state_changed(move |_, state, old, new| {
+            debug!("pipewire stream state {:?} -> {:?}", old, new);
+            match new {
+                StreamState::Error(e) => warn!("pipewire stream failed: {}", e),
+                StreamState::Unconnected => warn!("pipewire stream disconnected"),
+                _ => return,
+            }
+            state.failed.store(true, Ordering::Relaxed);
+            error_loop.quit();
+        })
+        .param_changed(|stream, state, id, param| {
+            let Some(param) = param else {
+                return;
+            };
+            if id != ParamType::Format.as_raw() {
+                return;
+            }
+            let Ok((media_type, media_subtype)) = format_utils::parse_format(param) else {
+                return;
+            };
+            if media_type != MediaType::Video || media_subtype != MediaSubtype::Raw {
+                return;
+            }
+            if let Err(e) = state.format.parse(param) {
+                error!("failed to parse stream format: {:?}", e);
+                return;
+            }
+
+            state.dmabuf = state.format.flags().bits() & spa::sys::SPA_VIDEO_FLAG_MODIFIER != 0;
+            info!(
+                "pipewire stream format {:?} {}x{} dmabuf {}",
+                state.format.format(),
+                state.format.size().width,
+                state.format.size().height,
+                state.dmabuf
+            );
+
+            // Tell the producer which kind of memory we can read.
+            let data_types = if state.dmabuf {
+                1 << DataType::DmaBuf.as_raw()
+            } else {
+                (1 << DataType::MemFd.as_raw()) | (1 << DataType::MemPtr.as_raw())
+            };
+            let buffers = serialize_pod(Object {
+                type_: SpaTypes::ObjectParamBuffers.as_raw(),
+                id: ParamType::Buffers.as_raw(),
+                properties: vec![Property::new(
+                    spa::sys::SPA_PARAM_BUFFERS_dataType,
+                    Value::Int(data_types as i32),
+                )],
+            });
+            if let Some(pod) = Pod::from_bytes(&buffers) {
+                if let Err(e) = stream.update_params(&mut [pod]) {
+                    error!("failed to update buffer params: {:?}", e);
+                }
+            }
+        })
+        .process(|stream, state| {
+            // Only the newest buffer matters, drop anything that queued up behind it.
+            let mut newest = None;
+            while let Some(buffer) = stream.dequeue_buffer() {
+                newest = Some(buffer);
+            }
+            let Some(mut buffer) = newest else {
+                return;
+            };
+            let datas = buffer.datas_mut();
+            if datas.is_empty() || datas[0].chunk().size() == 0 {
+                return;
+            }
+            if let Err(e) = state.copy_frame(&mut datas[0]) {
+                warn!("dropped pipewire frame: {:?}", e);
+            }
+        })
+        .register()?;
+
+    // Prefer linear DMA-BUFs and fall back to shared memory if the compositor can't do them.
+    let dmabuf_format = serialize_pod(enum_format(true));
+    let shm_format = serialize_pod(enum_format(false));
+    let mut params = [
+        Pod::from_bytes(&dmabuf_format).ok_or_else(|| anyhow!("bad format pod"))?,
+        Pod::from_bytes(&shm_format).ok_or_else(|| anyhow!("bad format pod"))?,
+    ];
+    stream.connect(
+        Direction::Input,
+        node_id,
+        StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS,
+        &mut params,
+    )?;
+
+    mainloop.run();
+    Ok(())
+}
+
+// Raw BGRx/BGRA video of any size, which is byte for byte what the encoders already expect.
+fn enum_format(dmabuf: bool) -> Object {
+    let mut obj = pw::spa::pod::object!(
+        SpaTypes::ObjectParamFormat,
+        ParamType::EnumFormat,
+        pw::spa::pod::property!(FormatProperties::MediaType, Id, MediaType::Video),
+        pw::spa::pod::property!(FormatProperties::MediaSubtype, Id, MediaSubtype::Raw),
+        pw::spa::pod::property!(
+            FormatProperties::VideoFormat,
+            Choice,
+            Enum,
+            Id,
+            VideoFormat::BGRx,
+            VideoFormat::BGRx,
+            VideoFormat::BGRA
+        ),
+        pw::spa::pod::property!(
+            FormatProperties::VideoSize,
+            Choice,
+            Range,
+            Rectangle,
+            Rectangle {
+                width: 1920,
+                height: 1080
+            },
+            Rectangle {
+                width: 1,
+                height: 1
+            },
+            Rectangle {
+                width: 8192,
+                height: 8192
+            }
+        ),
+        pw::spa::pod::property!(
+            FormatProperties::VideoFramerate,
+            Choice,
+            Range,
+            Fraction,
+            Fraction { num: 60, denom: 1 },
+            Fraction { num: 0, denom: 1 },
+            Fraction {
+                num: 1000,
+                denom: 1
+            }
+        ),
+    );
+    if dmabuf {
+        obj.properties.push(Property {
+            key: FormatProperties::VideoModifier.as_raw(),
+            flags: PropertyFlags::MANDATORY,
+            value: Value::Long(DRM_FORMAT_MOD_LINEAR),
+        });
+    }
+    obj
+}
+
+fn serialize_pod(obj: Object) -> Vec<u8> {
+    PodSerializer::serialize(Cursor::new(Vec::new()), &Value::Object(obj))
+        .expect("Failed to serialize pod")
+        .0
+        .into_inner()
+}
+
+impl LVStreamState {
+    // Copies the buffer into one from our pool, since PipeWire wants its buffer back before the
+    // encoder would be done with it.
+    fn copy_frame(&mut self, data: &mut Data) -> Result<(), Box<dyn std::error::Error>> {
+        let size = self.format.size();
+        // Buffers can show up before the format is negotiated, there's nothing to copy yet.
+        if size.width == 0 || size.height == 0 {
+            debug!("skipping frame with no size yet");
+            return Ok(());
+        }
+        let (crop_x, crop_y, width, height) = match self.crop {
+            Some((x, y, w, h)) => {
+                let x = x.clamp(0, size.width as i32 - 1) as u32;
+                let y = y.clamp(0, size.height as i32 - 1) as u32;
+                (x, y, w.min(size.width - x), h.min(size.height - y))
+            }
+            None => (0, 0, size.width, size.height),
+        };
+
+        let src_stride = match data.chunk().stride() {
+            stride if stride > 0 => stride as usize,
+            _ => 4 * size.width as usize,
+        };
+        let offset = data.chunk().offset() as usize;
+
+        // The buffer comes from the compositor, check it holds the whole crop before slicing
+        // into it rather than panicking in a PipeWire callback.
+        let copy_rows = move |src: &[u8], dst: &mut [u8]| -> Result<(), anyhow::Error> {
+            let row_len = 4 * width as usize;
+            let end = offset
+                + (crop_y + height).saturating_sub(1) as usize * src_stride
+                + 4 * (crop_x + width) as usize;
+            if end > src.len() {
+                return Err(anyhow!(
+                    "buffer is {} bytes, a {}x{} crop at {},{} with stride {} needs {}",
+                    src.len(),
+                    width,
+                    height,
+                    crop_x,
+                    crop_y,
+                    src_stride,
+                    end
+                ));
+            }
+            for row in 0..height as usize {
+                let start = offset + (row + crop_y as usize) * src_stride + 4 * crop_x as usize;
+                dst[row * row_len..(row + 1) * row_len]
+                    .copy_from_slice(&src[start..start + row_len]);
+            }
+            Ok(())
+        };
+
+        let data_type = data.type_();
+        let (fd, maxsize, mapoffset) = {
+            let raw = data.as_raw();
+            (raw.fd as i32, raw.maxsize as usize, raw.mapoffset as usize)
+        };
+
+        let timer = Instant::now();
+        let frame = self.pool.frame(width, height, |dst| {
+            match data.data() {
+                Some(src) => copy_rows(src, dst)?,
+                // PipeWire doesn't map DMA-BUFs for us. Linear ones can be read through an mmap
+                // bracketed by DMA_BUF_IOCTL_SYNC so the GPU is done writing before we look.
+                None if data_type == DataType::DmaBuf => unsafe {
+                    let len = maxsize + mapoffset;
+                    let ptr = libc::mmap(
+                        std::ptr::null_mut(),
+                        len,
+                        libc::PROT_READ,
+                        libc::MAP_SHARED,
+                        fd,
+                        0,
+                    );
+                    if ptr == libc::MAP_FAILED {
+                        return Err(anyhow!("failed to mmap dmabuf").into());
+                    }
+                    let sync_start = DmaBufSync {
+                        flags: DMA_BUF_SYNC_START | DMA_BUF_SYNC_READ,
+                    };
+                    if let Err(e) = dma_buf_sync(fd, &sync_start) {
+                        debug!("dmabuf sync start failed: {:?}", e);
+                    }
+                    let copied = copy_rows(
+                        slice::from_raw_parts((ptr as *const u8).add(mapoffset), maxsize),
+                        dst,
+                    );
+                    let sync_end = DmaBufSync {
+                        flags: DMA_BUF_SYNC_END | DMA_BUF_SYNC_READ,
+                    };
+                    if let Err(e) = dma_buf_sync(fd, &sync_end) {
+                        debug!("dmabuf sync end failed: {:?}", e);
+                    }
+                    libc::munmap(ptr, len);
+                    copied?;
+                },
+                None => return Err(anyhow!("unmappable {:?} buffer", data_type).into()),
+            }
+            Ok(())
+        })?;
+        LVStatisticsCollector::update_data(
+            "server_capture_pipewire_copy",
+            LVDataPoint::TimeElapsed(timer.elapsed()),
+        );
+
+        *self.latest.lock().expect("Failed to lock frame") = Some(frame);
+        let _ = self.frame_ready.try_send(());
+        Ok(())
+    }
+}
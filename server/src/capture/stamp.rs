@@ -0,0 +1,72 @@
+use log::{debug, warn};
+use net::quality;
+
+use super::{LVBufferPool, LVFrame};
+
+// The four the capture thread can have in flight, plus some for frames the loopback harness
+// holds on to while it copies them.
+const STAMP_BUFFERS: usize = 8;
+
+// LV_QUALITY=1 stamps an id into every captured frame (see net::quality) so the client can
+// measure what it decoded against the original.
+pub fn enabled() -> bool {
+    matches!(std::env::var("LV_QUALITY").as_deref(), Ok("1") | Ok("true"))
+}
+
+// Copies frames and stamps consecutive ids into the copies. Capture buffers can be shared
+// memory we shouldn't write to, hence the copy. Lives as long as the capture thread, so the ids
+// keep counting when the capturer is rebuilt.
+pub struct LVFrameStamper {
+    pool: LVBufferPool,
+    next_id: u32,
+    warned_size: bool,
+}
+
+impl LVFrameStamper {
+    pub fn new() -> Self {
+        Self {
+            pool: LVBufferPool::new(STAMP_BUFFERS),
+            next_id: 0,
+            warned_size: false,
+        }
+    }
+
+    pub fn stamp(&mut self, frame: &LVFrame) -> Result<LVFrame, Box<dyn std::error::Error>> {
+        let (width, height) = (frame.width() as usize, frame.height() as usize);
+        if width < quality::STAMP_WIDTH || height < quality::STAMP_HEIGHT {
+            if !std::mem::replace(&mut self.warned_size, true) {
+                warn!(
+                    "{}x{} is too small to stamp, frames need to be at least {}x{}",
+                    width,
+                    height,
+                    quality::STAMP_WIDTH,
+                    quality::STAMP_HEIGHT
+                );
+            }
+            return Ok(frame.clone());
+        }
+
+        let id = self.next_id;
+        self.next_id = (self.next_id + 1) & quality::MAX_ID;
+        let mut stamped = self.pool.frame(frame.width(), frame.height(), |buffer| {
+            for (dst, src) in buffer
+                .chunks_exact_mut(4 * width)
+                .zip(frame.as_bytes().chunks_exact(frame.stride()))
+            {
+                dst.copy_from_slice(&src[..4 * width]);
+            }
+            quality::stamp(buffer, width, height, id);
+            Ok(())
+        })?;
+        debug!("stamped frame {}", id);
+        stamped.set_focus(frame.focus());
+        stamped.set_stamp(Some(id));
+        Ok(stamped)
+    }
+}
+
+impl Default for LVFrameStamper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
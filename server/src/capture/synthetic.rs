@@ -0,0 +1,312 @@
+use std::time::Instant;
+
+use super::{LVBufferPool, LVCapturer, LVFrame, LVTestPattern, CAPTURE_BUFFERS};
+
+// Every glyph is 3 pixels wide and 5 tall, scaled up when drawn.
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+
+// BGRA
+const BLACK: [u8; 4] = [0, 0, 0, 255];
+const WHITE: [u8; 4] = [255, 255, 255, 255];
+const GREY: [u8; 4] = [64, 64, 64, 255];
+const BARS: [[u8; 4]; 8] = [
+    [255, 255, 255, 255],
+    [0, 255, 255, 255],
+    [255, 255, 0, 255],
+    [0, 255, 0, 255],
+    [255, 0, 255, 255],
+    [0, 0, 255, 255],
+    [255, 0, 0, 255],
+    [0, 0, 0, 255],
+];
+
+const SCROLL_TEXT: &str = "THE QUICK BROWN FOX JUMPS OVER THE LAZY DOG 0123456789";
+
+// Generates test patterns so the whole pipeline can run without a display, e.g. in CI or for
+// benchmarking the encoder against a known worst case.
+pub struct LVSyntheticCapturer {
+    pool: LVBufferPool,
+    generator: LVPatternGenerator,
+}
+
+impl LVSyntheticCapturer {
+    pub fn new(pattern: LVTestPattern, width: u32, height: u32) -> Self {
+        Self {
+            pool: LVBufferPool::new(CAPTURE_BUFFERS),
+            generator: LVPatternGenerator {
+                pattern,
+                width,
+                height,
+                frame_no: 0,
+                start: Instant::now(),
+                rng_state: 0x2545_f491_4f6c_dd1d,
+            },
+        }
+    }
+}
+
+impl LVCapturer for LVSyntheticCapturer {
+    fn capture(&mut self) -> Result<LVFrame, Box<dyn std::error::Error>> {
+        let (width, height) = (self.generator.width, self.generator.height);
+        self.pool.frame(width, height, |buffer| {
+            self.generator.render(buffer);
+            Ok(())
+        })
+    }
+}
+
+struct LVPatternGenerator {
+    pattern: LVTestPattern,
+    width: u32,
+    height: u32,
+    frame_no: u64,
+    start: Instant,
+    rng_state: u64,
+}
+
+impl LVPatternGenerator {
+    fn render(&mut self, buffer: &mut [u8]) {
+        match self.pattern {
+            LVTestPattern::Bars => self.bars(buffer),
+            LVTestPattern::Noise => self.noise(buffer),
+            LVTestPattern::Scroll => self.scroll(buffer),
+            LVTestPattern::Timestamp => self.timestamp(buffer),
+        }
+        self.frame_no += 1;
+    }
+
+    fn bars(&self, buffer: &mut [u8]) {
+        let bar_width = (self.width / BARS.len() as u32).max(1);
+        let shift = (self.frame_no * 4) as u32;
+        for row in buffer.chunks_exact_mut(4 * self.width as usize) {
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let bar = ((x as u32 + shift) / bar_width) as usize % BARS.len();
+                pixel.copy_from_slice(&BARS[bar]);
+            }
+        }
+    }
+
+    // xorshift64, plenty random enough to defeat the encoder's prediction.
+    fn noise(&mut self, buffer: &mut [u8]) {
+        for pixel in buffer.chunks_exact_mut(8) {
+            self.rng_state ^= self.rng_state << 13;
+            self.rng_state ^= self.rng_state >> 7;
+            self.rng_state ^= self.rng_state << 17;
+            pixel.copy_from_slice(&(self.rng_state | 0xff000000_ff000000).to_le_bytes());
+        }
+    }
+
+    fn scroll(&self, buffer: &mut [u8]) {
+        fill(buffer, BLACK);
+        let scale = 2;
+        let line_height = (GLYPH_HEIGHT + 2) * scale;
+        let offset = (self.frame_no * 2) as u32;
+        let first_line = offset / line_height;
+        let lines = self.height / line_height + 2;
+        for i in 0..lines {
+            let line_no = first_line + i;
+            let y = (i * line_height) as i32 - (offset % line_height) as i32;
+            let text = format!("{:05} {}", line_no, SCROLL_TEXT);
+            self.draw_text(buffer, 4, y, scale, &text, WHITE);
+        }
+    }
+
+    fn timestamp(&self, buffer: &mut [u8]) {
+        fill(buffer, GREY);
+        let scale = (self.height / 60).max(1);
+        let elapsed = self.start.elapsed().as_millis();
+        self.draw_text(
+            buffer,
+            scale as i32 * 4,
+            scale as i32 * 4,
+            scale,
+            &format!("T {}", elapsed),
+            WHITE,
+        );
+        self.draw_text(
+            buffer,
+            scale as i32 * 4,
+            scale as i32 * 12,
+            scale,
+            &format!("F {}", self.frame_no),
+            WHITE,
+        );
+
+        // Something moving so it's obvious when frames freeze or stutter.
+        let size = scale * 4;
+        let x = (self.frame_no as u32 * 8) % self.width.saturating_sub(size).max(1);
+        let y = self.height.saturating_sub(size + scale * 4);
+        self.draw_rect(buffer, x as i32, y as i32, size, size, WHITE);
+    }
+
+    fn draw_text(
+        &self,
+        buffer: &mut [u8],
+        x: i32,
+        y: i32,
+        scale: u32,
+        text: &str,
+        colour: [u8; 4],
+    ) {
+        for (i, c) in text.chars().enumerate() {
+            let glyph_x = x + (i as u32 * (GLYPH_WIDTH + 1) * scale) as i32;
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+                        self.draw_rect(
+                            buffer,
+                            glyph_x + (col * scale) as i32,
+                            y + (row as u32 * scale) as i32,
+                            scale,
+                            scale,
+                            colour,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Clipped to the frame.
+    fn draw_rect(&self, buffer: &mut [u8], x: i32, y: i32, w: u32, h: u32, colour: [u8; 4]) {
+        let x0 = x.clamp(0, self.width as i32) as usize;
+        let x1 = (x + w as i32).clamp(0, self.width as i32) as usize;
+        let y0 = y.clamp(0, self.height as i32) as usize;
+        let y1 = (y + h as i32).clamp(0, self.height as i32) as usize;
+        let stride = 4 * self.width as usize;
+        for row in y0..y1 {
+            fill(
+                &mut buffer[row * stride + 4 * x0..row * stride + 4 * x1],
+                colour,
+            );
+        }
+    }
+}
+
+fn fill(buffer: &mut [u8], colour: [u8; 4]) {
+    for pixel in buffer.chunks_exact_mut(4) {
+        pixel.copy_from_slice(&colour);
+    }
+}
+
+// A tiny 3x5 font, one row per byte with the leftmost pixel in bit 2.
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; 5],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(frame: &LVFrame, x: u32, y: u32) -> [u8; 4] {
+        let start = y as usize * frame.stride() + 4 * x as usize;
+        frame.as_bytes()[start..start + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn bars_slide_sideways() {
+        let mut capturer = LVSyntheticCapturer::new(LVTestPattern::Bars, 64, 4);
+        let frame = capturer.capture().unwrap();
+        assert_eq!((frame.width(), frame.height()), (64, 4));
+        assert_eq!(frame.as_bytes().len(), 64 * 4 * 4);
+        // 8 pixels per bar, every row the same
+        for (x, bar) in [(0, 0), (7, 0), (8, 1), (63, 7)] {
+            for y in 0..4 {
+                assert_eq!(pixel(&frame, x, y), BARS[bar], "{},{}", x, y);
+            }
+        }
+
+        // 4 pixels further along every frame
+        let frame = capturer.capture().unwrap();
+        assert_eq!(pixel(&frame, 0, 0), BARS[0]);
+        assert_eq!(pixel(&frame, 4, 0), BARS[1]);
+        assert_eq!(pixel(&frame, 63, 0), BARS[0]);
+    }
+
+    #[test]
+    fn noise_changes_every_frame() {
+        let mut capturer = LVSyntheticCapturer::new(LVTestPattern::Noise, 32, 16);
+        let first = capturer.capture().unwrap();
+        let second = capturer.capture().unwrap();
+        assert_ne!(first.as_bytes(), second.as_bytes());
+        for frame in [&first, &second] {
+            assert!(frame
+                .as_bytes()
+                .chunks_exact(4)
+                .all(|pixel| pixel[3] == 255));
+        }
+    }
+
+    #[test]
+    fn text_patterns_draw_on_the_background() {
+        for (pattern, background) in [
+            (LVTestPattern::Scroll, BLACK),
+            (LVTestPattern::Timestamp, GREY),
+        ] {
+            let mut capturer = LVSyntheticCapturer::new(pattern, 120, 60);
+            let frame = capturer.capture().unwrap();
+            let pixels = frame.as_bytes().chunks_exact(4).collect::<Vec<_>>();
+            assert_eq!(pixels.len(), 120 * 60);
+            assert!(
+                pixels
+                    .iter()
+                    .all(|&pixel| pixel == background || pixel == WHITE),
+                "{:?}",
+                pattern
+            );
+            assert!(pixels.iter().any(|&pixel| pixel == WHITE), "{:?}", pattern);
+            assert_eq!(pixel(&frame, 0, 0), background, "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn drawing_is_clipped_to_the_frame() {
+        // Too small for even one glyph, drawing mustn't run off the buffer
+        for pattern in [LVTestPattern::Scroll, LVTestPattern::Timestamp] {
+            let mut capturer = LVSyntheticCapturer::new(pattern, 2, 2);
+            assert_eq!(capturer.capture().unwrap().as_bytes().len(), 16);
+        }
+    }
+}
@@ -0,0 +1,580 @@
+// Just enough H.264 parsing to follow the reference picture bookkeeping of an encoder that
+// doesn't tell us about it (openh264): which IDR a slice belongs to, its frame_num, and which
+// pictures it marks as long-term references.
+
+// The slice header is at the front of the NAL, no need to unescape the whole thing.
+const MAX_HEADER_BYTES: usize = 64;
+
+const NAL_SLICE: u8 = 1;
+const NAL_IDR_SLICE: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+
+const SLICE_TYPE_P: u32 = 0;
+const SLICE_TYPE_B: u32 = 1;
+const SLICE_TYPE_SP: u32 = 3;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct LVSpsInfo {
+    log2_max_frame_num: u32,
+    poc_type: u32,
+    log2_max_poc_lsb: u32,
+    frame_mbs_only: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct LVPpsInfo {
+    bottom_field_pic_order_in_frame_present: bool,
+    redundant_pic_cnt_present: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LVSliceInfo {
+    // Of the IDR this slice belongs to or follows
+    pub idr_pic_id: u32,
+    pub frame_num: u32,
+    // frame_num of the picture this slice marks as a long-term reference, if any. Usually the
+    // slice's own picture, but a short-term one can get converted later on.
+    pub long_term: Option<u32>,
+}
+
+// Keeps the last SPS/PPS around, slices can't be parsed without them.
+#[derive(Default)]
+pub struct LVSliceParser {
+    sps: Option<LVSpsInfo>,
+    pps: Option<LVPpsInfo>,
+    idr_pic_id: u32,
+}
+
+impl LVSliceParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Takes an Annex B NAL unit, start code included. Returns the slice info for slices and
+    // None for everything else, or if the slice uses something we don't parse.
+    pub fn parse_nal(&mut self, nal: &[u8]) -> Option<LVSliceInfo> {
+        let start = nal.iter().position(|&b| b != 0)?;
+        if nal[start] != 1 {
+            return None;
+        }
+        let header = *nal.get(start + 1)?;
+        let body = &nal[start + 2..];
+        let nal_ref_idc = (header >> 5) & 3;
+        let nal_type = header & 0x1f;
+
+        let rbsp = unescape(&body[..body.len().min(MAX_HEADER_BYTES)]);
+        let mut bits = BitReader::new(&rbsp);
+        match nal_type {
+            NAL_SPS => {
+                self.sps = parse_sps(&mut bits);
+                None
+            }
+            NAL_PPS => {
+                self.pps = parse_pps(&mut bits);
+                None
+            }
+            NAL_SLICE | NAL_IDR_SLICE => {
+                self.parse_slice(&mut bits, nal_type == NAL_IDR_SLICE, nal_ref_idc != 0)
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_slice(
+        &mut self,
+        bits: &mut BitReader,
+        idr: bool,
+        reference: bool,
+    ) -> Option<LVSliceInfo> {
+        let sps = self.sps?;
+        let pps = self.pps?;
+
+        // first_mb_in_slice
+        bits.ue()?;
+        let slice_type = bits.ue()? % 5;
+        // pic_parameter_set_id
+        bits.ue()?;
+        let frame_num = bits.bits(sps.log2_max_frame_num)?;
+        let mut field_pic = false;
+        if !sps.frame_mbs_only {
+            field_pic = bits.flag()?;
+            if field_pic {
+                // bottom_field_flag
+                bits.flag()?;
+            }
+        }
+        if idr {
+            self.idr_pic_id = bits.ue()?;
+        }
+        if sps.poc_type == 0 {
+            // pic_order_cnt_lsb
+            bits.bits(sps.log2_max_poc_lsb)?;
+            if pps.bottom_field_pic_order_in_frame_present && !field_pic {
+                // delta_pic_order_cnt_bottom
+                bits.ue()?;
+            }
+        }
+        if pps.redundant_pic_cnt_present {
+            bits.ue()?;
+        }
+
+        let predicted = matches!(slice_type, SLICE_TYPE_P | SLICE_TYPE_SP | SLICE_TYPE_B);
+        if slice_type == SLICE_TYPE_B {
+            // direct_spatial_mv_pred_flag
+            bits.flag()?;
+        }
+        if predicted {
+            // num_ref_idx_active_override_flag
+            if bits.flag()? {
+                bits.ue()?;
+                if slice_type == SLICE_TYPE_B {
+                    bits.ue()?;
+                }
+            }
+            // ref_pic_list_modification
+            let lists = if slice_type == SLICE_TYPE_B { 2 } else { 1 };
+            for _ in 0..lists {
+                if bits.flag()? {
+                    loop {
+                        match bits.ue()? {
+                            3 => break,
+                            0..=2 => {
+                                bits.ue()?;
+                            }
+                            _ => return None,
+                        }
+                    }
+                }
+            }
+        }
+        // Weighted prediction would go here, parse_pps turns those streams away.
+
+        let mut long_term = None;
+        let max_frame_num = 1 << sps.log2_max_frame_num;
+        if reference {
+            if idr {
+                // no_output_of_prior_pics_flag
+                bits.flag()?;
+                if bits.flag()? {
+                    long_term = Some(frame_num);
+                }
+            } else if bits.flag()? {
+                // adaptive_ref_pic_marking_mode_flag
+                loop {
+                    match bits.ue()? {
+                        0 => break,
+                        // short-term unused / long-term unused
+                        1 | 2 => {
+                            bits.ue()?;
+                        }
+                        // short-term to long-term
+                        3 => {
+                            let difference_of_pic_nums = bits.ue()? + 1;
+                            bits.ue()?;
+                            long_term = Some(
+                                (frame_num + max_frame_num
+                                    - difference_of_pic_nums % max_frame_num)
+                                    % max_frame_num,
+                            );
+                        }
+                        4 => {
+                            bits.ue()?;
+                        }
+                        5 => {}
+                        // current picture to long-term
+                        6 => {
+                            bits.ue()?;
+                            long_term = Some(frame_num);
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+        }
+
+        Some(LVSliceInfo {
+            idr_pic_id: self.idr_pic_id,
+            frame_num,
+            long_term,
+        })
+    }
+}
+
+fn parse_sps(bits: &mut BitReader) -> Option<LVSpsInfo> {
+    let profile_idc = bits.bits(8)?;
+    // constraint flags, level_idc
+    bits.bits(16)?;
+    // seq_parameter_set_id
+    bits.ue()?;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        if bits.ue()? == 3 {
+            // separate_colour_plane_flag
+            bits.flag()?;
+        }
+        // bit depths
+        bits.ue()?;
+        bits.ue()?;
+        // qpprime_y_zero_transform_bypass_flag
+        bits.flag()?;
+        // Scaling lists, not worth parsing
+        if bits.flag()? {
+            return None;
+        }
+    }
+    let log2_max_frame_num = bits.ue()? + 4;
+    let poc_type = bits.ue()?;
+    let mut log2_max_poc_lsb = 0;
+    match poc_type {
+        0 => log2_max_poc_lsb = bits.ue()? + 4,
+        2 => {}
+        _ => return None,
+    }
+    // max_num_ref_frames, gaps_in_frame_num_value_allowed_flag
+    bits.ue()?;
+    bits.flag()?;
+    // pic_width_in_mbs_minus1, pic_height_in_map_units_minus1
+    bits.ue()?;
+    bits.ue()?;
+    let frame_mbs_only = bits.flag()?;
+
+    Some(LVSpsInfo {
+        log2_max_frame_num,
+        poc_type,
+        log2_max_poc_lsb,
+        frame_mbs_only,
+    })
+}
+
+fn parse_pps(bits: &mut BitReader) -> Option<LVPpsInfo> {
+    // pic_parameter_set_id, seq_parameter_set_id, entropy_coding_mode_flag
+    bits.ue()?;
+    bits.ue()?;
+    bits.flag()?;
+    let bottom_field_pic_order_in_frame_present = bits.flag()?;
+    // No slice groups
+    if bits.ue()? != 0 {
+        return None;
+    }
+    // num_ref_idx_l0/l1_default_active_minus1
+    bits.ue()?;
+    bits.ue()?;
+    // No weighted prediction either
+    let weighted_pred = bits.flag()?;
+    let weighted_bipred_idc = bits.bits(2)?;
+    if weighted_pred || weighted_bipred_idc != 0 {
+        return None;
+    }
+    // pic_init_qp_minus26, pic_init_qs_minus26, chroma_qp_index_offset
+    bits.ue()?;
+    bits.ue()?;
+    bits.ue()?;
+    // deblocking_filter_control_present_flag, constrained_intra_pred_flag
+    bits.flag()?;
+    bits.flag()?;
+    let redundant_pic_cnt_present = bits.flag()?;
+
+    Some(LVPpsInfo {
+        bottom_field_pic_order_in_frame_present,
+        redundant_pic_cnt_present,
+    })
+}
+
+// Drops the emulation prevention bytes (00 00 03 -> 00 00).
+pub fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros == 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        rbsp.push(byte);
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+    }
+    rbsp
+}
+
+// Reads MSB first. Everything returns None once it runs off the end.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn bits(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = *self.data.get(self.pos / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.pos % 8)) & 1) as u32;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    pub fn flag(&mut self) -> Option<bool> {
+        Some(self.bits(1)? == 1)
+    }
+
+    // Exp-Golomb. Signed values are read with this too since only their length matters here.
+    pub fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while !self.flag()? {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << zeros) - 1 + self.bits(zeros)?)
+    }
+}
+
+// Writes MSB first, for the parameter sets we have to make up ourselves (VA-API).
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    current: u8,
+    used: u8,
+}
+
+impl BitWriter {
+    pub fn put(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.current = (self.current << 1) | ((value >> i) & 1) as u8;
+            self.used += 1;
+            if self.used == 8 {
+                self.bytes.push(self.current);
+                self.current = 0;
+                self.used = 0;
+            }
+        }
+    }
+
+    // Exp-Golomb
+    pub fn put_ue(&mut self, value: u32) {
+        let value = value + 1;
+        let count = 32 - value.leading_zeros();
+        self.put(0, count - 1);
+        self.put(value, count);
+    }
+
+    pub fn put_se(&mut self, value: i32) {
+        if value > 0 {
+            self.put_ue(2 * value as u32 - 1);
+        } else {
+            self.put_ue(2 * (-value) as u32);
+        }
+    }
+
+    // Adds the stop bit and wraps the RBSP into an Annex B NAL unit, escaping anything that
+    // would look like a start code.
+    pub fn nal(mut self, header: u8) -> Vec<u8> {
+        self.put(1, 1);
+        while self.used != 0 {
+            self.put(0, 1);
+        }
+
+        let mut nal = vec![0, 0, 0, 1, header];
+        let mut zeros = 0;
+        for byte in self.bytes {
+            if zeros == 2 && byte <= 3 {
+                nal.push(3);
+                zeros = 0;
+            }
+            nal.push(byte);
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+        }
+        nal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Constrained baseline with an 8 bit POC LSB, like openh264 writes it
+    fn sps(frame_num_bits: u32) -> Vec<u8> {
+        let mut bits = BitWriter::default();
+        bits.put(66, 8);
+        bits.put(0xc0, 8);
+        bits.put(30, 8);
+        bits.put_ue(0);
+        bits.put_ue(frame_num_bits - 4);
+        bits.put_ue(0);
+        bits.put_ue(4);
+        bits.put_ue(1);
+        bits.put(0, 1);
+        bits.put_ue(19);
+        bits.put_ue(14);
+        bits.put(0b11, 2);
+        bits.put(0, 2);
+        bits.nal(0x67)
+    }
+
+    fn idr(idr_pic_id: u32, long_term: bool) -> Vec<u8> {
+        let mut bits = BitWriter::default();
+        bits.put_ue(0);
+        bits.put_ue(7);
+        bits.put_ue(0);
+        bits.put(0, 8);
+        bits.put_ue(idr_pic_id);
+        bits.put(0, 8);
+        bits.put(0, 1);
+        bits.put(long_term as u32, 1);
+        // slice_qp_delta, onwards
+        bits.put_ue(0);
+        bits.nal(0x65)
+    }
+
+    fn p_slice(frame_num: u32, marking: &[(u32, u32)]) -> Vec<u8> {
+        p_slice_with(8, frame_num, marking)
+    }
+
+    fn p_slice_with(frame_num_bits: u32, frame_num: u32, marking: &[(u32, u32)]) -> Vec<u8> {
+        let mut bits = BitWriter::default();
+        bits.put_ue(0);
+        bits.put_ue(5);
+        bits.put_ue(0);
+        bits.put(frame_num, frame_num_bits);
+        bits.put(2 * frame_num, 8);
+        // No ref idx override, no list modification
+        bits.put(0, 1);
+        bits.put(0, 1);
+        bits.put(!marking.is_empty() as u32, 1);
+        if !marking.is_empty() {
+            for &(operation, argument) in marking {
+                bits.put_ue(operation);
+                bits.put_ue(argument);
+                if operation == 3 {
+                    // long_term_frame_idx
+                    bits.put_ue(0);
+                }
+            }
+            bits.put_ue(0);
+        }
+        bits.put_ue(0);
+        bits.nal(0x41)
+    }
+
+    fn parser(frame_num_bits: u32) -> LVSliceParser {
+        let mut parser = LVSliceParser::new();
+        assert_eq!(parser.parse_nal(&sps(frame_num_bits)), None);
+        // The PPS x264 and openh264 write for baseline
+        assert_eq!(
+            parser.parse_nal(&[0, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80]),
+            None
+        );
+        parser
+    }
+
+    #[test]
+    fn reads_exp_golomb() {
+        // 1, 010, 011, 00100, 00101 and 0001000 then the end
+        let data = [0b1010_0110, 0b0100_0010, 0b1000_1000];
+        let mut bits = BitReader::new(&data);
+        let values: Vec<_> = (0..6).map(|_| bits.ue().unwrap()).collect();
+        assert_eq!(values, vec![0, 1, 2, 3, 4, 7]);
+        assert_eq!(bits.ue(), None);
+        assert_eq!(BitReader::new(&[0, 0, 0, 0, 0]).ue(), None);
+    }
+
+    #[test]
+    fn unescapes_emulation_prevention() {
+        assert_eq!(
+            unescape(&[0, 0, 3, 1, 0, 0, 3, 0, 3, 0, 0, 3]),
+            vec![0, 0, 1, 0, 0, 0, 3, 0, 0]
+        );
+    }
+
+    #[test]
+    fn slices_need_parameter_sets() {
+        let mut parser = LVSliceParser::new();
+        assert_eq!(parser.parse_nal(&idr(0, false)), None);
+        assert_eq!(parser.parse_nal(&sps(8)), None);
+        assert_eq!(parser.parse_nal(&idr(0, false)), None);
+    }
+
+    #[test]
+    fn parses_idr_and_long_term_flag() {
+        let mut parser = parser(8);
+        assert_eq!(
+            parser.parse_nal(&idr(3, true)),
+            Some(LVSliceInfo {
+                idr_pic_id: 3,
+                frame_num: 0,
+                long_term: Some(0),
+            })
+        );
+        // P slices belong to the last IDR
+        assert_eq!(
+            parser.parse_nal(&p_slice(1, &[])),
+            Some(LVSliceInfo {
+                idr_pic_id: 3,
+                frame_num: 1,
+                long_term: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_long_term_marking() {
+        let mut parser = parser(8);
+        parser.parse_nal(&idr(0, false)).unwrap();
+
+        // Current picture to long-term
+        let info = parser.parse_nal(&p_slice(5, &[(1, 0), (6, 0)])).unwrap();
+        assert_eq!(info.long_term, Some(5));
+        // Short-term picture to long-term, difference_of_pic_nums_minus1 = 2 is frame 7
+        let info = parser.parse_nal(&p_slice(10, &[(3, 2)])).unwrap();
+        assert_eq!(info.long_term, Some(7));
+        // Wraps around max_frame_num
+        let info = parser.parse_nal(&p_slice(1, &[(3, 2)])).unwrap();
+        assert_eq!(info.long_term, Some(254));
+    }
+
+    #[test]
+    fn parses_escaped_headers() {
+        // With a 16 bit frame_num, frame 0 and POC LSB 0 make more than two zero bytes in a
+        // row, which the writer has to escape
+        let mut parser = parser(16);
+        let nal = p_slice_with(16, 0, &[]);
+        assert!(nal.windows(3).any(|w| w == [0, 0, 3]), "{:02x?}", nal);
+        assert_eq!(parser.parse_nal(&nal).map(|info| info.frame_num), Some(0));
+        let nal = p_slice_with(16, 0x1234, &[(6, 0)]);
+        assert_eq!(
+            parser.parse_nal(&nal).map(|info| info.long_term),
+            Some(Some(0x1234))
+        );
+    }
+
+    #[test]
+    fn ignores_other_nals_and_unsupported_streams() {
+        let mut parser = parser(8);
+        // SEI, and something without a start code
+        assert_eq!(parser.parse_nal(&[0, 0, 0, 1, 0x06, 0x05, 0x80]), None);
+        assert_eq!(parser.parse_nal(&[0x65, 0x88, 0x84]), None);
+        // A PPS with weighted prediction isn't parsed, slices after it aren't either
+        let mut weighted = BitWriter::default();
+        weighted.put_ue(0);
+        weighted.put_ue(0);
+        weighted.put(0, 2);
+        weighted.put_ue(0);
+        weighted.put_ue(0);
+        weighted.put_ue(0);
+        weighted.put(1, 1);
+        weighted.put(0, 2);
+        weighted.put_ue(0);
+        weighted.put_ue(0);
+        weighted.put_ue(0);
+        weighted.put(0b100, 3);
+        parser.parse_nal(&weighted.nal(0x68));
+        assert_eq!(parser.parse_nal(&p_slice(1, &[])), None);
+    }
+}
@@ -1,47 +1,121 @@
 use std::os::raw::c_int;
 
 use bytes::{buf::Writer, BytesMut};
-use image::{ImageBuffer, Rgb};
+use net::codec::{LVCodec, LVEncoderBackend};
 use openh264::formats::YUVBuffer;
 
-use self::openh264_enc::LVOpenH264Encoder;
+use crate::capture::LVFrame;
+use roi::LVRoiMap;
+
+pub mod h264;
 
 #[cfg(feature = "nvidia-hwenc")]
 pub mod nvidia;
 
 pub mod openh264_enc;
 
+#[cfg(feature = "av1")]
+pub mod rav1e_enc;
+
+pub mod registry;
+
+pub mod roi;
+
+#[cfg(feature = "vaapi-hwenc")]
+pub mod vaapi;
+
+// LV_CODEC=av1 streams AV1 instead of H.264.
+pub fn default_codec() -> Result<LVCodec, Box<dyn std::error::Error>> {
+    match std::env::var("LV_CODEC") {
+        Ok(codec) => Ok(codec.parse()?),
+        Err(_) => Ok(LVCodec::default()),
+    }
+}
+
+// LV_INTRA_REFRESH=N spreads keyframes over N frames instead of sending IDRs, on backends that
+// can. Smoother on the network, but a lost frame takes N frames to fully heal. 0 sends IDRs,
+// unset leaves it to the backend.
+pub fn default_intra_refresh() -> Result<Option<u32>, Box<dyn std::error::Error>> {
+    match std::env::var("LV_INTRA_REFRESH") {
+        Ok(frames) => Ok(Some(frames.parse()?)),
+        Err(_) => Ok(None),
+    }
+}
+
+// Picks the backend from the registry, see registry.rs for the order they're tried in.
 pub fn default_encoder(
-    width: u32,
-    height: u32,
-    bitrate: u32,
-    fps: f32,
+    config: LVEncoderConfig,
 ) -> Result<Box<dyn LVEncoder>, Box<dyn std::error::Error>> {
-    let enc = LVOpenH264Encoder::new(width, height, bitrate, fps)?;
+    registry::create(config)
+}
 
-    #[cfg(feature = "nvidia-hwenc")]
-    let enc = nvidia::LVNvidiaEncoder::new(width, height, bitrate, fps)?;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LVEncoderComplexity {
+    Low,
+    Medium,
+    High,
+}
 
-    Ok(Box::new(enc))
+// Everything an encoder gets tuned with. Backends map what they can and ignore the rest, e.g.
+// the hardware encoders have no use for a thread count.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LVEncoderConfig {
+    pub codec: LVCodec,
+    pub width: u32,
+    pub height: u32,
+    // bits per second
+    pub bitrate: u32,
+    pub framerate: f32,
+    // Lower QP is better quality. The rate controller stays within this range, so raising
+    // min_qp stops it from wasting bits on static content and lowering max_qp keeps text
+    // readable at the cost of overshooting the bitrate. These are H.264 QPs (0-51), backends
+    // for other codecs scale them to their own range.
+    pub min_qp: u8,
+    pub max_qp: u8,
+    // Frames between IDRs, or between refresh waves when intra_refresh is on
+    pub gop: u32,
+    // Frames a refresh wave is spread over. 0 sends whole IDR frames instead, None leaves it
+    // to the backend (NVENC refreshes, the others send IDRs).
+    pub intra_refresh: Option<u32>,
+    pub complexity: LVEncoderComplexity,
+    // 0 lets the encoder decide
+    pub threads: u16,
+}
+
+impl LVEncoderConfig {
+    pub fn new(width: u32, height: u32, bitrate: u32, framerate: f32) -> Self {
+        Self {
+            codec: LVCodec::H264,
+            width,
+            height,
+            bitrate,
+            framerate,
+            min_qp: 21,
+            max_qp: 35,
+            gop: 120,
+            intra_refresh: None,
+            complexity: LVEncoderComplexity::Low,
+            threads: 8,
+        }
+    }
 }
 
 pub trait LVEncoder {
-    fn new(
-        width: u32,
-        height: u32,
-        bitrate: u32,
-        framerate: f32,
-    ) -> Result<Self, Box<dyn std::error::Error>>
+    fn new(config: LVEncoderConfig) -> Result<Self, Box<dyn std::error::Error>>
     where
         Self: Sized;
 
+    fn codec(&self) -> LVCodec;
+    // Which implementation this is, reported to the client and to statistics.
+    fn backend(&self) -> LVEncoderBackend;
     fn width(&self) -> u32;
     fn height(&self) -> u32;
 
-    // Convert the RGBA/whatever frame to something that the codec will understand
+    // Convert the BGRA frame to something that the codec will understand. The frame is
+    // borrowed so the conversion can read directly from the capturer's buffer.
     fn convert_frame(
         &mut self,
-        input_buffer: ImageBuffer<Rgb<u8>, Vec<u8>>,
+        input_buffer: &LVFrame,
         output_buffer: &mut YUVBuffer,
     ) -> Result<(), Box<dyn std::error::Error>>;
 
@@ -56,4 +130,61 @@ pub trait LVEncoder {
 
     fn bitrate(&self) -> u32;
     fn set_bitrate(&mut self, new_bitrate: u32) -> Result<(), Box<dyn std::error::Error>>;
+
+    // The config as it is now, including anything changed since construction.
+    fn config(&self) -> LVEncoderConfig;
+
+    fn set_framerate(&mut self, framerate: f32) -> Result<(), Box<dyn std::error::Error>>;
+    fn set_qp_range(&mut self, min_qp: u8, max_qp: u8) -> Result<(), Box<dyn std::error::Error>>;
+    fn set_gop(&mut self, gop: u32) -> Result<(), Box<dyn std::error::Error>>;
+
+    // Gradual decoder refresh: instead of an IDR every gop frames, a column of intra
+    // macroblocks sweeps across the picture over `frames` frames. IDRs are 5-10x the size of a
+    // P-frame and go out in one burst, this spreads the cost out. 0 goes back to IDRs.
+    fn supports_intra_refresh(&self) -> bool {
+        false
+    }
+    fn set_intra_refresh(&mut self, frames: u32) -> Result<(), Box<dyn std::error::Error>> {
+        if frames == 0 {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!("{} can't do intra refresh", self.backend()).into())
+    }
+
+    // The client lost part of a frame and can't conceal it. The next frame starts a refresh
+    // wave if intra refresh is on, otherwise it's an IDR.
+    fn request_recovery(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+
+    // Long-term references. Frames are numbered from 0 in the order this encoder produced
+    // them, frames that came out empty don't count.
+    //
+    // The client has decoded `frame` and everything before it, so references up to there are
+    // safe to predict from.
+    fn confirm_frame(&mut self, _frame: u32) {}
+    // Everything after `last_good` is lost on the client. Returns true if the next frame will
+    // be predicted from a reference the client still has, false if the encoder can't do that
+    // and the caller should fall back to request_recovery.
+    fn invalidate_frames(&mut self, _last_good: u32) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(false)
+    }
+
+    // Region of interest: a QP offset per macroblock for the frames encoded from now on, see
+    // roi.rs. None goes back to a uniform QP. Backends that can't vary the QP within a frame
+    // ignore it.
+    fn supports_roi(&self) -> bool {
+        false
+    }
+    fn set_roi(&mut self, _roi: Option<&LVRoiMap>) {}
+
+    // A real keyframe, even with intra refresh on, for something that has to start decoding
+    // from scratch (the recorder starting a file).
+    fn request_keyframe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.request_recovery()
+    }
+
+    // The decoder configuration record containers want, for codecs where it can't easily be
+    // built from the stream (av1C). H.264's avcC comes straight out of the SPS and PPS.
+    fn codec_config(&self) -> Option<Vec<u8>> {
+        None
+    }
 }
@@ -5,14 +5,14 @@ use std::time::Instant;
 
 use cudarc::driver::CudaDevice;
 use dcv_color_primitives::{convert_image, get_buffers_size, ImageFormat};
-use image::{ImageBuffer, Rgb};
 use log::{debug, error, info, trace};
+use net::codec::{LVCodec, LVEncoderBackend};
 use nvidia_video_codec_sdk::sys::nvEncodeAPI::{
     NV_ENC_BUFFER_FORMAT::*, NV_ENC_H264_PROFILE_BASELINE_GUID, NV_ENC_PIC_FLAGS,
-    NV_ENC_PRESET_LOW_LATENCY_HP_GUID,
+    NV_ENC_PIC_PARAMS_H264, NV_ENC_PRESET_LOW_LATENCY_HP_GUID, NVENC_INFINITE_GOPLENGTH,
 };
 use nvidia_video_codec_sdk::sys::nvEncodeAPI::{
-    NV_ENC_CODEC_H264_GUID, NV_ENC_INITIALIZE_PARAMS, NV_ENC_PRESET_P1_GUID, NV_ENC_PRESET_P2_GUID,
+    NV_ENC_CODEC_H264_GUID, NV_ENC_CONFIG, NV_ENC_INITIALIZE_PARAMS, NV_ENC_QP, NV_ENC_PRESET_P1_GUID, NV_ENC_PRESET_P2_GUID,
     NV_ENC_RECONFIGURE_PARAMS_VER, _NV_ENC_PARAMS_RC_MODE::NV_ENC_PARAMS_RC_CBR,
     _NV_ENC_RECONFIGURE_PARAMS,
 };
@@ -24,7 +24,15 @@ use openh264::formats::YUVBuffer;
 use statistics::collector::LVStatisticsCollector;
 use statistics::statistics::{LVDataPoint, LVDataType};
 
-use super::LVEncoder;
+use super::{
+    roi::{LVRoiMap, MB_SIZE},
+    LVEncoder, LVEncoderConfig,
+};
+use crate::capture::LVFrame;
+
+// What NVENC refreshes with when LV_INTRA_REFRESH isn't set: a 30 frame wave every 300 frames.
+const DEFAULT_REFRESH_FRAMES: u32 = 30;
+const DEFAULT_REFRESH_PERIOD: u32 = 300;
 
 pub struct LVNvidiaEncoder {
     enc_session: Session,
@@ -33,9 +41,18 @@ pub struct LVNvidiaEncoder {
     width: u32,
     height: u32,
     frame_no: u64,
+    // The client asked for a recovery point, see request_recovery
+    pending_recovery: bool,
+    // An IDR even if intra refresh is on
+    pending_idr: bool,
+    // QP offset per macroblock, empty when there's no region of interest
+    qp_delta_map: Vec<i8>,
 
     // parameters
+    config: LVEncoderConfig,
     enc_params: NV_ENC_INITIALIZE_PARAMS,
+    // enc_params points into this, so it lives on the heap where moving Self doesn't move it.
+    encode_config: Box<NV_ENC_CONFIG>,
 
     // image conversion stuff
     src_fmt: ImageFormat,
@@ -45,15 +62,20 @@ pub struct LVNvidiaEncoder {
 }
 
 impl LVEncoder for LVNvidiaEncoder {
-    fn new(
-        width: u32,
-        height: u32,
-        bitrate: u32,
-        framerate: f32,
-    ) -> Result<Self, Box<dyn std::error::Error>>
+    fn new(config: LVEncoderConfig) -> Result<Self, Box<dyn std::error::Error>>
     where
         Self: Sized,
     {
+        // Intra refresh has always been on for NVENC, keep it that way unless asked otherwise.
+        let config = match config.intra_refresh {
+            None => LVEncoderConfig {
+                intra_refresh: Some(DEFAULT_REFRESH_FRAMES),
+                gop: DEFAULT_REFRESH_PERIOD,
+                ..config
+            },
+            Some(_) => config,
+        };
+        let (width, height) = (config.width, config.height);
         let dev = CudaDevice::new(0)?;
         let enc = Encoder::initialize_with_cuda(dev)?;
 
@@ -103,35 +125,30 @@ impl LVEncoder for LVNvidiaEncoder {
                 .maxNumRefFrames = 1;
             preset_cfg.presetCfg.encodeCodecConfig.h264Config.sliceMode = 0;
             preset_cfg.presetCfg.rcParams.rateControlMode = NV_ENC_PARAMS_RC_CBR;
-            preset_cfg.presetCfg.rcParams.averageBitRate = bitrate;
+            preset_cfg.presetCfg.rcParams.averageBitRate = config.bitrate;
+            preset_cfg.presetCfg.rcParams.set_enableMinQP(1);
+            preset_cfg.presetCfg.rcParams.set_enableMaxQP(1);
+            preset_cfg.presetCfg.rcParams.minQP = qp(config.min_qp);
+            preset_cfg.presetCfg.rcParams.maxQP = qp(config.max_qp);
+            // Frames without a map get no offsets, so this can be on from the start instead of
+            // reconfiguring (and sending an IDR) once there is one.
+            #[cfg(feature = "nvidia-roi")]
+            {
+                preset_cfg.presetCfg.rcParams.qpMapMode =
+                    nvidia_video_codec_sdk::sys::nvEncodeAPI::_NV_ENC_QP_MAP_MODE::NV_ENC_QP_MAP_DELTA;
+            }
             preset_cfg
                 .presetCfg
                 .encodeCodecConfig
                 .h264Config
                 .sliceModeData = 0;
-            preset_cfg.presetCfg.encodeCodecConfig.h264Config.idrPeriod = 300;
-            preset_cfg.presetCfg.gopLength = 300;
 
             preset_cfg
                 .presetCfg
                 .encodeCodecConfig
                 .h264Config
                 .set_repeatSPSPPS(1);
-            preset_cfg
-                .presetCfg
-                .encodeCodecConfig
-                .h264Config
-                .set_enableIntraRefresh(1);
-            preset_cfg
-                .presetCfg
-                .encodeCodecConfig
-                .h264Config
-                .intraRefreshPeriod = 300;
-            preset_cfg
-                .presetCfg
-                .encodeCodecConfig
-                .h264Config
-                .intraRefreshCnt = 30;
+            set_refresh_params(&mut preset_cfg.presetCfg, &config);
 
             // Setting frameInter   valP messes with things, namely it makes the encoder never output P frames, or anythign past
             // the first SPS/PPS
@@ -143,9 +160,11 @@ impl LVEncoder for LVNvidiaEncoder {
 
         // info!("preset cfg is {:?}", preset_cfg.presetCfg.encodeCodecConfig.);
 
-        enc_params.framerate(framerate as u32, 1);
+        let mut encode_config = Box::new(preset_cfg.presetCfg);
+
+        enc_params.framerate(config.framerate as u32, 1);
         enc_params.enable_picture_type_decision();
-        enc_params.encode_config(&mut preset_cfg.presetCfg);
+        enc_params.encode_config(&mut encode_config);
 
         //
         let enc_session = enc.start_session(NV_ENC_BUFFER_FORMAT_NV12, &mut enc_params)?;
@@ -163,8 +182,13 @@ impl LVEncoder for LVNvidiaEncoder {
             width,
             height,
             enc_session,
+            config,
             enc_params,
+            encode_config,
             frame_no: 0,
+            pending_recovery: false,
+            pending_idr: false,
+            qp_delta_map: Vec::new(),
             src_fmt,
             dst_fmt,
             src_strides,
@@ -174,6 +198,14 @@ impl LVEncoder for LVNvidiaEncoder {
         })
     }
 
+    fn codec(&self) -> LVCodec {
+        LVCodec::H264
+    }
+
+    fn backend(&self) -> LVEncoderBackend {
+        LVEncoderBackend::Nvenc
+    }
+
     fn width(&self) -> u32 {
         self.width
     }
@@ -199,23 +231,36 @@ impl LVEncoder for LVNvidiaEncoder {
         debug!("Beginning frame encode");
         debug!("timestamp is {}", timestamp);
 
+        // Recovering with intra refresh starts a wave right away instead of waiting for the
+        // next one, without it the frame has to be an IDR.
+        let recover = std::mem::take(&mut self.pending_recovery);
+        let idr = std::mem::take(&mut self.pending_idr);
+        let (flags, codec_params) = if recover && !idr && refreshing(&self.config) {
+            let mut h264_params: NV_ENC_PIC_PARAMS_H264 = unsafe { std::mem::zeroed() };
+            h264_params.forceIntraRefreshWithFrameCnt = refresh_count(&self.config);
+            (0u8, Some(CodecPictureParams::H264(h264_params)))
+        } else if recover || idr {
+            (
+                (NV_ENC_PIC_FLAGS::NV_ENC_PIC_FLAG_FORCEIDR as u8)
+                    | (NV_ENC_PIC_FLAGS::NV_ENC_PIC_FLAG_OUTPUT_SPSPPS as u8),
+                None,
+            )
+        } else {
+            (0u8, None)
+        };
+
         let pre_enc = Instant::now();
 
         match self.enc_session.encode_picture(
             &mut self.input_buffer,
             &mut self.output_bitstream,
-            if self.frame_no % 120 == 0 {
-                0
-                /*debug!("sending spspps");
-                ((NV_ENC_PIC_FLAGS::NV_ENC_PIC_FLAG_FORCEIDR as u8)
-                    | (NV_ENC_PIC_FLAGS::NV_ENC_PIC_FLAG_OUTPUT_SPSPPS as u8)
-                    | (NV_ENC_PIC_FLAGS::NV_ENC_PIC_FLAG_FORCEINTRA as u8))
-                    .into()*/
-            } else {
-                0
-            },
+            flags.into(),
             EncodePictureParams {
                 input_timestamp: timestamp,
+                codec_params,
+                #[cfg(feature = "nvidia-roi")]
+                qp_delta_map: (!self.qp_delta_map.is_empty())
+                    .then_some(self.qp_delta_map.as_mut_slice()),
                 ..Default::default()
             },
         ) {
@@ -254,7 +299,7 @@ impl LVEncoder for LVNvidiaEncoder {
 
     fn convert_frame(
         &mut self,
-        input_buffer: ImageBuffer<Rgb<u8>, Vec<u8>>,
+        input_buffer: &LVFrame,
         output_buffer: &mut YUVBuffer,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let (mut y_slice, uv_slice) = output_buffer.yuv.split_at_mut(self.out_sizes[0]);
@@ -265,7 +310,7 @@ impl LVEncoder for LVNvidiaEncoder {
             input_buffer.height(),
             &self.src_fmt,
             Some(&self.src_strides),
-            &[&input_buffer.into_raw()],
+            &[input_buffer.as_bytes()],
             &self.dst_fmt,
             None,
             &mut [&mut y_slice, &mut u_slice, &mut v_slice],
@@ -274,35 +319,182 @@ impl LVEncoder for LVNvidiaEncoder {
         Ok(())
     }
     fn bitrate(&self) -> u32 {
-        unsafe { *self.enc_params.encodeConfig }
-            .rcParams
-            .averageBitRate
+        self.encode_config.rcParams.averageBitRate
     }
     fn set_bitrate(&mut self, new_bitrate: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.reconfigure(
+            "bitrate",
+            LVEncoderConfig {
+                bitrate: new_bitrate,
+                ..self.config
+            },
+            false,
+        )
+    }
+
+    fn config(&self) -> LVEncoderConfig {
+        self.config
+    }
+
+    fn set_framerate(&mut self, framerate: f32) -> Result<(), Box<dyn std::error::Error>> {
+        self.reconfigure(
+            "framerate",
+            LVEncoderConfig {
+                framerate,
+                ..self.config
+            },
+            false,
+        )
+    }
+
+    fn set_qp_range(&mut self, min_qp: u8, max_qp: u8) -> Result<(), Box<dyn std::error::Error>> {
+        self.reconfigure(
+            "qp range",
+            LVEncoderConfig {
+                min_qp,
+                max_qp,
+                ..self.config
+            },
+            false,
+        )
+    }
+
+    fn set_gop(&mut self, gop: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.reconfigure("gop", LVEncoderConfig { gop, ..self.config }, true)
+    }
+
+    fn supports_intra_refresh(&self) -> bool {
+        true
+    }
+
+    fn set_intra_refresh(&mut self, frames: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.reconfigure(
+            "intra refresh",
+            LVEncoderConfig {
+                intra_refresh: Some(frames),
+                ..self.config
+            },
+            true,
+        )
+    }
+
+    fn request_recovery(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.pending_recovery = true;
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.pending_idr = true;
+        Ok(())
+    }
+
+    // The map goes in through EncodePictureParams::qp_delta_map, which only our SDK fork has.
+    fn supports_roi(&self) -> bool {
+        cfg!(feature = "nvidia-roi")
+    }
+
+    fn set_roi(&mut self, roi: Option<&LVRoiMap>) {
+        self.qp_delta_map.clear();
+        if let Some(roi) = roi {
+            // NVENC wants one entry per macroblock of the encoded size
+            let mbs = self.width.div_ceil(MB_SIZE) * self.height.div_ceil(MB_SIZE);
+            if roi.deltas.len() == mbs as usize {
+                self.qp_delta_map.extend_from_slice(&roi.deltas);
+            }
+        }
+    }
+}
+
+fn refreshing(config: &LVEncoderConfig) -> bool {
+    config.intra_refresh.is_some_and(|frames| frames > 0)
+}
+
+// NVENC wants the refresh wave to be shorter than the period between waves.
+fn refresh_count(config: &LVEncoderConfig) -> u32 {
+    config
+        .intra_refresh
+        .unwrap_or(0)
+        .min(config.gop.saturating_sub(1))
+        .max(1)
+}
+
+// With intra refresh on, the periodic IDRs are replaced with a refresh wave every gop frames.
+// After the first frame the only IDRs are the ones asked for (recovery without refresh, the
+// recorder) and the one a GOP change forces.
+unsafe fn set_refresh_params(encode_config: &mut NV_ENC_CONFIG, config: &LVEncoderConfig) {
+    let h264_config = &mut encode_config.encodeCodecConfig.h264Config;
+    if refreshing(config) {
+        h264_config.set_enableIntraRefresh(1);
+        h264_config.intraRefreshPeriod = config.gop;
+        h264_config.intraRefreshCnt = refresh_count(config);
+        h264_config.idrPeriod = NVENC_INFINITE_GOPLENGTH;
+        encode_config.gopLength = NVENC_INFINITE_GOPLENGTH;
+    } else {
+        h264_config.set_enableIntraRefresh(0);
+        h264_config.idrPeriod = config.gop;
+        encode_config.gopLength = config.gop;
+    }
+}
+
+// Same QP for every frame type.
+fn qp(qp: u8) -> NV_ENC_QP {
+    NV_ENC_QP {
+        qpInterP: qp as u32,
+        qpInterB: qp as u32,
+        qpIntra: qp as u32,
+    }
+}
+
+impl LVNvidiaEncoder {
+    // Push a changed config to the running session. Nothing is kept if NVENC refuses it, so
+    // config() keeps describing what the encoder is actually doing.
+    //
+    // Rate, framerate and QP changes apply from the next frame on, which is what the rate
+    // controller needs every feedback tick. A new GOP structure only takes effect after a
+    // reset, and that has to start from an IDR.
+    fn reconfigure(
+        &mut self,
+        what: &str,
+        config: LVEncoderConfig,
+        reset: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut encode_config = Box::new(*self.encode_config);
+        encode_config.rcParams.averageBitRate = config.bitrate;
+        encode_config.rcParams.minQP = qp(config.min_qp);
+        encode_config.rcParams.maxQP = qp(config.max_qp);
         unsafe {
-            (*self.enc_params.encodeConfig).rcParams.averageBitRate = new_bitrate;
+            set_refresh_params(&mut encode_config, &config);
         }
 
-        debug!("x {:?}", unsafe { *self.enc_params.encodeConfig }.rcParams);
+        let mut enc_params = self.enc_params;
+        enc_params.framerate(config.framerate as u32, 1);
+        enc_params.encode_config(&mut encode_config);
 
         let mut reconfigure_params = _NV_ENC_RECONFIGURE_PARAMS {
             version: NV_ENC_RECONFIGURE_PARAMS_VER,
-            reInitEncodeParams: self.enc_params,
+            reInitEncodeParams: enc_params,
             ..Default::default()
         };
 
-        reconfigure_params.set_resetEncoder(1);
-        reconfigure_params.set_forceIDR(1);
+        if reset {
+            reconfigure_params.set_resetEncoder(1);
+            reconfigure_params.set_forceIDR(1);
+        }
 
-        match self
+        if let Err(e) = self
             .enc_session
             .get_encoder()
             .reconfigure_encoder(reconfigure_params)
         {
-            Ok(k) => debug!("finished reconfiguring encoder!"),
-            Err(e) => error!("failed to set bitrate {:?}", e),
+            error!("failed to set {} {:?}", what, e);
+            return Err(e.into());
         }
+        debug!("finished reconfiguring encoder!");
 
+        // enc_params points into encode_config, they're replaced together.
+        self.encode_config = encode_config;
+        self.enc_params = enc_params;
+        self.config = config;
         Ok(())
     }
 }
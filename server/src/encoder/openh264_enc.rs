@@ -1,26 +1,38 @@
 use std::{
+    collections::VecDeque,
     io::ErrorKind,
     os::raw::{c_int, c_void},
     time::Instant,
 };
 
+use anyhow::anyhow;
 use bytes::{buf::Writer, BytesMut};
 use dcv_color_primitives::{convert_image, get_buffers_size, ImageFormat};
-use image::{ImageBuffer, Rgb};
-use log::debug;
+use log::{debug, info, warn};
+use net::codec::{LVCodec, LVEncoderBackend};
 use openh264::{
     encoder::{EncodedBitStream, Encoder},
     formats::{YUVBuffer, YUVSource},
     Error as OpenH264Error, Timestamp,
 };
 
-use openh264_sys2::{SEncParamExt, ENCODER_OPTION_BITRATE, LOW_COMPLEXITY, RC_BITRATE_MODE};
+use openh264_sys2::{
+    SEncParamExt, SLTRMarkingFeedback, SLTRRecoverRequest, ENCODER_LTR_MARKING_FEEDBACK,
+    ENCODER_LTR_RECOVERY_REQUEST, ENCODER_OPTION, ENCODER_OPTION_BITRATE,
+    ENCODER_OPTION_FRAME_RATE, ENCODER_OPTION_IDR_INTERVAL, ENCODER_OPTION_SVC_ENCODE_PARAM_EXT,
+    HIGH_COMPLEXITY, LOW_COMPLEXITY, LTR_MARKING_SUCCESS, LTR_RECOVERY_REQUEST, MEDIUM_COMPLEXITY,
+    RC_BITRATE_MODE,
+};
 use statistics::{
     collector::LVStatisticsCollector,
     statistics::{LVDataPoint, LVDataType},
 };
 
-use super::LVEncoder;
+use super::{
+    h264::{LVSliceInfo, LVSliceParser},
+    LVEncoder, LVEncoderComplexity, LVEncoderConfig,
+};
+use crate::capture::LVFrame;
 
 pub struct LVOpenH264Encoder {
     encoder: Encoder,
@@ -28,6 +40,7 @@ pub struct LVOpenH264Encoder {
     height: u32,
 
     // Params
+    config: LVEncoderConfig,
     params: SEncParamExt,
 
     // Image conversion stuff
@@ -35,35 +48,56 @@ pub struct LVOpenH264Encoder {
     dst_fmt: ImageFormat,
     src_strides: [usize; 1],
     out_sizes: [usize; 3],
+
+    // openh264 wants LTR feedback in terms of idr_pic_id and frame_num, which it doesn't hand
+    // out, so we read them back from the slice headers.
+    slice_parser: LVSliceParser,
+    // (frame index, slice info) of the last HISTORY_FRAMES frames
+    history: VecDeque<(u32, LVSliceInfo)>,
+    // Frames produced so far, which is the index of the next one
+    frames: u32,
+    // The newest LTR we told openh264 the client has, as (idr_pic_id, frame_num)
+    confirmed_ltr: Option<(u32, u32)>,
 }
 
+// How many frames back we can still find a frame's frame_num. LTRs older than this are long
+// gone from the encoder anyway.
+const HISTORY_FRAMES: usize = 256;
+
 // The primary purpose of this is to tune the Encoder parameters in one place.
 impl LVEncoder for LVOpenH264Encoder {
-    fn new(
-        width: u32,
-        height: u32,
-        bitrate: u32,
-        framerate: f32,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    fn new(config: LVEncoderConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let (width, height) = (config.width, config.height);
         let mut params = SEncParamExt::default();
 
         params.iPicWidth = width as c_int;
         params.iPicHeight = height as c_int;
         // params.iRCMode = RC_BITRATE_MODE;
-        params.iComplexityMode = LOW_COMPLEXITY;
+        params.iComplexityMode = match config.complexity {
+            LVEncoderComplexity::Low => LOW_COMPLEXITY,
+            LVEncoderComplexity::Medium => MEDIUM_COMPLEXITY,
+            LVEncoderComplexity::High => HIGH_COMPLEXITY,
+        };
         params.bEnableFrameSkip = false;
-        params.iTargetBitrate = bitrate as c_int;
+        params.iTargetBitrate = config.bitrate as c_int;
         params.bEnableDenoise = true;
-        params.fMaxFrameRate = framerate;
+        params.fMaxFrameRate = config.framerate;
         params.bEnableAdaptiveQuant = false;
-        params.iMultipleThreadIdc = 8;
+        params.iMultipleThreadIdc = config.threads;
         params.iEntropyCodingModeFlag = 0;
         // GOP Size
-        params.uiIntraPeriod = 120;
+        params.uiIntraPeriod = config.gop;
+        // Long-term references let a lost frame be recovered from without an IDR. openh264
+        // overrides the count with what it supports for the usage type anyway.
+        params.bEnableLongTermReference = true;
+        params.iLTRRefNum = 2;
+        params.iLtrMarkPeriod = 30;
+
+        // Quantization parameters
+        params.iMinQp = config.min_qp as c_int;
+        params.iMaxQp = config.max_qp as c_int;
 
-        // Quantization parameters?
-        params.iMinQp = 21;
-        params.iMinQp = 35;
+        info!("creating openh264 encoder with {:?}", config);
 
         let src_fmt = ImageFormat {
             pixel_format: dcv_color_primitives::PixelFormat::Bgra,
@@ -97,15 +131,27 @@ impl LVEncoder for LVOpenH264Encoder {
                 encoder,
                 width,
                 height,
+                config,
                 params,
 
                 dst_fmt,
                 src_fmt,
                 out_sizes,
                 src_strides,
+
+                slice_parser: LVSliceParser::new(),
+                history: VecDeque::with_capacity(HISTORY_FRAMES),
+                frames: 0,
+                confirmed_ltr: None,
             })
         }
     }
+    fn codec(&self) -> LVCodec {
+        LVCodec::H264
+    }
+    fn backend(&self) -> LVEncoderBackend {
+        LVEncoderBackend::OpenH264
+    }
     fn width(&self) -> u32 {
         self.width
     }
@@ -133,6 +179,30 @@ impl LVEncoder for LVOpenH264Encoder {
         debug!("h264 bit stream layer count is {}", data.num_layers());
         debug!("bit stream is {:?}", data.to_vec());
 
+        // Every slice of a frame carries the same header fields, the first one will do.
+        let mut slice_info = None;
+        let mut produced = false;
+        for l in 0..data.num_layers() {
+            let layer = data.layer(l).unwrap();
+            for n in 0..layer.nal_count() {
+                let nal = layer.nal_unit(n).unwrap();
+                produced |= !nal.is_empty();
+                if let Some(info) = self.slice_parser.parse_nal(nal) {
+                    slice_info.get_or_insert(info);
+                }
+            }
+        }
+        // Frames that came out empty (skipped) don't get a frame id.
+        if produced {
+            if let Some(info) = slice_info {
+                if self.history.len() == HISTORY_FRAMES {
+                    self.history.pop_front();
+                }
+                self.history.push_back((self.frames, info));
+            }
+            self.frames += 1;
+        }
+
         let pre_enc = Instant::now();
         data.write(h264_buffer);
 
@@ -145,7 +215,7 @@ impl LVEncoder for LVOpenH264Encoder {
 
     fn convert_frame(
         &mut self,
-        input_buffer: ImageBuffer<Rgb<u8>, Vec<u8>>,
+        input_buffer: &LVFrame,
         output_buffer: &mut YUVBuffer,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let (mut y_slice, uv_slice) = output_buffer.yuv.split_at_mut(self.out_sizes[0]);
@@ -156,7 +226,7 @@ impl LVEncoder for LVOpenH264Encoder {
             input_buffer.height(),
             &self.src_fmt,
             Some(&self.src_strides),
-            &[&input_buffer.into_raw()],
+            &[input_buffer.as_bytes()],
             &self.dst_fmt,
             None,
             &mut [&mut y_slice, &mut u_slice, &mut v_slice],
@@ -168,12 +238,153 @@ impl LVEncoder for LVOpenH264Encoder {
         self.params.iTargetBitrate as u32
     }
     fn set_bitrate(&mut self, new_bitrate: u32) -> Result<(), Box<dyn std::error::Error>> {
-        self.params.iTargetBitrate = new_bitrate as c_int;
-        unsafe {
-            self.encoder.raw_api().set_option(
-                ENCODER_OPTION_BITRATE,
-                (&mut self.params.iTargetBitrate) as *mut i32 as *mut c_void,
-            );
+        let mut bitrate = new_bitrate as c_int;
+        self.set_option(
+            ENCODER_OPTION_BITRATE,
+            (&mut bitrate) as *mut c_int as *mut c_void,
+        )?;
+        self.params.iTargetBitrate = bitrate;
+        self.config.bitrate = new_bitrate;
+        Ok(())
+    }
+
+    fn config(&self) -> LVEncoderConfig {
+        self.config
+    }
+
+    fn set_framerate(&mut self, framerate: f32) -> Result<(), Box<dyn std::error::Error>> {
+        self.params.fMaxFrameRate = framerate;
+        self.set_option(
+            ENCODER_OPTION_FRAME_RATE,
+            (&mut self.params.fMaxFrameRate) as *mut f32 as *mut c_void,
+        )?;
+        self.config.framerate = framerate;
+        Ok(())
+    }
+
+    fn set_qp_range(&mut self, min_qp: u8, max_qp: u8) -> Result<(), Box<dyn std::error::Error>> {
+        if min_qp > max_qp || max_qp > 51 {
+            return Err(anyhow!("invalid qp range {}..{}", min_qp, max_qp).into());
+        }
+        // There's no option for just the QP range, so hand over the whole parameter set.
+        let mut params = self.params.clone();
+        params.iMinQp = min_qp as c_int;
+        params.iMaxQp = max_qp as c_int;
+        self.set_option(
+            ENCODER_OPTION_SVC_ENCODE_PARAM_EXT,
+            (&mut params) as *mut SEncParamExt as *mut c_void,
+        )?;
+        self.params = params;
+        self.config.min_qp = min_qp;
+        self.config.max_qp = max_qp;
+        Ok(())
+    }
+
+    fn set_gop(&mut self, gop: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let mut idr_interval = gop as c_int;
+        self.set_option(
+            ENCODER_OPTION_IDR_INTERVAL,
+            (&mut idr_interval) as *mut c_int as *mut c_void,
+        )?;
+        self.params.uiIntraPeriod = gop;
+        self.config.gop = gop;
+        Ok(())
+    }
+
+    // openh264 has no intra refresh, so this is always a whole IDR.
+    fn request_recovery(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let result = unsafe { self.encoder.raw_api().force_intra_frame(true) };
+        if result != 0 {
+            return Err(anyhow!("openh264 refused to force an IDR with {}", result).into());
+        }
+        Ok(())
+    }
+
+    fn confirm_frame(&mut self, frame: u32) {
+        // The newest LTR the client has seen, along with the frame that marked it.
+        let Some(&(_, current)) = self.history.back() else {
+            return;
+        };
+        let ltr = self
+            .history
+            .iter()
+            .rev()
+            .filter(|(index, info)| *index <= frame && info.idr_pic_id == current.idr_pic_id)
+            .find_map(|(_, info)| info.long_term.map(|ltr| (info.idr_pic_id, ltr)));
+        let Some((idr_pic_id, ltr_frame_num)) = ltr else {
+            return;
+        };
+        if self.confirmed_ltr == Some((idr_pic_id, ltr_frame_num)) {
+            return;
+        }
+
+        let mut feedback = SLTRMarkingFeedback {
+            uiFeedbackType: LTR_MARKING_SUCCESS as u32,
+            uiIDRPicId: idr_pic_id,
+            iLTRFrameNum: ltr_frame_num as c_int,
+            iLayerId: 0,
+        };
+        match self.set_option(
+            ENCODER_LTR_MARKING_FEEDBACK,
+            (&mut feedback) as *mut SLTRMarkingFeedback as *mut c_void,
+        ) {
+            Ok(()) => {
+                debug!("client has LTR frame_num {}", ltr_frame_num);
+                self.confirmed_ltr = Some((idr_pic_id, ltr_frame_num));
+            }
+            Err(e) => warn!("failed to confirm LTR {:?}", e),
+        }
+    }
+
+    // openh264 picks the newest confirmed LTR itself, but if there is none it quietly sends an
+    // IDR, so only ask when there's one the client had before things went wrong.
+    fn invalidate_frames(&mut self, last_good: u32) -> Result<bool, Box<dyn std::error::Error>> {
+        let (Some(good), Some(&(_, current))) = (self.frame_info(last_good), self.history.back())
+        else {
+            return Ok(false);
+        };
+        // The client never got the IDR we're on now, so there's nothing to go back to.
+        if good.idr_pic_id != current.idr_pic_id {
+            return Ok(false);
+        }
+        match self.confirmed_ltr {
+            Some((idr_pic_id, ltr_frame_num))
+                if idr_pic_id == current.idr_pic_id && ltr_frame_num <= good.frame_num => {}
+            _ => return Ok(false),
+        }
+
+        let mut request = SLTRRecoverRequest {
+            uiFeedbackType: LTR_RECOVERY_REQUEST as u32,
+            uiIDRPicId: current.idr_pic_id,
+            iLastCorrectFrameNum: good.frame_num as c_int,
+            iCurrentFrameNum: current.frame_num as c_int,
+            iLayerId: 0,
+        };
+        self.set_option(
+            ENCODER_LTR_RECOVERY_REQUEST,
+            (&mut request) as *mut SLTRRecoverRequest as *mut c_void,
+        )?;
+        Ok(true)
+    }
+}
+
+impl LVOpenH264Encoder {
+    fn frame_info(&self, frame: u32) -> Option<LVSliceInfo> {
+        self.history
+            .iter()
+            .find(|(index, _)| *index == frame)
+            .map(|(_, info)| *info)
+    }
+
+    // SetOption returns a CM_RETURN, anything but 0 means the option was rejected.
+    fn set_option(
+        &mut self,
+        option: ENCODER_OPTION,
+        value: *mut c_void,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let result = unsafe { self.encoder.raw_api().set_option(option, value) };
+        if result != 0 {
+            return Err(anyhow!("openh264 rejected option {} with {}", option, result).into());
         }
         Ok(())
     }
@@ -0,0 +1,340 @@
+use std::{
+    io::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use bytes::{buf::Writer, BytesMut};
+use dcv_color_primitives::{convert_image, get_buffers_size, ImageFormat};
+use log::{debug, info};
+use net::codec::{LVCodec, LVEncoderBackend};
+use openh264::formats::YUVBuffer;
+use rav1e::prelude::*;
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+
+use super::{LVEncoder, LVEncoderComplexity, LVEncoderConfig};
+use crate::capture::LVFrame;
+
+// AV1 through rav1e. It's a lot slower than openh264 at the same resolution, but keeps text
+// readable at bitrates where H.264 turns it into mush.
+//
+// rav1e can't change its settings on a running encoder, so changes are applied by building a
+// new context, which starts over with a keyframe. Rate control sends a new bitrate every
+// feedback tick, so bitrate changes under BITRATE_DEAD_BAND are ignored and the rebuilds are
+// kept at least MIN_REBUILD_INTERVAL apart.
+const BITRATE_DEAD_BAND: f64 = 0.1;
+const MIN_REBUILD_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct LVRav1eEncoder {
+    context: Context<u8>,
+    // What the current context was built with
+    config: LVEncoderConfig,
+    // What the next context gets built with, see rebuild_if_due
+    pending: Option<LVEncoderConfig>,
+    // Settings other than the rate, rebuild on the next frame without waiting
+    pending_now: bool,
+    last_rebuild: Instant,
+    // The next frame goes out as a keyframe
+    force_keyframe: bool,
+
+    // Image conversion stuff
+    src_fmt: ImageFormat,
+    dst_fmt: ImageFormat,
+    src_strides: [usize; 1],
+    out_sizes: [usize; 3],
+}
+
+impl LVRav1eEncoder {
+    fn new_context(config: &LVEncoderConfig) -> Result<Context<u8>, Box<dyn std::error::Error>> {
+        let speed = match config.complexity {
+            LVEncoderComplexity::Low => 10,
+            LVEncoderComplexity::Medium => 8,
+            LVEncoderComplexity::High => 6,
+        };
+        let mut enc = EncoderConfig::with_speed_preset(speed);
+        enc.width = config.width as usize;
+        enc.height = config.height as usize;
+        enc.chroma_sampling = ChromaSampling::Cs420;
+        enc.bit_depth = 8;
+        enc.time_base = Rational::new(1, config.framerate.round().max(1.) as u64);
+        enc.bitrate = config.bitrate as i32;
+        // No reordering, and as little lookahead as rav1e allows, otherwise every frame waits
+        // for the ones after it to be captured. Even so rav1e holds on to a few frames before
+        // the first packet comes out, so AV1 adds a few frames of latency over H.264.
+        enc.low_latency = true;
+        enc.speed_settings.rdo_lookahead_frames = 1;
+        // Keyframes only come from the GOP, scene cut detection needs more lookahead.
+        enc.speed_settings.scene_detection_mode = SceneDetectionSpeed::None;
+        enc.min_key_frame_interval = 0;
+        enc.max_key_frame_interval = config.gop as u64;
+        // AV1 quantizers go from 0 to 255 instead of 0 to 51. In bitrate mode the base
+        // quantizer is the most the rate controller will go up to.
+        enc.min_quantizer = Self::quantizer(config.min_qp) as u8;
+        enc.quantizer = Self::quantizer(config.max_qp);
+
+        Config::new()
+            .with_encoder_config(enc)
+            .with_threads(config.threads as usize)
+            .new_context()
+            .map_err(|e| anyhow!("invalid rav1e config {:?}", e).into())
+    }
+
+    fn quantizer(qp: u8) -> usize {
+        (qp as usize * 255 / 51).min(255)
+    }
+
+    fn reconfigure(&mut self, config: LVEncoderConfig, now: bool) {
+        self.pending = Some(config);
+        self.pending_now |= now;
+    }
+
+    // Builds a context with the pending config if there's a change worth the keyframe.
+    fn rebuild_if_due(
+        &mut self,
+        h264_buffer: &mut Writer<BytesMut>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(pending) = self.pending else {
+            return Ok(());
+        };
+        if pending == self.config {
+            self.pending = None;
+            return Ok(());
+        }
+        let bitrate_change = (pending.bitrate as f64 - self.config.bitrate as f64).abs()
+            / self.config.bitrate.max(1) as f64;
+        let rate_only = LVEncoderConfig {
+            bitrate: self.config.bitrate,
+            ..pending
+        } == self.config;
+        if !self.pending_now
+            && (self.last_rebuild.elapsed() < MIN_REBUILD_INTERVAL
+                || (rate_only && bitrate_change < BITRATE_DEAD_BAND))
+        {
+            return Ok(());
+        }
+
+        info!("rebuilding rav1e encoder with {:?}", pending);
+        self.pending = None;
+        self.pending_now = false;
+        self.last_rebuild = Instant::now();
+        let context = Self::new_context(&pending)?;
+
+        // Get the frames the old context is still holding on to out before dropping it
+        self.context.flush();
+        loop {
+            match self.context.receive_packet() {
+                Ok(packet) => h264_buffer.write_all(&packet.data)?,
+                Err(EncoderStatus::Encoded) => {}
+                Err(EncoderStatus::LimitReached) | Err(EncoderStatus::NeedMoreData) => break,
+                Err(e) => return Err(anyhow!("rav1e failed to flush: {}", e).into()),
+            }
+        }
+
+        self.context = context;
+        self.config = pending;
+        Ok(())
+    }
+}
+
+impl LVEncoder for LVRav1eEncoder {
+    fn new(config: LVEncoderConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        info!("creating rav1e encoder with {:?}", config);
+        let context = Self::new_context(&config)?;
+
+        let src_fmt = ImageFormat {
+            pixel_format: dcv_color_primitives::PixelFormat::Bgra,
+            color_space: dcv_color_primitives::ColorSpace::Rgb,
+            num_planes: 1,
+        };
+        let dst_fmt = ImageFormat {
+            pixel_format: dcv_color_primitives::PixelFormat::I420,
+            color_space: dcv_color_primitives::ColorSpace::Bt601,
+            num_planes: 3,
+        };
+
+        let mut out_sizes = [0usize; 3];
+        get_buffers_size(config.width, config.height, &dst_fmt, None, &mut out_sizes)?;
+
+        let src_strides = [4 * (config.width as usize)];
+
+        LVStatisticsCollector::register_data("server_encode_frame", LVDataType::TimeSeries);
+
+        Ok(Self {
+            context,
+            config,
+            pending: None,
+            pending_now: false,
+            last_rebuild: Instant::now(),
+            force_keyframe: false,
+            src_fmt,
+            dst_fmt,
+            src_strides,
+            out_sizes,
+        })
+    }
+
+    fn codec(&self) -> LVCodec {
+        LVCodec::Av1
+    }
+
+    fn backend(&self) -> LVEncoderBackend {
+        LVEncoderBackend::Rav1e
+    }
+
+    fn width(&self) -> u32 {
+        self.config.width
+    }
+
+    fn height(&self) -> u32 {
+        self.config.height
+    }
+
+    fn convert_frame(
+        &mut self,
+        input_buffer: &LVFrame,
+        output_buffer: &mut YUVBuffer,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut y_slice, uv_slice) = output_buffer.yuv.split_at_mut(self.out_sizes[0]);
+        let (mut u_slice, mut v_slice) = uv_slice.split_at_mut(self.out_sizes[1]);
+
+        convert_image(
+            input_buffer.width(),
+            input_buffer.height(),
+            &self.src_fmt,
+            Some(&self.src_strides),
+            &[input_buffer.as_bytes()],
+            &self.dst_fmt,
+            None,
+            &mut [&mut y_slice, &mut u_slice, &mut v_slice],
+        )?;
+
+        Ok(())
+    }
+
+    fn encode_frame(
+        &mut self,
+        buffer: &YUVBuffer,
+        // Milliseconds from start. rav1e counts frames itself.
+        _timestamp: u64,
+        h264_buffer: &mut Writer<BytesMut>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let pre_enc = Instant::now();
+
+        self.rebuild_if_due(h264_buffer)?;
+
+        // The YUV buffer is tightly packed I420, same as convert_frame wrote it.
+        let y_stride = self.config.width as usize;
+        let uv_stride = (self.config.width as usize + 1) / 2;
+        let (y, uv) = buffer.yuv.split_at(self.out_sizes[0]);
+        let (u, v) = uv.split_at(self.out_sizes[1]);
+
+        let mut frame = self.context.new_frame();
+        frame.planes[0].copy_from_raw_u8(y, y_stride, 1);
+        frame.planes[1].copy_from_raw_u8(u, uv_stride, 1);
+        frame.planes[2].copy_from_raw_u8(&v[..self.out_sizes[2]], uv_stride, 1);
+
+        let params = if std::mem::take(&mut self.force_keyframe) {
+            Some(FrameParameters {
+                frame_type_override: FrameTypeOverride::Key,
+                ..Default::default()
+            })
+        } else {
+            None
+        };
+        self.context
+            .send_frame((Arc::new(frame), params))
+            .map_err(|e| anyhow!("rav1e refused frame: {}", e))?;
+
+        loop {
+            match self.context.receive_packet() {
+                Ok(packet) => {
+                    debug!(
+                        "rav1e packet for frame {} is {:?}, {} bytes",
+                        packet.input_frameno,
+                        packet.frame_type,
+                        packet.data.len()
+                    );
+                    h264_buffer.write_all(&packet.data)?;
+                }
+                // Encoded means there's more to come, NeedMoreData that it's waiting for the
+                // next frame.
+                Err(EncoderStatus::Encoded) => {}
+                Err(EncoderStatus::NeedMoreData) => break,
+                Err(e) => return Err(anyhow!("rav1e failed to encode: {}", e).into()),
+            }
+        }
+
+        LVStatisticsCollector::update_data(
+            "server_encode_frame",
+            LVDataPoint::TimeElapsed(pre_enc.elapsed()),
+        );
+        Ok(())
+    }
+
+    fn bitrate(&self) -> u32 {
+        self.config.bitrate
+    }
+
+    fn set_bitrate(&mut self, new_bitrate: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let config = self.pending.unwrap_or(self.config);
+        self.reconfigure(
+            LVEncoderConfig {
+                bitrate: new_bitrate,
+                ..config
+            },
+            false,
+        );
+        Ok(())
+    }
+
+    fn config(&self) -> LVEncoderConfig {
+        self.config
+    }
+
+    fn set_framerate(&mut self, framerate: f32) -> Result<(), Box<dyn std::error::Error>> {
+        let config = self.pending.unwrap_or(self.config);
+        self.reconfigure(
+            LVEncoderConfig {
+                framerate,
+                ..config
+            },
+            false,
+        );
+        Ok(())
+    }
+
+    fn set_qp_range(&mut self, min_qp: u8, max_qp: u8) -> Result<(), Box<dyn std::error::Error>> {
+        if min_qp > max_qp || max_qp > 51 {
+            return Err(anyhow!("invalid qp range {}..{}", min_qp, max_qp).into());
+        }
+        let config = self.pending.unwrap_or(self.config);
+        self.reconfigure(
+            LVEncoderConfig {
+                min_qp,
+                max_qp,
+                ..config
+            },
+            true,
+        );
+        Ok(())
+    }
+
+    fn set_gop(&mut self, gop: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let config = self.pending.unwrap_or(self.config);
+        self.reconfigure(LVEncoderConfig { gop, ..config }, true);
+        Ok(())
+    }
+
+    fn request_recovery(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.force_keyframe = true;
+        Ok(())
+    }
+
+    fn codec_config(&self) -> Option<Vec<u8>> {
+        Some(self.context.container_sequence_header())
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use net::codec::LVEncoderBackend;
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+
+use super::{openh264_enc::LVOpenH264Encoder, LVEncoder, LVEncoderConfig};
+
+// Backends get probed with a small encoder, big enough that every one of them accepts it.
+const PROBE_WIDTH: u32 = 640;
+const PROBE_HEIGHT: u32 = 480;
+
+lazy_static! {
+    // The backends that came up when probed, in the order they should be tried. None until
+    // something probes them.
+    static ref AVAILABLE: Mutex<Option<Vec<LVEncoderBackend>>> = Mutex::new(None);
+}
+
+// Everything this build can encode with. Hardware goes first, it's much cheaper on the CPU
+// when it works.
+pub fn compiled_backends() -> Vec<LVEncoderBackend> {
+    let mut backends = Vec::new();
+    #[cfg(feature = "nvidia-hwenc")]
+    backends.push(LVEncoderBackend::Nvenc);
+    #[cfg(feature = "vaapi-hwenc")]
+    backends.push(LVEncoderBackend::Vaapi);
+    backends.push(LVEncoderBackend::OpenH264);
+    #[cfg(feature = "av1")]
+    backends.push(LVEncoderBackend::Rav1e);
+    backends
+}
+
+// A comma separated preference list, e.g. "vaapi,openh264".
+pub fn parse_preference(list: &str) -> Result<Vec<LVEncoderBackend>, Box<dyn std::error::Error>> {
+    let mut backends = Vec::new();
+    for name in list.split(',').filter(|name| !name.trim().is_empty()) {
+        backends.push(name.parse::<LVEncoderBackend>()?);
+    }
+    if backends.is_empty() {
+        return Err(anyhow!("empty encoder list {:?}", list).into());
+    }
+    Ok(backends)
+}
+
+// LV_ENCODER picks the backends when nothing is given on the command line.
+pub fn preference_from_env() -> Result<Option<Vec<LVEncoderBackend>>, Box<dyn std::error::Error>> {
+    match std::env::var("LV_ENCODER") {
+        Ok(list) => Ok(Some(parse_preference(&list)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn new_encoder(
+    backend: LVEncoderBackend,
+    config: LVEncoderConfig,
+) -> Result<Box<dyn LVEncoder>, Box<dyn std::error::Error>> {
+    match backend {
+        LVEncoderBackend::OpenH264 => Ok(Box::new(LVOpenH264Encoder::new(config)?)),
+        #[cfg(feature = "nvidia-hwenc")]
+        LVEncoderBackend::Nvenc => Ok(Box::new(super::nvidia::LVNvidiaEncoder::new(config)?)),
+        #[cfg(feature = "vaapi-hwenc")]
+        LVEncoderBackend::Vaapi => Ok(Box::new(super::vaapi::LVVaapiEncoder::new(config)?)),
+        #[cfg(feature = "av1")]
+        LVEncoderBackend::Rav1e => Ok(Box::new(super::rav1e_enc::LVRav1eEncoder::new(config)?)),
+        #[allow(unreachable_patterns)]
+        backend => Err(anyhow!("built without the {} encoder", backend).into()),
+    }
+}
+
+// Tries each preferred backend (or everything compiled in) and remembers the ones that work on
+// this machine, so a missing GPU or driver shows up once at startup instead of on every
+// encoder rebuild.
+pub fn probe(preference: Option<Vec<LVEncoderBackend>>) -> Vec<LVEncoderBackend> {
+    let candidates = preference.unwrap_or_else(compiled_backends);
+    let mut available = Vec::new();
+    for backend in candidates {
+        if available.contains(&backend) {
+            continue;
+        }
+        let config = LVEncoderConfig {
+            codec: backend.codec(),
+            ..LVEncoderConfig::new(PROBE_WIDTH, PROBE_HEIGHT, 1_000_000, 30.)
+        };
+        match new_encoder(backend, config) {
+            Ok(_) => {
+                info!("{} encoder is available", backend);
+                available.push(backend);
+            }
+            Err(e) => warn!("{} encoder is unavailable: {}", backend, e),
+        }
+    }
+
+    *AVAILABLE.lock().expect("Failed to lock encoder registry") = Some(available.clone());
+    available
+}
+
+// Builds an encoder with the first available backend for config.codec. If it fails, e.g.
+// because the frame is bigger than the hardware supports, the next one gets a go.
+pub fn create(config: LVEncoderConfig) -> Result<Box<dyn LVEncoder>, Box<dyn std::error::Error>> {
+    let available = AVAILABLE
+        .lock()
+        .expect("Failed to lock encoder registry")
+        .clone();
+    // Anything that skipped probing at startup (the benchmark) gets it done here.
+    let available = match available {
+        Some(available) => available,
+        None => probe(preference_from_env()?),
+    };
+
+    for backend in available.iter().filter(|b| b.codec() == config.codec) {
+        match new_encoder(*backend, config) {
+            Ok(encoder) => {
+                info!("encoding {:?} with {}", config.codec, backend);
+                if config.intra_refresh.is_some_and(|frames| frames > 0)
+                    && !encoder.supports_intra_refresh()
+                {
+                    warn!("{} can't do intra refresh, sending IDR frames", backend);
+                }
+                let stat = format!("server_encoder_{}", backend);
+                LVStatisticsCollector::register_data(&stat, LVDataType::Aggregate);
+                LVStatisticsCollector::update_data(&stat, LVDataPoint::Increment);
+                return Ok(encoder);
+            }
+            Err(e) => warn!(
+                "{} encoder failed to start ({}), trying the next one",
+                backend, e
+            ),
+        }
+    }
+
+    Err(anyhow!(
+        "no working encoder for {:?}, available backends are {:?}",
+        config.codec,
+        available
+    )
+    .into())
+}
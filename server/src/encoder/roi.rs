@@ -0,0 +1,151 @@
+// Region of interest encoding. Rate control spreads the bits evenly over the frame, but the
+// user is looking at the pointer and at the window they're typing into, so those get a lower
+// QP and the rest of the desktop pays for it.
+
+use crate::capture::LVFocus;
+
+// H.264 macroblocks, the granularity encoders take QP offsets in.
+pub const MB_SIZE: u32 = 16;
+
+// QP offsets, lower is better quality. Each step is ~12% more or fewer bits, the background
+// going up a bit more than the window goes down keeps the total about where it was.
+const CURSOR_DELTA: i8 = -6;
+const WINDOW_DELTA: i8 = -2;
+const BACKGROUND_DELTA: i8 = 3;
+// Distance from the pointer, in pixels, that still counts as being looked at
+const CURSOR_RADIUS: i32 = 96;
+
+// On by default wherever the encoder supports it, LV_ROI=0 turns it off.
+pub fn enabled_from_env() -> bool {
+    !matches!(std::env::var("LV_ROI").as_deref(), Ok("0") | Ok("false"))
+}
+
+// A QP offset for every macroblock of the frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LVRoiMap {
+    pub mb_width: u32,
+    pub mb_height: u32,
+    // Row by row
+    pub deltas: Vec<i8>,
+}
+
+impl LVRoiMap {
+    pub fn new(width: u32, height: u32) -> Self {
+        let mb_width = width.div_ceil(MB_SIZE);
+        let mb_height = height.div_ceil(MB_SIZE);
+        Self {
+            mb_width,
+            mb_height,
+            deltas: vec![0; (mb_width * mb_height) as usize],
+        }
+    }
+
+    // Favours the pointer and the focused window. None if the focus has neither, then there's
+    // nothing to favour.
+    pub fn from_focus(width: u32, height: u32, focus: &LVFocus) -> Option<Self> {
+        if focus.cursor.is_none() && focus.window.is_none() {
+            return None;
+        }
+
+        let mut map = Self::new(width, height);
+        map.deltas.fill(BACKGROUND_DELTA);
+        if let Some((x, y, width, height)) = focus.window {
+            map.fill(x, y, width as i32, height as i32, WINDOW_DELTA);
+        }
+        if let Some((x, y)) = focus.cursor {
+            map.fill(
+                x - CURSOR_RADIUS,
+                y - CURSOR_RADIUS,
+                2 * CURSOR_RADIUS,
+                2 * CURSOR_RADIUS,
+                CURSOR_DELTA,
+            );
+        }
+        Some(map)
+    }
+
+    // Sets every macroblock the rectangle (in pixels) touches, clipped to the frame. The
+    // rectangle can be partly or entirely off the frame.
+    pub fn fill(&mut self, x: i32, y: i32, width: i32, height: i32, delta: i8) {
+        if width <= 0 || height <= 0 {
+            return;
+        }
+        let mb = MB_SIZE as i32;
+        let (mb_width, mb_height) = (self.mb_width as i32, self.mb_height as i32);
+        let left = (x.max(0) / mb).min(mb_width);
+        let top = (y.max(0) / mb).min(mb_height);
+        let right = (x.saturating_add(width).saturating_add(mb - 1) / mb).clamp(left, mb_width);
+        let bottom = (y.saturating_add(height).saturating_add(mb - 1) / mb).clamp(top, mb_height);
+        for row in top..bottom {
+            let start = (row * mb_width) as usize;
+            self.deltas[start + left as usize..start + right as usize].fill(delta);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(map: &LVRoiMap, delta: i8) -> Vec<(u32, u32)> {
+        (0..map.mb_height)
+            .flat_map(|y| (0..map.mb_width).map(move |x| (x, y)))
+            .filter(|&(x, y)| map.deltas[(y * map.mb_width + x) as usize] == delta)
+            .collect()
+    }
+
+    #[test]
+    fn fill_covers_touched_macroblocks() {
+        let mut map = LVRoiMap::new(64, 48);
+        map.fill(15, 16, 2, 17, -1);
+        assert_eq!(filled(&map, -1), vec![(0, 1), (1, 1), (0, 2), (1, 2)]);
+    }
+
+    #[test]
+    fn fill_clips_rects_off_the_frame() {
+        // 4x3 macroblocks, the last column and row only partly in the frame
+        let mut map = LVRoiMap::new(60, 40);
+        for (x, y, width, height) in [
+            // Entirely to the right, below, left and above the macroblocks
+            (64, 0, 100, 100),
+            (0, 48, 100, 100),
+            (-200, 0, 100, 40),
+            (0, -200, 60, 100),
+            // Way off in every direction
+            (i32::MAX - 10, i32::MAX - 10, i32::MAX, i32::MAX),
+            (i32::MIN, i32::MIN, 10, 10),
+            // Empty and negative sizes
+            (10, 10, 0, 0),
+            (30, 30, -20, -20),
+        ] {
+            map.fill(x, y, width, height, -1);
+            assert!(
+                filled(&map, -1).is_empty(),
+                "{:?} filled {:?}",
+                (x, y, width, height),
+                filled(&map, -1)
+            );
+        }
+
+        // Partly off, only the part in the frame gets filled
+        map.fill(-100, 20, 120, 1000, -1);
+        assert_eq!(filled(&map, -1), vec![(0, 1), (1, 1), (0, 2), (1, 2)]);
+        map.fill(50, -5, 1000, 6, -2);
+        assert_eq!(filled(&map, -2), vec![(3, 0)]);
+    }
+
+    #[test]
+    fn focus_off_the_frame_is_background() {
+        let focus = LVFocus {
+            cursor: Some((-500, -500)),
+            window: Some((2000, 2000, 640, 480)),
+        };
+        let map = LVRoiMap::from_focus(320, 240, &focus).unwrap();
+        assert!(map.deltas.iter().all(|&delta| delta == BACKGROUND_DELTA));
+    }
+
+    #[test]
+    fn no_focus_no_map() {
+        assert_eq!(LVRoiMap::from_focus(320, 240, &LVFocus::default()), None);
+    }
+}
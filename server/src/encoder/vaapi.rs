@@ -0,0 +1,758 @@
+use std::{io::Write, rc::Rc, time::Instant};
+
+use anyhow::anyhow;
+use bytes::{buf::Writer, BytesMut};
+use cros_libva::{
+    BufferType, Config, Context, Display, EncCodedBuffer, EncMiscParameter,
+    EncMiscParameterFrameRate, EncMiscParameterRateControl, EncPictureParameter,
+    EncPictureParameterBufferH264, EncSequenceParameter, EncSequenceParameterBufferH264,
+    EncSliceParameter, EncSliceParameterBufferH264, H264EncFrameCropOffsets, H264EncPicFields,
+    H264EncSeqFields, Image, MappedCodedBuffer, Picture, PictureH264, RcFlags, Surface, UsageHint,
+    VAConfigAttrib, VAConfigAttribType, VAEntrypoint, VAProfile,
+};
+use dcv_color_primitives::{convert_image, get_buffers_size, ImageFormat};
+use log::{debug, info, warn};
+use net::codec::{LVCodec, LVEncoderBackend};
+use openh264::formats::YUVBuffer;
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+
+use super::{h264::BitWriter, LVEncoder, LVEncoderConfig};
+use crate::capture::LVFrame;
+
+// frame_num and the POC LSBs wrap at 256.
+const LOG2_MAX_FRAME_NUM_MINUS4: u32 = 4;
+const LOG2_MAX_POC_LSB_MINUS4: u32 = 4;
+const MAX_FRAME_NUM: u32 = 1 << (LOG2_MAX_FRAME_NUM_MINUS4 + 4);
+const MAX_POC_LSB: u32 = 1 << (LOG2_MAX_POC_LSB_MINUS4 + 4);
+// Where rate control starts before it has seen any frames.
+const INIT_QP: u8 = 26;
+// One for the captured frame, two for the reconstructed frames we take turns writing to and
+// referencing.
+const NUM_SURFACES: usize = 3;
+
+// Which render node to open, otherwise the first one that works.
+const DEVICE_ENV: &str = "LV_VAAPI_DEVICE";
+
+// H.264 through VA-API, for Intel and AMD GPUs. Every frame after the IDR is a P frame
+// referencing the one before it, same as the baseline profile openh264 gives us.
+//
+// cros-libva has no packed header buffers, and not every driver writes an SPS and PPS without
+// them, so we write our own in front of each IDR if the driver didn't.
+pub struct LVVaapiEncoder {
+    // Drop order matters here, everything has to go before the context and the context before
+    // the display.
+    coded_buffer: EncCodedBuffer,
+    surfaces: Vec<Surface<()>>,
+    context: Rc<Context>,
+    _va_config: Config,
+    _display: Rc<Display>,
+
+    config: LVEncoderConfig,
+    width_in_mbs: u32,
+    height_in_mbs: u32,
+    // Whether the driver wants rate control set up, or only understands constant QP.
+    cbr: bool,
+    // Rate control or framerate changed, tell the driver on the next frame.
+    rc_changed: bool,
+
+    frames_since_idr: u32,
+    frame_num: u32,
+    idr_pic_id: u16,
+    // Which of surfaces[1..] the last frame was reconstructed into.
+    last_recon: usize,
+
+    // Image conversion stuff
+    src_fmt: ImageFormat,
+    dst_fmt: ImageFormat,
+    src_strides: [usize; 1],
+    out_sizes: [usize; 3],
+}
+
+impl LVVaapiEncoder {
+    fn open_display() -> Result<Rc<Display>, Box<dyn std::error::Error>> {
+        match std::env::var(DEVICE_ENV) {
+            Ok(path) => Ok(Display::open_drm_display(&path)?),
+            Err(_) => Display::open().ok_or_else(|| anyhow!("no VA-API device found").into()),
+        }
+    }
+
+    // The smallest level that fits the frame size and rate, so decoders don't reject it.
+    fn level_idc(width_in_mbs: u32, height_in_mbs: u32, framerate: f32) -> u8 {
+        let mbs_per_sec = (width_in_mbs * height_in_mbs) as f32 * framerate;
+        match mbs_per_sec as u32 {
+            0..=245_760 => 41,
+            245_761..=522_240 => 42,
+            522_241..=983_040 => 51,
+            _ => 52,
+        }
+    }
+
+    fn invalid_picture() -> PictureH264 {
+        PictureH264::new(
+            cros_libva::VA_INVALID_ID,
+            0,
+            cros_libva::VA_PICTURE_H264_INVALID,
+            0,
+            0,
+        )
+    }
+
+    fn reference_picture(&self, surface: usize, frame_num: u32, poc: i32) -> PictureH264 {
+        PictureH264::new(
+            self.surfaces[surface].id(),
+            frame_num,
+            cros_libva::VA_PICTURE_H264_SHORT_TERM_REFERENCE,
+            poc,
+            0,
+        )
+    }
+
+    // Frame cropping for sizes that aren't a multiple of 16, in units of 2 pixels for 4:2:0.
+    fn crop(config: &LVEncoderConfig) -> Option<(u32, u32)> {
+        let right = (config.width.div_ceil(16) * 16 - config.width) / 2;
+        let bottom = (config.height.div_ceil(16) * 16 - config.height) / 2;
+        if right == 0 && bottom == 0 {
+            None
+        } else {
+            Some((right, bottom))
+        }
+    }
+
+    // Copies the I420 frame into the input surface, which the driver wants as NV12.
+    fn upload(&self, buffer: &YUVBuffer) -> Result<(), Box<dyn std::error::Error>> {
+        let (width, height) = (self.config.width as usize, self.config.height as usize);
+        let mut image =
+            Image::derive_from(&self.surfaces[0], (self.config.width, self.config.height))?;
+        let va_image = *image.image();
+        let dst = image.as_mut();
+
+        let (y, uv) = buffer.yuv.split_at(self.out_sizes[0]);
+        let (u, v) = uv.split_at(self.out_sizes[1]);
+
+        let y_offset = va_image.offsets[0] as usize;
+        let y_pitch = va_image.pitches[0] as usize;
+        for (row, src) in y.chunks_exact(width).take(height).enumerate() {
+            let start = y_offset + row * y_pitch;
+            dst[start..start + width].copy_from_slice(src);
+        }
+
+        let uv_offset = va_image.offsets[1] as usize;
+        let uv_pitch = va_image.pitches[1] as usize;
+        let uv_width = (width + 1) / 2;
+        for (row, (u_row, v_row)) in u
+            .chunks_exact(uv_width)
+            .zip(v.chunks_exact(uv_width))
+            .take((height + 1) / 2)
+            .enumerate()
+        {
+            let start = uv_offset + row * uv_pitch;
+            for (pair, (u, v)) in dst[start..start + 2 * uv_width]
+                .chunks_exact_mut(2)
+                .zip(u_row.iter().zip(v_row))
+            {
+                pair[0] = *u;
+                pair[1] = *v;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sequence_parameters(&self) -> BufferType {
+        let seq_fields = H264EncSeqFields::new(
+            1, // 4:2:0
+            1, // frames only
+            0,
+            0,
+            1,
+            LOG2_MAX_FRAME_NUM_MINUS4,
+            0, // POC type 0
+            LOG2_MAX_POC_LSB_MINUS4,
+            0,
+        );
+        let framerate = self.config.framerate.round().max(1.) as u32;
+        BufferType::EncSequenceParameter(EncSequenceParameter::H264(
+            EncSequenceParameterBufferH264::new(
+                0,
+                Self::level_idc(self.width_in_mbs, self.height_in_mbs, self.config.framerate),
+                self.config.gop,
+                self.config.gop,
+                // No B frames
+                1,
+                self.config.bitrate,
+                1,
+                self.width_in_mbs as u16,
+                self.height_in_mbs as u16,
+                &seq_fields,
+                0,
+                0,
+                0,
+                0,
+                0,
+                [0; 256],
+                Self::crop(&self.config)
+                    .map(|(right, bottom)| H264EncFrameCropOffsets::new(0, right, 0, bottom)),
+                None,
+                0,
+                0,
+                0,
+                // Some drivers take the framerate for rate control from here.
+                1,
+                2 * framerate,
+            ),
+        ))
+    }
+
+    // What the driver would have put in front of an IDR given the parameters above, since it
+    // writes the slice headers from those and they have to agree.
+    fn sps(config: &LVEncoderConfig) -> Vec<u8> {
+        let (width_in_mbs, height_in_mbs) = (config.width.div_ceil(16), config.height.div_ceil(16));
+        let mut bits = BitWriter::default();
+        // Constrained baseline
+        bits.put(66, 8);
+        bits.put(0b1100_0000, 8);
+        bits.put(
+            Self::level_idc(width_in_mbs, height_in_mbs, config.framerate) as u32,
+            8,
+        );
+        bits.put_ue(0);
+        bits.put_ue(LOG2_MAX_FRAME_NUM_MINUS4);
+        bits.put_ue(0);
+        bits.put_ue(LOG2_MAX_POC_LSB_MINUS4);
+        // One reference frame, no gaps in frame_num
+        bits.put_ue(1);
+        bits.put(0, 1);
+        bits.put_ue(width_in_mbs - 1);
+        bits.put_ue(height_in_mbs - 1);
+        // frame_mbs_only_flag, direct_8x8_inference_flag
+        bits.put(1, 1);
+        bits.put(1, 1);
+        match Self::crop(config) {
+            Some((right, bottom)) => {
+                bits.put(1, 1);
+                bits.put_ue(0);
+                bits.put_ue(right);
+                bits.put_ue(0);
+                bits.put_ue(bottom);
+            }
+            None => bits.put(0, 1),
+        }
+        // No VUI
+        bits.put(0, 1);
+        bits.nal(0x67)
+    }
+
+    fn rate_control_parameters(&self, reset: bool) -> Vec<BufferType> {
+        let framerate = self.config.framerate.round().max(1.) as u32;
+        vec![
+            BufferType::EncMiscParameter(EncMiscParameter::RateControl(
+                EncMiscParameterRateControl::new(
+                    self.config.bitrate,
+                    100,
+                    // ms of data the bitrate is averaged over, short so it reacts quickly
+                    1000,
+                    INIT_QP as u32,
+                    self.config.min_qp as u32,
+                    0,
+                    RcFlags::new(reset as u32, 0, 1, 0, 0, 0, 0, 0, 0),
+                    0,
+                    self.config.max_qp as u32,
+                    0,
+                    0,
+                ),
+            )),
+            BufferType::EncMiscParameter(EncMiscParameter::FrameRate(
+                EncMiscParameterFrameRate::new(framerate, 0),
+            )),
+        ]
+    }
+
+    fn reconfigure(&mut self, config: LVEncoderConfig) -> Result<(), Box<dyn std::error::Error>> {
+        if config.width != self.config.width || config.height != self.config.height {
+            return Err(anyhow!("VA-API encoder can't change size, make a new one").into());
+        }
+        info!("reconfiguring VA-API encoder with {:?}", config);
+        self.config = config;
+        self.rc_changed = true;
+        Ok(())
+    }
+}
+
+impl LVEncoder for LVVaapiEncoder {
+    fn new(config: LVEncoderConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let display = Self::open_display()?;
+        info!(
+            "creating VA-API encoder on {} with {:?}",
+            display
+                .query_vendor_string()
+                .unwrap_or_else(|_| "unknown driver".to_string()),
+            config
+        );
+
+        let profile = VAProfile::VAProfileH264ConstrainedBaseline;
+        if !display.query_config_profiles()?.contains(&profile) {
+            return Err(anyhow!("VA-API device can't do constrained baseline H.264").into());
+        }
+        // Low power is the fixed function encoder on newer Intel GPUs, some only have that.
+        let entrypoints = display.query_config_entrypoints(profile)?;
+        let entrypoint = [
+            VAEntrypoint::VAEntrypointEncSlice,
+            VAEntrypoint::VAEntrypointEncSliceLP,
+        ]
+        .into_iter()
+        .find(|entrypoint| entrypoints.contains(entrypoint))
+        .ok_or_else(|| anyhow!("VA-API device can't encode H.264"))?;
+
+        let mut attrs = vec![
+            VAConfigAttrib {
+                type_: VAConfigAttribType::VAConfigAttribRTFormat,
+                value: 0,
+            },
+            VAConfigAttrib {
+                type_: VAConfigAttribType::VAConfigAttribRateControl,
+                value: 0,
+            },
+        ];
+        display.get_config_attributes(profile, entrypoint, &mut attrs)?;
+        if attrs[0].value & cros_libva::VA_RT_FORMAT_YUV420 == 0 {
+            return Err(anyhow!("VA-API encoder doesn't take 4:2:0").into());
+        }
+        attrs[0].value = cros_libva::VA_RT_FORMAT_YUV420;
+        let supported_rc = attrs[1].value;
+        let cbr = supported_rc != cros_libva::VA_ATTRIB_NOT_SUPPORTED
+            && supported_rc & cros_libva::VA_RC_CBR != 0;
+        if cbr {
+            attrs[1].value = cros_libva::VA_RC_CBR;
+        } else {
+            warn!("VA-API encoder has no CBR, bitrate changes will be ignored");
+            attrs.pop();
+        }
+        let va_config = display.create_config(attrs, profile, entrypoint)?;
+
+        // The encoder works on whole macroblocks, the SPS crops the rest off.
+        let width_in_mbs = (config.width + 15) / 16;
+        let height_in_mbs = (config.height + 15) / 16;
+        let surfaces = display.create_surfaces(
+            cros_libva::VA_RT_FORMAT_YUV420,
+            Some(cros_libva::VA_FOURCC_NV12),
+            width_in_mbs * 16,
+            height_in_mbs * 16,
+            Some(UsageHint::USAGE_HINT_ENCODER),
+            vec![(); NUM_SURFACES],
+        )?;
+        let context = display.create_context(
+            &va_config,
+            width_in_mbs * 16,
+            height_in_mbs * 16,
+            Some(&surfaces),
+            true,
+        )?;
+        // An encoded frame is never bigger than the raw one.
+        let coded_buffer =
+            context.create_enc_coded((width_in_mbs * height_in_mbs * 16 * 16 * 3 / 2) as usize)?;
+
+        let src_fmt = ImageFormat {
+            pixel_format: dcv_color_primitives::PixelFormat::Bgra,
+            color_space: dcv_color_primitives::ColorSpace::Rgb,
+            num_planes: 1,
+        };
+        let dst_fmt = ImageFormat {
+            pixel_format: dcv_color_primitives::PixelFormat::I420,
+            color_space: dcv_color_primitives::ColorSpace::Bt601,
+            num_planes: 3,
+        };
+
+        let mut out_sizes = [0usize; 3];
+        get_buffers_size(config.width, config.height, &dst_fmt, None, &mut out_sizes)?;
+
+        let src_strides = [4 * (config.width as usize)];
+
+        LVStatisticsCollector::register_data("server_encode_frame", LVDataType::TimeSeries);
+
+        Ok(Self {
+            coded_buffer,
+            surfaces,
+            context,
+            _va_config: va_config,
+            _display: display,
+            config,
+            width_in_mbs,
+            height_in_mbs,
+            cbr,
+            rc_changed: true,
+            frames_since_idr: 0,
+            frame_num: 0,
+            idr_pic_id: 0,
+            last_recon: 2,
+            src_fmt,
+            dst_fmt,
+            src_strides,
+            out_sizes,
+        })
+    }
+
+    fn codec(&self) -> LVCodec {
+        LVCodec::H264
+    }
+
+    fn backend(&self) -> LVEncoderBackend {
+        LVEncoderBackend::Vaapi
+    }
+
+    fn width(&self) -> u32 {
+        self.config.width
+    }
+
+    fn height(&self) -> u32 {
+        self.config.height
+    }
+
+    fn convert_frame(
+        &mut self,
+        input_buffer: &LVFrame,
+        output_buffer: &mut YUVBuffer,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut y_slice, uv_slice) = output_buffer.yuv.split_at_mut(self.out_sizes[0]);
+        let (mut u_slice, mut v_slice) = uv_slice.split_at_mut(self.out_sizes[1]);
+
+        convert_image(
+            input_buffer.width(),
+            input_buffer.height(),
+            &self.src_fmt,
+            Some(&self.src_strides),
+            &[input_buffer.as_bytes()],
+            &self.dst_fmt,
+            None,
+            &mut [&mut y_slice, &mut u_slice, &mut v_slice],
+        )?;
+
+        Ok(())
+    }
+
+    fn encode_frame(
+        &mut self,
+        buffer: &YUVBuffer,
+        timestamp: u64,
+        h264_buffer: &mut Writer<BytesMut>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let pre_enc = Instant::now();
+        self.upload(buffer)?;
+
+        let idr = self.frames_since_idr == 0 || self.frames_since_idr >= self.config.gop;
+        if idr {
+            self.frames_since_idr = 0;
+            self.frame_num = 0;
+            self.idr_pic_id = self.idr_pic_id.wrapping_add(1);
+        }
+        let poc = (2 * self.frames_since_idr) % MAX_POC_LSB;
+        let recon = if self.last_recon == 1 { 2 } else { 1 };
+
+        let mut reference_frames: [PictureH264; 16] =
+            std::array::from_fn(|_| Self::invalid_picture());
+        let mut ref_pic_list_0: [PictureH264; 32] =
+            std::array::from_fn(|_| Self::invalid_picture());
+        if !idr {
+            let last_frame_num = (self.frame_num + MAX_FRAME_NUM - 1) % MAX_FRAME_NUM;
+            let last_poc = (poc + MAX_POC_LSB - 2) % MAX_POC_LSB;
+            reference_frames[0] =
+                self.reference_picture(self.last_recon, last_frame_num, last_poc as i32);
+            ref_pic_list_0[0] =
+                self.reference_picture(self.last_recon, last_frame_num, last_poc as i32);
+        }
+
+        let picture_parameters = BufferType::EncPictureParameter(EncPictureParameter::H264(
+            EncPictureParameterBufferH264::new(
+                PictureH264::new(self.surfaces[recon].id(), self.frame_num, 0, poc as i32, 0),
+                reference_frames,
+                self.coded_buffer.id(),
+                0,
+                0,
+                0,
+                self.frame_num as u16,
+                INIT_QP,
+                0,
+                0,
+                0,
+                0,
+                // IDR, reference, CAVLC, no weighted prediction, no 8x8 transform, deblocking
+                // control present
+                &H264EncPicFields::new(idr as u32, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0),
+            ),
+        ));
+
+        let total_mbs = self.width_in_mbs * self.height_in_mbs;
+        let slice_parameters = BufferType::EncSliceParameter(EncSliceParameter::H264(
+            EncSliceParameterBufferH264::new(
+                0,
+                total_mbs,
+                cros_libva::VA_INVALID_ID,
+                // 2 is I, 0 is P
+                if idr { 2 } else { 0 },
+                0,
+                self.idr_pic_id,
+                poc as u16,
+                0,
+                [0, 0],
+                0,
+                0,
+                0,
+                0,
+                ref_pic_list_0,
+                std::array::from_fn(|_| Self::invalid_picture()),
+                0,
+                0,
+                0,
+                [0; 32],
+                [0; 32],
+                0,
+                [[0; 2]; 32],
+                [[0; 2]; 32],
+                0,
+                [0; 32],
+                [0; 32],
+                0,
+                [[0; 2]; 32],
+                [[0; 2]; 32],
+                0,
+                0,
+                0,
+                0,
+                0,
+            ),
+        ));
+
+        let mut picture = Picture::new(timestamp, self.context.clone(), &self.surfaces[0]);
+        if idr {
+            picture.add_buffer(self.context.create_buffer(self.sequence_parameters())?);
+        }
+        if self.cbr && (idr || self.rc_changed) {
+            for misc in self.rate_control_parameters(self.rc_changed) {
+                picture.add_buffer(self.context.create_buffer(misc)?);
+            }
+        }
+        picture.add_buffer(self.context.create_buffer(picture_parameters)?);
+        picture.add_buffer(self.context.create_buffer(slice_parameters)?);
+
+        picture
+            .begin()?
+            .render()?
+            .end()?
+            .sync()
+            .map_err(|(e, _)| e)?;
+
+        let coded = MappedCodedBuffer::new(&self.coded_buffer)?;
+        if idr && !coded.segments().first().is_some_and(|s| has_sps(s.buf)) {
+            debug!("driver didn't write an SPS, adding our own");
+            h264_buffer.write_all(&Self::sps(&self.config))?;
+            h264_buffer.write_all(&pps())?;
+        }
+        for segment in coded.iter() {
+            h264_buffer.write_all(segment.buf)?;
+        }
+        drop(coded);
+
+        self.rc_changed = false;
+        self.last_recon = recon;
+        self.frames_since_idr += 1;
+        self.frame_num = (self.frame_num + 1) % MAX_FRAME_NUM;
+
+        LVStatisticsCollector::update_data(
+            "server_encode_frame",
+            LVDataPoint::TimeElapsed(pre_enc.elapsed()),
+        );
+        Ok(())
+    }
+
+    fn bitrate(&self) -> u32 {
+        self.config.bitrate
+    }
+
+    fn set_bitrate(&mut self, new_bitrate: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.reconfigure(LVEncoderConfig {
+            bitrate: new_bitrate,
+            ..self.config
+        })
+    }
+
+    fn config(&self) -> LVEncoderConfig {
+        self.config
+    }
+
+    fn set_framerate(&mut self, framerate: f32) -> Result<(), Box<dyn std::error::Error>> {
+        self.reconfigure(LVEncoderConfig {
+            framerate,
+            ..self.config
+        })
+    }
+
+    fn set_qp_range(&mut self, min_qp: u8, max_qp: u8) -> Result<(), Box<dyn std::error::Error>> {
+        if min_qp > max_qp || max_qp > 51 {
+            return Err(anyhow!("invalid qp range {}..{}", min_qp, max_qp).into());
+        }
+        self.reconfigure(LVEncoderConfig {
+            min_qp,
+            max_qp,
+            ..self.config
+        })
+    }
+
+    fn set_gop(&mut self, gop: u32) -> Result<(), Box<dyn std::error::Error>> {
+        // Takes effect from the next IDR, the SPS is only sent with those.
+        self.reconfigure(LVEncoderConfig { gop, ..self.config })
+    }
+
+    // cros-libva has no way to pass rolling intra refresh parameters, so this is an IDR.
+    fn request_recovery(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // encode_frame starts over with an IDR when the count is back at 0.
+        self.frames_since_idr = 0;
+        Ok(())
+    }
+}
+
+fn pps() -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    // PPS and SPS ids
+    bits.put_ue(0);
+    bits.put_ue(0);
+    // CAVLC, no field order, one slice group, one reference in each list
+    bits.put(0, 1);
+    bits.put(0, 1);
+    bits.put_ue(0);
+    bits.put_ue(0);
+    bits.put_ue(0);
+    // No weighted prediction
+    bits.put(0, 1);
+    bits.put(0, 2);
+    // pic_init_qp, pic_init_qs and chroma_qp_index_offset relative to 26
+    bits.put_se(INIT_QP as i32 - 26);
+    bits.put_se(0);
+    bits.put_se(0);
+    // deblocking_filter_control_present_flag, constrained_intra_pred_flag,
+    // redundant_pic_cnt_present_flag
+    bits.put(1, 1);
+    bits.put(0, 1);
+    bits.put(0, 1);
+    bits.nal(0x68)
+}
+
+// Looks for an SPS NAL among the first few the driver wrote.
+fn has_sps(data: &[u8]) -> bool {
+    data.windows(4)
+        .take(64)
+        .any(|w| w[0] == 0 && w[1] == 0 && w[2] == 1 && w[3] & 0x1f == 7)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BufMut;
+
+    use super::*;
+    use crate::encoder::h264::{unescape, BitReader};
+
+    #[test]
+    fn bit_writer_writes_exp_golomb() {
+        let mut bits = BitWriter::default();
+        // 1, 010, 011, 00100
+        for value in 0..4 {
+            bits.put_ue(value);
+        }
+        // 010, 011, 00100 for 1, -1, 2
+        bits.put_se(1);
+        bits.put_se(-1);
+        bits.put_se(2);
+        // 12 + 11 bits and the stop bit make exactly three bytes, no padding
+        assert_eq!(
+            bits.nal(0x01),
+            vec![0, 0, 0, 1, 0x01, 0b1010_0110, 0b0100_0100, 0b1100_1001]
+        );
+    }
+
+    #[test]
+    fn bit_writer_escapes_start_codes() {
+        let mut bits = BitWriter::default();
+        bits.put(0x000001, 24);
+        bits.put(0x0000, 16);
+        bits.put(0x02, 8);
+        let nal = bits.nal(0x06);
+        assert_eq!(
+            nal,
+            vec![0, 0, 0, 1, 0x06, 0, 0, 3, 1, 0, 0, 3, 2, 0b1000_0000]
+        );
+        assert_eq!(unescape(&nal[5..]), vec![0, 0, 1, 0, 0, 2, 0b1000_0000]);
+    }
+
+    #[test]
+    fn pps_matches_baseline() {
+        // The PPS x264 and openh264 write for baseline with pic_init_qp 26
+        assert_eq!(pps(), vec![0, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80]);
+    }
+
+    #[test]
+    fn sps_round_trips() {
+        for (width, height, level, crop) in [
+            (1920, 1080, 42, Some((0, 4))),
+            (1280, 720, 41, None),
+            (1366, 768, 42, Some((5, 0))),
+        ] {
+            let config = LVEncoderConfig::new(width, height, 8_000_000, 60.);
+            let nal = LVVaapiEncoder::sps(&config);
+            assert_eq!(nal[..5], [0, 0, 0, 1, 0x67]);
+            assert!(has_sps(&nal));
+
+            let rbsp = unescape(&nal[5..]);
+            let mut bits = BitReader::new(&rbsp);
+            assert_eq!(bits.bits(8), Some(66));
+            assert_eq!(bits.bits(8), Some(0b1100_0000));
+            assert_eq!(bits.bits(8), Some(level));
+            assert_eq!(bits.ue(), Some(0));
+            assert_eq!(bits.ue(), Some(LOG2_MAX_FRAME_NUM_MINUS4));
+            assert_eq!(bits.ue(), Some(0));
+            assert_eq!(bits.ue(), Some(LOG2_MAX_POC_LSB_MINUS4));
+            assert_eq!(bits.ue(), Some(1));
+            assert_eq!(bits.bits(1), Some(0));
+            assert_eq!((bits.ue().unwrap() + 1) * 16, width.div_ceil(16) * 16);
+            assert_eq!((bits.ue().unwrap() + 1) * 16, height.div_ceil(16) * 16);
+            assert_eq!(bits.bits(2), Some(0b11));
+            match crop {
+                Some((right, bottom)) => {
+                    assert_eq!(bits.bits(1), Some(1));
+                    assert_eq!(
+                        [bits.ue(), bits.ue(), bits.ue(), bits.ue()],
+                        [Some(0), Some(right), Some(0), Some(bottom)]
+                    );
+                }
+                None => assert_eq!(bits.bits(1), Some(0)),
+            }
+            // No VUI, then the stop bit
+            assert_eq!(bits.bits(2), Some(0b01));
+        }
+    }
+
+    // Needs a VA-API driver that can encode, LV_VAAPI_DEVICE picks the render node. Passes
+    // without doing anything on machines that have none.
+    #[test]
+    fn encodes_an_idr_with_parameter_sets() {
+        let config = LVEncoderConfig::new(320, 240, 1_000_000, 30.);
+        let mut encoder = match LVVaapiEncoder::new(config) {
+            Ok(encoder) => encoder,
+            Err(e) => {
+                eprintln!("no VA-API encoder, skipping: {}", e);
+                return;
+            }
+        };
+
+        let mut yuv = YUVBuffer::new(320, 240);
+        yuv.yuv.fill(128);
+        let mut out = BytesMut::new().writer();
+        encoder.encode_frame(&yuv, 0, &mut out).unwrap();
+        let data = out.into_inner();
+        assert!(
+            has_sps(&data),
+            "IDR without an SPS: {:02x?}",
+            &data[..64.min(data.len())]
+        );
+    }
+}
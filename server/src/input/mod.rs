@@ -1,7 +1,39 @@
+use log::debug;
 use net::input::LVInputEvent;
 
 pub mod x11;
 
+// Where the streamed area sits on the real desktop. The client sends coordinates relative to
+// the stream, so when only a window or region is captured they need shifting back.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LVInputMapping {
+    pub x_offset: i32,
+    pub y_offset: i32,
+}
+
+impl LVInputMapping {
+    pub fn map(&self, ev: LVInputEvent) -> LVInputEvent {
+        match ev {
+            LVInputEvent::MouseMoveEvent(mut move_ev) => {
+                move_ev.x += self.x_offset as f64;
+                move_ev.y += self.y_offset as f64;
+                LVInputEvent::MouseMoveEvent(move_ev)
+            }
+            ev => ev,
+        }
+    }
+}
+
 pub trait LVInputEmulator: Send {
     fn write_event(&mut self, ev: LVInputEvent) -> Result<(), anyhow::Error>;
 }
+
+// Drops every event. Test patterns and files have no desktop to send input to.
+pub struct LVNullInputEmulator;
+
+impl LVInputEmulator for LVNullInputEmulator {
+    fn write_event(&mut self, ev: LVInputEvent) -> Result<(), anyhow::Error> {
+        debug!("dropping input event {:?}", ev);
+        Ok(())
+    }
+}
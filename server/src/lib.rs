@@ -0,0 +1,9 @@
+// The server pipeline, capture -> encode -> packetize -> send, as a library so it can be run
+// in process next to a client (see the loopback crate). main.rs is the command line on top.
+
+pub mod benchmark;
+pub mod capture;
+pub mod encoder;
+pub mod input;
+pub mod packager;
+pub mod server;
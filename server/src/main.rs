@@ -1,21 +1,22 @@
-use std::net::{Ipv4Addr, SocketAddr};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
 
 use flexi_logger::Logger;
-use input::x11::LVX11InputEmulator;
 use log::debug;
 use server::{
-    feedback_server::LVFeedbackServer, input_server::LVInputServer,
-    streaming_server::LVStreamingServer,
+    benchmark,
+    capture::LVCaptureTarget,
+    encoder,
+    input::{x11::LVX11InputEmulator, LVInputEmulator, LVInputMapping, LVNullInputEmulator},
+    server::{
+        feedback_server::LVFeedbackServer, input_server::LVInputServer,
+        streaming_server::LVStreamingServer,
+    },
 };
 use statistics::collector::LVStatisticsCollector;
 
-mod benchmark;
-mod capture;
-mod encoder;
-mod input;
-mod packager;
-mod server;
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     Logger::try_with_str(
         "trace,server::input=info,server::server::feedback_server=debug,statistics=info,server::server::streaming_server=info, server::server::input_server=info, server::packager=info, server::capture=info, server::encoder=info, net=info",
@@ -24,14 +25,44 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let quit_rx = LVStatisticsCollector::start();
 
     match std::env::args().nth(1).as_deref() {
-        Some("bench") => benchmark::bench(),
+        Some("bench") => match std::env::args().nth(2) {
+            Some(target) => benchmark::bench(&target.parse()?),
+            None => benchmark::bench(&LVCaptureTarget::default()),
+        },
         Some("server") => match std::env::args().nth(2) {
             Some(addr) => {
                 let target_addr = std::env::args().nth(3).unwrap();
 
+                // Optional: which screen, window or region to stream. The client can switch
+                // monitors later through the feedback connection.
+                let capture_target: LVCaptureTarget = match std::env::args().nth(4) {
+                    Some(target) => target.parse()?,
+                    None => LVCaptureTarget::default(),
+                };
+                // Optional: encoders to try in order, e.g. vaapi,openh264. Falls back to
+                // LV_ENCODER, then to everything compiled in.
+                let encoder_preference = match std::env::args().nth(5) {
+                    Some(list) => Some(encoder::registry::parse_preference(&list)?),
+                    None => encoder::registry::preference_from_env()?,
+                };
+                encoder::registry::probe(encoder_preference);
+                let input_emulator: Box<dyn LVInputEmulator> = if capture_target.is_headless() {
+                    Box::new(LVNullInputEmulator)
+                } else {
+                    Box::new(LVX11InputEmulator::new()?)
+                };
+                let capture_target = Arc::new(Mutex::new(capture_target));
+                let framerate = Arc::new(Mutex::new(60));
+                let encoder_backend = Arc::new(Mutex::new(None));
+
                 let mut feedback_addr: SocketAddr = target_addr.parse()?;
                 feedback_addr.set_port(feedback_addr.port() + 2);
-                let feedback_server = LVFeedbackServer::new(&feedback_addr.to_string());
+                let feedback_server = LVFeedbackServer::new(
+                    &feedback_addr.to_string(),
+                    capture_target.clone(),
+                    framerate.clone(),
+                    encoder_backend.clone(),
+                );
 
                 let mut input_addr: SocketAddr = addr.parse()?;
                 let mut input_target_addr: SocketAddr = target_addr.parse()?;
@@ -39,7 +70,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 input_addr.set_port(input_addr.port() + 3);
 
                 let input_server = LVInputServer::new(&input_addr.to_string());
-                let input_emulator = Box::new(LVX11InputEmulator::new()?);
+
+                let input_mapping = Arc::new(Mutex::new(LVInputMapping::default()));
 
                 let bitrate_mtx = feedback_server.begin();
 
@@ -47,26 +79,31 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     &addr,
                     &target_addr,
                     60,
-                    0,
-                    1920,
-                    1080,
+                    capture_target,
                     900000,
                     quit_rx,
                     bitrate_mtx,
+                    input_mapping.clone(),
+                    framerate,
+                    encoder_backend,
+                    feedback_server.recovery(),
+                    feedback_server.recording(),
                 )?;
 
-                input_server.start_receive_loop(input_target_addr, input_emulator);
+                input_server.start_receive_loop(input_target_addr, input_emulator, input_mapping);
                 streaming_server.begin()?;
 
                 Ok(())
             }
             None => {
-                println!("Usage: ./server {{bench|server}} bind_addr target_addr");
+                println!(
+                    "Usage: ./server {{bench|server}} bind_addr target_addr [screen:N|screen:all|window:ID|title:TEXT|region:X,Y,WxH|pattern:bars|noise|scroll|timestamp[,WxH]|file:PATH[,WxH]] [encoder,...]"
+                );
                 Ok(())
             }
         },
         _ => {
-            println!("Usage: ./server {{bench|server}} (options)");
+            println!("Usage: ./server {{bench [target]|server}} (options)");
             Ok(())
         }
     }
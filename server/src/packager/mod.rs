@@ -1,14 +1,22 @@
-use std::{collections::VecDeque, fs::File, io::Write, net::UdpSocket, time::Instant};
+use std::{collections::VecDeque, time::Instant};
 
 use bytes::{buf::Writer, BufMut, Bytes, BytesMut};
 use dcv_color_primitives::{convert_image, get_buffers_size, ColorSpace, ImageFormat};
-use image::{ImageBuffer, Rgb};
-use log::{debug, trace};
-use net::packet::{LVErasureInformation, MTU_SIZE};
+use log::{debug, error, info, trace};
+use net::{
+    clock,
+    codec::{LVCodec, LVEncoderBackend},
+    impair::LVImpairedSocket,
+    packet::{
+        LVErasureInformation, LVFrameId, LVFrameSize, LVFrameTimestamps, LVRefineHeader,
+        LVRefineKind, FRAME_EXTENSIONS_BYTES, FRAME_ID_EXTENSION_ID, FRAME_SIZE_EXTENSION_ID,
+        FRAME_TIMESTAMPS_EXTENSION_ID, MTU_SIZE, REFINE_PAYLOAD_TYPE,
+    },
+};
 use openh264::formats::{YUVBuffer, YUVSource};
 use rand::Rng;
 use rtp::{
-    codecs::h264::H264Payloader,
+    codecs::{av1::Av1Payloader, h264::H264Payloader},
     header::Header,
     packet::Packet,
     packetizer::{Packetizer, Payloader},
@@ -20,13 +28,28 @@ use statistics::{
 };
 use webrtc_util::{Marshal, MarshalSize};
 
-use crate::encoder::LVEncoder;
+use crate::{
+    capture::LVFrame,
+    encoder::{self, roi::LVRoiMap, LVEncoder, LVEncoderConfig},
+};
 
-use self::packet::LVErasureManager;
+use self::{
+    packet::LVErasureManager,
+    record::{LVRecordConfig, LVRecorder},
+    refine::{LVRefineUpdate, LVRefinedTile, LVRefiner},
+    scaler::LVFrameScaler,
+};
 
 pub mod packet;
+pub mod record;
+pub mod refine;
+pub mod scaler;
 
 const SAMPLE_RATE: u32 = 90000;
+// Refinement packets carry no extensions, just the fixed RTP header and our own.
+const RTP_HEADER_BYTES: usize = 12;
+const REFINE_CHUNK_BYTES: usize =
+    MTU_SIZE - LVErasureInformation::no_bytes() - RTP_HEADER_BYTES - LVRefineHeader::no_bytes();
 
 // TODO update the error handling
 
@@ -40,9 +63,30 @@ pub struct LVPackager {
     rtp_queue: VecDeque<Packet>,
     packetizer: Box<dyn Packetizer>,
     erasure_manager: LVErasureManager,
-    file: File,
+    scaler: LVFrameScaler,
     rtp_pkt: BytesMut,
     fps: u32,
+
+    // Id of the next frame that comes out of the encoder
+    frame_id: u32,
+    // Id of the first frame the current encoder produced, the encoder counts its frames from
+    // there.
+    encoder_first_frame: u32,
+    // The next frame is a recovery point: the first from a new encoder, or the first after
+    // the encoder acted on a loss report.
+    recovery_point: bool,
+
+    // Lossless refinement of static tiles, if it's on
+    refiner: Option<LVRefiner>,
+    refine_ssrc: u32,
+    refine_seq: u16,
+
+    // Favour the pointer and the focused window, on encoders that can
+    roi: bool,
+
+    // Writes the encoded frames to disk while recording
+    record_config: LVRecordConfig,
+    recorder: Option<LVRecorder>,
 }
 
 //
@@ -50,10 +94,23 @@ impl LVPackager {
     pub fn new(encoder: Box<dyn LVEncoder>, fps: u32) -> Result<Self, Box<dyn std::error::Error>> {
         let width = encoder.width() as usize;
         let height = encoder.height() as usize;
+        let codec = encoder.codec();
         let mut rand = rand::thread_rng();
 
+        // The client picks its depacketizer and decoder from the payload type.
+        let payloader: Box<dyn Payloader + Send + Sync> = match codec {
+            LVCodec::H264 => Box::new(H264Payloader::default()),
+            LVCodec::Av1 => Box::new(Av1Payloader::default()),
+        };
+
+        LVStatisticsCollector::register_data("server_convert_frame", LVDataType::TimeSeries);
         LVStatisticsCollector::register_data("server_packetization", LVDataType::TimeSeries);
         LVStatisticsCollector::register_data("server_queuing", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("server_scale_divisor", LVDataType::TimeSeries);
+
+        let (record_config, record) = LVRecordConfig::from_env()?;
+        let recorder =
+            record.then(|| LVRecorder::new(record_config.clone(), width as u32, height as u32));
 
         Ok(Self {
             encoder,
@@ -61,31 +118,92 @@ impl LVPackager {
             h264_bitstream_writer: BytesMut::new().writer(),
             rtp_queue: VecDeque::new(),
             yuv_buffer: YUVBuffer::new(width, height),
+            // Leave room for the extensions every packet carries.
             packetizer: Box::new(rtp::packetizer::new_packetizer(
-                MTU_SIZE - LVErasureInformation::no_bytes(),
-                96,
+                MTU_SIZE - LVErasureInformation::no_bytes() - FRAME_EXTENSIONS_BYTES,
+                codec.payload_type(),
                 rand.gen_range(0..u32::MAX),
-                Box::new(H264Payloader::default()),
+                payloader,
                 Box::new(new_random_sequencer()),
                 SAMPLE_RATE,
             )),
-            file: File::create("cap.h264")?,
             rtp_pkt: BytesMut::new(),
             fps,
             erasure_manager: LVErasureManager::new()?,
+            scaler: LVFrameScaler::new(),
+            frame_id: 1,
+            encoder_first_frame: 1,
+            recovery_point: true,
+            refiner: refine::enabled_from_env().then(LVRefiner::new),
+            refine_ssrc: rand.gen_range(0..u32::MAX),
+            refine_seq: 0,
+            roi: encoder::roi::enabled_from_env(),
+            record_config,
+            recorder,
         })
     }
 
     // Encode frame and add to RTP queue
     pub fn process_frame(
         &mut self,
-        buffer: ImageBuffer<Rgb<u8>, Vec<u8>>,
+        buffer: &LVFrame,
         timestamp: u64,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        // At low enough bitrates fewer pixels look better than a smeared full size frame. The
+        // scaled frame has a different size, so the encoder gets rebuilt below.
+        if self.scaler.update(
+            self.encoder.bitrate(),
+            self.fps,
+            buffer.width(),
+            buffer.height(),
+        ) {
+            LVStatisticsCollector::update_data(
+                "server_scale_divisor",
+                LVDataPoint::FloatValue(self.scaler.divisor() as f32),
+            );
+        }
+        let source = buffer;
+        let scaled;
+        let buffer = match self.scaler.divisor() {
+            1 => source,
+            _ => {
+                scaled = self.scaler.downscale(source);
+                &scaled
+            }
+        };
+
+        if buffer.width() != self.encoder.width() || buffer.height() != self.encoder.height() {
+            self.resize(buffer.width(), buffer.height())?;
+        }
+
+        // Refining a downscaled frame doesn't get the text back, so it only runs at full size.
+        let bitrate = self.encoder.bitrate();
+        let refine_update = match self.refiner.as_mut() {
+            Some(refiner) if self.scaler.divisor() == 1 => {
+                refiner.update(buffer, bitrate, self.fps)
+            }
+            Some(refiner) => {
+                refiner.reset();
+                LVRefineUpdate::default()
+            }
+            None => LVRefineUpdate::default(),
+        };
+
         let pre_enc = Instant::now();
-        // Convert RGBA8 to YUV420
-        self.encoder.convert_frame(buffer, &mut self.yuv_buffer);
+        // Convert BGRA8 to YUV420, reading directly from the capture buffer
+        self.encoder.convert_frame(buffer, &mut self.yuv_buffer)?;
         debug!("convert image sequence is {:.4?}", pre_enc.elapsed());
+        LVStatisticsCollector::update_data(
+            "server_convert_frame",
+            LVDataPoint::TimeElapsed(pre_enc.elapsed()),
+        );
+
+        if self.uses_roi() {
+            let roi = buffer
+                .focus()
+                .and_then(|focus| LVRoiMap::from_focus(buffer.width(), buffer.height(), &focus));
+            self.encoder.set_roi(roi.as_ref());
+        }
 
         let pre_enc = Instant::now();
         let bit_stream = self.encoder.encode_frame(
@@ -104,8 +222,9 @@ impl LVPackager {
         let pre_enc = Instant::now();
         let unpacketized_payload: Bytes = Bytes::from(self.h264_bitstream_writer.get_mut().split());
 
-        // write this for debugging purposes
-        // self.file.write(&unpacketized_payload);
+        if !unpacketized_payload.is_empty() {
+            self.record_frame(&unpacketized_payload, buffer.capture_ts());
+        }
 
         debug!("unpacketized_payload len is {}", unpacketized_payload.len());
 
@@ -120,9 +239,52 @@ impl LVPackager {
 
         debug!("packetization: {:.4?}", pre_enc.elapsed());
 
+        // Everything from the end of the capture until now counts as encoding, including the
+        // time the frame spent waiting for us.
+        let capture_done = buffer.capture_ts() + buffer.capture_time().as_micros() as u64;
+        let timestamps = LVFrameTimestamps {
+            capture_ts: buffer.capture_ts(),
+            capture_us: buffer.capture_time().as_micros() as u32,
+            encode_us: clock::now_us().saturating_sub(capture_done) as u32,
+        };
+        let timestamps_bytes = Bytes::copy_from_slice(&timestamps.to_bytes());
+        let size = LVFrameSize {
+            source_width: source.width() as u16,
+            source_height: source.height() as u16,
+            width: buffer.width() as u16,
+            height: buffer.height() as u16,
+        };
+        let size_bytes = Bytes::copy_from_slice(&size.to_bytes());
+        // Frames the encoder skipped have no packets, so they don't get an id either or the
+        // client would think it lost them.
+        let frame_id = LVFrameId {
+            id: self.frame_id,
+            recovery_point: self.recovery_point,
+        };
+        let frame_id_bytes = Bytes::copy_from_slice(&frame_id.to_bytes());
+        if !payloads.is_empty() {
+            self.frame_id += 1;
+            self.recovery_point = false;
+        }
+
         let pre_enc = Instant::now();
+        // The client has to stop drawing refined tiles before it shows the frame they changed
+        // in, so those go first.
+        self.queue_refine_clears(&refine_update.cleared);
+
         let mut packet_count = 0;
-        for payload in payloads {
+        for mut payload in payloads {
+            // Every packet gets a copy so the client still has them if the first one is lost.
+            payload
+                .header
+                .set_extension(FRAME_TIMESTAMPS_EXTENSION_ID, timestamps_bytes.clone())?;
+            payload
+                .header
+                .set_extension(FRAME_SIZE_EXTENSION_ID, size_bytes.clone())?;
+            payload
+                .header
+                .set_extension(FRAME_ID_EXTENSION_ID, frame_id_bytes.clone())?;
+
             // Marshal into RTP.
             trace!("packet payload data: {:?}", &payload.payload.as_ref());
             trace!(
@@ -133,6 +295,10 @@ impl LVPackager {
             packet_count += 1;
         }
         debug!("wrote {} RTP packets into queue", packet_count);
+
+        for tile in &refine_update.tiles {
+            self.queue_refine_tile(tile);
+        }
         LVStatisticsCollector::update_data(
             "server_queuing",
             LVDataPoint::TimeElapsed(pre_enc.elapsed()),
@@ -141,9 +307,61 @@ impl LVPackager {
         Ok(())
     }
 
+    fn queue_refine_packet(&mut self, header: LVRefineHeader, data: &[u8]) {
+        let mut payload = BytesMut::with_capacity(LVRefineHeader::no_bytes() + data.len());
+        payload.extend_from_slice(&header.to_bytes());
+        payload.extend_from_slice(data);
+        let packet = Packet {
+            header: Header {
+                version: 2,
+                payload_type: REFINE_PAYLOAD_TYPE,
+                sequence_number: self.refine_seq,
+                ssrc: self.refine_ssrc,
+                ..Default::default()
+            },
+            payload: payload.freeze(),
+        };
+        self.refine_seq = self.refine_seq.wrapping_add(1);
+        self.rtp_queue.push_front(packet);
+    }
+
+    fn queue_refine_clears(&mut self, cleared: &[(u16, u16)]) {
+        for tiles in cleared.chunks(REFINE_CHUNK_BYTES / 4) {
+            let mut data = Vec::with_capacity(4 * tiles.len());
+            for (x, y) in tiles {
+                data.extend_from_slice(&x.to_be_bytes());
+                data.extend_from_slice(&y.to_be_bytes());
+            }
+            let header = LVRefineHeader {
+                kind: LVRefineKind::Clear,
+                tile_id: 0,
+                chunk: 0,
+                chunks: 1,
+                x: 0,
+                y: 0,
+            };
+            self.queue_refine_packet(header, &data);
+        }
+    }
+
+    fn queue_refine_tile(&mut self, tile: &LVRefinedTile) {
+        let chunks = tile.data.len().div_ceil(REFINE_CHUNK_BYTES);
+        for (chunk, bytes) in tile.data.chunks(REFINE_CHUNK_BYTES).enumerate() {
+            let header = LVRefineHeader {
+                kind: LVRefineKind::Tile,
+                tile_id: tile.id,
+                chunk: chunk as u16,
+                chunks: chunks as u16,
+                x: tile.x,
+                y: tile.y,
+            };
+            self.queue_refine_packet(header, bytes);
+        }
+    }
+
     pub fn send_next_pkt(
         &mut self,
-        socket: &UdpSocket,
+        socket: &LVImpairedSocket,
         target_addr: &str,
     ) -> Result<usize, Box<dyn std::error::Error>> {
         if let Some(pkt) = self.rtp_queue.pop_back() {
@@ -164,6 +382,143 @@ impl LVPackager {
         self.encoder.set_bitrate(new_bitrate)
     }
 
+    // The RTP timestamps advance by a frame's worth of clock ticks, so they have to follow
+    // the encoder's framerate.
+    pub fn update_framerate(&mut self, fps: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.encoder.set_framerate(fps as f32)?;
+        self.fps = fps;
+        Ok(())
+    }
+
+    // The client throws its refined tiles away when it loses a frame or refinement packets,
+    // they all have to be sent again.
+    pub fn forget_refinements(&mut self) {
+        if let Some(refiner) = self.refiner.as_mut() {
+            refiner.forget();
+        }
+    }
+
+    // The client is drawing the refined tile it got as tile_id.
+    pub fn confirm_refinement(&mut self, tile_id: u32) {
+        if let Some(refiner) = self.refiner.as_mut() {
+            refiner.confirm(tile_id);
+        }
+    }
+
+    // The next frame starts a refresh wave or is an IDR, depending on the encoder config.
+    pub fn request_recovery(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.encoder.request_recovery()?;
+        self.recovery_point = true;
+        Ok(())
+    }
+
+    // The client decoded everything up to this frame id.
+    pub fn confirm_frame(&mut self, frame_id: u32) {
+        if frame_id >= self.encoder_first_frame {
+            self.encoder
+                .confirm_frame(frame_id - self.encoder_first_frame);
+        }
+    }
+
+    // The client lost what came after last_good. Encodes against last_good if the encoder
+    // still has it as a reference, otherwise falls back to request_recovery. Returns true if
+    // that avoided a keyframe.
+    pub fn invalidate_frames(
+        &mut self,
+        last_good: u32,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        // Frames from before the encoder was rebuilt aren't references anymore.
+        let referenced = last_good >= self.encoder_first_frame
+            && self
+                .encoder
+                .invalidate_frames(last_good - self.encoder_first_frame)?;
+        if !referenced {
+            return self.request_recovery().map(|()| false);
+        }
+        self.recovery_point = true;
+        Ok(true)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Recording starts at the next keyframe, which is asked for right away.
+    pub fn set_recording(&mut self, record: bool) {
+        match (record, self.recorder.is_some()) {
+            (true, false) => {
+                self.recorder = Some(LVRecorder::new(
+                    self.record_config.clone(),
+                    self.encoder.width(),
+                    self.encoder.height(),
+                ))
+            }
+            (false, true) => self.recorder = None,
+            _ => {}
+        }
+    }
+
+    fn record_frame(&mut self, data: &[u8], capture_ts: u64) {
+        let Some(recorder) = self.recorder.as_mut() else {
+            return;
+        };
+        match recorder.write_frame(
+            self.encoder.codec(),
+            self.encoder.codec_config(),
+            data,
+            capture_ts,
+        ) {
+            Ok(true) => {
+                if let Err(e) = self.encoder.request_keyframe() {
+                    error!("failed to request a keyframe for the recording {:?}", e);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => {
+                error!("recording failed, stopping it: {:?}", e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    // Can change after a resize if the registry had to fall back to another backend.
+    pub fn encoder_backend(&self) -> LVEncoderBackend {
+        self.encoder.backend()
+    }
+
+    // Whether frames need their focus filled in
+    pub fn uses_roi(&self) -> bool {
+        self.roi && self.encoder.supports_roi()
+    }
+
+    // Replace the encoder with one for the new frame size. The new encoder starts with an IDR
+    // whose SPS carries the new resolution, which is how the client finds out about it.
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<(), Box<dyn std::error::Error>> {
+        info!(
+            "frame size changed from {}x{} to {}x{}, rebuilding encoder",
+            self.encoder.width(),
+            self.encoder.height(),
+            width,
+            height
+        );
+        // Keep whatever the rate controller changed at runtime.
+        self.encoder = encoder::default_encoder(LVEncoderConfig {
+            width,
+            height,
+            ..self.encoder.config()
+        })?;
+        self.yuv_buffer = YUVBuffer::new(width as usize, height as usize);
+        // Anything left over was encoded for the old size.
+        self.h264_bitstream_writer.get_mut().clear();
+        // The new encoder starts over with an IDR.
+        self.encoder_first_frame = self.frame_id;
+        self.recovery_point = true;
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.resize(width, height);
+        }
+        Ok(())
+    }
+
     // pub fn encrypt();
     // pub fn error_correct();
 }
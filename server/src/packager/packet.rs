@@ -10,14 +10,11 @@ use lazy_static::lazy_static;
 use log::{debug, trace};
 use reed_solomon_simd::ReedSolomonEncoder;
 use rtp::packet::Packet;
-use std::{
-    net::UdpSocket,
-    ops::Index,
-    slice::SliceIndex,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::{ops::Index, slice::SliceIndex};
 use webrtc_util::{Marshal, MarshalSize};
 
+use net::clock;
+use net::impair::LVImpairedSocket;
 use net::packet::{
     LVErasureInformation, EC_RATIO_RECOVERY_PACKETS, EC_RATIO_REGULAR_PACKETS, SIMD_PACKET_SIZE,
 };
@@ -59,7 +56,7 @@ impl LVErasureManager {
     // if the encoder gave us some.
     pub fn send_lv_packet(
         &mut self,
-        socket: &UdpSocket,
+        socket: &LVImpairedSocket,
         target_addr: &str,
         rtp: Packet,
     ) -> Result<usize, Box<dyn std::error::Error>> {
@@ -90,10 +87,7 @@ impl LVErasureManager {
                     min_fragment_size: EC_RATIO_REGULAR_PACKETS,
                     recovery_pkt: true,
                     pkt_sizes: self.pkt_sizes,
-                    send_timestamp: SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis(),
+                    send_timestamp: clock::now_us() as u128,
                 };
 
                 debug!("recovery header is {:?}", recovery_header);
@@ -148,10 +142,7 @@ impl LVErasureManager {
 
         // Doing the timestamp here will make it more reliable and not include
         // the time for the RS encoder.
-        pk.send_timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
+        pk.send_timestamp = clock::now_us() as u128;
         pk.to_bytes(&mut self.pkt_data);
 
         self.pkt_sizes[pk.fragment_index as usize] = marshal_size as u16;
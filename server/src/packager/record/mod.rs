@@ -0,0 +1,216 @@
+// Recording the outgoing stream to disk. The encoded access units are muxed as they are, with
+// their capture timestamps, into fragmented MP4 or Matroska (see net::container).
+
+use std::{fs::File, io::BufWriter, path::PathBuf, time::Duration};
+
+use anyhow::anyhow;
+use log::{info, warn};
+use net::{
+    codec::LVCodec,
+    container::{self, LVContainerFormat, LVMuxer, LVTrackInfo},
+};
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+
+// Where recordings go when recording is turned on at runtime without LV_RECORD.
+const DEFAULT_PATH: &str = "recording.mkv";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LVRecordConfig {
+    // Every file gets the time it was started at appended to its name, so rotating never
+    // overwrites anything.
+    pub path: PathBuf,
+    pub format: LVContainerFormat,
+    // Start a new file once the current one gets this big or this long, at the next keyframe.
+    pub max_bytes: Option<u64>,
+    pub max_duration: Option<Duration>,
+}
+
+impl LVRecordConfig {
+    // The format comes from the extension, .mp4 or .mkv (or .webm for AV1).
+    pub fn new(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let format = LVContainerFormat::from_path(&path)
+            .ok_or_else(|| anyhow!("can't tell the format of {:?}, use .mp4 or .mkv", path))?;
+        Ok(Self {
+            path,
+            format,
+            max_bytes: None,
+            max_duration: None,
+        })
+    }
+
+    // LV_RECORD=PATH records from the start, LV_RECORD_MAX_MB and LV_RECORD_MAX_MINUTES rotate.
+    // Without LV_RECORD recording can still be turned on at runtime, into recording.mkv.
+    pub fn from_env() -> Result<(Self, bool), Box<dyn std::error::Error>> {
+        let (path, record) = match std::env::var("LV_RECORD") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_PATH), false),
+        };
+        let mut config = Self::new(path)?;
+        if let Ok(mb) = std::env::var("LV_RECORD_MAX_MB") {
+            config.max_bytes = Some(mb.parse::<u64>()? * 1024 * 1024);
+        }
+        if let Ok(minutes) = std::env::var("LV_RECORD_MAX_MINUTES") {
+            config.max_duration = Some(Duration::from_secs_f32(minutes.parse::<f32>()? * 60.));
+        }
+        Ok((config, record))
+    }
+}
+
+struct LVRecording {
+    path: PathBuf,
+    muxer: Box<dyn LVMuxer>,
+    // Capture timestamp (us since the epoch) of the first frame
+    first_ts: u64,
+    last_ts: u64,
+}
+
+// Writes frames into the current file and starts the next one when it's due. Files have to
+// start with a keyframe, so a new file waits for one and write_frame asks for it.
+pub struct LVRecorder {
+    config: LVRecordConfig,
+    recording: Option<LVRecording>,
+    // The size the encoder is producing, set by the packager
+    width: u32,
+    height: u32,
+    // The next file is due, switch over at the next keyframe
+    rotate: bool,
+    keyframe_requested: bool,
+}
+
+impl LVRecorder {
+    pub fn new(config: LVRecordConfig, width: u32, height: u32) -> Self {
+        LVStatisticsCollector::register_data("server_record_bytes", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("server_record_files", LVDataType::Aggregate);
+        info!("recording to {:?}", config.path);
+        Self {
+            config,
+            recording: None,
+            width,
+            height,
+            rotate: false,
+            keyframe_requested: false,
+        }
+    }
+
+    // The encoder was rebuilt for another size, which the track in the current file can't
+    // describe.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.finish();
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(mut recording) = self.recording.take() {
+            match recording.muxer.finish() {
+                Ok(()) => info!(
+                    "finished recording {:?}, {} bytes over {:.1?}",
+                    recording.path,
+                    recording.muxer.bytes_written(),
+                    Duration::from_micros(recording.last_ts - recording.first_ts)
+                ),
+                Err(e) => warn!("failed to finish recording {:?}: {:?}", recording.path, e),
+            }
+        }
+        self.rotate = false;
+        self.keyframe_requested = false;
+    }
+
+    // Takes one encoded access unit as the encoder produced it (Annex B or an OBU stream).
+    // codec_config is the encoder's av1C for AV1, H.264's is built from the SPS and PPS.
+    // Returns true if the recorder is waiting for a keyframe the encoder hasn't been asked
+    // for yet.
+    pub fn write_frame(
+        &mut self,
+        codec: LVCodec,
+        codec_config: Option<Vec<u8>>,
+        data: &[u8],
+        capture_ts: u64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let (sample, keyframe, parameter_sets) = container::sample(codec, data);
+
+        if let Some(recording) = self.recording.as_ref() {
+            let duration = Duration::from_micros(capture_ts.saturating_sub(recording.first_ts));
+            if self
+                .config
+                .max_bytes
+                .is_some_and(|max| recording.muxer.bytes_written() >= max)
+                || self.config.max_duration.is_some_and(|max| duration >= max)
+            {
+                self.rotate = true;
+            }
+        }
+        if keyframe && (self.rotate || self.recording.is_none()) {
+            self.finish();
+            let codec_config = match (codec, codec_config) {
+                (_, Some(config)) => Some(config),
+                (LVCodec::H264, None) => {
+                    parameter_sets.and_then(|(sps, pps)| container::avcc(sps, pps))
+                }
+                (LVCodec::Av1, None) => None,
+            };
+            match codec_config {
+                Some(codec_config) => self.start(
+                    LVTrackInfo {
+                        codec,
+                        width: self.width,
+                        height: self.height,
+                        codec_config,
+                    },
+                    capture_ts,
+                )?,
+                None => warn!("keyframe without the codec configuration, can't start a file"),
+            }
+        }
+
+        let Some(recording) = self.recording.as_mut() else {
+            return Ok(!std::mem::replace(&mut self.keyframe_requested, true));
+        };
+        // Capture timestamps only go backwards if the clock does, keep the file monotonic.
+        let capture_ts = capture_ts.max(recording.last_ts);
+        recording
+            .muxer
+            .write_frame(&sample, capture_ts - recording.first_ts, keyframe)?;
+        recording.last_ts = capture_ts;
+        LVStatisticsCollector::update_data(
+            "server_record_bytes",
+            LVDataPoint::FloatValue(sample.len() as f32),
+        );
+
+        if self.rotate {
+            return Ok(!std::mem::replace(&mut self.keyframe_requested, true));
+        }
+        Ok(false)
+    }
+
+    fn start(
+        &mut self,
+        track: LVTrackInfo,
+        first_ts: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let path = container::file_path(&self.config.path);
+        let file = BufWriter::new(File::create(&path)?);
+        let muxer = container::new_muxer(self.config.format, file, &track)?;
+        info!(
+            "recording {:?} {}x{} to {:?}",
+            track.codec, track.width, track.height, path
+        );
+        LVStatisticsCollector::update_data("server_record_files", LVDataPoint::Increment);
+        self.recording = Some(LVRecording {
+            path,
+            muxer,
+            first_ts,
+            last_ts: first_ts,
+        });
+        Ok(())
+    }
+}
+
+impl Drop for LVRecorder {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -0,0 +1,346 @@
+use log::{debug, info};
+use net::packet::REFINE_TILE_SIZE;
+use statistics::{
+    collector::LVStatisticsCollector,
+    statistics::{LVDataPoint, LVDataType},
+};
+
+use crate::capture::LVFrame;
+
+// A tile has to sit still this many frames before it's worth refining, otherwise we'd be
+// sending lossless copies of something that's about to change again.
+const STATIC_FRAMES: u32 = 10;
+// Only refine while most of the screen is static (a cursor blinking, someone typing). With
+// more damage than this the bits are better spent on the video.
+const MAX_DAMAGE: f32 = 0.05;
+// Share of the video bitrate refinement gets to spend on top of it.
+const REFINE_SHARE: f32 = 0.25;
+// Caps what can be saved up while nothing needs refining, so a static screen doesn't turn
+// into one big burst once something does.
+const MAX_BUDGET: f32 = 64. * 1024.;
+// A tile the client hasn't confirmed after this many frames is sent again. Usually a loss
+// shows up as a gap in the sequence numbers first, but not if it was the last packet.
+const ACK_FRAMES: u32 = 60;
+
+// LV_REFINE=1 sends lossless copies of static parts of the screen. Mostly for text, which
+// H.264 blurs at the bitrates we run at.
+pub fn enabled_from_env() -> bool {
+    matches!(std::env::var("LV_REFINE").as_deref(), Ok("1") | Ok("true"))
+}
+
+#[derive(Clone, Copy, Default)]
+struct LVTileState {
+    unchanged_for: u32,
+    // The client confirmed it has a lossless copy of this tile
+    refined: bool,
+    // The id we sent the tile as and how many frames ago, until the client confirms it
+    sent: Option<(u32, u32)>,
+}
+
+// A QOI (RGBA) image of the tile at x, y.
+pub struct LVRefinedTile {
+    pub id: u32,
+    pub x: u16,
+    pub y: u16,
+    pub data: Vec<u8>,
+}
+
+// What the client has to be told about after a frame.
+#[derive(Default)]
+pub struct LVRefineUpdate {
+    // Tiles the client has (or might have) a refined copy of that changed in this frame
+    pub cleared: Vec<(u16, u16)>,
+    pub tiles: Vec<LVRefinedTile>,
+}
+
+// Finds the parts of the screen that stopped changing and encodes them losslessly, within a
+// budget that's a share of the video bitrate.
+pub struct LVRefiner {
+    width: u32,
+    height: u32,
+    columns: u32,
+    // The last frame, to find which tiles changed
+    previous: Vec<u8>,
+    tiles: Vec<LVTileState>,
+    // Bytes we can still send
+    budget: f32,
+    // Only goes up, the client keeps partial tiles apart by it
+    next_tile_id: u32,
+}
+
+impl LVRefiner {
+    pub fn new() -> Self {
+        LVStatisticsCollector::register_data("server_refine_tiles", LVDataType::Aggregate);
+        LVStatisticsCollector::register_data("server_refine_bytes", LVDataType::TimeSeries);
+        Self {
+            width: 0,
+            height: 0,
+            columns: 0,
+            previous: Vec::new(),
+            tiles: Vec::new(),
+            budget: 0.,
+            next_tile_id: 0,
+        }
+    }
+
+    // Starts over, e.g. because frames stopped being sent at the captured size.
+    pub fn reset(&mut self) {
+        self.width = 0;
+        self.height = 0;
+        self.previous.clear();
+        self.tiles.clear();
+    }
+
+    // The client dropped its refined tiles (it lost a frame or refinement packets), so they
+    // all need sending again.
+    pub fn forget(&mut self) {
+        for tile in self.tiles.iter_mut() {
+            tile.refined = false;
+            tile.sent = None;
+        }
+    }
+
+    // The client has the tile we sent as tile_id. Ignored if the tile changed or was sent
+    // again since.
+    pub fn confirm(&mut self, tile_id: u32) {
+        if let Some(tile) = self
+            .tiles
+            .iter_mut()
+            .find(|tile| tile.sent.is_some_and(|(id, _)| id == tile_id))
+        {
+            tile.refined = true;
+            tile.sent = None;
+        }
+    }
+
+    fn tile_rect(&self, index: usize) -> (u32, u32, u32, u32) {
+        let x = (index as u32 % self.columns) * REFINE_TILE_SIZE;
+        let y = (index as u32 / self.columns) * REFINE_TILE_SIZE;
+        (
+            x,
+            y,
+            REFINE_TILE_SIZE.min(self.width - x),
+            REFINE_TILE_SIZE.min(self.height - y),
+        )
+    }
+
+    fn tile_changed(&self, frame: &[u8], stride: usize, index: usize) -> bool {
+        let (x, y, width, height) = self.tile_rect(index);
+        (y..y + height).any(|row| {
+            let start = row as usize * stride + 4 * x as usize;
+            let end = start + 4 * width as usize;
+            frame[start..end] != self.previous[start..end]
+        })
+    }
+
+    // Copies the tile out as RGBA, the order the client's frame buffer is in, and compresses
+    // it.
+    fn encode_tile(&mut self, frame: &[u8], stride: usize, index: usize) -> LVRefinedTile {
+        let (x, y, width, height) = self.tile_rect(index);
+        let mut rgba = Vec::with_capacity(4 * (width * height) as usize);
+        for row in y..y + height {
+            let start = row as usize * stride + 4 * x as usize;
+            for bgra in frame[start..start + 4 * width as usize].chunks_exact(4) {
+                rgba.extend_from_slice(&[bgra[2], bgra[1], bgra[0], 255]);
+            }
+        }
+        let id = self.next_tile_id;
+        self.next_tile_id = self.next_tile_id.wrapping_add(1);
+        LVRefinedTile {
+            id,
+            x: x as u16,
+            y: y as u16,
+            data: qoi::encode_to_vec(&rgba, width, height)
+                .expect("tile size always matches its pixels"),
+        }
+    }
+
+    pub fn update(&mut self, frame: &LVFrame, bitrate: u32, fps: u32) -> LVRefineUpdate {
+        let mut update = LVRefineUpdate::default();
+        let pixels = frame.as_bytes();
+        let stride = frame.stride();
+
+        if frame.width() != self.width || frame.height() != self.height {
+            info!(
+                "refining {}x{} frames in {}px tiles",
+                frame.width(),
+                frame.height(),
+                REFINE_TILE_SIZE
+            );
+            self.width = frame.width();
+            self.height = frame.height();
+            self.columns = self.width.div_ceil(REFINE_TILE_SIZE);
+            let rows = self.height.div_ceil(REFINE_TILE_SIZE);
+            self.tiles = vec![LVTileState::default(); (self.columns * rows) as usize];
+            self.previous = pixels.to_vec();
+            return update;
+        }
+
+        let mut damaged = 0;
+        for index in 0..self.tiles.len() {
+            if self.tile_changed(pixels, stride, index) {
+                damaged += 1;
+                let tile = &mut self.tiles[index];
+                tile.unchanged_for = 0;
+                if tile.refined || tile.sent.is_some() {
+                    tile.refined = false;
+                    tile.sent = None;
+                    let (x, y, _, _) = self.tile_rect(index);
+                    update.cleared.push((x as u16, y as u16));
+                }
+            } else {
+                let tile = &mut self.tiles[index];
+                tile.unchanged_for = tile.unchanged_for.saturating_add(1);
+                if let Some((_, frames)) = tile.sent.as_mut() {
+                    *frames += 1;
+                }
+            }
+        }
+        self.previous.copy_from_slice(pixels);
+
+        self.budget =
+            (self.budget + bitrate as f32 / 8. / fps.max(1) as f32 * REFINE_SHARE).min(MAX_BUDGET);
+        let damage = damaged as f32 / self.tiles.len() as f32;
+        if damage > MAX_DAMAGE {
+            return update;
+        }
+
+        // The budget can go negative by one tile, that gets paid back over the next frames.
+        let mut sent = 0;
+        for index in 0..self.tiles.len() {
+            if self.budget <= 0. {
+                break;
+            }
+            let tile = self.tiles[index];
+            if tile.refined
+                || tile.unchanged_for < STATIC_FRAMES
+                || tile.sent.is_some_and(|(_, frames)| frames < ACK_FRAMES)
+            {
+                continue;
+            }
+            let refined = self.encode_tile(pixels, stride, index);
+            self.budget -= refined.data.len() as f32;
+            sent += refined.data.len();
+            self.tiles[index].sent = Some((refined.id, 0));
+            update.tiles.push(refined);
+            LVStatisticsCollector::update_data("server_refine_tiles", LVDataPoint::Increment);
+        }
+        if sent > 0 {
+            debug!("refined {} tiles in {} bytes", update.tiles.len(), sent);
+            LVStatisticsCollector::update_data(
+                "server_refine_bytes",
+                LVDataPoint::FloatValue(sent as f32),
+            );
+        }
+
+        update
+    }
+}
+
+impl Default for LVRefiner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    // Plenty of budget for both tiles every frame
+    const BITRATE: u32 = 10_000_000;
+
+    // 128x64, so two tiles side by side. Pixels in the left tile are left_value.
+    fn make_frame(left_value: u8) -> LVFrame {
+        let mut buffer = Vec::new();
+        for _ in 0..64 {
+            buffer.extend_from_slice(&[left_value; 4 * 64]);
+            buffer.extend_from_slice(&[0; 4 * 64]);
+        }
+        LVFrame::new(128, 64, Arc::new(buffer))
+    }
+
+    // Runs a new refiner's first frame through until its tiles are worth refining.
+    fn settle(refiner: &mut LVRefiner, frame: &LVFrame) -> LVRefineUpdate {
+        for _ in 0..STATIC_FRAMES {
+            let update = refiner.update(frame, BITRATE, 30);
+            assert!(update.tiles.is_empty() && update.cleared.is_empty());
+        }
+        refiner.update(frame, BITRATE, 30)
+    }
+
+    #[test]
+    fn static_tiles_wait_for_confirmation() {
+        let mut refiner = LVRefiner::new();
+        let frame = make_frame(0x40);
+        let update = settle(&mut refiner, &frame);
+        let positions: Vec<_> = update.tiles.iter().map(|tile| (tile.x, tile.y)).collect();
+        assert_eq!(positions, [(0, 0), (64, 0)]);
+        assert_ne!(update.tiles[0].id, update.tiles[1].id);
+        let (header, rgba) = qoi::decode_to_vec(&update.tiles[0].data).unwrap();
+        assert_eq!((header.width, header.height), (64, 64));
+        assert_eq!(&rgba[..4], [0x40, 0x40, 0x40, 255]);
+
+        // Not sent again while the client might still be putting them together, and only the
+        // confirmed one counts as refined
+        refiner.confirm(update.tiles[0].id);
+        assert!(refiner.update(&frame, BITRATE, 30).tiles.is_empty());
+        assert!(refiner.tiles[0].refined);
+        assert!(!refiner.tiles[1].refined);
+
+        // The client dropped them, both go out again with new ids
+        refiner.forget();
+        let resent = refiner.update(&frame, BITRATE, 30);
+        assert_eq!(resent.tiles.len(), 2);
+        assert!(resent.tiles.iter().all(|tile| tile.id > update.tiles[1].id));
+        // Confirming the old ids doesn't do anything anymore
+        refiner.confirm(update.tiles[1].id);
+        assert!(!refiner.tiles[1].refined);
+    }
+
+    #[test]
+    fn unconfirmed_tiles_are_sent_again() {
+        let mut refiner = LVRefiner::new();
+        let frame = make_frame(0);
+        let update = settle(&mut refiner, &frame);
+        refiner.confirm(update.tiles[0].id);
+        for _ in 1..ACK_FRAMES {
+            assert!(refiner.update(&frame, BITRATE, 30).tiles.is_empty());
+        }
+        let resent = refiner.update(&frame, BITRATE, 30);
+        assert_eq!(resent.tiles.len(), 1);
+        assert_eq!((resent.tiles[0].x, resent.tiles[0].y), (64, 0));
+    }
+
+    #[test]
+    fn changed_tiles_are_cleared() {
+        let mut refiner = LVRefiner::new();
+        let update = settle(&mut refiner, &make_frame(0));
+        refiner.confirm(update.tiles[0].id);
+        let update = refiner.update(&make_frame(0xff), BITRATE, 30);
+        assert_eq!(update.cleared, [(0, 0)]);
+        assert!(update.tiles.is_empty());
+        assert!(!refiner.tiles[0].refined);
+
+        // Sent but not confirmed yet, the client may have it by now
+        for _ in 1..STATIC_FRAMES {
+            assert!(refiner
+                .update(&make_frame(0xff), BITRATE, 30)
+                .tiles
+                .is_empty());
+        }
+        assert_eq!(
+            refiner.update(&make_frame(0xff), BITRATE, 30).tiles.len(),
+            1
+        );
+        let update = refiner.update(&make_frame(0), BITRATE, 30);
+        assert_eq!(update.cleared, [(0, 0)]);
+        // Nothing changed since, nothing to clear
+        assert!(refiner
+            .update(&make_frame(0), BITRATE, 30)
+            .cleared
+            .is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::capture::LVFrame;
+
+// Below this many bits per pixel per frame the encoder can't do much more than smear the
+// picture, so we'd rather send fewer, sharper pixels and let the client upscale.
+const MIN_BITS_PER_PIXEL: f32 = 0.004;
+// Only go back to a larger size once it would get comfortably more than the minimum, so we
+// don't flip back and forth around the threshold.
+const STEP_UP_BITS_PER_PIXEL: f32 = 1.5 * MIN_BITS_PER_PIXEL;
+// Every change rebuilds the encoder and costs an IDR frame.
+const HOLD_TIME: Duration = Duration::from_secs(3);
+// Each step halves both dimensions, so this is 1/4 of the captured size at most.
+const MAX_DIVISOR: u32 = 4;
+
+// Shrinks captured frames before encoding when the bitrate per pixel gets too low.
+pub struct LVFrameScaler {
+    divisor: u32,
+    last_change: Instant,
+    // Reused between frames once the encoder is done with the previous one.
+    buffer: Arc<Vec<u8>>,
+}
+
+impl LVFrameScaler {
+    pub fn new() -> Self {
+        Self {
+            divisor: 1,
+            last_change: Instant::now(),
+            buffer: Arc::new(Vec::new()),
+        }
+    }
+
+    pub fn divisor(&self) -> u32 {
+        self.divisor
+    }
+
+    fn bits_per_pixel(bitrate: u32, fps: u32, width: u32, height: u32, divisor: u32) -> f32 {
+        let pixels = (width / divisor) as f32 * (height / divisor) as f32;
+        bitrate as f32 / (pixels * fps.max(1) as f32)
+    }
+
+    // Whether the frame still has an even size left after scaling, the smallest the encoder
+    // takes is 2x2.
+    fn fits(width: u32, height: u32, divisor: u32) -> bool {
+        width / divisor >= 2 && height / divisor >= 2
+    }
+
+    // Picks the divisor for the current bitrate and framerate. Returns true if it changed.
+    pub fn update(&mut self, bitrate: u32, fps: u32, width: u32, height: u32) -> bool {
+        // A frame that shrank below the divisor would scale down to nothing, that can't wait.
+        if self.last_change.elapsed() < HOLD_TIME && Self::fits(width, height, self.divisor) {
+            return false;
+        }
+
+        let mut divisor = self.divisor;
+        while divisor < MAX_DIVISOR
+            && Self::fits(width, height, divisor * 2)
+            && Self::bits_per_pixel(bitrate, fps, width, height, divisor) < MIN_BITS_PER_PIXEL
+        {
+            divisor *= 2;
+        }
+        while divisor > 1
+            && (!Self::fits(width, height, divisor)
+                || Self::bits_per_pixel(bitrate, fps, width, height, divisor / 2)
+                    >= STEP_UP_BITS_PER_PIXEL)
+        {
+            divisor /= 2;
+        }
+
+        if divisor == self.divisor {
+            return false;
+        }
+        info!(
+            "{:.4} bits per pixel at {} bps, scaling {}x{} frames down by {}",
+            Self::bits_per_pixel(bitrate, fps, width, height, self.divisor),
+            bitrate,
+            width,
+            height,
+            divisor
+        );
+        self.divisor = divisor;
+        self.last_change = Instant::now();
+        true
+    }
+
+    // Box filters the frame down by the divisor. YUV 4:2:0 needs even dimensions, so the
+    // last row or column may get dropped. A frame smaller than the divisor comes out empty,
+    // update() steps back up before that happens.
+    pub fn downscale(&mut self, frame: &LVFrame) -> LVFrame {
+        let divisor = self.divisor as usize;
+        let width = (frame.width() as usize / divisor) & !1;
+        let height = (frame.height() as usize / divisor) & !1;
+        let src = frame.as_bytes();
+        let src_stride = frame.stride();
+
+        // The previous scaled frame is normally gone by now, otherwise start a new buffer.
+        if Arc::get_mut(&mut self.buffer).is_none() {
+            self.buffer = Arc::new(Vec::new());
+        }
+        let dst = Arc::get_mut(&mut self.buffer).unwrap();
+        dst.resize(4 * width * height, 0);
+
+        let area = (divisor * divisor) as u32;
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0u32; 4];
+                for sy in 0..divisor {
+                    let row = (y * divisor + sy) * src_stride + 4 * x * divisor;
+                    for sx in 0..divisor {
+                        let px = &src[row + 4 * sx..row + 4 * sx + 4];
+                        for (sum, value) in sum.iter_mut().zip(px) {
+                            *sum += *value as u32;
+                        }
+                    }
+                }
+                let out = 4 * (y * width + x);
+                for (value, sum) in dst[out..out + 4].iter_mut().zip(sum) {
+                    *value = (sum / area) as u8;
+                }
+            }
+        }
+
+        let mut scaled = LVFrame::new(width as u32, height as u32, self.buffer.clone());
+        scaled.set_capture_time(frame.capture_ts(), frame.capture_time());
+        scaled.set_focus(frame.focus().map(|focus| focus.scaled(self.divisor)));
+        scaled
+    }
+}
+
+impl Default for LVFrameScaler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1000x1000 at 10fps, so the floor is 40kbps at full size, 10kbps at half and 2.5kbps at a
+    // quarter. Going back up takes 1.5 times that.
+    fn update(scaler: &mut LVFrameScaler, bitrate: u32) -> bool {
+        scaler.last_change = Instant::now().checked_sub(HOLD_TIME).unwrap();
+        scaler.update(bitrate, 10, 1000, 1000)
+    }
+
+    fn make_frame(width: u32, height: u32, pixel: impl Fn(u32, u32) -> [u8; 4]) -> LVFrame {
+        let mut buffer = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                buffer.extend_from_slice(&pixel(x, y));
+            }
+        }
+        LVFrame::new(width, height, Arc::new(buffer))
+    }
+
+    #[test]
+    fn divisor_has_hysteresis() {
+        let mut scaler = LVFrameScaler::new();
+        assert!(!update(&mut scaler, 41_000));
+        assert!(update(&mut scaler, 39_000));
+        assert_eq!(scaler.divisor(), 2);
+        // Over the floor again, but not by enough to go back up
+        assert!(!update(&mut scaler, 59_000));
+        assert_eq!(scaler.divisor(), 2);
+        assert!(update(&mut scaler, 61_000));
+        assert_eq!(scaler.divisor(), 1);
+
+        // Straight to the smallest size, and no further
+        assert!(update(&mut scaler, 5_000));
+        assert_eq!(scaler.divisor(), 4);
+        assert!(!update(&mut scaler, 100));
+        assert_eq!(scaler.divisor(), MAX_DIVISOR);
+        assert!(update(&mut scaler, 16_000));
+        assert_eq!(scaler.divisor(), 2);
+
+        // Every change holds for a while
+        assert!(!scaler.update(1_000_000, 10, 1000, 1000));
+        assert_eq!(scaler.divisor(), 2);
+    }
+
+    #[test]
+    fn small_frames_are_never_scaled_to_nothing() {
+        let mut scaler = LVFrameScaler::new();
+        // Way too few bits, but halving 6x6 once more would leave 1x1
+        scaler.last_change = Instant::now().checked_sub(HOLD_TIME).unwrap();
+        assert!(scaler.update(1, 10, 6, 6));
+        assert_eq!(scaler.divisor(), 2);
+
+        // The frame shrinking below the divisor steps back up right away, hold time or not
+        assert!(scaler.update(1, 10, 3, 100));
+        assert_eq!(scaler.divisor(), 1);
+    }
+
+    #[test]
+    fn box_filters_the_frame() {
+        let mut scaler = LVFrameScaler::new();
+        update(&mut scaler, 39_000);
+        assert_eq!(scaler.divisor(), 2);
+
+        let frame = make_frame(4, 4, |x, y| {
+            [(x * 10) as u8, (y * 10) as u8, (x + y) as u8, 255]
+        });
+        let scaled = scaler.downscale(&frame);
+        assert_eq!((scaled.width(), scaled.height()), (2, 2));
+        assert_eq!(
+            scaled.as_bytes(),
+            [
+                [5, 5, 1, 255],
+                [25, 5, 3, 255],
+                [5, 25, 3, 255],
+                [25, 25, 5, 255]
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn rounds_down_to_even_sizes() {
+        let mut scaler = LVFrameScaler::new();
+        update(&mut scaler, 39_000);
+
+        // 5x3 after halving, the last column and row of blocks get dropped
+        let frame = make_frame(11, 7, |x, y| [x as u8, y as u8, 0, 255]);
+        let scaled = scaler.downscale(&frame);
+        assert_eq!((scaled.width(), scaled.height()), (4, 2));
+        assert_eq!(&scaled.as_bytes()[..8], [0, 0, 0, 255, 2, 0, 0, 255]);
+        assert_eq!(&scaled.as_bytes()[28..], [6, 2, 0, 255]);
+
+        // Smaller than the divisor, nothing is left rather than reading past the frame
+        let frame = make_frame(3, 9, |_, _| [255; 4]);
+        let scaled = scaler.downscale(&frame);
+        assert_eq!((scaled.width(), scaled.height()), (0, 4));
+        assert!(scaled.as_bytes().is_empty());
+    }
+}
@@ -2,65 +2,260 @@ use std::io::prelude::*;
 use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
-use log::{debug, error, info};
-use net::feedback_packet::{LVAck, LVFeedbackPacket, ACK_TYPE, FEEDBACK_TYPE};
+use log::{debug, error, info, warn};
+use net::clock::{self, LVClockSync, LVClockSyncPacket};
+use net::codec::LVEncoderBackend;
+use net::control_packet::{LVControlPacket, LVHandshake, LVMonitorInfo, ALL_MONITORS};
+use net::feedback_packet::{
+    LVAck, LVFeedbackPacket, LVMonitorSwitch, LVReferenceInvalidation, LVRefineAck, ACK_TYPE,
+    CLOCK_SYNC_REPLY_TYPE, CLOCK_SYNC_TYPE, FEEDBACK_TYPE, MONITOR_SWITCH_TYPE, RECORD_TOGGLE_TYPE,
+    REFERENCE_INVALIDATION_TYPE, REFINE_ACK_TYPE, REFINE_LOSS_TYPE,
+};
+use screenshots::Screen;
 use statistics::collector::LVStatisticsCollector;
 use statistics::statistics::{LVDataPoint, LVDataType};
 
+use crate::capture::LVCaptureTarget;
+
+// The bitrate controller never goes below this. Once it's stuck here the streaming server
+// starts dropping the framerate instead.
+pub const MIN_BITRATE: u32 = 20000;
+
+// What the client told us about lost frames since the streaming server last looked.
+#[derive(Debug, Clone, Default)]
+pub struct LVLossReport {
+    // The FEC couldn't rebuild a block. Only set for clients that don't report frame ids,
+    // the others send an invalidation for the frame instead.
+    pub fec_failure: bool,
+    // The newest loss the client reported
+    pub invalidation: Option<LVReferenceInvalidation>,
+    // The newest frame the client decoded cleanly, 0 if it hasn't said
+    pub last_good_frame: u32,
+    // Ids of refined tiles the client is drawing now
+    pub refined_tiles: Vec<u32>,
+    // The client missed refinement packets and dropped its refined tiles
+    pub refine_lost: bool,
+}
+
 pub struct LVFeedbackServer {
     bind_addr: String,
+    capture_target: Arc<Mutex<LVCaptureTarget>>,
+    framerate: Arc<Mutex<u32>>,
+    encoder_backend: Arc<Mutex<Option<LVEncoderBackend>>>,
+    clock: Arc<Mutex<LVClockSync>>,
+    recovery: Arc<Mutex<LVLossReport>>,
+    recording: Arc<Mutex<bool>>,
 }
 
 impl LVFeedbackServer {
-    pub fn new(bind_addr: &str) -> Self {
+    pub fn new(
+        bind_addr: &str,
+        capture_target: Arc<Mutex<LVCaptureTarget>>,
+        framerate: Arc<Mutex<u32>>,
+        encoder_backend: Arc<Mutex<Option<LVEncoderBackend>>>,
+    ) -> Self {
         Self {
             bind_addr: bind_addr.to_owned(),
+            capture_target,
+            framerate,
+            encoder_backend,
+            clock: Arc::new(Mutex::new(LVClockSync::new())),
+            recovery: Arc::new(Mutex::new(LVLossReport::default())),
+            recording: Arc::new(Mutex::new(false)),
         }
     }
 
-    fn handle_feedback(mut stream: TcpStream, bitrate_mtx: Arc<Mutex<u32>>) {
-        // The +1 should be for the extra byte that tells us what type of packet this is.
-        let mut msg_buffer =
-            vec![0; std::cmp::max(LVFeedbackPacket::no_bytes(), LVAck::no_bytes()) + 1];
+    // Filled in when the client reports frames it lost or decoded, for the streaming server to
+    // send a recovery point or confirm references.
+    pub fn recovery(&self) -> Arc<Mutex<LVLossReport>> {
+        self.recovery.clone()
+    }
+
+    // Whether the streaming server should be recording, the client can flip it.
+    pub fn recording(&self) -> Arc<Mutex<bool>> {
+        self.recording.clone()
+    }
+
+    // Our estimate of the client's clock, for anything that needs to compare the client's
+    // timestamps with ours.
+    pub fn clock(&self) -> Arc<Mutex<LVClockSync>> {
+        self.clock.clone()
+    }
+
+    // Tell the client which monitors exist and which one is being streamed.
+    fn send_handshake(
+        stream: &mut TcpStream,
+        capture_target: &Arc<Mutex<LVCaptureTarget>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Headless sources (test patterns, files) have no display to list monitors from.
+        let screens = Screen::all().unwrap_or_else(|e| {
+            warn!("could not list monitors: {:?}", e);
+            Vec::new()
+        });
+        let monitors = screens
+            .iter()
+            .map(|screen| {
+                // Same pixel coordinates the capturer uses, so the layout and sizes line up.
+                let info = &screen.display_info;
+                let scale = info.scale_factor;
+                LVMonitorInfo {
+                    x: (info.x as f32 * scale) as i32,
+                    y: (info.y as f32 * scale) as i32,
+                    width: (info.width as f32 * scale) as u32,
+                    height: (info.height as f32 * scale) as u32,
+                    primary: info.is_primary,
+                }
+            })
+            .collect();
+
+        let current_monitor = match *capture_target
+            .lock()
+            .expect("Failed to lock capture target")
+        {
+            LVCaptureTarget::Screen(screen_no) => screen_no as u32,
+            LVCaptureTarget::AllScreens => ALL_MONITORS,
+            // Windows and regions aren't a monitor, but the client still gets the list so it
+            // can switch to one.
+            _ => ALL_MONITORS,
+        };
+
+        let handshake = LVHandshake {
+            monitors,
+            current_monitor,
+        };
+        info!("sending handshake {:?}", handshake);
+        LVControlPacket::Handshake(handshake).write_to(stream)
+    }
+
+    fn handle_feedback(
+        mut stream: TcpStream,
+        bitrate_mtx: Arc<Mutex<u32>>,
+        capture_target: Arc<Mutex<LVCaptureTarget>>,
+        framerate: Arc<Mutex<u32>>,
+        encoder_backend: Arc<Mutex<Option<LVEncoderBackend>>>,
+        clock: Arc<Mutex<LVClockSync>>,
+        recovery: Arc<Mutex<LVLossReport>>,
+        recording: Arc<Mutex<bool>>,
+    ) {
+        let mut msg_type = [0; 1];
+        let mut msg_buffer = vec![
+            0;
+            *[
+                LVFeedbackPacket::no_bytes(),
+                LVAck::no_bytes(),
+                LVMonitorSwitch::no_bytes(),
+                LVClockSyncPacket::no_bytes(),
+                LVReferenceInvalidation::no_bytes(),
+            ]
+            .iter()
+            .max()
+            .unwrap()
+        ];
         let mut bitrate = 900000;
         let mut oo_blocks = 0;
         let mut decoder_failures = 0;
         let mut ticks_survived = 0;
         let mut ticks_to_survive = 10;
+        // The framerate the client was last told about
+        let mut sent_framerate = None;
+        // Same for the encoder backend
+        let mut sent_encoder = None;
 
         LVStatisticsCollector::register_data("server_bitrate_oo_blocks", LVDataType::XYData);
         LVStatisticsCollector::register_data("server_rtt_time", LVDataType::XYData);
         LVStatisticsCollector::register_data("server_rtt_bitrate", LVDataType::XYData);
+        LVStatisticsCollector::register_data("server_one_way_delay", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("server_clock_offset", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("server_clock_drift", LVDataType::TimeSeries);
         LVStatisticsCollector::register_data(
             "server_bitrate_ecc_decoder_failures",
             LVDataType::XYData,
         );
 
+        if let Err(e) = Self::send_handshake(&mut stream, &capture_target) {
+            error!("failed to send handshake to client {:?}", e);
+        }
+
         loop {
-            match stream.read(&mut msg_buffer[..]) {
-                Ok(data_read) => {
-                    let feedback_type = msg_buffer[0];
+            // Every message is a type byte followed by a fixed-size body, so read exactly that
+            // much instead of hoping each read lines up with one message.
+            // Any failure leaves us somewhere in the middle of a message with no way to find
+            // the start of the next one, so give up on the connection.
+            if let Err(e) = stream.read_exact(&mut msg_type) {
+                if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                    info!("client closed the feedback connection");
+                } else {
+                    error!("Could not read bytes from client {:?}, closing", e);
+                }
+                return;
+            }
+            // The read blocks until the message shows up, so this is when it arrived.
+            let recv_ts = clock::now_us();
+            let feedback_type = msg_type[0];
+            let body_len = match feedback_type {
+                ACK_TYPE => LVAck::no_bytes(),
+                FEEDBACK_TYPE => LVFeedbackPacket::no_bytes(),
+                MONITOR_SWITCH_TYPE => LVMonitorSwitch::no_bytes(),
+                CLOCK_SYNC_TYPE | CLOCK_SYNC_REPLY_TYPE => LVClockSyncPacket::no_bytes(),
+                REFERENCE_INVALIDATION_TYPE => LVReferenceInvalidation::no_bytes(),
+                RECORD_TOGGLE_TYPE | REFINE_LOSS_TYPE => 0,
+                REFINE_ACK_TYPE => LVRefineAck::no_bytes(),
+                _ => {
+                    error!(
+                        "unknown feedback packet type! type was {}! closing",
+                        feedback_type
+                    );
+                    return;
+                }
+            };
+
+            match stream.read_exact(&mut msg_buffer[..body_len]) {
+                Ok(()) => {
                     debug!("feedback type is {}", feedback_type);
                     match feedback_type {
                         ACK_TYPE => {
                             debug!(
                                 "ack packet to be decoded is {:?}",
-                                &msg_buffer[1..(LVAck::no_bytes() + 1)]
+                                &msg_buffer[..LVAck::no_bytes()]
                             );
                             let ack: LVAck =
-                                bincode::deserialize::<LVAck>(&msg_buffer[1..]).unwrap();
-
-                            // 1. Calculate RTT
-                            let current_time = SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap()
-                                .as_millis();
-                            let rtt = current_time - ack.send_ts;
+                                bincode::deserialize::<LVAck>(&msg_buffer[..]).unwrap();
+
+                            // Acks sent before the first packet arrived are all zeroes, there
+                            // is nothing to measure.
+                            if ack.send_ts == 0 {
+                                debug!("ack for no packet, skipping");
+                                continue;
+                            }
+
+                            // 1. Calculate RTT, leaving out the time the ack sat on the client
+                            // waiting for the next feedback tick.
+                            let current_time = clock::now_us();
+                            let send_ts = ack.send_ts as u64;
+                            let rtt = current_time
+                                .saturating_sub(send_ts)
+                                .saturating_sub(ack.hold_us as u64)
+                                / 1000;
                             debug!("rtt was {}", rtt);
 
-                            // NOTE: We hope that the u128 when they are subtracted will fit within an f32.
+                            // 2. With the clocks synced we can also tell how long the packet
+                            // took to get there.
+                            let client_recv_ts = clock
+                                .lock()
+                                .expect("Failed to lock clock")
+                                .to_local(ack.recv_ts);
+                            if let Some(client_recv_ts) = client_recv_ts {
+                                let one_way = (client_recv_ts - send_ts as i64).max(0) as u64;
+                                debug!("one way delay was {}us", one_way);
+                                LVStatisticsCollector::update_data(
+                                    "server_one_way_delay",
+                                    LVDataPoint::TimeElapsed(Duration::from_micros(one_way)),
+                                );
+                            }
+
+                            // NOTE: rtt is in ms, so it fits in an f32 without trouble.
                             LVStatisticsCollector::update_data(
                                 "server_rtt_time",
                                 LVDataPoint::XYValue((ack.rtp_seqno as f32, rtt as f32)),
@@ -74,10 +269,10 @@ impl LVFeedbackServer {
                         FEEDBACK_TYPE => {
                             debug!(
                                 "Feedback packet to be decoded is {:?}",
-                                &msg_buffer[1..LVFeedbackPacket::no_bytes() + 1]
+                                &msg_buffer[..LVFeedbackPacket::no_bytes()]
                             );
                             match bincode::deserialize::<LVFeedbackPacket>(
-                                &msg_buffer[1..LVFeedbackPacket::no_bytes() + 1],
+                                &msg_buffer[..LVFeedbackPacket::no_bytes()],
                             ) {
                                 Ok(feedback_packet) => {
                                     debug!("Feedback packet is {:?}", feedback_packet);
@@ -117,7 +312,7 @@ impl LVFeedbackServer {
 
                                                 ticks_survived = 0;
                                                 // Minimum bitrate
-                                                if bitrate >= 20000 {
+                                                if bitrate >= MIN_BITRATE {
                                                     // If there are failures, the bitrate we go to
                                                     // has to demonstrate a higher target of stability
                                                     // before we can upgrade the bitrate again.
@@ -131,7 +326,7 @@ impl LVFeedbackServer {
 
                                                     (bitrate as f32 * 0.6) as u32
                                                 } else {
-                                                    20000
+                                                    MIN_BITRATE
                                                 }
                                             } else if congestion < 0.2 && congestion > 0.15 {
                                                 ticks_survived += 1;
@@ -177,18 +372,206 @@ impl LVFeedbackServer {
 
                                         debug!("setting bitrate to {}", bitrate);
                                     }
+
+                                    // The FEC couldn't rebuild a block, so part of a frame is
+                                    // gone and everything predicted from it is wrong until the
+                                    // next IDR or refresh wave. Clients that track frame ids
+                                    // report the frame itself.
+                                    let mut report =
+                                        recovery.lock().expect("Failed to lock recovery");
+                                    report.last_good_frame = feedback_packet.last_good_frame;
+                                    if feedback_packet.ecc_decoder_failures > 0
+                                        && feedback_packet.last_good_frame == 0
+                                    {
+                                        report.fec_failure = true;
+                                    }
                                 }
                                 Err(e) => {
                                     error!("Failed to decode feedback packet with error {:?}", e)
                                 }
                             }
+
+                            // Feedback comes in once per quantum, which is as often as we want
+                            // to check on the client's clock.
+                            let request = LVClockSyncPacket {
+                                origin_ts: clock::now_us(),
+                                ..Default::default()
+                            };
+                            if let Err(e) =
+                                LVControlPacket::ClockSyncRequest(request).write_to(&mut stream)
+                            {
+                                error!("failed to send clock sync request {:?}", e);
+                            }
+
+                            // Let the client know if the streaming server changed the framerate
+                            // so it can pace its presentation to match.
+                            let current_framerate =
+                                *framerate.lock().expect("Failed to lock framerate");
+                            if sent_framerate != Some(current_framerate) {
+                                match LVControlPacket::Framerate(current_framerate)
+                                    .write_to(&mut stream)
+                                {
+                                    Ok(()) => sent_framerate = Some(current_framerate),
+                                    Err(e) => error!("failed to send framerate {:?}", e),
+                                }
+                            }
+
+                            let current_encoder = *encoder_backend
+                                .lock()
+                                .expect("Failed to lock encoder backend");
+                            if let Some(backend) = current_encoder {
+                                if sent_encoder != Some(backend) {
+                                    match LVControlPacket::Encoder(backend).write_to(&mut stream) {
+                                        Ok(()) => sent_encoder = Some(backend),
+                                        Err(e) => error!("failed to send encoder {:?}", e),
+                                    }
+                                }
+                            }
                         }
-                        _ => {
-                            error!("unknown feedback packet type! type was {}!", feedback_type)
+                        MONITOR_SWITCH_TYPE => {
+                            match bincode::deserialize::<LVMonitorSwitch>(
+                                &msg_buffer[..LVMonitorSwitch::no_bytes()],
+                            ) {
+                                Ok(switch) => {
+                                    let monitor = switch.monitor;
+                                    info!("client asked to stream monitor {}", monitor);
+                                    let new_target = if monitor == ALL_MONITORS {
+                                        Some(LVCaptureTarget::AllScreens)
+                                    } else {
+                                        match Screen::all() {
+                                            Ok(screens) if (monitor as usize) < screens.len() => {
+                                                Some(LVCaptureTarget::Screen(monitor as usize))
+                                            }
+                                            Ok(_) => {
+                                                warn!("monitor {} does not exist", monitor);
+                                                None
+                                            }
+                                            Err(e) => {
+                                                error!("failed to enumerate screens {:?}", e);
+                                                None
+                                            }
+                                        }
+                                    };
+
+                                    if let Some(new_target) = new_target {
+                                        // The capture thread picks this up on its next frame.
+                                        *capture_target
+                                            .lock()
+                                            .expect("Failed to lock capture target") = new_target;
+                                    }
+
+                                    // Either way, let the client know what is being streamed now.
+                                    if let Err(e) =
+                                        Self::send_handshake(&mut stream, &capture_target)
+                                    {
+                                        error!("failed to send handshake to client {:?}", e);
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("Failed to decode monitor switch packet {:?}", e)
+                                }
+                            }
+                        }
+                        REFERENCE_INVALIDATION_TYPE => {
+                            match bincode::deserialize::<LVReferenceInvalidation>(
+                                &msg_buffer[..LVReferenceInvalidation::no_bytes()],
+                            ) {
+                                Ok(invalidation) => {
+                                    let (last_good, lost) =
+                                        (invalidation.last_good_frame, invalidation.lost_frame);
+                                    info!(
+                                        "client lost frame {}, last good frame was {}",
+                                        lost, last_good
+                                    );
+                                    recovery
+                                        .lock()
+                                        .expect("Failed to lock recovery")
+                                        .invalidation = Some(invalidation);
+                                }
+                                Err(e) => {
+                                    error!("Failed to decode reference invalidation {:?}", e)
+                                }
+                            }
+                        }
+                        REFINE_ACK_TYPE => {
+                            match bincode::deserialize::<LVRefineAck>(
+                                &msg_buffer[..LVRefineAck::no_bytes()],
+                            ) {
+                                Ok(ack) => recovery
+                                    .lock()
+                                    .expect("Failed to lock recovery")
+                                    .refined_tiles
+                                    .push(ack.tile_id),
+                                Err(e) => error!("Failed to decode refine ack {:?}", e),
+                            }
+                        }
+                        REFINE_LOSS_TYPE => {
+                            debug!("client lost refinement packets");
+                            recovery
+                                .lock()
+                                .expect("Failed to lock recovery")
+                                .refine_lost = true;
+                        }
+                        RECORD_TOGGLE_TYPE => {
+                            let mut recording = recording.lock().expect("Failed to lock recording");
+                            *recording = !*recording;
+                            info!(
+                                "client asked to {} recording",
+                                if *recording { "start" } else { "stop" }
+                            );
+                        }
+                        CLOCK_SYNC_TYPE => {
+                            match bincode::deserialize::<LVClockSyncPacket>(
+                                &msg_buffer[..LVClockSyncPacket::no_bytes()],
+                            ) {
+                                Ok(request) => {
+                                    let reply = LVClockSyncPacket {
+                                        origin_ts: request.origin_ts,
+                                        recv_ts,
+                                        transmit_ts: clock::now_us(),
+                                    };
+                                    if let Err(e) =
+                                        LVControlPacket::ClockSyncReply(reply).write_to(&mut stream)
+                                    {
+                                        error!("failed to send clock sync reply {:?}", e);
+                                    }
+                                }
+                                Err(e) => error!("Failed to decode clock sync packet {:?}", e),
+                            }
+                        }
+                        CLOCK_SYNC_REPLY_TYPE => {
+                            match bincode::deserialize::<LVClockSyncPacket>(
+                                &msg_buffer[..LVClockSyncPacket::no_bytes()],
+                            ) {
+                                Ok(reply) => {
+                                    let mut clock = clock.lock().expect("Failed to lock clock");
+                                    clock.add_sample(&reply, recv_ts);
+                                    if let Some(offset) = clock.offset_us(recv_ts) {
+                                        debug!(
+                                            "client clock is {}us off ours, drifting {:.2}ppm",
+                                            offset,
+                                            clock.drift_ppm()
+                                        );
+                                        LVStatisticsCollector::update_data(
+                                            "server_clock_offset",
+                                            LVDataPoint::FloatValue(offset as f32 / 1000.),
+                                        );
+                                        LVStatisticsCollector::update_data(
+                                            "server_clock_drift",
+                                            LVDataPoint::FloatValue(clock.drift_ppm() as f32),
+                                        );
+                                    }
+                                }
+                                Err(e) => error!("Failed to decode clock sync packet {:?}", e),
+                            }
                         }
+                        _ => unreachable!(),
                     }
                 }
-                Err(e) => error!("Could not read bytes from client {:?}", e),
+                Err(e) => {
+                    error!("Could not read bytes from client {:?}, closing", e);
+                    return;
+                }
             }
         }
     }
@@ -196,13 +579,28 @@ impl LVFeedbackServer {
     pub fn start_receive_loop(
         bind_addr: &str,
         bitrate_shared: Arc<Mutex<u32>>,
+        capture_target: Arc<Mutex<LVCaptureTarget>>,
+        framerate: Arc<Mutex<u32>>,
+        encoder_backend: Arc<Mutex<Option<LVEncoderBackend>>>,
+        clock: Arc<Mutex<LVClockSync>>,
+        recovery: Arc<Mutex<LVLossReport>>,
+        recording: Arc<Mutex<bool>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         debug!("connecting to feedback server at {}", bind_addr);
         let tcp_stream = TcpStream::connect(bind_addr)?;
 
         debug!("connected to feedback server at {}", bind_addr);
 
-        Self::handle_feedback(tcp_stream, bitrate_shared.clone());
+        Self::handle_feedback(
+            tcp_stream,
+            bitrate_shared.clone(),
+            capture_target,
+            framerate,
+            encoder_backend,
+            clock,
+            recovery,
+            recording,
+        );
 
         Ok(())
     }
@@ -212,9 +610,24 @@ impl LVFeedbackServer {
         let bitrate_shared = Arc::new(Mutex::new(80000));
         let bitrate_shared_clone = bitrate_shared.clone();
         let bind_addr_clone = self.bind_addr.clone();
+        let capture_target = self.capture_target.clone();
+        let framerate = self.framerate.clone();
+        let encoder_backend = self.encoder_backend.clone();
+        let clock = self.clock.clone();
+        let recovery = self.recovery.clone();
+        let recording = self.recording.clone();
         thread::spawn(move || {
-            Self::start_receive_loop(&bind_addr_clone, bitrate_shared_clone)
-                .expect("Failed to start feedback server");
+            Self::start_receive_loop(
+                &bind_addr_clone,
+                bitrate_shared_clone,
+                capture_target,
+                framerate,
+                encoder_backend,
+                clock,
+                recovery,
+                recording,
+            )
+            .expect("Failed to start feedback server");
         });
         bitrate_shared
     }
@@ -0,0 +1,204 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use super::feedback_server::MIN_BITRATE;
+
+// How many halvings we allow, i.e. 60 -> 30 -> 15.
+const MAX_STEPS_DOWN: u32 = 2;
+// Frames to average the encode time over before acting on it.
+const ENCODE_WINDOW: usize = 30;
+// Give the bitrate controller and the encoder time to settle before changing again, otherwise
+// the two controllers chase each other.
+const HOLD_TIME: Duration = Duration::from_secs(3);
+// Only go back up if encoding would take at most this much of the higher rate's frame budget.
+const STEP_UP_BUDGET: f32 = 0.75;
+
+// Trades framerate for quality when lowering the bitrate isn't enough anymore. We step down when
+// the bitrate controller has hit its floor, or when encoding can't keep up with the frame
+// budget, and back up once there's room again.
+pub struct LVFramerateController {
+    max_fps: u32,
+    fps: u32,
+    encode_times: VecDeque<Duration>,
+    last_change: Instant,
+    // The bitrate at each step down, or None if it was the encoder that couldn't keep up.
+    // Doubling the framerate halves the bits each frame gets, so going back up waits for
+    // twice the bitrate we stepped down at.
+    step_down_bitrates: Vec<Option<u32>>,
+}
+
+impl LVFramerateController {
+    pub fn new(max_fps: u32) -> Self {
+        Self {
+            max_fps,
+            fps: max_fps,
+            encode_times: VecDeque::with_capacity(ENCODE_WINDOW),
+            last_change: Instant::now(),
+            step_down_bitrates: Vec::new(),
+        }
+    }
+
+    // Call once per encoded frame. Returns the new framerate if it should change.
+    pub fn update(&mut self, bitrate: u32, encode_time: Duration) -> Option<u32> {
+        if self.encode_times.len() == ENCODE_WINDOW {
+            self.encode_times.pop_front();
+        }
+        self.encode_times.push_back(encode_time);
+
+        if self.last_change.elapsed() < HOLD_TIME || self.encode_times.len() < ENCODE_WINDOW {
+            return None;
+        }
+
+        let encode_time = self.encode_times.iter().sum::<Duration>() / ENCODE_WINDOW as u32;
+        let budget = Duration::from_secs(1) / self.fps;
+        let can_step_down = (self.step_down_bitrates.len() as u32) < MAX_STEPS_DOWN;
+
+        if can_step_down && bitrate <= MIN_BITRATE {
+            info!(
+                "bitrate is at its floor ({}), lowering framerate to {}",
+                bitrate,
+                self.fps / 2
+            );
+            self.step_down_bitrates.push(Some(bitrate));
+            return Some(self.change(self.fps / 2));
+        }
+        if can_step_down && encode_time > budget {
+            info!(
+                "encoding takes {:.2?}, over the {:.2?} budget, lowering framerate to {}",
+                encode_time,
+                budget,
+                self.fps / 2
+            );
+            self.step_down_bitrates.push(None);
+            return Some(self.change(self.fps / 2));
+        }
+
+        if let Some(step_down_bitrate) = self.step_down_bitrates.last() {
+            let up_fps = (self.fps * 2).min(self.max_fps);
+            let up_budget = (Duration::from_secs(1) / up_fps).mul_f32(STEP_UP_BUDGET);
+            let enough_bitrate =
+                step_down_bitrate.is_none_or(|step_down_bitrate| bitrate >= 2 * step_down_bitrate);
+            if enough_bitrate && encode_time <= up_budget {
+                info!(
+                    "bitrate {} and encode time {:.2?} have recovered, raising framerate to {}",
+                    bitrate, encode_time, up_fps
+                );
+                self.step_down_bitrates.pop();
+                return Some(self.change(up_fps));
+            }
+        }
+
+        None
+    }
+
+    fn change(&mut self, fps: u32) -> u32 {
+        self.fps = fps.max(1);
+        self.last_change = Instant::now();
+        // Encode times at the old rate don't say much about the new one.
+        self.encode_times.clear();
+        self.fps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Duration = Duration::from_millis(1);
+
+    // Pretends the hold time is over and feeds a fresh window of frames, the controller only
+    // decides once it has a full one.
+    fn settle(
+        controller: &mut LVFramerateController,
+        bitrate: u32,
+        encode_time: Duration,
+    ) -> Option<u32> {
+        controller.last_change = Instant::now().checked_sub(HOLD_TIME).unwrap();
+        controller.encode_times.clear();
+        for _ in 1..ENCODE_WINDOW {
+            assert_eq!(controller.update(bitrate, encode_time), None);
+        }
+        controller.update(bitrate, encode_time)
+    }
+
+    #[test]
+    fn steps_down_at_the_bitrate_floor() {
+        let mut controller = LVFramerateController::new(60);
+        assert_eq!(settle(&mut controller, MIN_BITRATE + 1, FAST), None);
+        assert_eq!(settle(&mut controller, MIN_BITRATE, FAST), Some(30));
+        // Nothing changes again until the hold time is over
+        for _ in 0..2 * ENCODE_WINDOW {
+            assert_eq!(controller.update(MIN_BITRATE, FAST), None);
+        }
+    }
+
+    #[test]
+    fn steps_down_when_encoding_is_over_budget() {
+        let mut controller = LVFramerateController::new(60);
+        // 16.7ms at 60fps
+        assert_eq!(
+            settle(&mut controller, 1_000_000, Duration::from_millis(16)),
+            None
+        );
+        assert_eq!(
+            settle(&mut controller, 1_000_000, Duration::from_millis(17)),
+            Some(30)
+        );
+        // 33.3ms at 30fps
+        assert_eq!(
+            settle(&mut controller, 1_000_000, Duration::from_millis(30)),
+            None
+        );
+        assert_eq!(
+            settle(&mut controller, 1_000_000, Duration::from_millis(40)),
+            Some(15)
+        );
+    }
+
+    #[test]
+    fn stops_after_max_steps_down() {
+        let mut controller = LVFramerateController::new(60);
+        assert_eq!(settle(&mut controller, MIN_BITRATE, FAST), Some(30));
+        assert_eq!(settle(&mut controller, MIN_BITRATE, FAST), Some(15));
+        assert_eq!(MAX_STEPS_DOWN, 2);
+        assert_eq!(settle(&mut controller, MIN_BITRATE, FAST), None);
+        assert_eq!(
+            settle(&mut controller, MIN_BITRATE, Duration::from_millis(100)),
+            None
+        );
+    }
+
+    #[test]
+    fn recovers_one_step_at_a_time() {
+        let mut controller = LVFramerateController::new(60);
+        assert_eq!(settle(&mut controller, MIN_BITRATE, FAST), Some(30));
+        assert_eq!(
+            settle(&mut controller, 1_000_000, Duration::from_millis(40)),
+            Some(15)
+        );
+
+        // The last step down was the encoder's, so only the encode time matters, and it has to
+        // fit in 75% of 33.3ms
+        assert_eq!(
+            settle(&mut controller, MIN_BITRATE + 1, Duration::from_millis(26)),
+            None
+        );
+        assert_eq!(
+            settle(&mut controller, MIN_BITRATE + 1, Duration::from_millis(24)),
+            Some(30)
+        );
+
+        // This one was the bitrate's, which has to double first
+        assert_eq!(settle(&mut controller, 2 * MIN_BITRATE - 1, FAST), None);
+        assert_eq!(
+            settle(&mut controller, 2 * MIN_BITRATE, Duration::from_millis(13)),
+            None
+        );
+        assert_eq!(settle(&mut controller, 2 * MIN_BITRATE, FAST), Some(60));
+        assert_eq!(settle(&mut controller, 1_000_000, FAST), None);
+    }
+}
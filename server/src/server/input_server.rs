@@ -1,6 +1,7 @@
 use std::{
     mem::size_of,
     net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
     thread,
 };
 
@@ -10,7 +11,7 @@ use net::input::{
     LVMouseMoveEvent, LVMouseWheelEvent,
 };
 
-use crate::input::LVInputEmulator;
+use crate::input::{LVInputEmulator, LVInputMapping};
 
 pub struct LVInputServer {
     bind_addr: String,
@@ -27,6 +28,7 @@ impl LVInputServer {
         &self,
         input_target_addr: SocketAddr,
         mut input_emulator: Box<dyn LVInputEmulator>,
+        input_mapping: Arc<Mutex<LVInputMapping>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         debug!("bind address is {:?}", self.bind_addr);
         debug!("input target address is {:?}", input_target_addr);
@@ -87,6 +89,11 @@ impl LVInputServer {
 
                                 debug!("Received input event {:?}", input_event);
 
+                                let input_event = input_mapping
+                                    .lock()
+                                    .expect("Failed to lock input mapping")
+                                    .map(input_event);
+
                                 input_emulator.write_event(input_event);
                             }
                             Err(e) => {
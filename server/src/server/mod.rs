@@ -1,3 +1,4 @@
 pub mod feedback_server;
+pub mod framerate;
 pub mod input_server;
 pub mod streaming_server;
@@ -1,10 +1,13 @@
 use bytes::{BufMut, BytesMut};
 use flume::{Receiver, Sender, TryRecvError};
-use image::{ImageBuffer, Rgb};
 use libc::TIOCOUTQ;
 use log::{debug, error, info, trace, warn};
+use net::{
+    clock,
+    codec::LVEncoderBackend,
+    impair::{LVImpairedSocket, LVImpairment},
+};
 use nix::ioctl_read_bad;
-use screenshots::Screen;
 use statistics::{
     collector::LVStatisticsCollector,
     statistics::{LVDataPoint, LVDataType},
@@ -19,24 +22,49 @@ use std::{
 use webrtc_util::{Marshal, MarshalSize};
 
 use crate::{
-    capture::{linux::LVLinuxCapturer, LVCapturer},
-    encoder,
+    capture::{self, stamp::LVFrameStamper, LVCaptureTarget, LVFrame},
+    encoder::{self, LVEncoderConfig},
+    input::LVInputMapping,
     packager::LVPackager,
 };
 
+use super::{feedback_server::LVLossReport, framerate::LVFramerateController};
+
 ioctl_read_bad!(tiocoutq, TIOCOUTQ, u32);
 
+// Recovery points cost bandwidth, and the client keeps reporting losses until the first one
+// arrives, so don't ask for them more often than this.
+const MIN_RECOVERY_INTERVAL: Duration = Duration::from_millis(500);
+
 pub struct LVStreamingServer {
     bind_addr: String,
     target_addr: String,
+    // The most we'll capture at, the framerate controller can go below this under congestion.
     fps: u32,
-    screen_no: usize,
-    width: u32,
-    height: u32,
+    // What the capture thread is currently running at, shared with the feedback server so it
+    // can tell the client.
+    framerate_mtx: Arc<Mutex<u32>>,
+    // The encoder backend in use, also for the feedback server to pass on.
+    encoder_backend: Arc<Mutex<Option<LVEncoderBackend>>>,
+    // Filled in by the feedback server when the client lost or decoded frames.
+    recovery_mtx: Arc<Mutex<LVLossReport>>,
+    // Whether to record the stream, the client can toggle it through the feedback server.
+    recording_mtx: Arc<Mutex<bool>>,
+    // Whether the encoder takes a region of interest, finding the pointer and the focused
+    // window costs the capture thread a few X round trips per frame otherwise.
+    focus_mtx: Arc<Mutex<bool>>,
+    target: Arc<Mutex<LVCaptureTarget>>,
+    input_mapping: Arc<Mutex<LVInputMapping>>,
     quit_rx: Receiver<bool>,
     old_bitrate: u32,
     bitrate_mtx: Arc<Mutex<u32>>,
     udp_fd: Option<RawFd>,
+    // Emulated bad link in front of the client, from LV_IMPAIR unless set_impairment replaced it
+    impairment: Option<LVImpairment>,
+    // Stamp frame ids into the captured frames for quality measurements, LV_QUALITY
+    stamp_frames: bool,
+    // Gets every captured frame, after stamping
+    source_tap: Option<Sender<LVFrame>>,
 
     // queue-occupancy/bitrate tradeoff
     total_queue_occupancy: u64,
@@ -48,30 +76,57 @@ impl LVStreamingServer {
         bind_addr: &str,
         target_addr: &str,
         fps: u32,
-        screen_no: usize,
-        width: u32,
-        height: u32,
+        target: Arc<Mutex<LVCaptureTarget>>,
         bitrate: u32,
         quit_rx: Receiver<bool>,
         bitrate_mtx: Arc<Mutex<u32>>,
+        input_mapping: Arc<Mutex<LVInputMapping>>,
+        framerate_mtx: Arc<Mutex<u32>>,
+        encoder_backend: Arc<Mutex<Option<LVEncoderBackend>>>,
+        recovery_mtx: Arc<Mutex<LVLossReport>>,
+        recording_mtx: Arc<Mutex<bool>>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
+        *framerate_mtx.lock().expect("Failed to lock framerate mtx") = fps;
         Ok(Self {
             bind_addr: bind_addr.to_owned(),
             target_addr: target_addr.to_owned(),
             fps,
-            screen_no,
-            width,
-            height,
+            framerate_mtx,
+            encoder_backend,
+            recovery_mtx,
+            recording_mtx,
+            focus_mtx: Arc::new(Mutex::new(false)),
+            target,
+            input_mapping,
             quit_rx,
             old_bitrate: bitrate,
             bitrate_mtx,
             udp_fd: None,
+            impairment: LVImpairment::from_env()?,
+            stamp_frames: capture::stamp::enabled(),
+            source_tap: None,
             // Statistics stuff
             total_queue_occupancy: 0,
             total_cycles: 0,
         })
     }
 
+    // Takes effect when the send loop starts, None sends straight to the socket.
+    pub fn set_impairment(&mut self, impairment: Option<LVImpairment>) {
+        self.impairment = impairment;
+    }
+
+    // Overrides LV_QUALITY. Takes effect when the capture thread starts.
+    pub fn set_stamp_frames(&mut self, stamp_frames: bool) {
+        self.stamp_frames = stamp_frames;
+    }
+
+    // A copy of every frame the capture thread produces, for comparing with what the client
+    // decodes. Frames are dropped when the tap falls behind.
+    pub fn set_source_tap(&mut self, tap: Sender<LVFrame>) {
+        self.source_tap = Some(tap);
+    }
+
     pub fn bytes_in_send_queue(&self) -> Result<u32, Box<dyn std::error::Error>> {
         // info!("udp fd set to {:?}", self.udp_fd);
         match self.udp_fd {
@@ -84,6 +139,20 @@ impl LVStreamingServer {
         }
     }
 
+    // Tells the capture thread and the feedback server about the encoder in use.
+    fn encoder_changed(&self, packager: &LVPackager) {
+        *self.focus_mtx.lock().expect("Failed to lock focus mtx") = packager.uses_roi();
+        let backend = packager.encoder_backend();
+        let mut current = self
+            .encoder_backend
+            .lock()
+            .expect("Failed to lock encoder backend");
+        if *current != Some(backend) {
+            info!("encoder backend is now {}", backend);
+            *current = Some(backend);
+        }
+    }
+
     pub fn begin(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         // HUGE lag because frame backlog exists if this is like anything more than 2
         let (frame_push, frame_recv) = flume::bounded(2);
@@ -94,18 +163,72 @@ impl LVStreamingServer {
 
     pub fn start_capture_thread(
         &self,
-        frame_push: Sender<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+        frame_push: Sender<LVFrame>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let sixty_fps = Duration::new(0, (1000000000. / self.fps as f32) as u32);
-        let screen = *Screen::all()?
-            .get(self.screen_no)
-            .expect("Expected a screen");
+        let framerate_mtx = self.framerate_mtx.clone();
+        let focus_mtx = self.focus_mtx.clone();
+        let target_mtx = self.target.clone();
+        let input_mapping = self.input_mapping.clone();
+        let mut target = target_mtx
+            .lock()
+            .expect("Failed to lock capture target")
+            .clone();
+        let mut capturer = capture::default_capturer(&target)?;
+        let mut stamper = self.stamp_frames.then(LVFrameStamper::new);
+        let source_tap = self.source_tap.clone();
 
         thread::spawn(move || {
-            let mut capturer = LVLinuxCapturer::new(screen).expect("Could not start capturer");
             loop {
+                // The client asked for a different monitor.
+                let rebuild_for_target = {
+                    let new_target = target_mtx.lock().expect("Failed to lock capture target");
+                    if *new_target != target {
+                        info!("switching capture from {:?} to {:?}", target, *new_target);
+                        target = new_target.clone();
+                        true
+                    } else {
+                        false
+                    }
+                };
+
+                // The captured area changed size, so build a capturer for the new geometry. The
+                // send loop notices the new frame size and rebuilds the encoder to match.
+                if capturer.needs_rebuild() || rebuild_for_target {
+                    match capture::default_capturer(&target) {
+                        Ok(new_capturer) => {
+                            info!("rebuilt capturer for {:?}", target);
+                            capturer = new_capturer;
+                        }
+                        Err(e) => error!("failed to rebuild capturer for {:?}: {:?}", target, e),
+                    }
+                }
+
+                // Keep input pointed at wherever the captured area is now.
+                let (x_offset, y_offset) = capturer.origin();
+                {
+                    let mut mapping = input_mapping.lock().expect("Failed to lock input mapping");
+                    mapping.x_offset = x_offset;
+                    mapping.y_offset = y_offset;
+                }
+
+                let capture_ts = clock::now_us();
+                let capture_start = Instant::now();
                 match capturer.capture() {
-                    Ok(frame) => {
+                    Ok(mut frame) => {
+                        if let Some(stamper) = stamper.as_mut() {
+                            match stamper.stamp(&frame) {
+                                Ok(stamped) => frame = stamped,
+                                Err(e) => error!("failed to stamp frame: {:?}", e),
+                            }
+                        }
+                        frame.set_capture_time(capture_ts, capture_start.elapsed());
+                        if *focus_mtx.lock().expect("Failed to lock focus mtx") {
+                            frame.set_focus(capturer.focus());
+                        }
+                        if let Some(tap) = &source_tap {
+                            let _ = tap.try_send(frame.clone());
+                        }
+
                         // Throw the stuff into the mpmc
                         match frame_push.try_send(frame) {
                             // This is normal.
@@ -118,7 +241,9 @@ impl LVStreamingServer {
                     }
                 }
 
-                spin_sleep::sleep(sixty_fps);
+                // The send loop lowers this when it can't keep up.
+                let fps = *framerate_mtx.lock().expect("Failed to lock framerate mtx");
+                spin_sleep::sleep(Duration::from_secs(1) / fps.max(1));
             }
         });
 
@@ -127,18 +252,52 @@ impl LVStreamingServer {
 
     pub fn start_send_loop(
         &mut self,
-        frame_recv: Receiver<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+        frame_recv: Receiver<LVFrame>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         info!("bind addr {}", self.bind_addr);
         let socket = UdpSocket::bind(&self.bind_addr).expect("Failed to make socket");
-        let encoder =
-            encoder::default_encoder(self.width, self.height, self.old_bitrate, self.fps as f32)
-                .expect("Failed to make encoder");
+        let socket = LVImpairedSocket::new(socket, self.impairment.take());
+
+        // Size the encoder from what the capturer actually produces.
+        let first_frame = frame_recv.recv()?;
+        info!(
+            "initialising encoder with frame size {}x{}",
+            first_frame.width(),
+            first_frame.height()
+        );
+        let encoder = encoder::default_encoder(LVEncoderConfig {
+            codec: encoder::default_codec()?,
+            intra_refresh: encoder::default_intra_refresh()?,
+            ..LVEncoderConfig::new(
+                first_frame.width(),
+                first_frame.height(),
+                self.old_bitrate,
+                self.fps as f32,
+            )
+        })
+        .expect("Failed to make encoder");
         let mut packager = LVPackager::new(encoder, self.fps).expect("Failed to make packager");
+        // LV_RECORD may have started recording already
+        *self
+            .recording_mtx
+            .lock()
+            .expect("Failed to lock recording mtx") = packager.is_recording();
+        self.encoder_changed(&packager);
+        let mut framerate_controller = LVFramerateController::new(self.fps);
+        let mut pending_frame = Some(first_frame);
+        let mut last_recovery: Option<Instant> = None;
+        // The newest frame id passed on to the encoder as received
+        let mut confirmed_frame = 0;
         let mut rtp_pkt = BytesMut::new();
 
         LVStatisticsCollector::register_data("server_packet_sending", LVDataType::TimeSeries);
         LVStatisticsCollector::register_data("server_bitrate_queue_occupancy", LVDataType::XYData);
+        LVStatisticsCollector::register_data("server_framerate", LVDataType::TimeSeries);
+        LVStatisticsCollector::register_data("server_recovery_requests", LVDataType::Aggregate);
+        LVStatisticsCollector::register_data("server_reference_recoveries", LVDataType::Aggregate);
+        // Frame id against the bitrate it was encoded at, to line up with the client's quality
+        // measurements
+        LVStatisticsCollector::register_data("server_quality_bitrate", LVDataType::XYData);
 
         info!("server bound to {}", self.bind_addr);
 
@@ -153,6 +312,8 @@ impl LVStreamingServer {
             match self.quit_rx.try_recv() {
                 Ok(val) if val => {
                     info!("Ctrl-c received, statistics logged, quitting...");
+                    // Flushes what's left of the recording.
+                    packager.set_recording(false);
                     break;
                 }
                 Err(e) => {
@@ -163,12 +324,101 @@ impl LVStreamingServer {
                 _ => warn!("quit_rx gave false value!"),
             }
 
-            match frame_recv.recv() {
+            // Have the next frame be a recovery point if the client lost one.
+            let report = std::mem::take(
+                &mut *self
+                    .recovery_mtx
+                    .lock()
+                    .expect("Failed to lock recovery mtx"),
+            );
+            if report.last_good_frame > confirmed_frame {
+                packager.confirm_frame(report.last_good_frame);
+                confirmed_frame = report.last_good_frame;
+            }
+            for tile_id in report.refined_tiles {
+                packager.confirm_refinement(tile_id);
+            }
+            if report.invalidation.is_some() || report.refine_lost {
+                // The client dropped its refined tiles along with the lost frame or packets,
+                // including any it confirmed before that.
+                packager.forget_refinements();
+            }
+            if (report.fec_failure || report.invalidation.is_some())
+                && last_recovery.is_none_or(|last| last.elapsed() >= MIN_RECOVERY_INTERVAL)
+            {
+                // Encoding against a frame the client still has is much cheaper than a
+                // keyframe, the packager falls back to one if the encoder can't.
+                let recovery = match report.invalidation {
+                    Some(invalidation) => packager.invalidate_frames(invalidation.last_good_frame),
+                    None => packager.request_recovery().map(|()| false),
+                };
+                match recovery {
+                    Ok(true) => {
+                        debug!("recovering from a long-term reference");
+                        LVStatisticsCollector::update_data(
+                            "server_reference_recoveries",
+                            LVDataPoint::Increment,
+                        );
+                    }
+                    Ok(false) => debug!("requested a recovery point"),
+                    Err(e) => error!("Failed to request recovery with {:?}", e),
+                }
+                last_recovery = Some(Instant::now());
+                LVStatisticsCollector::update_data(
+                    "server_recovery_requests",
+                    LVDataPoint::Increment,
+                );
+            }
+
+            let record = *self
+                .recording_mtx
+                .lock()
+                .expect("Failed to lock recording mtx");
+            if record != packager.is_recording() {
+                packager.set_recording(record);
+            }
+
+            let next_frame = match pending_frame.take() {
+                Some(frame) => Ok(frame),
+                None => frame_recv.recv(),
+            };
+
+            match next_frame {
                 Ok(frame) => {
-                    match packager.process_frame(frame, timer.elapsed().as_millis() as u64) {
+                    // The frame is dropped at the end of this arm, which hands its buffer back
+                    // to the capturer.
+                    let encode_start = Instant::now();
+                    match packager.process_frame(&frame, timer.elapsed().as_millis() as u64) {
                         Ok(_) => {}
                         Err(e) => error!("process_frame returned {:?}", e),
                     }
+                    // A resize rebuilds the encoder, possibly with a different backend.
+                    self.encoder_changed(&packager);
+                    if let Some(id) = frame.stamp() {
+                        LVStatisticsCollector::update_data(
+                            "server_quality_bitrate",
+                            LVDataPoint::XYValue((id as f32, self.old_bitrate as f32)),
+                        );
+                    }
+
+                    // Drop the framerate if the bitrate can't go any lower or the encoder can't
+                    // keep up, and bring it back once things get better.
+                    if let Some(fps) =
+                        framerate_controller.update(self.old_bitrate, encode_start.elapsed())
+                    {
+                        match packager.update_framerate(fps) {
+                            Ok(()) => info!("framerate set to {}", fps),
+                            Err(e) => error!("Failed to set framerate with {:?}", e),
+                        }
+                        *self
+                            .framerate_mtx
+                            .lock()
+                            .expect("Failed to lock framerate mtx") = fps;
+                        LVStatisticsCollector::update_data(
+                            "server_framerate",
+                            LVDataPoint::FloatValue(fps as f32),
+                        );
+                    }
                 }
                 Err(e) => error!("frame_recv returned {:?}", e),
             }
@@ -52,6 +52,13 @@ impl LVStatistics {
     }
 
     pub fn register_data(&mut self, data_name: String, data_type: LVDataType) {
+        // Components that get rebuilt mid-session (capturers, encoders) register their data
+        // again; keep what has already been collected.
+        if self.data.contains_key(&data_name) {
+            debug!("data {:?} is already registered", data_name);
+            return;
+        }
+
         match data_type {
             LVDataType::XYData => {
                 self.data.insert(data_name, LVStoredData::XYData(vec![]));